@@ -0,0 +1,597 @@
+use super::commit::CommitOptions;
+use super::conflict::{ConflictSide, RegionChoice};
+use super::diff::default_three_dot;
+use super::error::{GitError, GitResult};
+use super::file::FileHistoryQuery;
+use super::ignore::{IgnoreFile, IgnoreKind};
+use super::log::HistoryQuery;
+use super::message::MessageRules;
+use super::partial::LineSelection;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::fmt;
+use std::ops::Deref;
+
+/// A `git_command` call: the workspace plus the action and its fields.
+#[derive(Debug, Deserialize)]
+pub struct GitRequest {
+    pub workspace: Workspace,
+    #[serde(flatten)]
+    pub action: GitAction,
+}
+
+impl GitRequest {
+    /// Merges the `action` string into the payload object and validates it.
+    pub fn parse(action: &str, payload: Value) -> GitResult<Self> {
+        let mut fields = match payload {
+            Value::Object(fields) => fields,
+            Value::Null => Map::new(),
+            _ => return Err(GitError::invalid("payload must be an object")),
+        };
+        fields.insert("action".into(), Value::String(action.into()));
+        serde_json::from_value(Value::Object(fields)).map_err(|e| GitError::invalid(e.to_string()))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(
+    tag = "action",
+    rename_all = "kebab-case",
+    rename_all_fields = "camelCase"
+)]
+pub enum GitAction {
+    Init,
+    Status,
+    #[serde(rename = "file_status")]
+    FileStatus {
+        file: RepoFile,
+    },
+    SyncStatus,
+    Stage {
+        file: RepoFile,
+    },
+    StageAll,
+    Unstage {
+        file: RepoFile,
+    },
+    UnstageAll,
+    Discard {
+        file: RepoFile,
+    },
+    DiscardAll,
+    /// Stages the chosen hunks or lines of one file.
+    StageLines(LineSelection),
+    UnstageLines(LineSelection),
+    DiscardLines(LineSelection),
+    Commit {
+        /// Optional only when amending, to keep the old message.
+        message: Option<CommitMessage>,
+        #[serde(flatten)]
+        options: CommitOptions,
+    },
+    /// `commit.template`, or the repository's `.gitmessage`.
+    CommitTemplate,
+    /// Checks a message against the commit policy without committing.
+    CheckCommitMessage {
+        message: String,
+        /// Overrides the `commitPolicy` git config.
+        rules: Option<MessageRules>,
+    },
+    /// Renames the current branch to `main`.
+    #[serde(rename = "renamebranch")]
+    RenameBranch,
+    Push {
+        #[serde(default)]
+        remote: RemoteName,
+        /// Defaults to the current branch.
+        branch: Option<BranchName>,
+    },
+    Branch,
+    /// Local and remote branches with tracking information.
+    Branches,
+    #[serde(rename = "rename-branch")]
+    RenameBranchTo {
+        /// Defaults to the current branch.
+        from: Option<BranchName>,
+        to: BranchName,
+        #[serde(default)]
+        force: bool,
+    },
+    DeleteBranch {
+        name: BranchName,
+        /// Delete even when not merged.
+        #[serde(default)]
+        force: bool,
+    },
+    DeleteRemoteBranch {
+        #[serde(default)]
+        remote: RemoteName,
+        name: BranchName,
+    },
+    Tags,
+    CreateTag {
+        name: TagName,
+        /// Defaults to HEAD.
+        target: Option<Revision>,
+        /// Makes it an annotated tag.
+        message: Option<String>,
+        #[serde(default)]
+        force: bool,
+    },
+    DeleteTag {
+        name: TagName,
+    },
+    DeleteRemoteTag {
+        #[serde(default)]
+        remote: RemoteName,
+        name: TagName,
+    },
+    /// Pushes `name`, or all tags when omitted.
+    PushTags {
+        #[serde(default)]
+        remote: RemoteName,
+        name: Option<TagName>,
+    },
+    Merge {
+        branch: Revision,
+        /// Defaults to the current branch.
+        into: Option<BranchName>,
+        #[serde(default)]
+        no_ff: bool,
+        message: Option<CommitMessage>,
+    },
+    Rebase {
+        /// Defaults to the current branch.
+        branch: Option<BranchName>,
+        onto: Revision,
+    },
+    /// Applies `commits` in order on top of HEAD.
+    CherryPick {
+        commits: Vec<Revision>,
+        /// 1-based parent to diff a merge commit against.
+        mainline: Option<u32>,
+        #[serde(default)]
+        no_commit: bool,
+    },
+    Revert {
+        commit: Revision,
+        mainline: Option<u32>,
+        #[serde(default)]
+        no_commit: bool,
+    },
+    /// Continue, abort or skip the merge, rebase, cherry-pick or revert in
+    /// progress, whichever it is.
+    ContinueOperation,
+    AbortOperation,
+    SkipOperation,
+    #[serde(rename = "create branch")]
+    CreateBranch {
+        name: BranchName,
+    },
+    Checkout {
+        name: Revision,
+        /// Check out the submodule commits the target records afterwards.
+        #[serde(default)]
+        update_submodules: bool,
+    },
+    Pull {
+        #[serde(default)]
+        remote: PullSource,
+        branch: Option<BranchName>,
+        #[serde(default)]
+        update_submodules: bool,
+    },
+    Submodules,
+    /// Registers submodules, all of them when `paths` is empty.
+    InitSubmodules {
+        #[serde(default)]
+        paths: Vec<RepoFile>,
+    },
+    UpdateSubmodules {
+        #[serde(default)]
+        paths: Vec<RepoFile>,
+        /// Initialize submodules that aren't yet, like `--init`.
+        #[serde(default)]
+        init: bool,
+        #[serde(default)]
+        recursive: bool,
+    },
+    SyncSubmodules {
+        #[serde(default)]
+        paths: Vec<RepoFile>,
+        #[serde(default)]
+        recursive: bool,
+    },
+    SetRemote {
+        #[serde(default)]
+        name: RemoteName,
+        url: RemoteUrl,
+    },
+    #[serde(rename = "remove origin")]
+    RemoveOrigin,
+    Remotes,
+    AddRemote {
+        name: RemoteName,
+        url: RemoteUrl,
+        /// Fetch it right away.
+        #[serde(default)]
+        fetch: bool,
+    },
+    RenameRemote {
+        from: RemoteName,
+        to: RemoteName,
+    },
+    RemoveRemote {
+        name: RemoteName,
+    },
+    SetRemoteUrl {
+        name: RemoteName,
+        url: RemoteUrl,
+        /// Only change the URL used for pushing.
+        #[serde(default)]
+        push: bool,
+    },
+    /// Fetches `remote`, or every remote when omitted.
+    Fetch {
+        remote: Option<RemoteName>,
+        #[serde(default)]
+        prune: bool,
+    },
+    PruneRemote {
+        #[serde(default)]
+        remote: RemoteName,
+    },
+    Worktrees,
+    /// Adds a linked worktree on `branch`, or on `newBranch` created from
+    /// `base`.
+    AddWorktree {
+        path: RepoFile,
+        branch: Option<BranchName>,
+        new_branch: Option<BranchName>,
+        base: Option<Revision>,
+        #[serde(default)]
+        lock: bool,
+    },
+    LockWorktree {
+        worktree: WorktreeRef,
+        reason: Option<String>,
+    },
+    UnlockWorktree {
+        worktree: WorktreeRef,
+    },
+    RemoveWorktree {
+        worktree: WorktreeRef,
+        #[serde(default)]
+        force: bool,
+    },
+    PruneWorktrees,
+    Graph,
+    /// A page of structured history with lane columns.
+    History(HistoryQuery),
+    /// A page of the commits that changed one file, following renames.
+    FileHistory(FileHistoryQuery),
+    /// Merge base, commits unique to each side and changed files of two refs.
+    Compare {
+        base: Revision,
+        head: Revision,
+        #[serde(default = "default_three_dot")]
+        three_dot: bool,
+        #[serde(default = "default_compare_limit")]
+        limit: usize,
+    },
+    /// A file as it was at a commit, tag or stash.
+    FileContent {
+        file: RepoFile,
+        revision: Revision,
+    },
+    /// The ignore rule that decides whether `file` is ignored.
+    ExplainIgnore {
+        file: RepoFile,
+    },
+    /// Ignores `file` by name, extension or folder.
+    AddIgnoreRule {
+        file: RepoFile,
+        kind: IgnoreKind,
+        #[serde(default)]
+        ignore_file: IgnoreFile,
+        /// Also remove the files it now ignores from the index.
+        #[serde(default)]
+        untrack: bool,
+    },
+    /// Lists conflicted files.
+    Conflicts,
+    /// Base, ours, theirs and the marked regions of one conflicted file.
+    Conflict {
+        file: RepoFile,
+    },
+    StashList,
+    StashPush {
+        message: Option<String>,
+        #[serde(default)]
+        include_untracked: bool,
+        #[serde(default)]
+        keep_index: bool,
+        /// Only stash these files, everything when empty.
+        #[serde(default)]
+        paths: Vec<RepoFile>,
+    },
+    StashApply {
+        #[serde(default)]
+        index: usize,
+        /// Also restore what was staged, like `--index`.
+        #[serde(default)]
+        reinstate_index: bool,
+    },
+    StashPop {
+        #[serde(default)]
+        index: usize,
+        #[serde(default)]
+        reinstate_index: bool,
+    },
+    StashDrop {
+        #[serde(default)]
+        index: usize,
+    },
+    StashShow {
+        #[serde(default)]
+        index: usize,
+        #[serde(default = "default_context_lines")]
+        context_lines: u32,
+    },
+    ResolveConflict {
+        file: RepoFile,
+        #[serde(default)]
+        regions: Vec<RegionChoice>,
+        /// Resolve the whole file to one side instead of region by region.
+        take: Option<ConflictSide>,
+    },
+    /// Movements of HEAD, or of `reference`, newest first.
+    Reflog {
+        reference: Option<Revision>,
+        #[serde(default = "default_reflog_limit")]
+        limit: usize,
+    },
+    /// Safety refs recorded before destructive actions, newest first.
+    UndoHistory,
+    /// Restores the state from before the last destructive action.
+    Undo,
+}
+
+fn default_context_lines() -> u32 {
+    3
+}
+
+fn default_compare_limit() -> usize {
+    250
+}
+
+fn default_reflog_limit() -> usize {
+    100
+}
+
+/// Declares a `String` newtype that rejects blank values when deserialized,
+/// or values `$valid` returns false for, with `$message` formatted with the
+/// value.
+macro_rules! non_empty_string {
+    ($(#[$meta:meta])* $name:ident, $message:literal) => {
+        non_empty_string!(@define $(#[$meta])* $name, |value: &str| {
+            if value.trim().is_empty() {
+                return Err($message.into());
+            }
+            Ok(())
+        });
+    };
+    ($(#[$meta:meta])* $name:ident, $message:literal, $valid:expr) => {
+        non_empty_string!(@define $(#[$meta])* $name, |value: &str| {
+            let valid: fn(&str) -> bool = $valid;
+            if !valid(value) {
+                return Err(format!($message, value));
+            }
+            Ok(())
+        });
+    };
+    (@define $(#[$meta:meta])* $name:ident, $check:expr) => {
+        $(#[$meta])*
+        #[derive(Debug, Clone, Deserialize)]
+        #[serde(try_from = "String")]
+        pub struct $name(String);
+
+        impl TryFrom<String> for $name {
+            type Error = String;
+
+            fn try_from(value: String) -> Result<Self, Self::Error> {
+                let check: fn(&str) -> Result<(), String> = $check;
+                check(&value)?;
+                Ok($name(value))
+            }
+        }
+
+        impl Deref for $name {
+            type Target = str;
+
+            fn deref(&self) -> &str {
+                &self.0
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str(&self.0)
+            }
+        }
+    };
+}
+
+non_empty_string!(Workspace, "Missing workspace path");
+non_empty_string!(
+    /// A file path, absolute or relative to the workspace.
+    RepoFile,
+    "file path must not be empty"
+);
+non_empty_string!(
+    /// Anything `git rev-parse` understands: a branch, tag or commit.
+    Revision,
+    "revision must not be empty"
+);
+non_empty_string!(CommitMessage, "commit message must not be empty");
+non_empty_string!(RemoteUrl, "remote url must not be empty");
+non_empty_string!(
+    /// A linked worktree's name or path.
+    WorktreeRef,
+    "worktree must not be empty"
+);
+
+non_empty_string!(
+    /// A name that is valid as `refs/heads/<name>`.
+    BranchName,
+    "'{}' is not a valid branch name",
+    |name| git2::Branch::name_is_valid(name).unwrap_or(false)
+);
+non_empty_string!(
+    /// A name that is valid as `refs/tags/<name>`.
+    TagName,
+    "'{}' is not a valid tag name",
+    |name| git2::Reference::is_valid_name(&format!("refs/tags/{}", name))
+);
+non_empty_string!(
+    /// A remote name, `origin` when omitted.
+    RemoteName,
+    "'{}' is not a valid remote name",
+    git2::Remote::is_valid_name
+);
+
+impl Default for RemoteName {
+    fn default() -> Self {
+        RemoteName("origin".into())
+    }
+}
+
+non_empty_string!(
+    /// A remote name, or a URL to pull from without configuring a remote
+    /// like `git pull <url>`. `origin` when omitted.
+    PullSource,
+    "'{}' is not a valid remote name or url",
+    |source| {
+        // URLs, scp-like `host:path` and local paths all have a separator
+        let url = !source.trim().is_empty() && source.contains([':', '/', '\\']);
+        url || git2::Remote::is_valid_name(source)
+    }
+);
+
+impl Default for PullSource {
+    fn default() -> Self {
+        PullSource("origin".into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git::GitErrorKind;
+    use serde_json::json;
+
+    fn parse(action: &str, payload: Value) -> GitResult<GitAction> {
+        GitRequest::parse(action, payload).map(|request| request.action)
+    }
+
+    #[test]
+    fn merges_action_into_payload() {
+        let request =
+            GitRequest::parse("stage", json!({ "workspace": "/w", "file": "a" })).unwrap();
+        assert_eq!(&*request.workspace, "/w");
+        assert!(matches!(request.action, GitAction::Stage { file } if &*file == "a"));
+    }
+
+    #[test]
+    fn keeps_legacy_action_names() {
+        let w = || json!({ "workspace": "/w", "name": "x" });
+        assert!(matches!(
+            parse("file_status", json!({ "workspace": "/w", "file": "a" })),
+            Ok(GitAction::FileStatus { .. })
+        ));
+        assert!(matches!(
+            parse("create branch", w()),
+            Ok(GitAction::CreateBranch { .. })
+        ));
+        assert!(matches!(
+            parse("renamebranch", w()),
+            Ok(GitAction::RenameBranch)
+        ));
+        assert!(matches!(
+            parse("remove origin", w()),
+            Ok(GitAction::RemoveOrigin)
+        ));
+    }
+
+    #[test]
+    fn rejects_invalid_requests() {
+        let invalid = |action: &str, payload: Value| {
+            parse(action, payload).map(|_| ()).unwrap_err().kind == GitErrorKind::InvalidRequest
+        };
+        assert!(invalid("status", json!({})));
+        assert!(invalid("status", json!({ "workspace": "  " })));
+        assert!(invalid("status", json!("/w")));
+        assert!(invalid("no-such-action", json!({ "workspace": "/w" })));
+        assert!(invalid("stage", json!({ "workspace": "/w" })));
+        assert!(invalid(
+            "create branch",
+            json!({ "workspace": "/w", "name": "a..b" })
+        ));
+        assert!(invalid(
+            "push",
+            json!({ "workspace": "/w", "remote": "bad name" })
+        ));
+        assert!(invalid(
+            "commit",
+            json!({ "workspace": "/w", "message": " " })
+        ));
+        assert!(invalid(
+            "create-tag",
+            json!({ "workspace": "/w", "name": "v1 ^" })
+        ));
+        assert!(invalid(
+            "reflog",
+            json!({ "workspace": "/w", "reference": "" })
+        ));
+    }
+
+    #[test]
+    fn pull_takes_remote_name_or_url() {
+        let remote = |payload: Value| match parse("pull", payload) {
+            Ok(GitAction::Pull { remote, .. }) => Ok(remote.to_string()),
+            Ok(action) => panic!("parsed as {:?}", action),
+            Err(e) => Err(e.kind),
+        };
+        assert_eq!(remote(json!({ "workspace": "/w" })), Ok("origin".into()));
+        for source in [
+            "upstream",
+            "https://github.com/a/b.git",
+            "git@github.com:a/b.git",
+            "../b",
+        ] {
+            assert_eq!(
+                remote(json!({ "workspace": "/w", "remote": source })),
+                Ok(source.into())
+            );
+        }
+        assert_eq!(
+            remote(json!({ "workspace": "/w", "remote": "bad name" })),
+            Err(GitErrorKind::InvalidRequest)
+        );
+    }
+
+    #[test]
+    fn fills_defaults() {
+        match parse("stash-push", json!({ "workspace": "/w" })).unwrap() {
+            GitAction::StashPush {
+                message,
+                include_untracked,
+                keep_index,
+                paths,
+            } => {
+                assert!(message.is_none() && !include_untracked && !keep_index && paths.is_empty());
+            }
+            action => panic!("parsed as {:?}", action),
+        }
+    }
+}
@@ -0,0 +1,324 @@
+use super::action::{RepoFile, Revision, Workspace};
+use super::{head_commit, open, repo_path, short_id, GitError, GitErrorKind, GitResult};
+use git2::{Blame, BlameOptions, Oid, Repository};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BlameRequest {
+    pub workspace: Workspace,
+    pub file: RepoFile,
+    /// Blame the file as of this commit instead of the working tree.
+    pub revision: Option<Revision>,
+    /// Unsaved editor content, blamed against HEAD.
+    pub contents: Option<String>,
+    /// When set, lines are emitted in `git-blame-chunk` events tagged with
+    /// this id instead of being returned all at once.
+    pub request_id: Option<String>,
+    #[serde(default = "default_chunk_lines")]
+    pub chunk_lines: usize,
+}
+
+fn default_chunk_lines() -> usize {
+    500
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct BlameLine {
+    /// 1-based line in the blamed content.
+    pub line: usize,
+    pub hash: String,
+    pub short_hash: String,
+    pub author: String,
+    pub author_email: String,
+    /// Seconds since the epoch.
+    pub author_time: i64,
+    pub summary: String,
+    /// Line number in the commit that introduced it.
+    pub orig_line: usize,
+    pub orig_path: Option<String>,
+    /// The line only exists in the working tree or the unsaved buffer.
+    pub uncommitted: bool,
+}
+
+/// Payload of the `git-blame-chunk` event.
+#[derive(Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct BlameChunk {
+    pub request_id: String,
+    pub lines: Vec<BlameLine>,
+    pub done: bool,
+}
+
+/// Blames a file and hands its lines to `on_chunk` in order, `chunk_lines`
+/// at a time, the last chunk with `done` set. libgit2 has no incremental
+/// blame, so the history is walked once for the whole file and the chunks
+/// spread out looking up the commits and sending the lines. Returns the
+/// line count.
+pub fn blame(
+    request: &BlameRequest,
+    mut on_chunk: impl FnMut(Vec<BlameLine>, bool),
+) -> GitResult<usize> {
+    let repo = open(&request.workspace)?;
+    let path = repo_path(&repo, &request.file);
+    let mut commits = CommitCache::default();
+    let mut send = |total: usize, line: &mut dyn FnMut(usize) -> BlameLine| {
+        let chunk_lines = request.chunk_lines.max(1);
+        if total == 0 {
+            on_chunk(Vec::new(), true);
+        }
+        let mut start = 1;
+        while start <= total {
+            let end = (start + chunk_lines - 1).min(total);
+            on_chunk((start..=end).map(&mut *line).collect(), end == total);
+            start = end + 1;
+        }
+        total
+    };
+
+    if let Some(revision) = &request.revision {
+        let commit = repo.revparse_single(revision)?.peel_to_commit()?;
+        let blob = commit
+            .tree()?
+            .get_path(Path::new(&path))
+            .and_then(|entry| entry.to_object(&repo))
+            .map_err(|_| not_found(&path, revision))?
+            .peel_to_blob()?;
+        let mut options = BlameOptions::new();
+        options.newest_commit(commit.id());
+        let blame = repo.blame_file(Path::new(&path), Some(&mut options))?;
+        return Ok(send(line_count(blob.content()), &mut |line| {
+            blame_line(&repo, &blame, line, &mut commits)
+        }));
+    }
+
+    // The working tree file or the editor buffer, on top of HEAD
+    let contents = match &request.contents {
+        Some(contents) => contents.clone().into_bytes(),
+        None => {
+            let workdir = repo
+                .workdir()
+                .ok_or_else(|| GitError::invalid("cannot blame in a bare repository"))?;
+            std::fs::read(workdir.join(&path)).map_err(|e| GitError::other(e.to_string()))?
+        }
+    };
+    let total = line_count(&contents);
+    let committed = match head_commit(&repo)? {
+        Some(head) => head.tree()?.get_path(Path::new(&path)).is_ok(),
+        None => false,
+    };
+    let head_blame = if committed {
+        Some(repo.blame_file(Path::new(&path), None)?)
+    } else {
+        None
+    };
+    let blame = match &head_blame {
+        Some(head_blame) => Some(head_blame.blame_buffer(&contents)?),
+        None => None,
+    };
+
+    Ok(send(total, &mut |line| match &blame {
+        Some(blame) => blame_line(&repo, blame, line, &mut commits),
+        None => uncommitted(line),
+    }))
+}
+
+pub(crate) fn not_found(path: &str, revision: &str) -> GitError {
+    GitError::new(
+        GitErrorKind::NotFound,
+        format!("path '{}' does not exist in '{}'", path, revision),
+    )
+}
+
+/// Lines the way blame counts them: a last line without a newline counts.
+fn line_count(contents: &[u8]) -> usize {
+    contents.split_inclusive(|b| *b == b'\n').count()
+}
+
+/// What every line of a commit repeats, looked up once per commit.
+#[derive(Clone, Default)]
+struct CommitInfo {
+    short_hash: String,
+    author: String,
+    author_email: String,
+    author_time: i64,
+    summary: String,
+}
+
+type CommitCache = HashMap<Oid, CommitInfo>;
+
+fn blame_line(repo: &Repository, blame: &Blame, line: usize, cache: &mut CommitCache) -> BlameLine {
+    let hunk = match blame.get_line(line) {
+        Some(hunk) => hunk,
+        None => return uncommitted(line),
+    };
+    let id = hunk.final_commit_id();
+    if id.is_zero() {
+        return uncommitted(line);
+    }
+    let info = cache
+        .entry(id)
+        .or_insert_with(|| match repo.find_commit(id) {
+            Ok(commit) => CommitInfo {
+                short_hash: short_id(repo, id),
+                author: commit.author().name().unwrap_or("").to_string(),
+                author_email: commit.author().email().unwrap_or("").to_string(),
+                author_time: commit.author().when().seconds(),
+                summary: commit.summary().unwrap_or("").to_string(),
+            },
+            Err(_) => CommitInfo::default(),
+        })
+        .clone();
+    BlameLine {
+        line,
+        hash: id.to_string(),
+        short_hash: info.short_hash,
+        author: info.author,
+        author_email: info.author_email,
+        author_time: info.author_time,
+        summary: info.summary,
+        orig_line: hunk.orig_start_line() + (line - hunk.final_start_line()),
+        orig_path: hunk.path().map(|p| p.to_string_lossy().replace('\\', "/")),
+        uncommitted: false,
+    }
+}
+
+fn uncommitted(line: usize) -> BlameLine {
+    BlameLine {
+        line,
+        hash: Oid::zero().to_string(),
+        short_hash: "0000000".into(),
+        author: "Not Committed Yet".into(),
+        author_email: String::new(),
+        author_time: 0,
+        summary: String::new(),
+        orig_line: line,
+        orig_path: None,
+        uncommitted: true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git::testing::TestRepo;
+    use serde_json::json;
+
+    fn chunks(t: &TestRepo, request: serde_json::Value) -> GitResult<Vec<(Vec<BlameLine>, bool)>> {
+        let mut request = request;
+        request["workspace"] = t.url().into();
+        let mut chunks = Vec::new();
+        blame(&serde_json::from_value(request).unwrap(), |lines, done| {
+            chunks.push((lines, done))
+        })?;
+        Ok(chunks)
+    }
+
+    fn run(t: &TestRepo, request: serde_json::Value) -> GitResult<Vec<BlameLine>> {
+        Ok(chunks(t, request)?
+            .into_iter()
+            .flat_map(|(lines, _)| lines)
+            .collect())
+    }
+
+    fn hashes(lines: &[BlameLine]) -> Vec<String> {
+        lines.iter().map(|l| l.hash.clone()).collect()
+    }
+
+    #[test]
+    fn counts_lines_like_blame() {
+        assert_eq!(line_count(b""), 0);
+        assert_eq!(line_count(b"a"), 1);
+        assert_eq!(line_count(b"a\n"), 1);
+        assert_eq!(line_count(b"a\nb"), 2);
+    }
+
+    #[test]
+    fn sends_chunks_in_order() {
+        let t = TestRepo::new();
+        let text: String = (1..=7).map(|i| format!("{}\n", i)).collect();
+        t.write("a.txt", &text);
+        let id = t.commit("init").to_string();
+        for request in [
+            json!({ "file": "a.txt", "chunkLines": 3 }),
+            json!({ "file": "a.txt", "chunkLines": 3, "revision": "HEAD" }),
+        ] {
+            let chunks = chunks(&t, request).unwrap();
+            let sizes: Vec<(usize, bool)> = chunks.iter().map(|(l, d)| (l.len(), *d)).collect();
+            assert_eq!(sizes, [(3, false), (3, false), (1, true)]);
+            let lines: Vec<&BlameLine> = chunks.iter().flat_map(|(l, _)| l).collect();
+            assert_eq!(
+                lines.iter().map(|l| l.line).collect::<Vec<_>>(),
+                (1..=7).collect::<Vec<_>>()
+            );
+            assert!(lines.iter().all(|l| l.hash == id));
+        }
+
+        t.write("empty.txt", "");
+        let chunks = chunks(&t, json!({ "file": "empty.txt" })).unwrap();
+        assert_eq!(chunks.len(), 1);
+        assert!(chunks[0].0.is_empty() && chunks[0].1);
+    }
+
+    #[test]
+    fn blames_revision() {
+        let t = TestRepo::new();
+        t.write("a.txt", "one\ntwo\n");
+        let first = t.commit("first").to_string();
+        t.write("a.txt", "one\n2\nthree\n");
+        let second = t.commit("second").to_string();
+
+        let lines = run(&t, json!({ "file": "a.txt", "revision": "HEAD" })).unwrap();
+        assert_eq!(hashes(&lines), [first.as_str(), &second, &second]);
+        assert_eq!(lines[1].summary, "second");
+        assert_eq!(lines[1].author, "Test");
+        assert_eq!(lines[0].orig_line, 1);
+
+        let lines = run(&t, json!({ "file": "a.txt", "revision": first })).unwrap();
+        assert_eq!(hashes(&lines), [first.as_str(), &first]);
+    }
+
+    #[test]
+    fn working_tree_and_buffer_edits_are_uncommitted() {
+        let t = TestRepo::new();
+        t.write("a.txt", "one\ntwo\n");
+        let first = t.commit("first").to_string();
+        t.write("a.txt", "zero\none\ntwo\n");
+
+        let lines = run(&t, json!({ "file": "a.txt" })).unwrap();
+        assert!(lines[0].uncommitted);
+        assert_eq!(lines[1].hash, first);
+        assert_eq!((lines[1].line, lines[1].orig_line), (2, 1));
+
+        let lines = run(
+            &t,
+            json!({ "file": "a.txt", "contents": "one\nnew\ntwo\n" }),
+        )
+        .unwrap();
+        assert_eq!(lines.len(), 3);
+        assert!(!lines[0].uncommitted && lines[1].uncommitted && !lines[2].uncommitted);
+    }
+
+    #[test]
+    fn untracked_file_is_all_uncommitted() {
+        let t = TestRepo::new();
+        t.write("a.txt", "a\n");
+        t.commit("init");
+        t.write("new.txt", "x\ny\n");
+        let lines = run(&t, json!({ "file": "new.txt" })).unwrap();
+        assert_eq!(lines.len(), 2);
+        assert!(lines.iter().all(|l| l.uncommitted));
+    }
+
+    #[test]
+    fn missing_path_at_revision() {
+        let t = TestRepo::new();
+        t.write("a.txt", "a\n");
+        t.commit("init");
+        let err = run(&t, json!({ "file": "b.txt", "revision": "HEAD" })).unwrap_err();
+        assert_eq!(err.kind, GitErrorKind::NotFound);
+    }
+}
@@ -0,0 +1,493 @@
+use super::status::conflicted_paths;
+use super::{current_branch, head_commit, remote, short_id, GitError, GitErrorKind, GitResult};
+use git2::{
+    build::CheckoutBuilder, AnnotatedCommit, Branch, BranchType, Commit, ErrorCode, Oid,
+    RebaseOptions, Repository,
+};
+use serde_json::{json, Value};
+
+/// What a merge did to the checked out branch.
+pub(crate) enum MergeOutcome {
+    UpToDate,
+    FastForward(Oid),
+    Merged(Oid),
+    /// The merge stopped with these paths conflicted, `MERGE_HEAD` is set.
+    Conflicts(Vec<String>),
+}
+
+/// Merges `incoming` into HEAD, fast-forwarding when possible unless `no_ff`.
+pub(crate) fn merge_commit(
+    repo: &Repository,
+    incoming: &AnnotatedCommit,
+    message: &str,
+    reflog: &str,
+    no_ff: bool,
+) -> GitResult<MergeOutcome> {
+    let (analysis, _) = repo.merge_analysis(&[incoming])?;
+    if analysis.is_up_to_date() {
+        return Ok(MergeOutcome::UpToDate);
+    }
+    let target = repo.find_commit(incoming.id())?;
+
+    if analysis.is_unborn() || (analysis.is_fast_forward() && !no_ff) {
+        repo.checkout_tree(target.as_object(), Some(CheckoutBuilder::new().safe()))?;
+        match current_branch(repo) {
+            Some(branch) => {
+                repo.reference(&format!("refs/heads/{}", branch), target.id(), true, reflog)?;
+            }
+            None => repo.set_head_detached(target.id())?,
+        }
+        return Ok(MergeOutcome::FastForward(target.id()));
+    }
+
+    repo.merge(&[incoming], None, Some(CheckoutBuilder::new().safe()))?;
+    let mut index = repo.index()?;
+    if index.has_conflicts() {
+        return Ok(MergeOutcome::Conflicts(conflicted_paths(repo)?));
+    }
+
+    let signature = repo.signature()?;
+    let tree = repo.find_tree(index.write_tree()?)?;
+    let head = head_commit(repo)?
+        .ok_or_else(|| GitError::new(GitErrorKind::NotFound, "HEAD has no commits"))?;
+    let id = repo.commit(
+        Some("HEAD"),
+        &signature,
+        &signature,
+        message,
+        &tree,
+        &[&head, &target],
+    )?;
+    repo.cleanup_state()?;
+    Ok(MergeOutcome::Merged(id))
+}
+
+fn commit_json(repo: &Repository, commit: &Commit) -> Value {
+    json!({
+        "hash": commit.id().to_string(),
+        "shortHash": short_id(repo, commit.id()),
+        "summary": commit.summary().unwrap_or(""),
+        "author": commit.author().name().unwrap_or(""),
+        "date": commit.time().seconds(),
+    })
+}
+
+/// Whether `tip` is already contained in `base`.
+fn is_merged(repo: &Repository, tip: Oid, base: Option<Oid>) -> bool {
+    match base {
+        Some(base) => tip == base || repo.graph_descendant_of(base, tip).unwrap_or(false),
+        None => false,
+    }
+}
+
+/// Local and remote-tracking branches with tracking and merge information.
+pub fn list(repo: &Repository) -> GitResult<Value> {
+    let current = current_branch(repo);
+    let head = head_commit(repo)?.map(|c| c.id());
+
+    let mut local = Vec::new();
+    for branch in repo.branches(Some(BranchType::Local))? {
+        let (branch, _) = branch?;
+        let name = match branch.name()? {
+            Some(name) => name.to_string(),
+            None => continue,
+        };
+        let commit = branch.get().peel_to_commit()?;
+        let refname = format!("refs/heads/{}", name);
+        // Configured but missing upstream, what `git branch -vv` shows as gone
+        let configured = repo.branch_upstream_name(&refname).is_ok();
+        let upstream = branch.upstream().ok();
+        let (ahead, behind) = match upstream.as_ref().and_then(|u| u.get().target()) {
+            Some(upstream_id) => repo.graph_ahead_behind(commit.id(), upstream_id)?,
+            None => (0, 0),
+        };
+        let upstream_name = upstream
+            .as_ref()
+            .and_then(|u| u.name().ok().flatten().map(String::from));
+        local.push(json!({
+            "name": name,
+            "isHead": current.as_deref() == Some(name.as_str()),
+            "upstream": upstream_name,
+            "upstreamGone": configured && upstream.is_none(),
+            "ahead": ahead,
+            "behind": behind,
+            "lastCommit": commit_json(repo, &commit),
+            "merged": is_merged(repo, commit.id(), head),
+        }));
+    }
+
+    let mut remote = Vec::new();
+    for branch in repo.branches(Some(BranchType::Remote))? {
+        let (branch, _) = branch?;
+        let name = match branch.name()? {
+            Some(name) if !name.ends_with("/HEAD") => name.to_string(),
+            _ => continue,
+        };
+        let commit = match branch.get().peel_to_commit() {
+            Ok(commit) => commit,
+            Err(_) => continue,
+        };
+        let (remote_name, short_name) = name.split_once('/').unwrap_or(("", &name));
+        remote.push(json!({
+            "name": name,
+            "remote": remote_name,
+            "branch": short_name,
+            "lastCommit": commit_json(repo, &commit),
+            "merged": is_merged(repo, commit.id(), head),
+        }));
+    }
+
+    Ok(json!({
+        "current": current,
+        "detached": repo.head_detached().unwrap_or(false),
+        "local": local,
+        "remote": remote,
+    }))
+}
+
+/// Renames `from`, the current branch when omitted.
+pub fn rename(repo: &Repository, from: Option<&str>, to: &str, force: bool) -> GitResult<Value> {
+    let current = current_branch(repo);
+    let from = match from {
+        Some(from) => from.to_string(),
+        None => current
+            .clone()
+            .ok_or_else(|| GitError::invalid("HEAD is detached"))?,
+    };
+    match repo.find_branch(&from, BranchType::Local) {
+        Ok(mut branch) => {
+            branch.rename(to, force)?;
+        }
+        // No commits yet, so only HEAD has to point at the new name
+        Err(e) if e.code() == ErrorCode::NotFound && current.as_deref() == Some(&from) => {
+            repo.set_head(&format!("refs/heads/{}", to))?;
+        }
+        Err(e) => return Err(e.into()),
+    }
+    Ok(json!({ "from": from, "to": to }))
+}
+
+/// Deletes a local branch. Without `force` it has to be merged into its
+/// upstream, or into HEAD when it has none, like `git branch -d`.
+pub fn delete(repo: &Repository, name: &str, force: bool) -> GitResult<Value> {
+    if current_branch(repo).as_deref() == Some(name) {
+        return Err(GitError::invalid(format!(
+            "Cannot delete branch '{}' checked out",
+            name
+        )));
+    }
+    let mut branch = repo.find_branch(name, BranchType::Local)?;
+    let tip = branch.get().peel_to_commit()?.id();
+    if !force {
+        let base = match branch.upstream() {
+            Ok(upstream) => upstream.get().target(),
+            Err(_) => head_commit(repo)?.map(|c| c.id()),
+        };
+        if !is_merged(repo, tip, base) {
+            return Err(GitError::new(
+                GitErrorKind::NotMerged,
+                format!(
+                    "The branch '{}' is not fully merged. Delete it with force to lose its commits.",
+                    name
+                ),
+            ));
+        }
+    }
+    branch.delete()?;
+    println!("Deleted branch {} (was {})", name, short_id(repo, tip));
+    Ok(json!({ "name": name, "hash": tip.to_string() }))
+}
+
+/// `git push <remote> --delete <name>`, also dropping the tracking ref.
+pub fn delete_remote(repo: &Repository, remote_name: &str, name: &str) -> GitResult<Value> {
+    remote::push_refspecs(repo, remote_name, &[format!(":refs/heads/{}", name)])?;
+    if let Ok(mut tracking) =
+        repo.find_branch(&format!("{}/{}", remote_name, name), BranchType::Remote)
+    {
+        tracking.delete()?;
+    }
+    Ok(json!({ "remote": remote_name, "name": name }))
+}
+
+/// Merges `branch` into `into`, checking `into` out first when it isn't
+/// the current branch.
+pub fn merge(
+    repo: &Repository,
+    branch: &str,
+    into: Option<&str>,
+    no_ff: bool,
+    message: Option<&str>,
+) -> GitResult<Value> {
+    if let Some(into) = into {
+        if current_branch(repo).as_deref() != Some(into) {
+            super::checkout(repo, into)?;
+        }
+    }
+    let target = current_branch(repo).unwrap_or_else(|| "HEAD".into());
+    let incoming = repo.revparse_single(branch)?.peel_to_commit()?;
+    let incoming = repo.find_annotated_commit(incoming.id())?;
+    let message = match message {
+        Some(message) => message.to_string(),
+        None if target == "main" || target == "master" => format!("Merge branch '{}'", branch),
+        None => format!("Merge branch '{}' into {}", branch, target),
+    };
+
+    let outcome = merge_commit(
+        repo,
+        &incoming,
+        &message,
+        &format!("merge {}: Fast-forward", branch),
+        no_ff,
+    )?;
+    Ok(match outcome {
+        MergeOutcome::UpToDate => {
+            let head = head_commit(repo)?.map(|c| c.id().to_string());
+            json!({ "result": "upToDate", "head": head })
+        }
+        MergeOutcome::FastForward(id) => json!({ "result": "fastForward", "head": id.to_string() }),
+        MergeOutcome::Merged(id) => json!({ "result": "merged", "head": id.to_string() }),
+        MergeOutcome::Conflicts(conflicts) => {
+            let head = head_commit(repo)?.map(|c| c.id().to_string());
+            json!({ "result": "conflicts", "head": head, "conflicts": conflicts })
+        }
+    })
+}
+
+/// Replays `branch`, the current branch when omitted, on top of `onto`.
+/// Stops at the first conflicting commit, leaving the rebase in progress.
+pub fn rebase(repo: &Repository, branch: Option<&str>, onto: &str) -> GitResult<Value> {
+    let onto = repo.revparse_single(onto)?.peel_to_commit()?;
+    let branch_ref = match branch {
+        Some(name) => Some(repo.find_branch(name, BranchType::Local)?),
+        None => None,
+    };
+    let tip = match &branch_ref {
+        Some(branch) => branch.get().peel_to_commit()?.id(),
+        None => head_commit(repo)?
+            .ok_or_else(|| GitError::new(GitErrorKind::NotFound, "HEAD has no commits"))?
+            .id(),
+    };
+    if is_merged(repo, onto.id(), Some(tip)) {
+        return Ok(json!({ "result": "upToDate", "head": tip.to_string() }));
+    }
+
+    let upstream = repo.find_annotated_commit(onto.id())?;
+    let annotated = match &branch_ref {
+        Some(branch) => Some(annotated_branch(repo, branch)?),
+        None => None,
+    };
+    let mut options = RebaseOptions::new();
+    let mut rebase = repo.rebase(
+        annotated.as_ref(),
+        Some(&upstream),
+        None,
+        Some(&mut options),
+    )?;
+    let signature = repo.signature()?;
+    let total = rebase.len();
+    let mut applied = 0;
+    while let Some(operation) = rebase.next() {
+        let operation = match operation {
+            Ok(operation) => operation,
+            // Nothing was replayed yet, so don't leave a half started rebase
+            Err(e) if applied == 0 => {
+                let _ = rebase.abort();
+                return Err(e.into());
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if repo.index()?.has_conflicts() {
+            return Ok(json!({
+                "result": "conflicts",
+                "applied": applied,
+                "total": total,
+                "stoppedAt": operation.id().to_string(),
+                "conflicts": conflicted_paths(repo)?,
+            }));
+        }
+        match rebase.commit(None, &signature, None) {
+            Ok(_) => applied += 1,
+            // Already upstream, git drops these as well
+            Err(e) if e.code() == ErrorCode::Applied => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    rebase.finish(Some(&signature))?;
+
+    let head = head_commit(repo)?.map(|c| c.id().to_string());
+    let result = if total == 0 { "fastForward" } else { "rebased" };
+    Ok(json!({
+        "result": result,
+        "applied": applied,
+        "total": total,
+        "head": head,
+    }))
+}
+
+fn annotated_branch<'r>(repo: &'r Repository, branch: &Branch) -> GitResult<AnnotatedCommit<'r>> {
+    Ok(repo.reference_to_annotated_commit(branch.get())?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git::testing::TestRepo;
+
+    /// `main` and `feature` both one commit ahead of `base`.
+    fn diverged() -> TestRepo {
+        let t = TestRepo::new();
+        t.write("base.txt", "base\n");
+        let base = t.commit("base");
+        t.repo
+            .branch("feature", &t.repo.find_commit(base).unwrap(), false)
+            .unwrap();
+        t.write("main.txt", "main\n");
+        t.commit("on main");
+        t.switch("feature");
+        t.write("feature.txt", "feature\n");
+        t.commit("on feature");
+        t.switch("main");
+        t
+    }
+
+    fn local<'a>(list: &'a Value, name: &str) -> &'a Value {
+        list["local"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|b| b["name"] == name)
+            .unwrap()
+    }
+
+    #[test]
+    fn lists_tracking_and_merge_state() {
+        let t = diverged();
+        t.repo
+            .remote("origin", "https://example.com/repo.git")
+            .unwrap();
+        let mut feature = t.repo.find_branch("feature", BranchType::Local).unwrap();
+        let main = t.repo.refname_to_id("refs/heads/main").unwrap();
+        t.repo
+            .reference("refs/remotes/origin/feature", main, true, "test")
+            .unwrap();
+        feature.set_upstream(Some("origin/feature")).unwrap();
+
+        let list = list(&t.repo).unwrap();
+        assert_eq!(list["current"], "main");
+        let feature = local(&list, "feature");
+        assert_eq!(feature["upstream"], "origin/feature");
+        assert_eq!(
+            (feature["ahead"].as_u64(), feature["behind"].as_u64()),
+            (Some(1), Some(1))
+        );
+        assert_eq!(feature["merged"], false);
+        assert_eq!(local(&list, "main")["isHead"], true);
+        assert_eq!(list["remote"][0]["name"], "origin/feature");
+        assert_eq!(list["remote"][0]["merged"], true);
+
+        t.repo
+            .find_reference("refs/remotes/origin/feature")
+            .unwrap()
+            .delete()
+            .unwrap();
+        let list = super::list(&t.repo).unwrap();
+        assert_eq!(local(&list, "feature")["upstreamGone"], true);
+    }
+
+    #[test]
+    fn rename_current_and_other() {
+        let t = diverged();
+        rename(&t.repo, None, "trunk", false).unwrap();
+        assert_eq!(current_branch(&t.repo).as_deref(), Some("trunk"));
+        let err = rename(&t.repo, Some("feature"), "trunk", false).unwrap_err();
+        assert_eq!(err.kind, GitErrorKind::AlreadyExists);
+        rename(&t.repo, Some("feature"), "topic", false).unwrap();
+        assert!(t.repo.find_branch("topic", BranchType::Local).is_ok());
+    }
+
+    #[test]
+    fn rename_unborn_branch() {
+        let t = TestRepo::new();
+        rename(&t.repo, None, "trunk", false).unwrap();
+        assert_eq!(current_branch(&t.repo).as_deref(), Some("trunk"));
+    }
+
+    #[test]
+    fn delete_needs_force_when_unmerged() {
+        let t = diverged();
+        let err = delete(&t.repo, "feature", false).unwrap_err();
+        assert_eq!(err.kind, GitErrorKind::NotMerged);
+        let err = delete(&t.repo, "main", true).unwrap_err();
+        assert_eq!(err.kind, GitErrorKind::InvalidRequest);
+        let deleted = delete(&t.repo, "feature", true).unwrap();
+        assert!(deleted["hash"].is_string());
+        assert!(t.repo.find_branch("feature", BranchType::Local).is_err());
+    }
+
+    #[test]
+    fn merge_fast_forward_and_no_ff() {
+        let t = diverged();
+        t.repo
+            .branch("behind", &t.repo.find_commit(t.head()).unwrap(), false)
+            .unwrap();
+        t.write("more.txt", "more\n");
+        let tip = t.commit("more");
+
+        let result = merge(&t.repo, "main", Some("behind"), false, None).unwrap();
+        assert_eq!(result["result"], "fastForward");
+        assert_eq!(result["head"], tip.to_string());
+        assert_eq!(current_branch(&t.repo).as_deref(), Some("behind"));
+
+        let result = merge(&t.repo, "main", None, true, None).unwrap();
+        assert_eq!(result["result"], "upToDate");
+    }
+
+    #[test]
+    fn merge_creates_merge_commit() {
+        let t = diverged();
+        let result = merge(&t.repo, "feature", None, false, None).unwrap();
+        assert_eq!(result["result"], "merged");
+        let head = t.repo.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(head.parent_count(), 2);
+        assert_eq!(head.message(), Some("Merge branch 'feature'"));
+        assert_eq!(t.read("feature.txt"), "feature\n");
+        assert_eq!(t.repo.state(), git2::RepositoryState::Clean);
+    }
+
+    #[test]
+    fn merge_reports_conflicts() {
+        let t = TestRepo::new();
+        t.write("c.txt", "base\n");
+        let base = t.commit("base");
+        t.repo
+            .branch("feature", &t.repo.find_commit(base).unwrap(), false)
+            .unwrap();
+        t.write("c.txt", "main\n");
+        t.commit("main");
+        t.switch("feature");
+        t.write("c.txt", "feature\n");
+        t.commit("feature");
+        t.switch("main");
+
+        let result = merge(&t.repo, "feature", None, false, None).unwrap();
+        assert_eq!(result["result"], "conflicts");
+        assert_eq!(result["conflicts"], json!(["c.txt"]));
+        assert_eq!(t.repo.state(), git2::RepositoryState::Merge);
+    }
+
+    #[test]
+    fn rebase_replays_commits() {
+        let t = diverged();
+        let main = t.head();
+        let result = rebase(&t.repo, Some("feature"), "main").unwrap();
+        assert_eq!(result["result"], "rebased");
+        assert_eq!(result["applied"], 1);
+        let feature = t.repo.find_branch("feature", BranchType::Local).unwrap();
+        let tip = feature.get().peel_to_commit().unwrap();
+        assert_eq!(tip.parent_id(0).unwrap(), main);
+        assert_eq!(tip.summary(), Some("on feature"));
+
+        let result = rebase(&t.repo, Some("feature"), "main").unwrap();
+        assert_eq!(result["result"], "upToDate");
+    }
+}
@@ -0,0 +1,606 @@
+use super::action::RepoFile;
+use super::{current_branch, head_commit, merge_heads, repo_path, short_id};
+use super::{GitError, GitErrorKind, GitResult};
+use git2::{Commit, Config, Index, Oid, Pathspec, PathspecFlags, Repository, Signature, Tree};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// Everything `git commit` takes besides the message. All off by default.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CommitOptions {
+    /// Replace the HEAD commit, keeping its message and author unless given.
+    #[serde(default)]
+    pub amend: bool,
+    /// Add a `Signed-off-by` trailer for the committer.
+    #[serde(default)]
+    pub signoff: bool,
+    /// Sign with GPG or SSH, as `gpg.format` says. Follows `commit.gpgSign`
+    /// when omitted.
+    pub sign: Option<bool>,
+    pub author: Option<Author>,
+    #[serde(default)]
+    pub allow_empty: bool,
+    /// Commit only these files as they are in the worktree, like
+    /// `git commit -- <paths>`. Other staged changes stay staged.
+    #[serde(default)]
+    pub paths: Vec<RepoFile>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Author {
+    pub name: String,
+    pub email: String,
+}
+
+pub fn commit(
+    repo: &Repository,
+    message: Option<&str>,
+    options: &CommitOptions,
+) -> GitResult<Value> {
+    let head = head_commit(repo)?;
+    let merge_heads = merge_heads(repo);
+    if options.amend && !merge_heads.is_empty() {
+        return Err(GitError::invalid(
+            "You are in the middle of a merge -- cannot amend.",
+        ));
+    }
+    if !options.paths.is_empty() && !merge_heads.is_empty() {
+        return Err(GitError::invalid(
+            "Cannot do a partial commit during a merge.",
+        ));
+    }
+    let amended = match (options.amend, &head) {
+        (true, Some(head)) => Some(head),
+        (true, None) => return Err(GitError::invalid("You have nothing to amend.")),
+        (false, _) => None,
+    };
+
+    let message = match (message, amended) {
+        (Some(message), _) => message.to_string(),
+        (None, Some(amended)) => amended.message().unwrap_or("").to_string(),
+        (None, None) => String::new(),
+    };
+    let mut message = git2::message_prettify(message, Some(b'#'))?;
+    if message.trim().is_empty() {
+        return Err(GitError::invalid(
+            "Aborting commit due to empty commit message.",
+        ));
+    }
+    let committer = repo.signature()?;
+    if options.signoff {
+        message = sign_off(&message, &committer);
+    }
+    let author = match (&options.author, amended) {
+        (Some(author), _) => Signature::now(&author.name, &author.email)
+            .map_err(|e| GitError::invalid(format!("Invalid author: {}", e.message())))?,
+        (None, Some(amended)) => amended.author().to_owned(),
+        (None, None) => committer.clone(),
+    };
+
+    if repo.index()?.has_conflicts() {
+        return Err(GitError::new(
+            GitErrorKind::MergeConflict,
+            "Committing is not possible because you have unmerged files.",
+        ));
+    }
+    // A partial commit stages its paths in memory, the index file is only
+    // written once the commit exists
+    let (tree, mut staged) = if options.paths.is_empty() {
+        (repo.find_tree(repo.index()?.write_tree()?)?, None)
+    } else {
+        let paths: Vec<String> = options.paths.iter().map(|p| repo_path(repo, p)).collect();
+        match partial_tree(repo, head.as_ref(), &paths) {
+            Ok((tree, index)) => (tree, Some(index)),
+            Err(e) => {
+                let _ = repo.index().and_then(|mut index| index.read(true));
+                return Err(e);
+            }
+        }
+    };
+
+    let summary = message.lines().next().unwrap_or("").to_string();
+    let create = || -> GitResult<(Oid, bool)> {
+        let mut parents = Vec::new();
+        match amended {
+            Some(amended) => parents.extend(amended.parents()),
+            None => parents.extend(head.clone()),
+        }
+        for id in &merge_heads {
+            parents.push(repo.find_commit(*id)?);
+        }
+        if !options.allow_empty && parents.len() == 1 && parents[0].tree_id() == tree.id() {
+            return Err(GitError::new(
+                GitErrorKind::NothingToCommit,
+                match amended {
+                    Some(_) => {
+                        "You asked to amend the most recent commit, but doing so would make it empty."
+                    }
+                    None => "nothing to commit, working tree clean",
+                },
+            ));
+        }
+
+        let config = repo.config()?;
+        let sign = options
+            .sign
+            .unwrap_or_else(|| config.get_bool("commit.gpgSign").unwrap_or(false));
+        let parent_refs: Vec<&Commit> = parents.iter().collect();
+        let id = if sign {
+            let buffer =
+                repo.commit_create_buffer(&author, &committer, &message, &tree, &parent_refs)?;
+            let buffer = std::str::from_utf8(&buffer)
+                .map_err(|_| GitError::invalid("commit is not valid UTF-8, can't sign it"))?;
+            let signature = sign_buffer(&config, &committer, buffer)?;
+            repo.commit_signed(buffer, &signature, None)?
+        } else {
+            repo.commit(None, &author, &committer, &message, &tree, &parent_refs)?
+        };
+
+        let kind = if amended.is_some() {
+            " (amend)"
+        } else if head.is_none() {
+            " (initial)"
+        } else if !merge_heads.is_empty() {
+            " (merge)"
+        } else {
+            ""
+        };
+        update_head(
+            repo,
+            id,
+            head.as_ref().map(|h| h.id()),
+            &format!("commit{}: {}", kind, summary),
+        )?;
+        Ok((id, sign))
+    };
+    let created = create();
+    if let Some(index) = &mut staged {
+        match &created {
+            Ok(_) => index.write()?,
+            // Drop what was staged in memory, the handle's index is shared
+            Err(_) => {
+                let _ = index.read(true);
+            }
+        }
+    }
+    let (id, sign) = created?;
+    if !merge_heads.is_empty() {
+        repo.cleanup_state()?;
+    }
+
+    let branch = current_branch(repo);
+    println!(
+        "[{} {}] {}",
+        branch.as_deref().unwrap_or("HEAD"),
+        short_id(repo, id),
+        summary
+    );
+    Ok(json!({
+        "hash": id.to_string(),
+        "shortHash": short_id(repo, id),
+        "summary": summary,
+        "branch": branch,
+        "amended": amended.is_some(),
+        "signed": sign,
+    }))
+}
+
+/// HEAD's tree with `paths` taken from the worktree, and the index with the
+/// same update so the committed files don't show up as changed afterwards.
+/// The index is only updated in memory, the caller writes it.
+fn partial_tree<'r>(
+    repo: &'r Repository,
+    head: Option<&Commit>,
+    paths: &[String],
+) -> GitResult<(Tree<'r>, Index)> {
+    let mut index = repo.index()?;
+    index.add_all(paths, git2::IndexAddOption::DEFAULT, None)?;
+    index.update_all(paths, None)?;
+
+    let spec = Pathspec::new(paths)?;
+    let matches = |path: &[u8]| {
+        std::str::from_utf8(path)
+            .map(|p| spec.matches_path(Path::new(p), PathspecFlags::DEFAULT))
+            .unwrap_or(false)
+    };
+    let mut partial = Index::new()?;
+    if let Some(head) = head {
+        partial.read_tree(&head.tree()?)?;
+    }
+    let covered: Vec<PathBuf> = partial
+        .iter()
+        .filter(|entry| matches(&entry.path))
+        .map(|entry| PathBuf::from(String::from_utf8_lossy(&entry.path).into_owned()))
+        .collect();
+    let mut matched = !covered.is_empty();
+    for path in &covered {
+        partial.remove_path(path)?;
+    }
+    for entry in index.iter().filter(|entry| matches(&entry.path)) {
+        partial.add(&entry)?;
+        matched = true;
+    }
+    if !matched {
+        return Err(GitError::new(
+            GitErrorKind::NotFound,
+            format!(
+                "pathspec '{}' did not match any file(s) known to git",
+                paths.join("', '")
+            ),
+        ));
+    }
+    Ok((repo.find_tree(partial.write_tree_to(repo)?)?, index))
+}
+
+/// Moves the checked out branch, or a detached HEAD, to the new commit.
+/// Fails if it moved since `previous` was read.
+fn update_head(repo: &Repository, id: Oid, previous: Option<Oid>, reflog: &str) -> GitResult<()> {
+    let head = repo.find_reference("HEAD")?;
+    let name = head.symbolic_target().unwrap_or("HEAD").to_string();
+    match previous {
+        Some(previous) => repo.reference_matching(&name, id, true, previous, reflog)?,
+        None => repo.reference(&name, id, false, reflog)?,
+    };
+    Ok(())
+}
+
+/// Appends `Signed-off-by` to the trailers, unless it's already the last one.
+fn sign_off(message: &str, committer: &Signature) -> String {
+    let line = format!(
+        "Signed-off-by: {} <{}>",
+        committer.name().unwrap_or(""),
+        committer.email().unwrap_or("")
+    );
+    let message = message.trim_end();
+    if message.lines().last() == Some(line.as_str()) {
+        return format!("{}\n", message);
+    }
+    let has_trailers = git2::message_trailers_strs(message)
+        .map(|trailers| trailers.len() > 0)
+        .unwrap_or(false);
+    let separator = if has_trailers { "\n" } else { "\n\n" };
+    format!("{}{}{}\n", message, separator, line)
+}
+
+/// Signs the raw commit with the program `gpg.format` asks for, the way
+/// `git commit -S` does.
+fn sign_buffer(config: &Config, committer: &Signature, buffer: &str) -> GitResult<String> {
+    let format = config
+        .get_string("gpg.format")
+        .unwrap_or_else(|_| "openpgp".into());
+    let key = config
+        .get_string("user.signingKey")
+        .ok()
+        .filter(|k| !k.trim().is_empty());
+    println!("[COMMIT] Signing with {}", format);
+    match format.as_str() {
+        "ssh" => {
+            let key = key.ok_or_else(|| {
+                GitError::invalid("Set user.signingKey to sign commits with SSH.")
+            })?;
+            let program = config
+                .get_string("gpg.ssh.program")
+                .unwrap_or_else(|_| "ssh-keygen".into());
+            sign_ssh(&program, &key, buffer)
+        }
+        "openpgp" | "x509" => {
+            let default = if format == "x509" { "gpgsm" } else { "gpg" };
+            let program = config
+                .get_string(&format!("gpg.{}.program", format))
+                .or_else(|_| config.get_string("gpg.program"))
+                .unwrap_or_else(|_| default.into());
+            // Without a key gpg picks one by the committer identity
+            let key = key.unwrap_or_else(|| {
+                format!(
+                    "{} <{}>",
+                    committer.name().unwrap_or(""),
+                    committer.email().unwrap_or("")
+                )
+            });
+            run_signer(
+                Command::new(program).args(["--status-fd=2", "-bsau", &key]),
+                buffer,
+            )
+        }
+        other => Err(GitError::invalid(format!(
+            "unsupported value for gpg.format: {}",
+            other
+        ))),
+    }
+}
+
+/// `user.signingKey` is either a key file or, like git allows, the public
+/// key itself with the private one in ssh-agent.
+fn sign_ssh(program: &str, key: &str, buffer: &str) -> GitResult<String> {
+    let literal = key
+        .strip_prefix("key::")
+        .or_else(|| key.starts_with("ssh-").then_some(key));
+    let Some(public_key) = literal else {
+        let path = match key.strip_prefix("~/") {
+            Some(rest) => std::env::var_os("HOME")
+                .map(|home| Path::new(&home).join(rest))
+                .unwrap_or_else(|| PathBuf::from(key)),
+            None => PathBuf::from(key),
+        };
+        return run_signer(
+            Command::new(program)
+                .args(["-Y", "sign", "-n", "git", "-f"])
+                .arg(path),
+            buffer,
+        );
+    };
+
+    // A fresh file nobody else can have created, removed again on drop
+    let mut key_file = tempfile::Builder::new()
+        .prefix(".git_signing_key_")
+        .tempfile()
+        .map_err(|e| GitError::other(e.to_string()))?;
+    writeln!(key_file, "{}", public_key.trim()).map_err(|e| GitError::other(e.to_string()))?;
+    run_signer(
+        Command::new(program)
+            .args(["-Y", "sign", "-n", "git", "-U", "-f"])
+            .arg(key_file.path()),
+        buffer,
+    )
+}
+
+/// Feeds `buffer` to the signing program and returns what it prints.
+fn run_signer(command: &mut Command, buffer: &str) -> GitResult<String> {
+    let program = command.get_program().to_string_lossy().into_owned();
+    #[cfg(windows)]
+    {
+        use std::os::windows::process::CommandExt;
+        command.creation_flags(0x08000000);
+    }
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| GitError::invalid(format!("Could not run {}: {}", program, e)))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin
+            .write_all(buffer.as_bytes())
+            .map_err(|e| GitError::other(e.to_string()))?;
+    }
+    let output = child
+        .wait_with_output()
+        .map_err(|e| GitError::other(e.to_string()))?;
+    let signature = String::from_utf8_lossy(&output.stdout).into_owned();
+    if !output.status.success() || signature.trim().is_empty() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        eprintln!("[COMMIT] {} failed: {}", program, stderr.trim());
+        return Err(GitError::invalid(format!(
+            "{} failed to sign the data: {}",
+            program,
+            stderr.trim()
+        )));
+    }
+    Ok(signature)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git::testing::TestRepo;
+
+    fn options(value: Value) -> CommitOptions {
+        serde_json::from_value(value).unwrap()
+    }
+
+    fn repo() -> TestRepo {
+        let t = TestRepo::new();
+        t.write("a.txt", "a\n");
+        t.write("b.txt", "b\n");
+        t.commit("first");
+        t
+    }
+
+    fn stage(t: &TestRepo, file: &str) {
+        let mut index = t.index();
+        index.add_path(Path::new(file)).unwrap();
+        index.write().unwrap();
+    }
+
+    #[test]
+    fn commits_the_index() {
+        let t = repo();
+        t.write("a.txt", "changed\n");
+        stage(&t, "a.txt");
+        let result = commit(
+            &t.repo,
+            Some("Change a\n\n# comment\n"),
+            &options(json!({})),
+        )
+        .unwrap();
+        assert_eq!(result["summary"], "Change a");
+        assert_eq!(result["hash"], t.head().to_string());
+        assert_eq!(result["branch"], "main");
+        let head = t.repo.find_commit(t.head()).unwrap();
+        assert_eq!(head.message(), Some("Change a\n"));
+    }
+
+    #[test]
+    fn refuses_empty_messages_and_commits() {
+        let t = repo();
+        t.write("a.txt", "changed\n");
+        stage(&t, "a.txt");
+        let err = commit(&t.repo, Some("  \n# only a comment"), &options(json!({}))).unwrap_err();
+        assert_eq!(err.kind, GitErrorKind::InvalidRequest);
+
+        let t = repo();
+        let err = commit(&t.repo, Some("nothing"), &options(json!({}))).unwrap_err();
+        assert_eq!(err.kind, GitErrorKind::NothingToCommit);
+        commit(
+            &t.repo,
+            Some("empty"),
+            &options(json!({ "allowEmpty": true })),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn amends_with_author_and_signoff() {
+        let t = repo();
+        let first = t.head();
+        let result = commit(
+            &t.repo,
+            Some("Reworded"),
+            &options(json!({
+                "amend": true,
+                "allowEmpty": true,
+                "signoff": true,
+                "author": { "name": "Someone", "email": "someone@example.com" },
+            })),
+        )
+        .unwrap();
+        assert_eq!(result["amended"], true);
+        let head = t.repo.find_commit(t.head()).unwrap();
+        assert_ne!(head.id(), first);
+        assert_eq!(head.parent_count(), 0);
+        assert_eq!(head.author().name(), Some("Someone"));
+        assert_eq!(
+            head.message(),
+            Some("Reworded\n\nSigned-off-by: Test <test@example.com>\n")
+        );
+
+        commit(
+            &t.repo,
+            None,
+            &options(json!({ "amend": true, "allowEmpty": true })),
+        )
+        .unwrap();
+        let again = t.repo.find_commit(t.head()).unwrap();
+        assert_eq!(again.message(), head.message());
+        assert_eq!(again.author().name(), Some("Someone"));
+    }
+
+    #[test]
+    fn sign_off_is_not_repeated() {
+        let committer = Signature::now("Test", "test@example.com").unwrap();
+        let once = sign_off("Subject\n", &committer);
+        assert_eq!(once, "Subject\n\nSigned-off-by: Test <test@example.com>\n");
+        assert_eq!(sign_off(&once, &committer), once);
+        assert_eq!(
+            sign_off("Subject\n\nCo-authored-by: A <a@example.com>\n", &committer),
+            "Subject\n\nCo-authored-by: A <a@example.com>\nSigned-off-by: Test <test@example.com>\n"
+        );
+    }
+
+    #[test]
+    fn partial_commit_keeps_other_staged_changes() {
+        let t = repo();
+        t.write("a.txt", "staged\n");
+        stage(&t, "a.txt");
+        t.write("b.txt", "worktree\n");
+        commit(
+            &t.repo,
+            Some("Only b"),
+            &options(json!({ "paths": ["b.txt"] })),
+        )
+        .unwrap();
+
+        let head = t.repo.find_commit(t.head()).unwrap();
+        let tree = head.tree().unwrap();
+        let blob = |name: &str| {
+            let entry = tree.get_name(name).unwrap();
+            let blob = t.repo.find_blob(entry.id()).unwrap();
+            String::from_utf8(blob.content().to_vec()).unwrap()
+        };
+        assert_eq!(blob("a.txt"), "a\n");
+        assert_eq!(blob("b.txt"), "worktree\n");
+        let staged = t
+            .repo
+            .diff_tree_to_index(Some(&tree), Some(&t.index()), None)
+            .unwrap();
+        let paths: Vec<_> = staged
+            .deltas()
+            .map(|d| d.new_file().path().unwrap().to_path_buf())
+            .collect();
+        assert_eq!(paths, [PathBuf::from("a.txt")]);
+    }
+
+    #[test]
+    fn failed_partial_commit_leaves_the_index() {
+        let t = repo();
+        t.write("b.txt", "worktree\n");
+        let before = t.index().write_tree().unwrap();
+        let err = commit(
+            &t.repo,
+            Some("Missing"),
+            &options(json!({ "paths": ["nope.txt"] })),
+        )
+        .unwrap_err();
+        assert_eq!(err.kind, GitErrorKind::NotFound);
+        assert_eq!(t.index().write_tree().unwrap(), before);
+
+        // Fails after staging b.txt in memory, the file on disk is untouched
+        t.repo
+            .config()
+            .unwrap()
+            .set_str("gpg.format", "unknown")
+            .unwrap();
+        let err = commit(
+            &t.repo,
+            Some("Unsigned"),
+            &options(json!({ "paths": ["b.txt"], "sign": true })),
+        )
+        .unwrap_err();
+        assert_eq!(err.kind, GitErrorKind::InvalidRequest);
+        assert_eq!(t.index().write_tree().unwrap(), before);
+        assert_eq!(t.repo.index().unwrap().write_tree().unwrap(), before);
+    }
+
+    #[test]
+    fn refuses_unmerged_files() {
+        let t = TestRepo::new();
+        t.conflict("c.txt", "base\n", "ours\n", "theirs\n");
+        let err = commit(&t.repo, Some("Merge"), &options(json!({}))).unwrap_err();
+        assert_eq!(err.kind, GitErrorKind::MergeConflict);
+        let err = commit(&t.repo, Some("Merge"), &options(json!({ "amend": true }))).unwrap_err();
+        assert_eq!(err.kind, GitErrorKind::InvalidRequest);
+    }
+
+    /// A stand-in for ssh-keygen that records the key file it was given.
+    #[cfg(unix)]
+    #[test]
+    fn ssh_signing_key_file_is_private_and_removed() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let t = repo();
+        let log = t.path().join("signer.log");
+        let program = t.path().join("fake-ssh-keygen");
+        std::fs::write(
+            &program,
+            format!(
+                "#!/bin/sh\nfor key; do :; done\n\
+                 echo \"$key\" > '{0}'\nstat -c %a \"$key\" >> '{0}'\ncat \"$key\" >> '{0}'\n\
+                 cat > /dev/null\necho SIGNATURE\n",
+                log.display()
+            ),
+        )
+        .unwrap();
+        std::fs::set_permissions(&program, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let signature =
+            sign_ssh(program.to_str().unwrap(), "ssh-ed25519 AAAA test", "data").unwrap();
+        assert_eq!(signature.trim(), "SIGNATURE");
+        let log = std::fs::read_to_string(log).unwrap();
+        let mut lines = log.lines();
+        let key_file = PathBuf::from(lines.next().unwrap());
+        assert_eq!(lines.next(), Some("600"));
+        assert_eq!(lines.next(), Some("ssh-ed25519 AAAA test"));
+        assert!(!key_file.exists());
+        let name = key_file.file_name().unwrap().to_str().unwrap();
+        assert_ne!(name, format!(".git_signing_key_{}", std::process::id()));
+        assert!(name.starts_with(".git_signing_key_"));
+
+        // The file goes away when the signer fails as well
+        let err = sign_ssh("/nonexistent/ssh-keygen", "key::ssh-ed25519 AAAA", "data").unwrap_err();
+        assert_eq!(err.kind, GitErrorKind::InvalidRequest);
+    }
+}
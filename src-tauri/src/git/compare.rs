@@ -0,0 +1,238 @@
+use super::diff::file_stats;
+use super::log::Person;
+use super::{short_id, GitError, GitErrorKind, GitResult};
+use git2::{Commit, DiffFindOptions, Oid, Repository, Sort};
+use serde::Serialize;
+use serde_json::{json, Value};
+
+/// The two sides of a comparison and the commit the diff starts from.
+pub(crate) struct Range<'r> {
+    pub base: Commit<'r>,
+    pub head: Commit<'r>,
+    pub merge_base: Option<Oid>,
+    /// The merge base for `base...head`, `base` itself for `base..head`.
+    pub from: Commit<'r>,
+}
+
+pub(crate) fn range<'r>(
+    repo: &'r Repository,
+    base: &str,
+    head: &str,
+    three_dot: bool,
+) -> GitResult<Range<'r>> {
+    let base_commit = repo.revparse_single(base)?.peel_to_commit()?;
+    let head_commit = repo.revparse_single(head)?.peel_to_commit()?;
+    let merge_base = match repo.merge_base(base_commit.id(), head_commit.id()) {
+        Ok(id) => Some(id),
+        Err(e) if e.code() == git2::ErrorCode::NotFound => None,
+        Err(e) => return Err(e.into()),
+    };
+    let from = match (three_dot, merge_base) {
+        (false, _) => base_commit.clone(),
+        (true, Some(id)) => repo.find_commit(id)?,
+        (true, None) => {
+            return Err(GitError::new(
+                GitErrorKind::NotFound,
+                format!("'{}' and '{}' have no merge base", base, head),
+            ))
+        }
+    };
+    Ok(Range {
+        base: base_commit,
+        head: head_commit,
+        merge_base,
+        from,
+    })
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct RangeCommit {
+    hash: String,
+    short_hash: String,
+    parents: Vec<String>,
+    author: Person,
+    summary: String,
+}
+
+/// What `head` has that `base` doesn't and the other way around, plus the
+/// files that differ, like a pull request. The files are `base...head`, the
+/// changes since the merge base, unless `three_dot` is off. Each side lists
+/// at most `limit` commits, `ahead` and `behind` count all of them.
+pub fn compare(
+    repo: &Repository,
+    base: &str,
+    head: &str,
+    three_dot: bool,
+    limit: usize,
+) -> GitResult<Value> {
+    let range = range(repo, base, head, three_dot)?;
+    let (ahead, behind) = repo.graph_ahead_behind(range.head.id(), range.base.id())?;
+    let head_commits = unique_commits(repo, range.head.id(), range.base.id(), limit)?;
+    let base_commits = unique_commits(repo, range.base.id(), range.head.id(), limit)?;
+
+    let mut diff =
+        repo.diff_tree_to_tree(Some(&range.from.tree()?), Some(&range.head.tree()?), None)?;
+    diff.find_similar(Some(DiffFindOptions::new().renames(true).copies(true)))?;
+    let files = file_stats(&diff)?;
+    let additions: usize = files.iter().map(|f| f.additions).sum();
+    let deletions: usize = files.iter().map(|f| f.deletions).sum();
+    println!(
+        "[COMPARE] {}{}{}: {} ahead, {} behind, {} files",
+        base,
+        if three_dot { "..." } else { ".." },
+        head,
+        ahead,
+        behind,
+        files.len()
+    );
+
+    let side = |revision: &str, commit: &Commit| {
+        json!({
+            "revision": revision,
+            "hash": commit.id().to_string(),
+            "shortHash": short_id(repo, commit.id()),
+        })
+    };
+    Ok(json!({
+        "base": side(base, &range.base),
+        "head": side(head, &range.head),
+        "mergeBase": range.merge_base.map(|id| id.to_string()),
+        "mergeBaseShort": range.merge_base.map(|id| short_id(repo, id)),
+        "threeDot": three_dot,
+        "ahead": ahead,
+        "behind": behind,
+        "headCommits": head_commits,
+        "baseCommits": base_commits,
+        "files": files,
+        "additions": additions,
+        "deletions": deletions,
+    }))
+}
+
+/// `git log tip ^other`, newest first.
+fn unique_commits(
+    repo: &Repository,
+    tip: Oid,
+    other: Oid,
+    limit: usize,
+) -> GitResult<Vec<RangeCommit>> {
+    let mut walk = repo.revwalk()?;
+    walk.set_sorting(Sort::TOPOLOGICAL | Sort::TIME)?;
+    walk.push(tip)?;
+    walk.hide(other)?;
+    let mut commits = Vec::new();
+    for id in walk.take(limit) {
+        let commit = repo.find_commit(id?)?;
+        commits.push(RangeCommit {
+            hash: commit.id().to_string(),
+            short_hash: short_id(repo, commit.id()),
+            parents: commit.parent_ids().map(|p| p.to_string()).collect(),
+            author: commit.author().into(),
+            summary: commit.summary().unwrap_or("").to_string(),
+        });
+    }
+    Ok(commits)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git::testing::TestRepo;
+
+    /// `main` and `feature` each two commits past `base.txt`.
+    fn diverged() -> TestRepo {
+        let t = TestRepo::new();
+        t.write("base.txt", "base\n");
+        let base = t.commit("base");
+        t.repo
+            .branch("feature", &t.repo.find_commit(base).unwrap(), false)
+            .unwrap();
+        t.write("main.txt", "main\n");
+        t.commit("main 1");
+        t.write("base.txt", "base\nmain\n");
+        t.commit("main 2");
+        t.switch("feature");
+        t.write("feature.txt", "one\ntwo\n");
+        t.commit("feature 1");
+        t.write("feature.txt", "one\n");
+        t.commit("feature 2");
+        t.switch("main");
+        t
+    }
+
+    fn paths(result: &Value) -> Vec<&str> {
+        result["files"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|f| f["newPath"].as_str().or(f["oldPath"].as_str()).unwrap())
+            .collect()
+    }
+
+    fn summaries<'v>(result: &'v Value, side: &str) -> Vec<&'v str> {
+        result[side]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|c| c["summary"].as_str().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn three_dot_shows_the_head_side() {
+        let t = diverged();
+        let result = t
+            .run("compare", json!({ "base": "main", "head": "feature" }))
+            .unwrap();
+        assert_eq!(result["threeDot"], true);
+        assert_eq!(
+            (result["ahead"].as_u64(), result["behind"].as_u64()),
+            (Some(2), Some(2))
+        );
+        assert_eq!(
+            summaries(&result, "headCommits"),
+            ["feature 2", "feature 1"]
+        );
+        assert_eq!(summaries(&result, "baseCommits"), ["main 2", "main 1"]);
+        assert_eq!(paths(&result), ["feature.txt"]);
+        assert_eq!(
+            (result["additions"].as_u64(), result["deletions"].as_u64()),
+            (Some(1), Some(0))
+        );
+        let merge_base = t.repo.revparse_single("main~2").unwrap().id();
+        assert_eq!(result["mergeBase"], merge_base.to_string());
+        assert_eq!(result["head"]["revision"], "feature");
+    }
+
+    #[test]
+    fn two_dot_diffs_the_tips() {
+        let t = diverged();
+        let result = compare(&t.repo, "main", "feature", false, 1).unwrap();
+        assert_eq!(paths(&result), ["base.txt", "feature.txt", "main.txt"]);
+        assert_eq!(result["files"][2]["status"], "deleted");
+        assert_eq!(summaries(&result, "headCommits"), ["feature 2"]);
+        assert_eq!(result["ahead"], 2);
+    }
+
+    #[test]
+    fn unrelated_histories() {
+        let t = diverged();
+        let signature = crate::git::testing::signature();
+        let tree = t.repo.find_commit(t.head()).unwrap().tree().unwrap();
+        let orphan = t
+            .repo
+            .commit(None, &signature, &signature, "orphan", &tree, &[])
+            .unwrap();
+        t.repo
+            .reference("refs/heads/orphan", orphan, false, "test")
+            .unwrap();
+
+        let err = compare(&t.repo, "main", "orphan", true, 10).unwrap_err();
+        assert_eq!(err.kind, GitErrorKind::NotFound);
+        let result = compare(&t.repo, "main", "orphan", false, 10).unwrap();
+        assert_eq!(result["mergeBase"], Value::Null);
+        assert_eq!(result["files"], json!([]));
+        assert_eq!(summaries(&result, "headCommits"), ["orphan"]);
+    }
+}
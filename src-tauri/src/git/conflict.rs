@@ -0,0 +1,456 @@
+use super::status::conflict_codes;
+use super::{repo_path, GitError, GitErrorKind, GitResult};
+use git2::{IndexEntry, Repository};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::path::Path;
+
+const MARKER_LEN: usize = 7;
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ConflictSide {
+    Ours,
+    Theirs,
+}
+
+/// How to resolve one `<<<<<<<` … `>>>>>>>` region.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "choice", rename_all = "camelCase")]
+pub enum Resolution {
+    Ours,
+    Theirs,
+    /// Ours followed by theirs.
+    Both,
+    Custom {
+        text: String,
+    },
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RegionChoice {
+    /// `index` of the region in the `conflict` result.
+    pub region: usize,
+    #[serde(flatten)]
+    pub resolution: Resolution,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Region {
+    index: usize,
+    /// 1-based lines of the `<<<<<<<` and `>>>>>>>` markers.
+    start_line: usize,
+    end_line: usize,
+    ours_label: String,
+    theirs_label: String,
+    ours: String,
+    /// Only present for `merge.conflictStyle=diff3` markers.
+    base: Option<String>,
+    theirs: String,
+    #[serde(skip)]
+    raw: String,
+}
+
+enum Segment {
+    Text(String),
+    Region(Region),
+}
+
+/// Conflicted paths with their unmerged `XY` code, like the `conflicts`
+/// list of `status`.
+pub fn list(repo: &Repository) -> GitResult<Value> {
+    let mut files: Vec<Value> = conflict_codes(repo)?
+        .into_iter()
+        .map(|(path, code)| json!({ "path": path, "status": code }))
+        .collect();
+    files.sort_by(|a, b| a["path"].as_str().cmp(&b["path"].as_str()));
+    Ok(files.into())
+}
+
+/// The three stages of a conflicted file and the regions still marked in
+/// the working tree copy.
+pub fn details(repo: &Repository, file: &str) -> GitResult<Value> {
+    let path = repo_path(repo, file);
+    let (base, ours, theirs) = stages(repo, &path)?;
+    let blob_text = |entry: &Option<IndexEntry>| -> GitResult<Option<String>> {
+        match entry {
+            Some(entry) => Ok(Some(
+                String::from_utf8_lossy(repo.find_blob(entry.id)?.content()).into_owned(),
+            )),
+            None => Ok(None),
+        }
+    };
+    let binary = [&base, &ours, &theirs]
+        .iter()
+        .filter_map(|e| e.as_ref())
+        .any(|e| repo.find_blob(e.id).is_ok_and(|b| b.is_binary()));
+
+    let working = read_working(repo, &path)?;
+    let regions: Vec<Region> = match (&working, binary) {
+        (Some(text), false) => parse(text)
+            .into_iter()
+            .filter_map(|s| match s {
+                Segment::Region(region) => Some(region),
+                Segment::Text(_) => None,
+            })
+            .collect(),
+        _ => Vec::new(),
+    };
+
+    Ok(json!({
+        "path": path,
+        "status": conflict_codes(repo)?.get(&path).copied().unwrap_or("UU"),
+        "binary": binary,
+        "base": if binary { None } else { blob_text(&base)? },
+        "ours": if binary { None } else { blob_text(&ours)? },
+        "theirs": if binary { None } else { blob_text(&theirs)? },
+        "working": if binary { None } else { working },
+        "regions": regions,
+    }))
+}
+
+/// Applies the region choices to the working tree file, or takes one side
+/// whole, and marks the file resolved once no conflict markers are left.
+pub fn resolve(
+    repo: &Repository,
+    file: &str,
+    regions: &[RegionChoice],
+    take: Option<ConflictSide>,
+) -> GitResult<Value> {
+    let path = repo_path(repo, file);
+    let (_, ours, theirs) = stages(repo, &path)?;
+    let workdir = repo
+        .workdir()
+        .ok_or_else(|| GitError::invalid("cannot resolve conflicts in a bare repository"))?;
+    let full_path = workdir.join(&path);
+    let mut index = repo.index()?;
+
+    if let Some(side) = take {
+        let entry = match side {
+            ConflictSide::Ours => ours,
+            ConflictSide::Theirs => theirs,
+        };
+        match entry {
+            Some(entry) => {
+                let blob = repo.find_blob(entry.id)?;
+                write_file(&full_path, blob.content())?;
+                index.add_path(Path::new(&path))?;
+            }
+            // That side deleted the file
+            None => {
+                if full_path.exists() {
+                    std::fs::remove_file(&full_path).map_err(io_error)?;
+                }
+                index.remove_path(Path::new(&path))?;
+            }
+        }
+        index.write()?;
+        return Ok(json!({ "path": path, "remaining": 0 }));
+    }
+
+    let working = read_working(repo, &path)?.ok_or_else(|| {
+        GitError::new(
+            GitErrorKind::NotFound,
+            format!("'{}' is not in the working tree, pick ours or theirs", path),
+        )
+    })?;
+    let segments = parse(&working);
+    let count = segments
+        .iter()
+        .filter(|s| matches!(s, Segment::Region(_)))
+        .count();
+    if let Some(choice) = regions.iter().find(|c| c.region >= count) {
+        return Err(GitError::new(
+            GitErrorKind::Stale,
+            format!(
+                "'{}' has no conflict region {}, refresh and try again",
+                path, choice.region
+            ),
+        ));
+    }
+
+    let mut resolved = String::with_capacity(working.len());
+    let mut remaining = 0;
+    for segment in &segments {
+        match segment {
+            Segment::Text(text) => resolved.push_str(text),
+            Segment::Region(region) => {
+                match regions.iter().rev().find(|c| c.region == region.index) {
+                    Some(choice) => match &choice.resolution {
+                        Resolution::Ours => resolved.push_str(&region.ours),
+                        Resolution::Theirs => resolved.push_str(&region.theirs),
+                        Resolution::Both => {
+                            resolved.push_str(&region.ours);
+                            resolved.push_str(&region.theirs);
+                        }
+                        Resolution::Custom { text } => {
+                            resolved.push_str(text);
+                            if !text.is_empty() && !text.ends_with('\n') {
+                                resolved.push('\n');
+                            }
+                        }
+                    },
+                    None => {
+                        resolved.push_str(&region.raw);
+                        remaining += 1;
+                    }
+                }
+            }
+        }
+    }
+
+    write_file(&full_path, resolved.as_bytes())?;
+    if remaining == 0 {
+        index.add_path(Path::new(&path))?;
+        index.write()?;
+    }
+    Ok(json!({ "path": path, "remaining": remaining }))
+}
+
+/// Base, ours and theirs index entries of a conflicted path.
+type Stages = (Option<IndexEntry>, Option<IndexEntry>, Option<IndexEntry>);
+
+fn stages(repo: &Repository, path: &str) -> GitResult<Stages> {
+    let index = repo.index()?;
+    let stage = |n| index.get_path(Path::new(path), n);
+    let stages = (stage(1), stage(2), stage(3));
+    if stages.0.is_none() && stages.1.is_none() && stages.2.is_none() {
+        return Err(GitError::new(
+            GitErrorKind::NotFound,
+            format!("'{}' is not in conflict", path),
+        ));
+    }
+    Ok(stages)
+}
+
+fn read_working(repo: &Repository, path: &str) -> GitResult<Option<String>> {
+    let full_path = match repo.workdir() {
+        Some(workdir) => workdir.join(path),
+        None => return Ok(None),
+    };
+    match std::fs::read(full_path) {
+        Ok(bytes) => Ok(Some(String::from_utf8_lossy(&bytes).into_owned())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(io_error(e)),
+    }
+}
+
+fn write_file(path: &Path, content: &[u8]) -> GitResult<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent).map_err(io_error)?;
+    }
+    std::fs::write(path, content).map_err(io_error)
+}
+
+fn io_error(e: std::io::Error) -> GitError {
+    GitError::other(e.to_string())
+}
+
+/// Returns the label after a marker line like `<<<<<<< HEAD`.
+fn marker(line: &str, c: char) -> Option<&str> {
+    let rest = line.strip_prefix(&c.to_string().repeat(MARKER_LEN))?;
+    if rest.starts_with(c) {
+        return None;
+    }
+    let rest = rest.trim_end_matches(['\n', '\r']);
+    match rest.strip_prefix(' ') {
+        Some(label) => Some(label),
+        None if rest.is_empty() => Some(""),
+        None => None,
+    }
+}
+
+/// Splits a file into plain text and conflict regions. Unterminated regions
+/// are kept as plain text.
+fn parse(text: &str) -> Vec<Segment> {
+    enum Part {
+        Ours,
+        Base,
+        Theirs,
+    }
+
+    let mut segments = Vec::new();
+    let mut plain = String::new();
+    let mut current: Option<(Region, Part)> = None;
+    for (lineno, line) in text.split_inclusive('\n').enumerate() {
+        let Some((region, part)) = current.as_mut() else {
+            match marker(line, '<') {
+                Some(label) => {
+                    current = Some((
+                        Region {
+                            index: 0,
+                            start_line: lineno + 1,
+                            end_line: 0,
+                            ours_label: label.to_string(),
+                            theirs_label: String::new(),
+                            ours: String::new(),
+                            base: None,
+                            theirs: String::new(),
+                            raw: line.to_string(),
+                        },
+                        Part::Ours,
+                    ))
+                }
+                None => plain.push_str(line),
+            }
+            continue;
+        };
+
+        region.raw.push_str(line);
+        match part {
+            Part::Ours if marker(line, '|').is_some() => {
+                region.base = Some(String::new());
+                *part = Part::Base;
+            }
+            Part::Ours | Part::Base if marker(line, '=').is_some() => *part = Part::Theirs,
+            Part::Theirs if marker(line, '>').is_some() => {
+                let (mut region, _) = current.take().expect("inside a region");
+                region.theirs_label = marker(line, '>').unwrap_or("").to_string();
+                region.end_line = lineno + 1;
+                region.index = segments
+                    .iter()
+                    .filter(|s| matches!(s, Segment::Region(_)))
+                    .count();
+                if !plain.is_empty() {
+                    segments.push(Segment::Text(std::mem::take(&mut plain)));
+                }
+                segments.push(Segment::Region(region));
+            }
+            Part::Ours => region.ours.push_str(line),
+            Part::Base => region.base.get_or_insert_with(String::new).push_str(line),
+            Part::Theirs => region.theirs.push_str(line),
+        }
+    }
+    if let Some((region, _)) = current {
+        plain.push_str(&region.raw);
+    }
+    if !plain.is_empty() {
+        segments.push(Segment::Text(plain));
+    }
+    segments
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git::testing::TestRepo;
+
+    fn regions(text: &str) -> Vec<Region> {
+        parse(text)
+            .into_iter()
+            .filter_map(|s| match s {
+                Segment::Region(region) => Some(region),
+                Segment::Text(_) => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn parses_marker_regions() {
+        let text = "a\n<<<<<<< HEAD\nours\n=======\ntheirs\n>>>>>>> feature\nb\n<<<<<<< HEAD\n=======\nx\n>>>>>>> feature\n";
+        let regions = regions(text);
+        assert_eq!(regions.len(), 2);
+        let first = &regions[0];
+        assert_eq!((first.index, first.start_line, first.end_line), (0, 2, 6));
+        assert_eq!(
+            (first.ours_label.as_str(), first.theirs_label.as_str()),
+            ("HEAD", "feature")
+        );
+        assert_eq!(
+            (first.ours.as_str(), first.theirs.as_str()),
+            ("ours\n", "theirs\n")
+        );
+        assert_eq!(first.base, None);
+        assert_eq!((regions[1].index, regions[1].ours.as_str()), (1, ""));
+    }
+
+    #[test]
+    fn parses_diff3_base() {
+        let text = "<<<<<<< ours\nA\n||||||| base\nB\n=======\nC\n>>>>>>> theirs\n";
+        let region = &regions(text)[0];
+        assert_eq!(region.base.as_deref(), Some("B\n"));
+        assert_eq!(
+            (region.ours.as_str(), region.theirs.as_str()),
+            ("A\n", "C\n")
+        );
+    }
+
+    #[test]
+    fn ignores_non_markers() {
+        // Longer runs and unterminated regions are plain text
+        assert!(regions("<<<<<<<< x\n=======\n>>>>>>>>\n").is_empty());
+        let text = "<<<<<<< HEAD\nours\n=======\n";
+        let segments = parse(text);
+        assert!(matches!(&segments[..], [Segment::Text(t)] if t == text));
+        assert_eq!(marker(">>>>>>>\r\n", '>'), Some(""));
+        assert_eq!(marker(">>>>>>>x\n", '>'), None);
+    }
+
+    #[test]
+    fn details_has_three_stages_and_regions() {
+        let t = TestRepo::new();
+        t.conflict("c.txt", "base\n", "ours\n", "theirs\n");
+        let listed = list(&t.repo).unwrap();
+        assert_eq!(listed[0]["path"], "c.txt");
+        assert_eq!(listed[0]["status"], "UU");
+
+        let details = details(&t.repo, "c.txt").unwrap();
+        assert_eq!(details["base"], "base\n");
+        assert_eq!(details["ours"], "ours\n");
+        assert_eq!(details["theirs"], "theirs\n");
+        assert_eq!(details["regions"][0]["ours"], "ours\n");
+        assert_eq!(details["regions"][0]["theirs"], "theirs\n");
+    }
+
+    #[test]
+    fn resolves_regions() {
+        let t = TestRepo::new();
+        t.conflict("c.txt", "base\n", "ours\n", "theirs\n");
+        let choice = RegionChoice {
+            region: 0,
+            resolution: Resolution::Both,
+        };
+        let result = resolve(&t.repo, "c.txt", &[choice], None).unwrap();
+        assert_eq!(result["remaining"], 0);
+        assert_eq!(t.read("c.txt"), "ours\ntheirs\n");
+        assert!(!t.index().has_conflicts());
+    }
+
+    #[test]
+    fn custom_text_gets_newline() {
+        let t = TestRepo::new();
+        t.conflict("c.txt", "base\n", "ours\n", "theirs\n");
+        let choice = RegionChoice {
+            region: 0,
+            resolution: Resolution::Custom {
+                text: "mine".into(),
+            },
+        };
+        resolve(&t.repo, "c.txt", &[choice], None).unwrap();
+        assert_eq!(t.read("c.txt"), "mine\n");
+    }
+
+    #[test]
+    fn takes_one_side_whole() {
+        let t = TestRepo::new();
+        t.conflict("c.txt", "base\n", "ours\n", "theirs\n");
+        resolve(&t.repo, "c.txt", &[], Some(ConflictSide::Theirs)).unwrap();
+        assert_eq!(t.read("c.txt"), "theirs\n");
+        assert!(!t.index().has_conflicts());
+    }
+
+    #[test]
+    fn unknown_region_is_stale() {
+        let t = TestRepo::new();
+        t.conflict("c.txt", "base\n", "ours\n", "theirs\n");
+        let choice = RegionChoice {
+            region: 3,
+            resolution: Resolution::Ours,
+        };
+        let err = resolve(&t.repo, "c.txt", &[choice], None).unwrap_err();
+        assert_eq!(err.kind, GitErrorKind::Stale);
+        let err = details(&t.repo, "other.txt").unwrap_err();
+        assert_eq!(err.kind, GitErrorKind::NotFound);
+    }
+}
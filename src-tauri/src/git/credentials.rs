@@ -0,0 +1,176 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{LazyLock, Mutex};
+
+/// Username GitHub expects in front of an OAuth token.
+const TOKEN_USERNAME: &str = "x-access-token";
+
+/// An access token for one host. Kept in memory only and never printed.
+#[derive(Clone)]
+pub(crate) struct Token {
+    pub(crate) username: String,
+    pub(crate) secret: String,
+    /// Also sent over plain http, only when the host was set as `http://…`.
+    insecure: bool,
+}
+
+impl fmt::Debug for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Token({}, ***)", self.username)
+    }
+}
+
+static TOKENS: LazyLock<Mutex<HashMap<String, Token>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Uses `token` for https remotes on `host`, e.g. `github.com`, or forgets
+/// the host's token when `None`. Plain http remotes only get it when the
+/// host is given with the scheme, e.g. `http://localhost:8080`.
+pub fn set_token(host: &str, username: Option<&str>, token: Option<String>) {
+    let host = host.trim().to_lowercase();
+    let (host, insecure) = match host.strip_prefix("http://") {
+        Some(host) => (host.to_string(), true),
+        None => (host.trim_start_matches("https://").to_string(), false),
+    };
+    let mut tokens = TOKENS.lock().unwrap_or_else(|e| e.into_inner());
+    match token.filter(|t| !t.trim().is_empty()) {
+        Some(secret) => {
+            println!("[AUTH] Token set for {}", host);
+            tokens.insert(
+                host,
+                Token {
+                    username: username.unwrap_or(TOKEN_USERNAME).to_string(),
+                    secret,
+                    insecure,
+                },
+            );
+        }
+        None => {
+            println!("[AUTH] Token cleared for {}", host);
+            tokens.remove(&host);
+        }
+    }
+}
+
+/// The token for the host of an http(s) remote URL. Tokens are only handed
+/// to the host they were set for, and over http only when allowed.
+pub(crate) fn token_for(url: &str) -> Option<Token> {
+    let (host, secure) = host(url)?;
+    TOKENS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .get(&host)
+        .filter(|token| secure || token.insecure)
+        .cloned()
+}
+
+/// `host[:port]` of an http(s) URL without any user info, and whether the
+/// URL is https.
+fn host(url: &str) -> Option<(String, bool)> {
+    let (rest, secure) = match url.strip_prefix("https://") {
+        Some(rest) => (rest, true),
+        None => (url.strip_prefix("http://")?, false),
+    };
+    let authority = rest.split(['/', '?', '#']).next()?;
+    let host = authority.rsplit('@').next()?;
+    (!host.is_empty()).then(|| (host.to_lowercase(), secure))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git::testing::TestRepo;
+    use crate::git::{remote, GitErrorKind};
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::Arc;
+
+    /// base64 of `x-access-token:s3cret`.
+    const BASIC: &str = "Basic eC1hY2Nlc3MtdG9rZW46czNjcmV0";
+
+    /// A stand-in for an http git server that requires basic auth and
+    /// rejects everything. Returns its address and the `Authorization`
+    /// headers of every request it got.
+    fn server() -> (String, Arc<Mutex<Vec<Option<String>>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let seen = requests.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(mut stream) = stream else { return };
+                let mut authorization = None;
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap_or(0) > 0 && line != "\r\n" {
+                    if let Some((name, value)) = line.split_once(':') {
+                        if name.eq_ignore_ascii_case("authorization") {
+                            authorization = Some(value.trim().to_string());
+                        }
+                    }
+                    line.clear();
+                }
+                seen.lock().unwrap().push(authorization);
+                let _ = stream.write_all(
+                    b"HTTP/1.1 401 Unauthorized\r\n\
+                      WWW-Authenticate: Basic realm=\"git\"\r\n\
+                      Content-Length: 0\r\n\
+                      Connection: close\r\n\r\n",
+                );
+            }
+        });
+        (address, requests)
+    }
+
+    #[test]
+    fn token_is_sent_once_and_rejection_fails() {
+        let (address, requests) = server();
+        set_token(
+            &format!("http://{}", address),
+            None,
+            Some("s3cret".to_string()),
+        );
+        let t = TestRepo::new();
+        let url = format!("http://{}/repo.git", address);
+        let err = remote::fetch(&t.repo, &url, &[]).unwrap_err();
+        set_token(&format!("http://{}", address), None, None);
+
+        assert_eq!(err.kind, GitErrorKind::AuthenticationRequired);
+        assert!(!err.message.contains("s3cret"));
+        let requests = requests.lock().unwrap();
+        let sent = requests.iter().filter(|a| a.as_deref() == Some(BASIC));
+        assert_eq!(sent.count(), 1);
+        assert!(requests.len() <= 3, "{} requests", requests.len());
+    }
+
+    #[test]
+    fn tokens_stay_off_plain_http() {
+        set_token("Example.com", Some("me"), Some("secret".to_string()));
+        assert_eq!(
+            token_for("https://user@example.com/repo.git")
+                .unwrap()
+                .username,
+            "me"
+        );
+        assert!(token_for("http://example.com/repo.git").is_none());
+        assert!(token_for("https://example.org/repo.git").is_none());
+        assert!(token_for("git@example.com:repo.git").is_none());
+        set_token("example.com", None, None);
+        assert!(token_for("https://example.com/repo.git").is_none());
+
+        set_token("http://localhost:8080", None, Some("secret".to_string()));
+        assert!(token_for("http://localhost:8080/repo.git").is_some());
+        assert!(token_for("https://localhost:8080/repo.git").is_some());
+        set_token("http://localhost:8080", None, None);
+    }
+
+    #[test]
+    fn debug_hides_the_secret() {
+        let token = Token {
+            username: "me".to_string(),
+            secret: "s3cret".to_string(),
+            insecure: false,
+        };
+        assert_eq!(format!("{:?}", token), "Token(me, ***)");
+    }
+}
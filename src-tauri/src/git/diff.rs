@@ -0,0 +1,578 @@
+use super::action::{RepoFile, Revision, Workspace};
+use super::{compare, head_commit, open, repo_path, GitResult};
+use git2::{Delta, Diff, DiffFindOptions, DiffOptions, ObjectType, Oid, Patch, Repository, Tree};
+use serde::{Deserialize, Serialize};
+
+/// Which two sides to compare.
+#[derive(Debug, Deserialize)]
+#[serde(
+    tag = "mode",
+    rename_all = "camelCase",
+    rename_all_fields = "camelCase"
+)]
+pub enum DiffTarget {
+    /// Working tree against the index, what `git diff` shows.
+    Unstaged {
+        #[serde(default)]
+        include_untracked: bool,
+    },
+    /// Index against HEAD, what `git diff --cached` shows.
+    Staged,
+    Commits {
+        from: Revision,
+        to: Revision,
+    },
+    /// A commit against its first parent.
+    Commit {
+        commit: Revision,
+    },
+    /// `git diff base...head`, or `base..head` without `threeDot`, the files
+    /// of a `compare`.
+    Compare {
+        base: Revision,
+        head: Revision,
+        #[serde(default = "default_three_dot")]
+        three_dot: bool,
+    },
+}
+
+pub(crate) fn default_three_dot() -> bool {
+    true
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DiffRequest {
+    pub workspace: Workspace,
+    #[serde(flatten)]
+    pub target: DiffTarget,
+    /// Only diff these files, everything when empty.
+    #[serde(default)]
+    pub paths: Vec<RepoFile>,
+    #[serde(default = "default_context_lines")]
+    pub context_lines: u32,
+}
+
+fn default_context_lines() -> u32 {
+    3
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FileDiff {
+    pub old_path: Option<String>,
+    pub new_path: Option<String>,
+    /// added, deleted, modified, renamed, copied, typechange or untracked
+    pub status: &'static str,
+    pub old_mode: u32,
+    pub new_mode: u32,
+    pub binary: bool,
+    pub additions: usize,
+    pub deletions: usize,
+    pub hunks: Vec<DiffHunk>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DiffHunk {
+    /// Hash of the hunk text, sent back to stage or discard this hunk.
+    pub id: String,
+    pub header: String,
+    pub old_start: u32,
+    pub old_lines: u32,
+    pub new_start: u32,
+    pub new_lines: u32,
+    pub lines: Vec<DiffLine>,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum LineKind {
+    Context,
+    Addition,
+    Deletion,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DiffLine {
+    pub kind: LineKind,
+    /// Line text without the trailing newline.
+    pub content: String,
+    pub old_lineno: Option<u32>,
+    pub new_lineno: Option<u32>,
+    /// Set on the last line of a side that doesn't end with a newline.
+    pub no_newline: bool,
+    /// Changed `[start, end)` ranges within `content`, in UTF-16 code units
+    /// so they can be used on JavaScript strings directly.
+    pub word_ranges: Vec<[usize; 2]>,
+}
+
+pub fn diff(request: DiffRequest) -> GitResult<Vec<FileDiff>> {
+    let repo = open(&request.workspace)?;
+    let mut options = DiffOptions::new();
+    options.context_lines(request.context_lines);
+    for path in &request.paths {
+        options.pathspec(repo_path(&repo, path));
+    }
+    if !request.paths.is_empty() {
+        options.disable_pathspec_match(true);
+    }
+
+    let mut diff = match &request.target {
+        DiffTarget::Unstaged { include_untracked } => {
+            options
+                .include_untracked(*include_untracked)
+                .recurse_untracked_dirs(*include_untracked)
+                .show_untracked_content(*include_untracked);
+            repo.diff_index_to_workdir(None, Some(&mut options))?
+        }
+        DiffTarget::Staged => {
+            let head = match head_commit(&repo)? {
+                Some(head) => Some(head.tree()?),
+                None => None,
+            };
+            repo.diff_tree_to_index(head.as_ref(), None, Some(&mut options))?
+        }
+        DiffTarget::Commits { from, to } => {
+            let from = revision_tree(&repo, from)?;
+            let to = revision_tree(&repo, to)?;
+            repo.diff_tree_to_tree(Some(&from), Some(&to), Some(&mut options))?
+        }
+        DiffTarget::Commit { commit } => {
+            let commit = repo.revparse_single(commit)?.peel_to_commit()?;
+            let parent = match commit.parent_count() {
+                0 => None,
+                _ => Some(commit.parent(0)?.tree()?),
+            };
+            repo.diff_tree_to_tree(parent.as_ref(), Some(&commit.tree()?), Some(&mut options))?
+        }
+        DiffTarget::Compare {
+            base,
+            head,
+            three_dot,
+        } => {
+            let range = compare::range(&repo, base, head, *three_dot)?;
+            repo.diff_tree_to_tree(
+                Some(&range.from.tree()?),
+                Some(&range.head.tree()?),
+                Some(&mut options),
+            )?
+        }
+    };
+    diff.find_similar(Some(DiffFindOptions::new().renames(true).copies(true)))?;
+    file_diffs(&diff)
+}
+
+pub(crate) fn revision_tree<'r>(repo: &'r Repository, revision: &str) -> GitResult<Tree<'r>> {
+    Ok(repo.revparse_single(revision)?.peel_to_tree()?)
+}
+
+pub(crate) fn file_diffs(diff: &Diff) -> GitResult<Vec<FileDiff>> {
+    files(diff, true)
+}
+
+/// Like `file_diffs` but only the line stats, for diffs too big to send
+/// whole.
+pub(crate) fn file_stats(diff: &Diff) -> GitResult<Vec<FileDiff>> {
+    files(diff, false)
+}
+
+fn files(diff: &Diff, with_hunks: bool) -> GitResult<Vec<FileDiff>> {
+    let mut files = Vec::with_capacity(diff.deltas().len());
+    for idx in 0..diff.deltas().len() {
+        let patch = Patch::from_diff(diff, idx)?;
+        let delta = diff.get_delta(idx).expect("delta index in range");
+        let status = match delta.status() {
+            Delta::Added => "added",
+            Delta::Deleted => "deleted",
+            Delta::Renamed => "renamed",
+            Delta::Copied => "copied",
+            Delta::Typechange => "typechange",
+            Delta::Untracked => "untracked",
+            _ => "modified",
+        };
+        let path =
+            |file: git2::DiffFile| file.path().map(|p| p.to_string_lossy().replace('\\', "/"));
+        let old_path = match delta.status() {
+            Delta::Added | Delta::Untracked => None,
+            _ => path(delta.old_file()),
+        };
+        let new_path = match delta.status() {
+            Delta::Deleted => None,
+            _ => path(delta.new_file()),
+        };
+
+        let mut file = FileDiff {
+            old_path,
+            new_path,
+            status,
+            old_mode: u32::from(delta.old_file().mode()),
+            new_mode: u32::from(delta.new_file().mode()),
+            binary: delta.flags().is_binary(),
+            additions: 0,
+            deletions: 0,
+            hunks: Vec::new(),
+        };
+        if let Some(patch) = patch {
+            // Loading the patch is what detects binary content
+            file.binary |= patch.delta().flags().is_binary();
+            if !file.binary {
+                let (_, additions, deletions) = patch.line_stats()?;
+                file.additions = additions;
+                file.deletions = deletions;
+                if with_hunks {
+                    file.hunks = hunks(&patch)?;
+                }
+            }
+        }
+        files.push(file);
+    }
+    Ok(files)
+}
+
+fn hunks(patch: &Patch) -> GitResult<Vec<DiffHunk>> {
+    let mut hunks = Vec::with_capacity(patch.num_hunks());
+    for hunk_idx in 0..patch.num_hunks() {
+        let (hunk, line_count) = patch.hunk(hunk_idx)?;
+        let mut lines: Vec<DiffLine> = Vec::with_capacity(line_count);
+        for line_idx in 0..line_count {
+            let line = patch.line_in_hunk(hunk_idx, line_idx)?;
+            let kind = match line.origin() {
+                '+' => LineKind::Addition,
+                '-' => LineKind::Deletion,
+                ' ' => LineKind::Context,
+                // `\ No newline at end of file` belongs to the line before it
+                _ => {
+                    if let Some(last) = lines.last_mut() {
+                        last.no_newline = true;
+                    }
+                    continue;
+                }
+            };
+            let content = String::from_utf8_lossy(line.content());
+            lines.push(DiffLine {
+                kind,
+                content: content.trim_end_matches(['\n', '\r']).to_string(),
+                old_lineno: line.old_lineno(),
+                new_lineno: line.new_lineno(),
+                no_newline: false,
+                word_ranges: Vec::new(),
+            });
+        }
+        add_word_ranges(&mut lines);
+        hunks.push(DiffHunk {
+            id: hunk_id(patch, hunk_idx)?,
+            header: String::from_utf8_lossy(hunk.header())
+                .trim_end()
+                .to_string(),
+            old_start: hunk.old_start(),
+            old_lines: hunk.old_lines(),
+            new_start: hunk.new_start(),
+            new_lines: hunk.new_lines(),
+            lines,
+        });
+    }
+    Ok(hunks)
+}
+
+/// Identifies a hunk by its header and every line, so any edit to the file
+/// that touches the hunk or shifts it gives it a different id.
+pub(crate) fn hunk_id(patch: &Patch, hunk_idx: usize) -> GitResult<String> {
+    let (hunk, line_count) = patch.hunk(hunk_idx)?;
+    let mut text = hunk.header().to_vec();
+    for line_idx in 0..line_count {
+        let line = patch.line_in_hunk(hunk_idx, line_idx)?;
+        text.push(line.origin() as u8);
+        text.extend_from_slice(line.content());
+    }
+    Ok(Oid::hash_object(ObjectType::Blob, &text)?.to_string())
+}
+
+/// Pairs each run of deletions with the additions that follow it and marks
+/// the words that differ between the paired lines.
+fn add_word_ranges(lines: &mut [DiffLine]) {
+    let mut i = 0;
+    while i < lines.len() {
+        if lines[i].kind != LineKind::Deletion {
+            i += 1;
+            continue;
+        }
+        let deletions = i;
+        while i < lines.len() && lines[i].kind == LineKind::Deletion {
+            i += 1;
+        }
+        let additions = i;
+        while i < lines.len() && lines[i].kind == LineKind::Addition {
+            i += 1;
+        }
+        let pairs = (additions - deletions).min(i - additions);
+        for n in 0..pairs {
+            let (old, new) =
+                word_diff(&lines[deletions + n].content, &lines[additions + n].content);
+            lines[deletions + n].word_ranges = old;
+            lines[additions + n].word_ranges = new;
+        }
+    }
+}
+
+/// Splits a line into identifier runs, whitespace runs and single symbols.
+fn tokens(line: &str) -> Vec<&str> {
+    let class = |c: char| {
+        if c.is_alphanumeric() || c == '_' {
+            0
+        } else if c.is_whitespace() {
+            1
+        } else {
+            2
+        }
+    };
+    let mut tokens = Vec::new();
+    let mut start = 0;
+    let mut chars = line.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        let next_same = chars
+            .peek()
+            .is_some_and(|&(_, n)| class(c) != 2 && class(n) == class(c));
+        if !next_same {
+            let end = i + c.len_utf8();
+            tokens.push(&line[start..end]);
+            start = end;
+        }
+    }
+    tokens
+}
+
+/// Token-level LCS between two lines, returning the changed ranges of each.
+fn word_diff(old: &str, new: &str) -> (Vec<[usize; 2]>, Vec<[usize; 2]>) {
+    let a = tokens(old);
+    let b = tokens(new);
+    // Very long lines aren't worth a quadratic table, mark them whole
+    if a.len() * b.len() > 250_000 {
+        return (whole(old), whole(new));
+    }
+
+    let mut table = vec![vec![0u32; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            table[i][j] = if a[i] == b[j] {
+                table[i + 1][j + 1] + 1
+            } else {
+                table[i + 1][j].max(table[i][j + 1])
+            };
+        }
+    }
+    let mut same_a = vec![false; a.len()];
+    let mut same_b = vec![false; b.len()];
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        if a[i] == b[j] {
+            same_a[i] = true;
+            same_b[j] = true;
+            i += 1;
+            j += 1;
+        } else if table[i + 1][j] >= table[i][j + 1] {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    (ranges(&a, &same_a), ranges(&b, &same_b))
+}
+
+fn whole(line: &str) -> Vec<[usize; 2]> {
+    match line.encode_utf16().count() {
+        0 => Vec::new(),
+        len => vec![[0, len]],
+    }
+}
+
+fn ranges(tokens: &[&str], same: &[bool]) -> Vec<[usize; 2]> {
+    let mut ranges: Vec<[usize; 2]> = Vec::new();
+    let mut offset = 0;
+    for (token, same) in tokens.iter().zip(same) {
+        let len = token.encode_utf16().count();
+        if !same {
+            match ranges.last_mut() {
+                Some(last) if last[1] == offset => last[1] += len,
+                _ => ranges.push([offset, offset + len]),
+            }
+        }
+        offset += len;
+    }
+    ranges
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git::testing::TestRepo;
+    use serde_json::{json, Value};
+
+    fn run_diff(t: &TestRepo, request: Value) -> Vec<FileDiff> {
+        let mut request = request;
+        request["workspace"] = t.url().into();
+        diff(serde_json::from_value(request).unwrap()).unwrap()
+    }
+
+    fn lines(file: &FileDiff) -> Vec<(LineKind, &str)> {
+        file.hunks
+            .iter()
+            .flat_map(|h| &h.lines)
+            .map(|l| (l.kind, l.content.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn splits_tokens() {
+        assert_eq!(
+            tokens("let foo_1 = a+b;  // x"),
+            ["let", " ", "foo_1", " ", "=", " ", "a", "+", "b", ";", "  ", "/", "/", " ", "x"]
+        );
+        assert!(tokens("").is_empty());
+    }
+
+    #[test]
+    fn word_ranges_use_utf16_offsets() {
+        let (old, new) = word_diff("let x = 1;", "let y = 1;");
+        assert_eq!(old, [[4, 5]]);
+        assert_eq!(new, [[4, 5]]);
+        // `é` is one UTF-16 unit, `😀` two
+        let (old, new) = word_diff("é 😀 old", "é 😀 new");
+        assert_eq!(old, [[5, 8]]);
+        assert_eq!(new, [[5, 8]]);
+        let (old, new) = word_diff("", "added");
+        assert!(old.is_empty());
+        assert_eq!(new, [[0, 5]]);
+    }
+
+    #[test]
+    fn pairs_deletions_with_additions() {
+        let line = |kind, content: &str| DiffLine {
+            kind,
+            content: content.into(),
+            old_lineno: None,
+            new_lineno: None,
+            no_newline: false,
+            word_ranges: Vec::new(),
+        };
+        let mut lines = vec![
+            line(LineKind::Context, "same"),
+            line(LineKind::Deletion, "a b"),
+            line(LineKind::Addition, "a c"),
+            line(LineKind::Addition, "extra"),
+        ];
+        add_word_ranges(&mut lines);
+        assert!(lines[0].word_ranges.is_empty());
+        assert_eq!(lines[1].word_ranges, [[2, 3]]);
+        assert_eq!(lines[2].word_ranges, [[2, 3]]);
+        // Unpaired additions are left unmarked
+        assert!(lines[3].word_ranges.is_empty());
+    }
+
+    #[test]
+    fn unstaged_and_staged_diffs() {
+        let t = TestRepo::new();
+        t.write("a.txt", "one\ntwo\nthree\n");
+        t.commit("init");
+        t.write("a.txt", "one\n2\nthree\n");
+        t.run("stage", json!({ "file": "a.txt" })).unwrap();
+        t.write("a.txt", "one\n2\nthree\nfour");
+
+        let staged = run_diff(&t, json!({ "mode": "staged" }));
+        assert_eq!(staged.len(), 1);
+        assert_eq!(staged[0].status, "modified");
+        assert_eq!((staged[0].additions, staged[0].deletions), (1, 1));
+        assert_eq!(
+            lines(&staged[0]),
+            [
+                (LineKind::Context, "one"),
+                (LineKind::Deletion, "two"),
+                (LineKind::Addition, "2"),
+                (LineKind::Context, "three"),
+            ]
+        );
+
+        let unstaged = run_diff(&t, json!({ "mode": "unstaged" }));
+        let hunk = &unstaged[0].hunks[0];
+        let last = hunk.lines.last().unwrap();
+        assert_eq!(
+            (last.kind, last.content.as_str()),
+            (LineKind::Addition, "four")
+        );
+        assert_eq!(last.new_lineno, Some(4));
+        assert!(last.no_newline);
+        assert!(hunk.header.starts_with("@@ -1,3 +1,4 @@"));
+    }
+
+    #[test]
+    fn untracked_files_only_on_request() {
+        let t = TestRepo::new();
+        t.write("a.txt", "a\n");
+        t.commit("init");
+        t.write("new.txt", "n\n");
+        assert!(run_diff(&t, json!({ "mode": "unstaged" })).is_empty());
+        let files = run_diff(&t, json!({ "mode": "unstaged", "includeUntracked": true }));
+        assert_eq!(files[0].status, "untracked");
+        assert_eq!(files[0].old_path, None);
+        assert_eq!(lines(&files[0]), [(LineKind::Addition, "n")]);
+    }
+
+    #[test]
+    fn commit_diff_against_parent_and_paths() {
+        let t = TestRepo::new();
+        t.write("a.txt", "a\n");
+        t.write("b.txt", "b\n");
+        let first = t.commit("init");
+        t.write("a.txt", "changed\n");
+        t.remove("b.txt");
+        t.commit("second");
+
+        let files = run_diff(&t, json!({ "mode": "commit", "commit": "HEAD" }));
+        let statuses: Vec<_> = files.iter().map(|f| f.status).collect();
+        assert_eq!(statuses, ["modified", "deleted"]);
+        assert_eq!(files[1].new_path, None);
+
+        let files = run_diff(
+            &t,
+            json!({ "mode": "commits", "from": first.to_string(), "to": "HEAD", "paths": ["b.txt"] }),
+        );
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].old_path.as_deref(), Some("b.txt"));
+
+        // The root commit diffs against the empty tree
+        let files = run_diff(&t, json!({ "mode": "commit", "commit": first.to_string() }));
+        assert!(files.iter().all(|f| f.status == "added"));
+    }
+
+    #[test]
+    fn binary_files_have_no_hunks() {
+        let t = TestRepo::new();
+        t.write("a.bin", "a\0b");
+        t.commit("init");
+        t.write("a.bin", "a\0c");
+        let files = run_diff(&t, json!({ "mode": "unstaged" }));
+        assert!(files[0].binary);
+        assert!(files[0].hunks.is_empty());
+    }
+
+    #[test]
+    fn hunk_ids_change_with_content() {
+        let t = TestRepo::new();
+        t.write("a.txt", "one\ntwo\n");
+        t.commit("init");
+        t.write("a.txt", "one\n2\n");
+        let first = run_diff(&t, json!({ "mode": "unstaged" }))[0].hunks[0]
+            .id
+            .clone();
+        assert_eq!(
+            run_diff(&t, json!({ "mode": "unstaged" }))[0].hunks[0].id,
+            first
+        );
+        t.write("a.txt", "one\n3\n");
+        assert_ne!(
+            run_diff(&t, json!({ "mode": "unstaged" }))[0].hunks[0].id,
+            first
+        );
+    }
+}
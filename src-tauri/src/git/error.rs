@@ -0,0 +1,177 @@
+use git2::{ErrorClass, ErrorCode};
+use serde::Serialize;
+use std::fmt;
+
+/// What went wrong, so the frontend can react without matching on messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum GitErrorKind {
+    /// The request was malformed or had invalid fields.
+    InvalidRequest,
+    NotARepository,
+    NotFound,
+    AlreadyExists,
+    /// Another git process holds `index.lock` or a ref lock.
+    Locked,
+    MergeConflict,
+    AuthenticationRequired,
+    NonFastForward,
+    /// Local changes would be overwritten.
+    DirtyWorkingTree,
+    NothingToCommit,
+    /// Deleting the branch would lose commits.
+    NotMerged,
+    /// The file changed since the diff the request was built from.
+    Stale,
+    /// The job was cancelled by the user.
+    Cancelled,
+    Other,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct GitError {
+    pub kind: GitErrorKind,
+    pub message: String,
+}
+
+pub type GitResult<T> = Result<T, GitError>;
+
+impl GitError {
+    pub fn new(kind: GitErrorKind, message: impl Into<String>) -> Self {
+        GitError {
+            kind,
+            message: message.into(),
+        }
+    }
+
+    pub fn invalid(message: impl Into<String>) -> Self {
+        GitError::new(GitErrorKind::InvalidRequest, message)
+    }
+
+    pub fn other(message: impl Into<String>) -> Self {
+        GitError::new(GitErrorKind::Other, message)
+    }
+
+    /// Prefixes the message, keeping the kind.
+    pub fn context(self, what: &str) -> Self {
+        GitError::new(self.kind, format!("{}: {}", what, self.message))
+    }
+}
+
+impl fmt::Display for GitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl From<git2::Error> for GitError {
+    fn from(e: git2::Error) -> Self {
+        let message = e.message().to_string();
+        let lower = message.to_lowercase();
+        let kind = match e.code() {
+            ErrorCode::Auth | ErrorCode::Certificate => GitErrorKind::AuthenticationRequired,
+            ErrorCode::NotFastForward => GitErrorKind::NonFastForward,
+            ErrorCode::Conflict | ErrorCode::Uncommitted => GitErrorKind::DirtyWorkingTree,
+            ErrorCode::MergeConflict | ErrorCode::Unmerged => GitErrorKind::MergeConflict,
+            ErrorCode::Locked => GitErrorKind::Locked,
+            ErrorCode::Exists => GitErrorKind::AlreadyExists,
+            ErrorCode::InvalidSpec | ErrorCode::Invalid | ErrorCode::Ambiguous => {
+                GitErrorKind::InvalidRequest
+            }
+            ErrorCode::NotFound if e.class() == ErrorClass::Repository => {
+                GitErrorKind::NotARepository
+            }
+            ErrorCode::NotFound | ErrorCode::UnbornBranch => GitErrorKind::NotFound,
+            _ if lower.contains("unstaged changes") || lower.contains("uncommitted changes") => {
+                GitErrorKind::DirtyWorkingTree
+            }
+            // Transports report rejected credentials as generic http/ssh errors
+            _ if (e.class() == ErrorClass::Http && lower.contains("401"))
+                || lower.contains("authentication") =>
+            {
+                GitErrorKind::AuthenticationRequired
+            }
+            _ => GitErrorKind::Other,
+        };
+        GitError::new(kind, message)
+    }
+}
+
+impl From<tokio::task::JoinError> for GitError {
+    fn from(e: tokio::task::JoinError) -> Self {
+        GitError::other(format!("Git task failed: {}", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kind(code: ErrorCode, class: ErrorClass, message: &str) -> GitErrorKind {
+        GitError::from(git2::Error::new(code, class, message)).kind
+    }
+
+    #[test]
+    fn maps_error_codes() {
+        use GitErrorKind::*;
+        assert_eq!(
+            kind(ErrorCode::Auth, ErrorClass::Http, "x"),
+            AuthenticationRequired
+        );
+        assert_eq!(
+            kind(ErrorCode::NotFastForward, ErrorClass::Reference, "x"),
+            NonFastForward
+        );
+        assert_eq!(
+            kind(ErrorCode::Conflict, ErrorClass::Checkout, "x"),
+            DirtyWorkingTree
+        );
+        assert_eq!(kind(ErrorCode::Locked, ErrorClass::Index, "x"), Locked);
+        assert_eq!(
+            kind(ErrorCode::Exists, ErrorClass::Reference, "x"),
+            AlreadyExists
+        );
+        assert_eq!(
+            kind(ErrorCode::InvalidSpec, ErrorClass::Reference, "x"),
+            InvalidRequest
+        );
+        assert_eq!(
+            kind(ErrorCode::NotFound, ErrorClass::Repository, "x"),
+            NotARepository
+        );
+        assert_eq!(
+            kind(ErrorCode::NotFound, ErrorClass::Reference, "x"),
+            NotFound
+        );
+        assert_eq!(kind(ErrorCode::GenericError, ErrorClass::Net, "x"), Other);
+    }
+
+    #[test]
+    fn maps_transport_messages() {
+        use GitErrorKind::*;
+        let generic = ErrorCode::GenericError;
+        assert_eq!(
+            kind(
+                generic,
+                ErrorClass::Http,
+                "unexpected http status code: 401"
+            ),
+            AuthenticationRequired
+        );
+        assert_eq!(
+            kind(generic, ErrorClass::Ssh, "Authentication failed"),
+            AuthenticationRequired
+        );
+        assert_eq!(
+            kind(generic, ErrorClass::Merge, "you have unstaged changes"),
+            DirtyWorkingTree
+        );
+    }
+
+    #[test]
+    fn context_keeps_kind() {
+        let error =
+            GitError::new(GitErrorKind::Locked, "index.lock exists").context("Git add failed");
+        assert_eq!(error.kind, GitErrorKind::Locked);
+        assert_eq!(error.message, "Git add failed: index.lock exists");
+    }
+}
@@ -0,0 +1,367 @@
+use super::action::{RepoFile, Revision};
+use super::blame::not_found;
+use super::log::Person;
+use super::{repo_path, short_id, GitResult};
+use git2::{Commit, Delta, DiffFindOptions, DiffOptions, Patch, Repository, Sort, Tree};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::path::Path;
+
+/// A page of `git log --follow --numstat -- <file>`.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FileHistoryQuery {
+    pub file: RepoFile,
+    /// Where to start walking, `HEAD` when omitted.
+    pub revision: Option<Revision>,
+    /// Keep going under the file's old name when it was renamed.
+    #[serde(default = "default_follow")]
+    pub follow: bool,
+    #[serde(default = "default_page_size")]
+    pub limit: usize,
+    /// Commits of the file already loaded, `nextSkip` of the previous page.
+    #[serde(default)]
+    pub skip: usize,
+}
+
+fn default_follow() -> bool {
+    true
+}
+
+fn default_page_size() -> usize {
+    100
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct FileCommit {
+    hash: String,
+    short_hash: String,
+    author: Person,
+    summary: String,
+    /// The file's path in this commit, it changes going back past a rename.
+    path: String,
+    /// Set when this commit renamed the file from here.
+    old_path: Option<String>,
+    /// added, deleted, modified, renamed or typechange
+    status: &'static str,
+    binary: bool,
+    additions: usize,
+    deletions: usize,
+}
+
+/// Commits that changed the file, newest first, each with its line stats.
+pub fn history(repo: &Repository, query: &FileHistoryQuery) -> GitResult<Value> {
+    let mut path = repo_path(repo, &query.file);
+    let start = query.revision.as_deref().unwrap_or("HEAD");
+    let mut walk = repo.revwalk()?;
+    walk.set_sorting(Sort::TOPOLOGICAL | Sort::TIME)?;
+    walk.push(repo.revparse_single(start)?.peel_to_commit()?.id())?;
+
+    let limit = query.limit.max(1);
+    let mut seen = 0;
+    let mut commits = Vec::new();
+    let mut more = false;
+    for id in walk {
+        let commit = repo.find_commit(id?)?;
+        let Some(change) = change(repo, &commit, &path, query.follow)? else {
+            continue;
+        };
+        seen += 1;
+        if seen > query.skip {
+            if commits.len() == limit {
+                more = true;
+                break;
+            }
+            commits.push(FileCommit {
+                hash: commit.id().to_string(),
+                short_hash: short_id(repo, commit.id()),
+                author: commit.author().into(),
+                summary: commit.summary().unwrap_or("").to_string(),
+                path: path.clone(),
+                old_path: change.old_path.clone(),
+                status: change.status,
+                binary: change.binary,
+                additions: change.additions,
+                deletions: change.deletions,
+            });
+        }
+        // Older commits know the file by its old name
+        if let Some(old_path) = change.old_path {
+            path = old_path;
+        }
+    }
+    Ok(json!({
+        "commits": commits,
+        "nextSkip": more.then_some(query.skip + limit),
+    }))
+}
+
+struct Change {
+    status: &'static str,
+    old_path: Option<String>,
+    binary: bool,
+    additions: usize,
+    deletions: usize,
+}
+
+/// How `commit` changed `path` against its first parent, `None` when it
+/// didn't. Merges only count when they differ from every parent, like
+/// `git log -- <path>`.
+fn change(
+    repo: &Repository,
+    commit: &Commit,
+    path: &str,
+    follow: bool,
+) -> GitResult<Option<Change>> {
+    let entry_id = |tree: &Tree| tree.get_path(Path::new(path)).ok().map(|e| e.id());
+    let tree = commit.tree()?;
+    let own = entry_id(&tree);
+    let mut parent_trees = Vec::new();
+    for parent in commit.parents() {
+        let parent_tree = parent.tree()?;
+        if entry_id(&parent_tree) == own {
+            return Ok(None);
+        }
+        parent_trees.push(parent_tree);
+    }
+    let parent = parent_trees.first();
+    if parent.is_none() && own.is_none() {
+        return Ok(None);
+    }
+
+    let mut options = DiffOptions::new();
+    options.pathspec(path).disable_pathspec_match(true);
+    let diff = repo.diff_tree_to_tree(parent, Some(&tree), Some(&mut options))?;
+    let mut change = match diff.deltas().next().map(|d| d.status()) {
+        Some(Delta::Added) if follow && parent.is_some() => {
+            // Only a diff of everything can tell an add from a rename
+            let mut all = repo.diff_tree_to_tree(parent, Some(&tree), None)?;
+            all.find_similar(Some(DiffFindOptions::new().renames(true)))?;
+            let renamed = all.deltas().enumerate().find(|(_, d)| {
+                d.status() == Delta::Renamed && d.new_file().path() == Some(Path::new(path))
+            });
+            match renamed {
+                Some((idx, delta)) => stats(
+                    Patch::from_diff(&all, idx)?,
+                    "renamed",
+                    delta
+                        .old_file()
+                        .path()
+                        .map(|p| p.to_string_lossy().replace('\\', "/")),
+                )?,
+                None => stats(Patch::from_diff(&diff, 0)?, "added", None)?,
+            }
+        }
+        Some(status) => {
+            let status = match status {
+                Delta::Added => "added",
+                Delta::Deleted => "deleted",
+                Delta::Typechange => "typechange",
+                _ => "modified",
+            };
+            stats(Patch::from_diff(&diff, 0)?, status, None)?
+        }
+        None => return Ok(None),
+    };
+    if change.old_path.as_deref() == Some(path) {
+        change.old_path = None;
+    }
+    Ok(Some(change))
+}
+
+fn stats(
+    patch: Option<Patch>,
+    status: &'static str,
+    old_path: Option<String>,
+) -> GitResult<Change> {
+    let mut change = Change {
+        status,
+        old_path,
+        binary: false,
+        additions: 0,
+        deletions: 0,
+    };
+    if let Some(patch) = patch {
+        change.binary = patch.delta().flags().is_binary();
+        if !change.binary {
+            let (_, additions, deletions) = patch.line_stats()?;
+            change.additions = additions;
+            change.deletions = deletions;
+        }
+    }
+    Ok(change)
+}
+
+/// The file as it is in a commit, tag or stash (`stash@{0}`), read only.
+/// Binary files come without `content`.
+pub fn content(repo: &Repository, file: &str, revision: &str) -> GitResult<Value> {
+    let path = repo_path(repo, file);
+    let commit = repo.revparse_single(revision)?.peel_to_commit()?;
+    let entry = commit
+        .tree()?
+        .get_path(Path::new(&path))
+        .map_err(|_| not_found(&path, revision))?;
+    let blob = entry
+        .to_object(repo)?
+        .into_blob()
+        .map_err(|_| not_found(&path, revision))?;
+    let binary = blob.is_binary();
+    let content = match binary {
+        true => None,
+        false => Some(String::from_utf8_lossy(blob.content()).into_owned()),
+    };
+    Ok(json!({
+        "path": path,
+        "revision": revision,
+        "commit": commit.id().to_string(),
+        "shortCommit": short_id(repo, commit.id()),
+        "blob": blob.id().to_string(),
+        "size": blob.size(),
+        "binary": binary,
+        "content": content,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git::testing::TestRepo;
+    use crate::git::GitErrorKind;
+
+    fn query(value: Value) -> FileHistoryQuery {
+        serde_json::from_value(value).unwrap()
+    }
+
+    /// `old.txt` created and changed, renamed to `new.txt`, changed again,
+    /// with an unrelated commit in between.
+    fn renamed() -> TestRepo {
+        let t = TestRepo::new();
+        let body = "one\ntwo\nthree\nfour\nfive\n";
+        t.write("old.txt", body);
+        t.commit("create");
+        t.write("old.txt", &format!("{}six\n", body));
+        t.commit("grow");
+        t.write("other.txt", "other\n");
+        t.commit("unrelated");
+        t.remove("old.txt");
+        t.write("new.txt", &format!("{}six\n", body));
+        t.commit("rename");
+        t.write(
+            "new.txt",
+            &format!("zero\n{}six\n", body.replacen("one\n", "", 1)),
+        );
+        t.commit("edit");
+        t
+    }
+
+    fn summaries(history: &Value) -> Vec<&str> {
+        history["commits"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|c| c["summary"].as_str().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn follows_renames() {
+        let t = renamed();
+        let page = history(&t.repo, &query(json!({ "file": "new.txt" }))).unwrap();
+        assert_eq!(summaries(&page), ["edit", "rename", "grow", "create"]);
+        let commits = page["commits"].as_array().unwrap();
+        assert_eq!(commits[0]["status"], "modified");
+        assert_eq!(
+            (
+                commits[0]["additions"].as_u64(),
+                commits[0]["deletions"].as_u64()
+            ),
+            (Some(1), Some(1))
+        );
+        assert_eq!(commits[1]["status"], "renamed");
+        assert_eq!(commits[1]["path"], "new.txt");
+        assert_eq!(commits[1]["oldPath"], "old.txt");
+        assert_eq!(commits[2]["path"], "old.txt");
+        assert_eq!(commits[3]["status"], "added");
+        assert_eq!(commits[3]["additions"], 5);
+        assert_eq!(page["nextSkip"], Value::Null);
+
+        let page = history(
+            &t.repo,
+            &query(json!({ "file": "new.txt", "follow": false })),
+        )
+        .unwrap();
+        assert_eq!(summaries(&page), ["edit", "rename"]);
+        assert_eq!(page["commits"][1]["status"], "added");
+    }
+
+    #[test]
+    fn pages() {
+        let t = renamed();
+        let first = history(&t.repo, &query(json!({ "file": "new.txt", "limit": 2 }))).unwrap();
+        assert_eq!(summaries(&first), ["edit", "rename"]);
+        assert_eq!(first["nextSkip"], 2);
+        let second = history(
+            &t.repo,
+            &query(json!({ "file": "new.txt", "limit": 2, "skip": 2 })),
+        )
+        .unwrap();
+        assert_eq!(summaries(&second), ["grow", "create"]);
+        assert_eq!(second["nextSkip"], Value::Null);
+
+        let older = history(
+            &t.repo,
+            &query(json!({ "file": "old.txt", "revision": "HEAD~2" })),
+        )
+        .unwrap();
+        assert_eq!(summaries(&older), ["grow", "create"]);
+    }
+
+    #[test]
+    fn merges_only_count_when_they_change_the_file() {
+        let t = TestRepo::new();
+        t.conflict("c.txt", "base\n", "ours\n", "theirs\n");
+        t.write("c.txt", "merged\n");
+        let mut index = t.index();
+        index.add_path(Path::new("c.txt")).unwrap();
+        index.write().unwrap();
+        crate::git::operation::continue_operation(&t.repo).unwrap();
+
+        let page = history(&t.repo, &query(json!({ "file": "c.txt" }))).unwrap();
+        let mut summaries = summaries(&page);
+        assert_eq!(summaries.first(), Some(&"Merge branch 'other'"));
+        summaries.sort();
+        assert_eq!(
+            summaries,
+            ["Merge branch 'other'", "base", "ours", "theirs"]
+        );
+    }
+
+    #[test]
+    fn reads_content_at_a_revision() {
+        let t = renamed();
+        let old = content(&t.repo, "old.txt", "HEAD~3").unwrap();
+        assert_eq!(old["content"], "one\ntwo\nthree\nfour\nfive\nsix\n");
+        assert_eq!(old["binary"], false);
+        assert_eq!(old["size"], 28);
+        let err = content(&t.repo, "old.txt", "HEAD").unwrap_err();
+        assert_eq!(err.kind, GitErrorKind::NotFound);
+
+        t.write("image.bin", "\0\x01\x02");
+        t.commit("binary");
+        let binary = content(&t.repo, "image.bin", "HEAD").unwrap();
+        assert_eq!(binary["binary"], true);
+        assert_eq!(binary["content"], Value::Null);
+    }
+
+    #[test]
+    fn reads_content_from_a_stash() {
+        let t = renamed();
+        t.write("new.txt", "stashed\n");
+        let signature = crate::git::testing::signature();
+        let mut repo = git2::Repository::open(t.path()).unwrap();
+        repo.stash_save(&signature, "wip", None).unwrap();
+        let stashed = content(&t.repo, "new.txt", "stash@{0}").unwrap();
+        assert_eq!(stashed["content"], "stashed\n");
+    }
+}
@@ -0,0 +1,633 @@
+use super::{GitError, GitResult};
+use git2::Repository;
+use regex::{Regex, RegexBuilder};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::path::{Path, PathBuf};
+
+/// Which kind of pattern `add` writes for a path.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum IgnoreKind {
+    /// Just this file, `/path/to/file`.
+    Exact,
+    /// Every file with its extension, `*.log`.
+    Extension,
+    /// Its folder, or itself if it is one, `/path/to/`.
+    Folder,
+}
+
+/// Where `add` writes the pattern.
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum IgnoreFile {
+    /// `.gitignore` at the top of the repository.
+    #[default]
+    Root,
+    /// `.gitignore` in the path's own directory.
+    Directory,
+    /// `.git/info/exclude`, not shared with anyone.
+    Exclude,
+    /// `core.excludesFile`, for every repository of this user.
+    Global,
+}
+
+/// One line of an ignore file.
+struct Rule {
+    pattern: String,
+    line: usize,
+    negated: bool,
+    dir_only: bool,
+    regex: Regex,
+}
+
+/// The rules of one ignore file, matched against paths relative to `base`.
+struct Source {
+    /// How the file is shown: repository relative, or absolute for the
+    /// global one.
+    name: String,
+    base: String,
+    rules: Vec<Rule>,
+}
+
+/// Why `path` is (not) ignored: the last pattern that matches it or one of
+/// its folders, and the file and line it's on, like `git check-ignore -v`.
+pub fn explain(repo: &Repository, path: &str) -> GitResult<Value> {
+    let workdir = workdir(repo)?;
+    let path = path.trim_end_matches('/');
+    let is_dir = workdir.join(path).is_dir();
+    let ignore_case = ignore_case(repo);
+    let sources = sources(repo, path, ignore_case)?;
+
+    // A file inside an ignored folder can't be re-included, so the folders
+    // are asked first
+    let components: Vec<&str> = path.split('/').collect();
+    let mut matched = None;
+    for end in 1..=components.len() {
+        let prefix = components[..end].join("/");
+        let dir = end < components.len() || is_dir;
+        matched = find_match(&sources, &prefix, dir);
+        if matches!(matched, Some((_, rule)) if !rule.negated) {
+            break;
+        }
+    }
+
+    // libgit2 disagrees with git about some negations across files, the rule
+    // we found is what git itself would go by
+    let ignored = matches!(matched, Some((_, rule)) if !rule.negated);
+    let tracked = repo.index()?.get_path(Path::new(path), 0).is_some();
+    let rule = matched.map(|(source, rule)| {
+        json!({
+            "pattern": rule.pattern,
+            "source": source.name,
+            "line": rule.line,
+            "negated": rule.negated,
+        })
+    });
+    Ok(json!({
+        "path": path,
+        "ignored": ignored,
+        "tracked": tracked,
+        "rule": rule,
+        "suggestions": {
+            "exact": suggest(path, is_dir, IgnoreKind::Exact, ""),
+            "extension": suggest(path, is_dir, IgnoreKind::Extension, ""),
+            "folder": suggest(path, is_dir, IgnoreKind::Folder, ""),
+        },
+    }))
+}
+
+/// Appends a pattern for `path` to `file` unless it's already there, and
+/// with `untrack` removes the tracked files it covers from the index.
+pub fn add(
+    repo: &Repository,
+    path: &str,
+    kind: IgnoreKind,
+    file: IgnoreFile,
+    untrack: bool,
+) -> GitResult<Value> {
+    let workdir = workdir(repo)?;
+    let path = path.trim_end_matches('/');
+    let is_dir = workdir.join(path).is_dir();
+    let (target, base) = match file {
+        IgnoreFile::Root => (workdir.join(".gitignore"), String::new()),
+        IgnoreFile::Directory => {
+            let dir = parent(path);
+            (workdir.join(dir).join(".gitignore"), dir.to_string())
+        }
+        IgnoreFile::Exclude => (repo.commondir().join("info").join("exclude"), String::new()),
+        IgnoreFile::Global => (
+            global_excludes(repo).ok_or_else(|| {
+                GitError::invalid("No global excludes file, set core.excludesFile or HOME.")
+            })?,
+            String::new(),
+        ),
+    };
+    let pattern = suggest(path, is_dir, kind, &base);
+
+    let existing = std::fs::read_to_string(&target).unwrap_or_default();
+    let added = !existing.lines().any(|line| line.trim() == pattern);
+    if added {
+        let mut content = existing;
+        if !content.is_empty() && !content.ends_with('\n') {
+            content.push('\n');
+        }
+        content.push_str(&pattern);
+        content.push('\n');
+        if let Some(dir) = target.parent() {
+            std::fs::create_dir_all(dir).map_err(|e| GitError::other(e.to_string()))?;
+        }
+        std::fs::write(&target, content).map_err(|e| GitError::other(e.to_string()))?;
+        println!("[IGNORE] Added '{}' to {}", pattern, target.display());
+    }
+
+    let mut untracked = Vec::new();
+    if untrack {
+        let rule = parse_rule(&pattern, 0, ignore_case(repo))
+            .ok_or_else(|| GitError::invalid(format!("'{}' is not a pattern", pattern)))?;
+        let mut index = repo.index()?;
+        let tracked: Vec<String> = index
+            .iter()
+            .map(|entry| String::from_utf8_lossy(&entry.path).into_owned())
+            .collect();
+        for tracked_path in tracked {
+            let Some(relative) = relative_to(&tracked_path, &base) else {
+                continue;
+            };
+            if covers(&rule, relative) {
+                index.remove_path(Path::new(&tracked_path))?;
+                untracked.push(tracked_path);
+            }
+        }
+        if !untracked.is_empty() {
+            index.write()?;
+            println!("[IGNORE] Untracked {} file(s)", untracked.len());
+        }
+    }
+
+    Ok(json!({
+        "pattern": pattern,
+        "file": display(&target, &workdir),
+        "added": added,
+        "untracked": untracked,
+        "ignored": explain(repo, path)?["ignored"],
+    }))
+}
+
+fn workdir(repo: &Repository) -> GitResult<PathBuf> {
+    repo.workdir()
+        .map(Path::to_path_buf)
+        .ok_or_else(|| GitError::invalid("this operation must be run in a work tree"))
+}
+
+fn ignore_case(repo: &Repository) -> bool {
+    repo.config()
+        .and_then(|c| c.get_bool("core.ignoreCase"))
+        .unwrap_or(false)
+}
+
+/// Lowest precedence first: the global excludes, `info/exclude`, then the
+/// `.gitignore` files from the top down to the path's folder.
+fn sources(repo: &Repository, path: &str, ignore_case: bool) -> GitResult<Vec<Source>> {
+    let workdir = workdir(repo)?;
+    let mut sources = Vec::new();
+    let mut push = |file: PathBuf, base: String| {
+        if let Ok(content) = std::fs::read_to_string(&file) {
+            sources.push(Source {
+                name: display(&file, &workdir),
+                base,
+                rules: content
+                    .lines()
+                    .enumerate()
+                    .filter_map(|(i, line)| parse_rule(line, i + 1, ignore_case))
+                    .collect(),
+            });
+        }
+    };
+    if let Some(global) = global_excludes(repo) {
+        push(global, String::new());
+    }
+    push(repo.commondir().join("info").join("exclude"), String::new());
+    push(workdir.join(".gitignore"), String::new());
+    let mut dir = String::new();
+    for component in parent(path).split('/').filter(|c| !c.is_empty()) {
+        dir = if dir.is_empty() {
+            component.to_string()
+        } else {
+            format!("{}/{}", dir, component)
+        };
+        push(workdir.join(&dir).join(".gitignore"), dir.clone());
+    }
+    Ok(sources)
+}
+
+/// The rule that decides `path`: the last one matching in the source with
+/// the highest precedence.
+fn find_match<'s>(
+    sources: &'s [Source],
+    path: &str,
+    is_dir: bool,
+) -> Option<(&'s Source, &'s Rule)> {
+    sources.iter().rev().find_map(|source| {
+        let relative = relative_to(path, &source.base)?;
+        source
+            .rules
+            .iter()
+            .rev()
+            .find(|rule| (is_dir || !rule.dir_only) && rule.regex.is_match(relative))
+            .map(|rule| (source, rule))
+    })
+}
+
+/// Whether `rule` ignores `path`, directly or through one of its folders.
+fn covers(rule: &Rule, path: &str) -> bool {
+    let components: Vec<&str> = path.split('/').collect();
+    (1..=components.len()).any(|end| {
+        let is_dir = end < components.len();
+        (is_dir || !rule.dir_only) && rule.regex.is_match(&components[..end].join("/"))
+    })
+}
+
+fn relative_to<'p>(path: &'p str, base: &str) -> Option<&'p str> {
+    if base.is_empty() {
+        return Some(path);
+    }
+    path.strip_prefix(base)?.strip_prefix('/')
+}
+
+fn parent(path: &str) -> &str {
+    path.rsplit_once('/').map(|(dir, _)| dir).unwrap_or("")
+}
+
+/// The pattern for `path` as written in an ignore file at `base`.
+fn suggest(path: &str, is_dir: bool, kind: IgnoreKind, base: &str) -> String {
+    let relative = relative_to(path, base).unwrap_or(path);
+    let pattern = match kind {
+        IgnoreKind::Exact if is_dir => format!("/{}/", escape(relative)),
+        IgnoreKind::Exact => format!("/{}", escape(relative)),
+        IgnoreKind::Extension => {
+            let name = relative.rsplit('/').next().unwrap_or(relative);
+            match name.rsplit_once('.') {
+                Some((stem, extension)) if !stem.is_empty() && !is_dir => {
+                    format!("*.{}", escape(extension))
+                }
+                _ => escape(name),
+            }
+        }
+        IgnoreKind::Folder if is_dir => format!("/{}/", escape(relative)),
+        IgnoreKind::Folder => match parent(relative) {
+            "" => format!("/{}", escape(relative)),
+            dir => format!("/{}/", escape(dir)),
+        },
+    };
+    // Would be a comment or a negation otherwise
+    if pattern.starts_with(['#', '!']) {
+        format!("\\{}", pattern)
+    } else {
+        pattern
+    }
+}
+
+/// `name` as a glob that only matches itself.
+fn escape(name: &str) -> String {
+    let mut escaped = String::with_capacity(name.len());
+    for c in name.chars() {
+        if matches!(c, '*' | '?' | '[' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    // Trailing spaces are dropped unless escaped
+    let kept = escaped.trim_end_matches(' ').len();
+    let spaces = escaped.len() - kept;
+    escaped.truncate(kept);
+    escaped.push_str(&"\\ ".repeat(spaces));
+    escaped
+}
+
+/// `core.excludesFile`, or git's default `~/.config/git/ignore`.
+fn global_excludes(repo: &Repository) -> Option<PathBuf> {
+    if let Ok(path) = repo.config().and_then(|c| c.get_path("core.excludesFile")) {
+        return Some(path);
+    }
+    let config_home = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")))?;
+    Some(config_home.join("git").join("ignore"))
+}
+
+fn display(path: &Path, workdir: &Path) -> String {
+    match path.strip_prefix(workdir) {
+        Ok(relative) => relative.to_string_lossy().replace('\\', "/"),
+        Err(_) => path.to_string_lossy().into_owned(),
+    }
+}
+
+/// A `.gitignore` line, `None` for blanks and comments.
+fn parse_rule(line: &str, number: usize, ignore_case: bool) -> Option<Rule> {
+    // Trailing spaces don't count unless escaped
+    let mut pattern = line.trim_end_matches(['\r', '\n']);
+    while pattern.ends_with(' ') && !pattern.ends_with("\\ ") {
+        pattern = &pattern[..pattern.len() - 1];
+    }
+    if pattern.is_empty() || pattern.starts_with('#') {
+        return None;
+    }
+    let original = pattern.to_string();
+    let negated = pattern.starts_with('!');
+    if negated {
+        pattern = &pattern[1..];
+    }
+    let dir_only = pattern.ends_with('/');
+    let pattern = pattern.trim_end_matches('/');
+    // With a slash anywhere but the end, the pattern is relative to the
+    // ignore file's folder, otherwise it matches a name at any depth
+    let anchored = pattern.contains('/');
+    let glob = pattern.strip_prefix('/').unwrap_or(pattern);
+    if glob.is_empty() {
+        return None;
+    }
+    let prefix = if anchored { "^" } else { "^(?:.*/)?" };
+    let regex = RegexBuilder::new(&format!("{}{}$", prefix, glob_to_regex(glob)))
+        .case_insensitive(ignore_case)
+        .build()
+        .ok()?;
+    Some(Rule {
+        pattern: original,
+        line: number,
+        negated,
+        dir_only,
+        regex,
+    })
+}
+
+/// Translates a gitignore glob, where `*` stays within one folder and `**`
+/// spans any number of them.
+fn glob_to_regex(glob: &str) -> String {
+    let chars: Vec<char> = glob.chars().collect();
+    let mut regex = String::new();
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            '*' if chars.get(i + 1) == Some(&'*') => {
+                let at_start = i == 0 || chars[i - 1] == '/';
+                let at_end = i + 2 == chars.len();
+                if at_start && chars.get(i + 2) == Some(&'/') {
+                    // `**/` is zero or more folders
+                    regex.push_str("(?:.*/)?");
+                    i += 3;
+                    continue;
+                }
+                if at_start && at_end {
+                    regex.push_str(".*");
+                } else {
+                    regex.push_str("[^/]*");
+                }
+                i += 2;
+                continue;
+            }
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            '[' => match chars[i + 1..].iter().position(|&c| c == ']') {
+                Some(len) if len > 0 => {
+                    let class: String = chars[i + 1..i + 1 + len].iter().collect();
+                    let (negate, class) = match class.strip_prefix(['!', '^']) {
+                        Some(rest) => (true, rest.to_string()),
+                        None => (false, class),
+                    };
+                    regex.push('[');
+                    if negate {
+                        regex.push('^');
+                        regex.push('/');
+                    }
+                    regex.push_str(&class.replace('[', "\\["));
+                    regex.push(']');
+                    i += len + 2;
+                    continue;
+                }
+                _ => regex.push_str("\\["),
+            },
+            '\\' if i + 1 < chars.len() => {
+                regex.push_str(&regex::escape(&chars[i + 1].to_string()));
+                i += 2;
+                continue;
+            }
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+        i += 1;
+    }
+    regex
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git::testing::TestRepo;
+
+    fn matches(pattern: &str, path: &str) -> bool {
+        let rule = parse_rule(pattern, 1, false).unwrap();
+        covers(&rule, path)
+    }
+
+    #[test]
+    fn translates_globs() {
+        assert!(matches("*.log", "a.log"));
+        assert!(matches("*.log", "deep/down/a.log"));
+        assert!(!matches("*.log", "a.log.txt"));
+        assert!(matches("/build", "build/out.o"));
+        assert!(!matches("/build", "src/build"));
+        assert!(matches("doc/*.txt", "doc/a.txt"));
+        assert!(!matches("doc/*.txt", "doc/sub/a.txt"));
+        assert!(matches("**/temp", "a/b/temp"));
+        assert!(matches("logs/**", "logs/a/b.txt"));
+        assert!(matches("a/**/b", "a/b"));
+        assert!(matches("a/**/b", "a/x/y/b"));
+        assert!(matches("file?.txt", "file1.txt"));
+        assert!(!matches("file?.txt", "file10.txt"));
+        assert!(matches("[abc].txt", "b.txt"));
+        assert!(matches("[!abc].txt", "d.txt"));
+        assert!(!matches("[!abc].txt", "a.txt"));
+        assert!(matches("\\#notes", "#notes"));
+        assert!(matches("trailing\\ ", "trailing "));
+
+        // Folder only patterns don't match files, but do match what's in them
+        assert!(!matches("out/", "out"));
+        assert!(matches("out/", "out/a.o"));
+        assert!(parse_rule("# comment", 1, false).is_none());
+        assert!(parse_rule("   ", 1, false).is_none());
+        assert!(parse_rule("!keep.log", 1, false).unwrap().negated);
+    }
+
+    #[test]
+    fn suggestions() {
+        assert_eq!(
+            suggest("src/a.log", false, IgnoreKind::Exact, ""),
+            "/src/a.log"
+        );
+        assert_eq!(
+            suggest("src/a.log", false, IgnoreKind::Exact, "src"),
+            "/a.log"
+        );
+        assert_eq!(
+            suggest("src/a.log", false, IgnoreKind::Extension, ""),
+            "*.log"
+        );
+        assert_eq!(
+            suggest("src/.env", false, IgnoreKind::Extension, ""),
+            ".env"
+        );
+        assert_eq!(suggest("src/a.log", false, IgnoreKind::Folder, ""), "/src/");
+        assert_eq!(suggest("target", true, IgnoreKind::Folder, ""), "/target/");
+    }
+
+    #[test]
+    fn suggestions_escape_glob_characters() {
+        let cases = [
+            ("data[1]*.csv", IgnoreKind::Exact, "/data\\[1]\\*.csv"),
+            ("what?.txt", IgnoreKind::Exact, "/what\\?.txt"),
+            ("back\\slash", IgnoreKind::Exact, "/back\\\\slash"),
+            ("#notes", IgnoreKind::Extension, "\\#notes"),
+            ("!important", IgnoreKind::Extension, "\\!important"),
+            ("a.[ch]", IgnoreKind::Extension, "*.\\[ch]"),
+            ("space ", IgnoreKind::Exact, "/space\\ "),
+        ];
+        for (path, kind, expected) in cases {
+            let pattern = suggest(path, false, kind, "");
+            assert_eq!(pattern, expected);
+            let rule = parse_rule(&pattern, 1, false).unwrap();
+            assert!(!rule.negated, "{}", pattern);
+            assert!(covers(&rule, path), "{} should match {}", pattern, path);
+        }
+        let rule = parse_rule(
+            &suggest("data[1]*.csv", false, IgnoreKind::Exact, ""),
+            1,
+            false,
+        )
+        .unwrap();
+        assert!(!covers(&rule, "data1.csv"));
+        assert!(!covers(&rule, "data[1]x.csv"));
+    }
+
+    #[test]
+    fn explains_the_deciding_rule() {
+        let t = TestRepo::new();
+        t.write(".gitignore", "*.log\n!keep.log\nbuild/\n");
+        t.write("sub/.gitignore", "local.txt\n");
+        t.write("build/out.o", "");
+        t.write("sub/local.txt", "");
+        std::fs::create_dir_all(t.repo.path().join("info")).unwrap();
+        std::fs::write(t.repo.path().join("info/exclude"), "secret\n").unwrap();
+
+        let explained = explain(&t.repo, "a.log").unwrap();
+        assert_eq!(explained["ignored"], true);
+        assert_eq!(explained["rule"]["source"], ".gitignore");
+        assert_eq!(explained["rule"]["line"], 1);
+
+        let explained = explain(&t.repo, "keep.log").unwrap();
+        assert_eq!(explained["ignored"], false);
+        assert_eq!(explained["rule"]["negated"], true);
+
+        assert_eq!(
+            explain(&t.repo, "build/out.o").unwrap()["rule"]["pattern"],
+            "build/"
+        );
+        assert_eq!(
+            explain(&t.repo, "sub/local.txt").unwrap()["rule"]["source"],
+            "sub/.gitignore"
+        );
+        assert_eq!(
+            explain(&t.repo, "secret").unwrap()["rule"]["source"],
+            ".git/info/exclude"
+        );
+        let explained = explain(&t.repo, "main.rs").unwrap();
+        assert_eq!(explained["ignored"], false);
+        assert_eq!(explained["rule"], Value::Null);
+    }
+
+    #[test]
+    fn adds_rules_and_untracks() {
+        let t = TestRepo::new();
+        t.write("logs/a.log", "a\n");
+        t.write("logs/b.log", "b\n");
+        t.write("main.rs", "\n");
+        t.commit("tracked");
+
+        let added = add(
+            &t.repo,
+            "logs/a.log",
+            IgnoreKind::Extension,
+            IgnoreFile::Root,
+            true,
+        )
+        .unwrap();
+        assert_eq!(added["pattern"], "*.log");
+        assert_eq!(added["file"], ".gitignore");
+        assert_eq!(added["added"], true);
+        assert_eq!(added["untracked"], json!(["logs/a.log", "logs/b.log"]));
+        assert_eq!(added["ignored"], true);
+        assert!(t.index().get_path(Path::new("logs/a.log"), 0).is_none());
+        assert!(t.path().join("logs/a.log").exists());
+
+        let again = add(
+            &t.repo,
+            "logs/b.log",
+            IgnoreKind::Extension,
+            IgnoreFile::Root,
+            false,
+        )
+        .unwrap();
+        assert_eq!(again["added"], false);
+        assert_eq!(t.read(".gitignore"), "*.log\n");
+
+        let local = add(
+            &t.repo,
+            "logs/a.log",
+            IgnoreKind::Exact,
+            IgnoreFile::Directory,
+            false,
+        )
+        .unwrap();
+        assert_eq!(local["file"], "logs/.gitignore");
+        assert_eq!(t.read("logs/.gitignore"), "/a.log\n");
+        add(
+            &t.repo,
+            "main.rs",
+            IgnoreKind::Exact,
+            IgnoreFile::Exclude,
+            false,
+        )
+        .unwrap();
+        assert!(t.repo.status_should_ignore(Path::new("main.rs")).unwrap());
+    }
+
+    #[test]
+    fn escaped_rules_agree_with_libgit2() {
+        let t = TestRepo::new();
+        t.write("#notes", "");
+        t.write("data[1].csv", "");
+        t.write("data1.csv", "");
+        add(
+            &t.repo,
+            "#notes",
+            IgnoreKind::Extension,
+            IgnoreFile::Root,
+            false,
+        )
+        .unwrap();
+        add(
+            &t.repo,
+            "data[1].csv",
+            IgnoreKind::Exact,
+            IgnoreFile::Root,
+            false,
+        )
+        .unwrap();
+        assert!(t.repo.status_should_ignore(Path::new("#notes")).unwrap());
+        assert!(t
+            .repo
+            .status_should_ignore(Path::new("data[1].csv"))
+            .unwrap());
+        assert!(!t.repo.status_should_ignore(Path::new("data1.csv")).unwrap());
+    }
+}
@@ -0,0 +1,300 @@
+use super::{GitError, GitErrorKind, GitResult};
+use regex::Regex;
+use serde::Serialize;
+use serde_json::Value;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+/// Payload of the `git-progress` event.
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Progress {
+    pub job_id: String,
+    /// `counting`, `compressing`, `receiving`, `resolving`, `writing` or
+    /// `checkout`, or `remote` for other lines the server printed.
+    pub phase: String,
+    /// Objects, deltas or files done in this phase, out of `total`.
+    pub current: usize,
+    pub total: usize,
+    pub percent: u32,
+    pub received_bytes: usize,
+    /// The line the remote printed, for `remote:` output in the UI.
+    pub message: Option<String>,
+}
+
+/// Payload of the `git-job-finished` event.
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct JobFinished {
+    pub job_id: String,
+    pub result: Option<Value>,
+    pub error: Option<GitError>,
+}
+
+/// A long running clone, fetch, pull or push that reports progress and can
+/// be cancelled by its id.
+pub struct Job {
+    id: String,
+    cancelled: Arc<AtomicBool>,
+    report: Box<dyn Fn(Progress) + Send + Sync>,
+    last: Mutex<Option<(String, u32, Instant)>>,
+}
+
+static JOBS: LazyLock<Mutex<HashMap<String, Arc<AtomicBool>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+thread_local! {
+    /// The job the current thread works for. Remote callbacks are built deep
+    /// inside the actions, so they pick it up from here.
+    static CURRENT: RefCell<Option<Arc<Job>>> = const { RefCell::new(None) };
+}
+
+/// Progress lines git servers send, e.g. `Compressing objects:  45% (9/20)`.
+static SIDEBAND: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^(\w+) objects:\s+(\d+)% \((\d+)/(\d+)\)").unwrap());
+
+const THROTTLE: Duration = Duration::from_millis(100);
+
+impl Job {
+    pub fn new(
+        id: String,
+        report: impl Fn(Progress) + Send + Sync + 'static,
+    ) -> GitResult<Arc<Self>> {
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let mut jobs = JOBS.lock().unwrap_or_else(|e| e.into_inner());
+        if jobs.contains_key(&id) {
+            return Err(GitError::new(
+                GitErrorKind::AlreadyExists,
+                format!("job '{}' is already running", id),
+            ));
+        }
+        jobs.insert(id.clone(), cancelled.clone());
+        Ok(Arc::new(Job {
+            id,
+            cancelled,
+            report: Box::new(report),
+            last: Mutex::new(None),
+        }))
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    /// Runs `f` on this thread as the job, turning the error of a cancelled
+    /// operation into `Cancelled`.
+    pub fn run<T>(self: &Arc<Self>, f: impl FnOnce() -> GitResult<T>) -> GitResult<T> {
+        let previous = CURRENT.with(|current| current.replace(Some(self.clone())));
+        let result = f();
+        CURRENT.with(|current| *current.borrow_mut() = previous);
+        result.map_err(|e| {
+            if self.is_cancelled() {
+                GitError::new(GitErrorKind::Cancelled, "Cancelled")
+            } else {
+                e
+            }
+        })
+    }
+
+    /// Fetch side progress. Returning false makes libgit2 stop the transfer.
+    pub(crate) fn transfer(&self, stats: &git2::Progress) -> bool {
+        let (phase, current, total) =
+            if stats.received_objects() < stats.total_objects() || stats.total_deltas() == 0 {
+                ("receiving", stats.received_objects(), stats.total_objects())
+            } else {
+                ("resolving", stats.indexed_deltas(), stats.total_deltas())
+            };
+        self.emit(phase, current, total, stats.received_bytes(), None);
+        !self.is_cancelled()
+    }
+
+    /// Text the remote prints while it prepares the pack.
+    pub(crate) fn sideband(&self, data: &[u8]) -> bool {
+        let text = String::from_utf8_lossy(data);
+        // Servers redraw the same line with `\r`, only the latest one matters
+        if let Some(line) = text
+            .split(['\r', '\n'])
+            .map(str::trim)
+            .rfind(|line| !line.is_empty())
+        {
+            match SIDEBAND.captures(line) {
+                Some(caps) => {
+                    let phase = caps[1].to_lowercase();
+                    let current = caps[3].parse().unwrap_or(0);
+                    let total = caps[4].parse().unwrap_or(0);
+                    self.emit(&phase, current, total, 0, Some(line.to_string()));
+                }
+                None => self.emit("remote", 0, 0, 0, Some(line.to_string())),
+            }
+        }
+        !self.is_cancelled()
+    }
+
+    pub(crate) fn push_transfer(&self, current: usize, total: usize, bytes: usize) {
+        self.emit("writing", current, total, bytes, None);
+    }
+
+    pub(crate) fn checkout(&self, current: usize, total: usize) {
+        self.emit("checkout", current, total, 0, None);
+    }
+
+    /// Errors out of a callback that can't be stopped by returning false.
+    pub(crate) fn check(&self) -> Result<(), git2::Error> {
+        if self.is_cancelled() {
+            return Err(git2::Error::from_str("cancelled"));
+        }
+        Ok(())
+    }
+
+    fn emit(
+        &self,
+        phase: &str,
+        current: usize,
+        total: usize,
+        bytes: usize,
+        message: Option<String>,
+    ) {
+        let percent = (current * 100).checked_div(total).unwrap_or(0) as u32;
+        // Only the phase changing, a new percentage or some time passing is
+        // worth an event, libgit2 calls back for every object
+        let mut last = self.last.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some((last_phase, last_percent, at)) = last.as_ref() {
+            if last_phase == phase && *last_percent == percent && at.elapsed() < THROTTLE {
+                return;
+            }
+        }
+        *last = Some((phase.to_string(), percent, Instant::now()));
+        drop(last);
+        (self.report)(Progress {
+            job_id: self.id.clone(),
+            phase: phase.to_string(),
+            current,
+            total,
+            percent,
+            received_bytes: bytes,
+            message,
+        });
+    }
+}
+
+impl Drop for Job {
+    fn drop(&mut self) {
+        JOBS.lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&self.id);
+    }
+}
+
+/// The job the current thread is running, if any.
+pub(crate) fn current() -> Option<Arc<Job>> {
+    CURRENT.with(|current| current.borrow().clone())
+}
+
+/// Asks a running job to stop. False when there is no such job.
+pub fn cancel(id: &str) -> bool {
+    match JOBS.lock().unwrap_or_else(|e| e.into_inner()).get(id) {
+        Some(cancelled) => {
+            println!("[JOB] Cancelling {}", id);
+            cancelled.store(true, Ordering::Relaxed);
+            true
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A job that records what it reports.
+    fn job(id: &str) -> (Arc<Job>, Arc<Mutex<Vec<Progress>>>) {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let sink = events.clone();
+        let job = Job::new(id.to_string(), move |p| sink.lock().unwrap().push(p)).unwrap();
+        (job, events)
+    }
+
+    #[test]
+    fn ids_are_unique_while_running() {
+        let (first, _) = job("job-unique");
+        let err = Job::new("job-unique".to_string(), |_| {}).err().unwrap();
+        assert_eq!(err.kind, GitErrorKind::AlreadyExists);
+        drop(first);
+        assert!(Job::new("job-unique".to_string(), |_| {}).is_ok());
+    }
+
+    #[test]
+    fn run_sets_the_current_job() {
+        let (job, _) = job("job-current");
+        assert!(current().is_none());
+        let id = job
+            .run(|| Ok(current().map(|j| j.id().to_string())))
+            .unwrap();
+        assert_eq!(id.as_deref(), Some("job-current"));
+        assert!(current().is_none());
+    }
+
+    #[test]
+    fn cancelled_errors_become_cancelled() {
+        let (job, _) = job("job-cancel");
+        assert!(!cancel("job-missing"));
+        let err = job
+            .run(|| -> GitResult<()> { Err(GitError::other("boom")) })
+            .unwrap_err();
+        assert_eq!(err.kind, GitErrorKind::Other);
+
+        assert!(cancel("job-cancel"));
+        assert!(job.check().is_err());
+        assert!(!job.sideband(b"remote: hi\n"));
+        let err = job
+            .run(|| -> GitResult<()> { Err(GitError::other("boom")) })
+            .unwrap_err();
+        assert_eq!(err.kind, GitErrorKind::Cancelled);
+    }
+
+    #[test]
+    fn parses_sideband_progress() {
+        let (job, events) = job("job-sideband");
+        assert!(job.sideband(b"Counting objects:  10% (1/10)\rCounting objects:  50% (5/10)\r"));
+        job.sideband(b"remote: Enumerating\n");
+        let events = events.lock().unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].phase, "counting");
+        assert_eq!(
+            (events[0].current, events[0].total, events[0].percent),
+            (5, 10, 50)
+        );
+        assert_eq!(events[1].phase, "remote");
+        assert_eq!(events[1].message.as_deref(), Some("remote: Enumerating"));
+    }
+
+    #[test]
+    fn throttles_repeated_progress() {
+        let (job, events) = job("job-throttle");
+        job.push_transfer(1, 400, 10);
+        job.push_transfer(2, 400, 20);
+        job.push_transfer(200, 400, 30);
+        job.checkout(0, 0);
+        let phases: Vec<_> = events
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|p| (p.phase.clone(), p.percent))
+            .collect();
+        assert_eq!(
+            phases,
+            [
+                ("writing".to_string(), 0),
+                ("writing".to_string(), 50),
+                ("checkout".to_string(), 0),
+            ]
+        );
+    }
+}
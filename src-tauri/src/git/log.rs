@@ -0,0 +1,507 @@
+use super::action::{RepoFile, Revision};
+use super::{head_commit, repo_path, short_id, GitError, GitErrorKind, GitResult};
+use git2::{Commit, Oid, Repository, Signature, Sort};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, LazyLock, Mutex};
+
+/// Assigns each commit a column the way `git log --graph` does: a commit takes
+/// the lane that was waiting for it, its first parent inherits that lane and
+/// further parents open new ones.
+#[derive(Default)]
+pub(crate) struct Lanes {
+    lanes: Vec<Option<Oid>>,
+}
+
+impl Lanes {
+    /// Returns the commit's column and the lanes that were active before it.
+    pub(crate) fn place(&mut self, id: Oid, parents: &[Oid]) -> (usize, Vec<bool>) {
+        let column = match self.lanes.iter().position(|l| *l == Some(id)) {
+            Some(column) => column,
+            None => match self.lanes.iter().position(|l| l.is_none()) {
+                Some(free) => free,
+                None => {
+                    self.lanes.push(None);
+                    self.lanes.len() - 1
+                }
+            },
+        };
+        let active = self
+            .lanes
+            .iter()
+            .enumerate()
+            .map(|(i, l)| i == column || l.is_some())
+            .collect();
+
+        // Other lanes waiting for this commit merge into it
+        for lane in self.lanes.iter_mut() {
+            if *lane == Some(id) {
+                *lane = None;
+            }
+        }
+        self.lanes[column] = parents.first().copied();
+        for parent in parents.iter().skip(1) {
+            if self.lanes.contains(&Some(*parent)) {
+                continue;
+            }
+            match self.lanes.iter().position(|l| l.is_none()) {
+                Some(free) => self.lanes[free] = Some(*parent),
+                None => self.lanes.push(Some(*parent)),
+            }
+        }
+        while self.lanes.last() == Some(&None) {
+            self.lanes.pop();
+        }
+        (column, active)
+    }
+
+    /// Column a parent's edge continues in after `place`.
+    pub(crate) fn column_of(&self, id: Oid) -> Option<usize> {
+        self.lanes.iter().position(|l| *l == Some(id))
+    }
+}
+
+/// Ref names pointing at a commit, like the `%d` decoration.
+#[derive(Default, Serialize)]
+pub(crate) struct Decorations {
+    pub local: Vec<String>,
+    pub remote: Vec<String>,
+    pub tags: Vec<String>,
+}
+
+pub(crate) fn decorations(repo: &Repository) -> GitResult<HashMap<Oid, Decorations>> {
+    let mut refs: HashMap<Oid, Decorations> = HashMap::new();
+    for reference in repo.references()?.flatten() {
+        let name = match reference.shorthand() {
+            Some(name) => name.to_string(),
+            None => continue,
+        };
+        let commit = match reference.peel_to_commit() {
+            Ok(commit) => commit,
+            Err(_) => continue,
+        };
+        let entry = refs.entry(commit.id()).or_default();
+        if reference.is_branch() {
+            entry.local.push(name);
+        } else if reference.is_remote() {
+            // `origin/HEAD` only repeats the remote's default branch
+            if !name.ends_with("/HEAD") {
+                entry.remote.push(name);
+            }
+        } else if reference.is_tag() {
+            entry.tags.push(name);
+        }
+    }
+    Ok(refs)
+}
+
+pub fn graph(repo: &Repository) -> GitResult<Value> {
+    let head = head_commit(repo)?
+        .ok_or_else(|| {
+            GitError::new(
+                GitErrorKind::NotFound,
+                "your current branch does not have any commits yet",
+            )
+        })?
+        .id();
+    let refs = decorations(repo)?;
+
+    let mut walk = repo.revwalk()?;
+    walk.set_sorting(Sort::TOPOLOGICAL | Sort::TIME)?;
+    walk.push(head)?;
+
+    let mut lanes = Lanes::default();
+    let mut commits = Vec::new();
+    for id in walk {
+        let id = id?;
+        let commit = repo.find_commit(id)?;
+        let parents: Vec<Oid> = commit.parent_ids().collect();
+        let (column, active) = lanes.place(id, &parents);
+        let graph_ascii = active
+            .iter()
+            .enumerate()
+            .map(|(i, active)| match (i == column, active) {
+                (true, _) => "*",
+                (false, true) => "|",
+                (false, false) => " ",
+            })
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        let names = refs.get(&id);
+        commits.push(json!({
+            "graph": format!("{} ", graph_ascii),
+            "hash": short_id(repo, id),
+            "message": commit.summary().unwrap_or(""),
+            "isHead": id == head,
+            "remote": names.is_some_and(|n| !n.remote.is_empty()),
+            "tags": names.map(|n| n.tags.clone()).unwrap_or_default(),
+        }));
+    }
+    Ok(commits.into())
+}
+
+/// A page of `history`.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HistoryQuery {
+    /// Where to start walking, `HEAD` when empty.
+    #[serde(default)]
+    pub revisions: Vec<Revision>,
+    /// Walk every branch, remote branch and tag instead.
+    #[serde(default)]
+    pub all: bool,
+    /// Only commits that change this file or directory.
+    pub path: Option<RepoFile>,
+    /// Only commits whose author name or email contains this, ignoring case.
+    pub author: Option<String>,
+    #[serde(default = "default_page_size")]
+    pub limit: usize,
+    /// `nextCursor` of the previous page.
+    pub cursor: Option<HistoryCursor>,
+}
+
+fn default_page_size() -> usize {
+    200
+}
+
+/// Where the previous page stopped, with the lane state at that point so
+/// the next page doesn't have to replay the history before it.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HistoryCursor {
+    /// Commits of the walk already consumed, including filtered out ones.
+    offset: usize,
+    lanes: Vec<Option<String>>,
+    /// Tips the walk started from, to notice when refs moved in between.
+    tips: Vec<String>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Person {
+    name: String,
+    email: String,
+    /// Seconds since the epoch.
+    time: i64,
+    /// Timezone offset in minutes.
+    offset: i32,
+}
+
+impl From<Signature<'_>> for Person {
+    fn from(signature: Signature<'_>) -> Self {
+        Person {
+            name: signature.name().unwrap_or("").to_string(),
+            email: signature.email().unwrap_or("").to_string(),
+            time: signature.when().seconds(),
+            offset: signature.when().offset_minutes(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct HistoryCommit {
+    hash: String,
+    short_hash: String,
+    parents: Vec<String>,
+    author: Person,
+    committer: Person,
+    summary: String,
+    message: String,
+    refs: Decorations,
+    is_head: bool,
+    /// Column of the commit's dot.
+    column: usize,
+    /// Lanes passing through this row, the commit's own included.
+    lanes: Vec<bool>,
+    /// Column each parent's edge continues in, in `parents` order.
+    parent_columns: Vec<Option<usize>>,
+}
+
+/// Structured, paginated `git log --graph`. Filtered views keep the columns
+/// of the full graph so pages line up with the unfiltered one.
+pub fn history(repo: &Repository, query: &HistoryQuery) -> GitResult<Value> {
+    let head = head_commit(repo)?.map(|c| c.id());
+    let tips = tips(repo, query, head)?;
+    if tips.is_empty() {
+        return Ok(json!({ "commits": [], "nextCursor": null }));
+    }
+    let tip_names: Vec<String> = tips.iter().map(|t| t.to_string()).collect();
+
+    let mut lanes = Lanes::default();
+    let mut offset = 0;
+    if let Some(cursor) = &query.cursor {
+        if cursor.tips != tip_names {
+            return Err(GitError::new(
+                GitErrorKind::Stale,
+                "history changed since the first page, reload it",
+            ));
+        }
+        lanes.lanes = cursor
+            .lanes
+            .iter()
+            .map(|l| l.as_deref().and_then(|id| Oid::from_str(id).ok()))
+            .collect();
+        offset = cursor.offset;
+    }
+
+    let path = query.path.as_ref().map(|p| repo_path(repo, p));
+    let author = query.author.as_ref().map(|a| a.to_lowercase());
+    let mut refs = decorations(repo)?;
+    let mut commits = Vec::new();
+    let mut done = true;
+    let order = walk_order(repo, query.all, &tips)?;
+    for &id in order.iter().skip(offset) {
+        if commits.len() >= query.limit.max(1) {
+            done = false;
+            break;
+        }
+        offset += 1;
+        let commit = repo.find_commit(id)?;
+        let parents: Vec<Oid> = commit.parent_ids().collect();
+        let (column, active) = lanes.place(id, &parents);
+
+        if let Some(author) = &author {
+            let signature = commit.author();
+            let name = signature.name().unwrap_or("").to_lowercase();
+            let email = signature.email().unwrap_or("").to_lowercase();
+            if !name.contains(author.as_str()) && !email.contains(author.as_str()) {
+                continue;
+            }
+        }
+        if let Some(path) = &path {
+            if !touches(&commit, path)? {
+                continue;
+            }
+        }
+
+        commits.push(HistoryCommit {
+            hash: id.to_string(),
+            short_hash: short_id(repo, id),
+            parents: parents.iter().map(|p| p.to_string()).collect(),
+            author: commit.author().into(),
+            committer: commit.committer().into(),
+            summary: commit.summary().unwrap_or("").to_string(),
+            message: commit.message().unwrap_or("").to_string(),
+            // Every commit comes up once per walk
+            refs: refs.remove(&id).unwrap_or_default(),
+            is_head: Some(id) == head,
+            column,
+            lanes: active,
+            parent_columns: parents.iter().map(|p| lanes.column_of(*p)).collect(),
+        });
+    }
+
+    let next_cursor = (!done).then(|| HistoryCursor {
+        offset,
+        lanes: lanes
+            .lanes
+            .iter()
+            .map(|l| l.map(|id| id.to_string()))
+            .collect(),
+        tips: tip_names,
+    });
+    Ok(json!({ "commits": commits, "nextCursor": next_cursor }))
+}
+
+/// The commits the walk starts from, in a stable order.
+fn tips(repo: &Repository, query: &HistoryQuery, head: Option<Oid>) -> GitResult<Vec<Oid>> {
+    let mut tips = Vec::new();
+    if query.all {
+        for reference in repo.references()?.flatten() {
+            if reference.is_branch() || reference.is_remote() || reference.is_tag() {
+                if let Ok(commit) = reference.peel_to_commit() {
+                    tips.push(commit.id());
+                }
+            }
+        }
+        tips.extend(head);
+        tips.sort();
+        tips.dedup();
+    } else if query.revisions.is_empty() {
+        tips.extend(head);
+    } else {
+        for revision in &query.revisions {
+            tips.push(repo.revparse_single(revision)?.peel_to_commit()?.id());
+        }
+    }
+    Ok(tips)
+}
+
+struct CachedWalk {
+    all: bool,
+    tips: Vec<Oid>,
+    order: Arc<Vec<Oid>>,
+}
+
+/// Last walk per repository. A topological walk has to read the whole
+/// history before it yields anything, so later pages reuse the order.
+static WALKS: LazyLock<Mutex<HashMap<PathBuf, CachedWalk>>> = LazyLock::new(Default::default);
+
+fn walk_order(repo: &Repository, all: bool, tips: &[Oid]) -> GitResult<Arc<Vec<Oid>>> {
+    let key = repo.path().to_path_buf();
+    if let Some(cached) = WALKS.lock().unwrap_or_else(|e| e.into_inner()).get(&key) {
+        if cached.all == all && cached.tips == tips {
+            return Ok(cached.order.clone());
+        }
+    }
+
+    let mut walk = repo.revwalk()?;
+    walk.set_sorting(Sort::TOPOLOGICAL | Sort::TIME)?;
+    for tip in tips {
+        walk.push(*tip)?;
+    }
+    let order = Arc::new(walk.collect::<Result<Vec<_>, _>>()?);
+    WALKS.lock().unwrap_or_else(|e| e.into_inner()).insert(
+        key,
+        CachedWalk {
+            all,
+            tips: tips.to_vec(),
+            order: order.clone(),
+        },
+    );
+    Ok(order)
+}
+
+/// Whether the commit changes `path`, the way `git log -- <path>` picks
+/// commits: merges only when they differ from every parent.
+fn touches(commit: &Commit, path: &str) -> GitResult<bool> {
+    let path = Path::new(path.trim_end_matches('/'));
+    let entry_id = |tree: &git2::Tree| tree.get_path(path).ok().map(|e| e.id());
+    let own = entry_id(&commit.tree()?);
+    if commit.parent_count() == 0 {
+        return Ok(own.is_some());
+    }
+    for parent in commit.parents() {
+        if entry_id(&parent.tree()?) == own {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git::testing::TestRepo;
+    use serde_json::json;
+
+    fn oid(n: u8) -> Oid {
+        Oid::from_bytes(&[n; 20]).unwrap()
+    }
+
+    #[test]
+    fn linear_history_stays_in_one_lane() {
+        let mut lanes = Lanes::default();
+        assert_eq!(lanes.place(oid(3), &[oid(2)]), (0, vec![true]));
+        assert_eq!(lanes.place(oid(2), &[oid(1)]), (0, vec![true]));
+        assert_eq!(lanes.place(oid(1), &[]), (0, vec![true]));
+        assert_eq!(lanes.column_of(oid(1)), None);
+    }
+
+    #[test]
+    fn merge_opens_and_closes_a_lane() {
+        let (merge, a, b, base) = (oid(4), oid(3), oid(2), oid(1));
+        let mut lanes = Lanes::default();
+        assert_eq!(lanes.place(merge, &[a, b]), (0, vec![true]));
+        assert_eq!(lanes.column_of(a), Some(0));
+        assert_eq!(lanes.column_of(b), Some(1));
+        assert_eq!(lanes.place(a, &[base]), (0, vec![true, true]));
+        assert_eq!(lanes.place(b, &[base]), (1, vec![true, true]));
+        // Both lanes wait for the base, it takes the first and closes the other
+        assert_eq!(lanes.place(base, &[]), (0, vec![true, true]));
+        assert!(lanes.lanes.is_empty());
+    }
+
+    #[test]
+    fn unrelated_tip_takes_free_lane() {
+        let mut lanes = Lanes::default();
+        lanes.place(oid(5), &[oid(1)]);
+        assert_eq!(lanes.place(oid(6), &[oid(2)]), (1, vec![true, true]));
+        lanes.place(oid(1), &[]);
+        // Lane 0 is free again
+        assert_eq!(lanes.place(oid(7), &[]), (0, vec![true, true]));
+    }
+
+    fn query(value: serde_json::Value) -> HistoryQuery {
+        serde_json::from_value(value).unwrap()
+    }
+
+    fn hashes(page: &Value) -> Vec<String> {
+        page["commits"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|c| c["hash"].as_str().unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn pages_continue_from_cursor() {
+        let t = TestRepo::new();
+        let mut ids = Vec::new();
+        for n in 0..5 {
+            t.write("a.txt", &n.to_string());
+            ids.push(t.commit(&format!("commit {}", n)).to_string());
+        }
+        ids.reverse();
+
+        let first = history(&t.repo, &query(json!({ "limit": 2 }))).unwrap();
+        assert_eq!(hashes(&first), ids[..2]);
+        assert_eq!(first["commits"][0]["isHead"], true);
+        let cursor = first["nextCursor"].clone();
+        let second = history(&t.repo, &query(json!({ "limit": 10, "cursor": cursor }))).unwrap();
+        assert_eq!(hashes(&second), ids[2..]);
+        assert!(second["nextCursor"].is_null());
+    }
+
+    #[test]
+    fn cursor_is_stale_after_new_commit() {
+        let t = TestRepo::new();
+        t.write("a.txt", "1");
+        t.commit("one");
+        t.write("a.txt", "2");
+        t.commit("two");
+        let first = history(&t.repo, &query(json!({ "limit": 1 }))).unwrap();
+        t.write("a.txt", "3");
+        t.commit("three");
+        let cursor = first["nextCursor"].clone();
+        let err = history(&t.repo, &query(json!({ "cursor": cursor }))).unwrap_err();
+        assert_eq!(err.kind, GitErrorKind::Stale);
+    }
+
+    #[test]
+    fn filters_by_path_and_author() {
+        let t = TestRepo::new();
+        t.write("a.txt", "a");
+        let first = t.commit("add a");
+        t.write("dir/b.txt", "b");
+        let second = t.commit("add b");
+
+        let by_path = history(&t.repo, &query(json!({ "path": "dir/" }))).unwrap();
+        assert_eq!(hashes(&by_path), [second.to_string()]);
+        let by_path = history(&t.repo, &query(json!({ "path": "a.txt" }))).unwrap();
+        assert_eq!(hashes(&by_path), [first.to_string()]);
+        let by_author = history(&t.repo, &query(json!({ "author": "EXAMPLE.com" }))).unwrap();
+        assert_eq!(hashes(&by_author).len(), 2);
+        let nobody = history(&t.repo, &query(json!({ "author": "nobody" }))).unwrap();
+        assert!(hashes(&nobody).is_empty());
+    }
+
+    #[test]
+    fn decorates_commits_with_refs() {
+        let t = TestRepo::new();
+        t.write("a.txt", "a");
+        let id = t.commit("init");
+        let commit = t.repo.find_commit(id).unwrap();
+        t.repo
+            .tag_lightweight("v1", commit.as_object(), false)
+            .unwrap();
+        let page = history(&t.repo, &query(json!({}))).unwrap();
+        assert_eq!(page["commits"][0]["refs"]["local"], json!(["main"]));
+        assert_eq!(page["commits"][0]["refs"]["tags"], json!(["v1"]));
+    }
+}
@@ -0,0 +1,403 @@
+use super::{GitError, GitResult};
+use git2::{Config, Repository};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::path::{Path, PathBuf};
+use std::sync::LazyLock;
+
+/// `type(scope)!: description`
+static CONVENTIONAL: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"^(?P<type>[A-Za-z]+)(?:\((?P<scope>[^()]*)\))?!?: (?P<description>.*)$").unwrap()
+});
+
+/// Messages git writes itself, they don't follow anyone's format.
+const GENERATED_PREFIXES: &[&str] = &["Merge ", "Revert \"", "fixup! ", "squash! ", "amend! "];
+
+const DEFAULT_TYPES: &[&str] = &[
+    "feat", "fix", "docs", "style", "refactor", "perf", "test", "build", "ci", "chore", "revert",
+];
+
+/// What `check` enforces. Read from the `commitPolicy` section of the git
+/// config when the request doesn't bring its own.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MessageRules {
+    /// Subjects must be `type(scope): description`.
+    #[serde(default)]
+    pub conventional: bool,
+    /// Allowed Conventional Commits types, the usual ones when empty.
+    #[serde(default)]
+    pub types: Vec<String>,
+    /// Allowed scopes, any when empty.
+    #[serde(default)]
+    pub scopes: Vec<String>,
+    #[serde(default)]
+    pub require_scope: bool,
+    #[serde(default = "default_max_subject_length")]
+    pub max_subject_length: Option<usize>,
+    #[serde(default = "default_true")]
+    pub blank_second_line: bool,
+    /// A regex some line of the message has to match, e.g. `[A-Z]+-\d+`.
+    pub ticket_pattern: Option<String>,
+}
+
+fn default_max_subject_length() -> Option<usize> {
+    Some(72)
+}
+
+fn default_true() -> bool {
+    true
+}
+
+impl Default for MessageRules {
+    fn default() -> Self {
+        MessageRules {
+            conventional: false,
+            types: Vec::new(),
+            scopes: Vec::new(),
+            require_scope: false,
+            max_subject_length: default_max_subject_length(),
+            blank_second_line: true,
+            ticket_pattern: None,
+        }
+    }
+}
+
+impl MessageRules {
+    /// `commitPolicy.*` keys, lists are comma separated and a
+    /// `maxSubjectLength` of 0 turns the length check off.
+    fn from_config(config: &Config) -> Self {
+        let defaults = MessageRules::default();
+        let list = |key: &str| -> Vec<String> {
+            config
+                .get_string(key)
+                .map(|value| {
+                    value
+                        .split(',')
+                        .map(str::trim)
+                        .filter(|v| !v.is_empty())
+                        .map(String::from)
+                        .collect()
+                })
+                .unwrap_or_default()
+        };
+        MessageRules {
+            conventional: config
+                .get_bool("commitPolicy.conventional")
+                .unwrap_or(false),
+            types: list("commitPolicy.types"),
+            scopes: list("commitPolicy.scopes"),
+            require_scope: config
+                .get_bool("commitPolicy.requireScope")
+                .unwrap_or(false),
+            max_subject_length: match config.get_i64("commitPolicy.maxSubjectLength") {
+                Ok(length) if length > 0 => Some(length as usize),
+                Ok(_) => None,
+                Err(_) => defaults.max_subject_length,
+            },
+            blank_second_line: config
+                .get_bool("commitPolicy.blankSecondLine")
+                .unwrap_or(defaults.blank_second_line),
+            ticket_pattern: config
+                .get_string("commitPolicy.ticketPattern")
+                .ok()
+                .filter(|p| !p.trim().is_empty()),
+        }
+    }
+}
+
+/// One broken rule, `line` is 1-based in the message without comments.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Diagnostic {
+    pub rule: &'static str,
+    pub line: usize,
+    pub message: String,
+}
+
+impl Diagnostic {
+    fn new(rule: &'static str, line: usize, message: impl Into<String>) -> Self {
+        Diagnostic {
+            rule,
+            line,
+            message: message.into(),
+        }
+    }
+}
+
+/// The message to start a commit from: `commit.template`, or the
+/// repository's `.gitmessage` when that isn't set.
+pub fn template(repo: &Repository) -> GitResult<Value> {
+    let workdir = repo.workdir().unwrap_or_else(|| repo.path());
+    let configured = repo
+        .config()?
+        .get_path("commit.template")
+        .ok()
+        .map(|path| workdir.join(path));
+    let path = configured.or_else(|| {
+        let path = workdir.join(".gitmessage");
+        path.is_file().then_some(path)
+    });
+    let Some(path) = path else {
+        return Ok(json!({ "template": null, "path": null }));
+    };
+    let template = std::fs::read_to_string(&path).map_err(|e| {
+        GitError::invalid(format!(
+            "could not read commit message template '{}': {}",
+            display(&path, workdir),
+            e
+        ))
+    })?;
+    Ok(json!({
+        "template": template,
+        "path": display(&path, workdir),
+    }))
+}
+
+fn display(path: &Path, workdir: &Path) -> String {
+    path.strip_prefix(workdir)
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| path.to_path_buf())
+        .to_string_lossy()
+        .replace('\\', "/")
+}
+
+/// Every rule `message` breaks. Comment lines are ignored like `commit`
+/// strips them.
+pub fn check(repo: &Repository, message: &str, rules: Option<&MessageRules>) -> GitResult<Value> {
+    let from_config;
+    let rules = match rules {
+        Some(rules) => rules,
+        None => {
+            from_config = MessageRules::from_config(&repo.config()?);
+            &from_config
+        }
+    };
+    let ticket = rules
+        .ticket_pattern
+        .as_deref()
+        .map(Regex::new)
+        .transpose()
+        .map_err(|e| GitError::invalid(format!("invalid ticket pattern: {}", e)))?;
+
+    let message = git2::message_prettify(message, Some(b'#'))?;
+    let lines: Vec<&str> = message.lines().collect();
+    let mut diagnostics = Vec::new();
+    let Some(subject) = lines.first() else {
+        diagnostics.push(Diagnostic::new("empty", 1, "Commit message is empty."));
+        return Ok(json!({ "valid": false, "diagnostics": diagnostics }));
+    };
+
+    if let Some(max) = rules.max_subject_length {
+        let length = subject.chars().count();
+        if length > max {
+            diagnostics.push(Diagnostic::new(
+                "subject-length",
+                1,
+                format!("Subject is {} characters, the limit is {}.", length, max),
+            ));
+        }
+    }
+    if rules.blank_second_line && lines.get(1).is_some_and(|line| !line.trim().is_empty()) {
+        diagnostics.push(Diagnostic::new(
+            "blank-second-line",
+            2,
+            "Separate the subject from the body with a blank line.",
+        ));
+    }
+    let generated = GENERATED_PREFIXES.iter().any(|p| subject.starts_with(p));
+    if rules.conventional && !generated {
+        check_conventional(subject, rules, &mut diagnostics);
+    }
+    if let Some(ticket) = &ticket {
+        if !generated && !lines.iter().any(|line| ticket.is_match(line)) {
+            diagnostics.push(Diagnostic::new(
+                "ticket",
+                1,
+                format!("Reference a ticket matching `{}`.", ticket.as_str()),
+            ));
+        }
+    }
+
+    Ok(json!({
+        "valid": diagnostics.is_empty(),
+        "diagnostics": diagnostics,
+    }))
+}
+
+fn check_conventional(subject: &str, rules: &MessageRules, diagnostics: &mut Vec<Diagnostic>) {
+    let Some(caps) = CONVENTIONAL.captures(subject) else {
+        diagnostics.push(Diagnostic::new(
+            "conventional",
+            1,
+            "Subject must look like `type(scope): description`.",
+        ));
+        return;
+    };
+    let kind = &caps["type"];
+    let allowed = |types: &[&str]| types.contains(&kind);
+    let known = if rules.types.is_empty() {
+        allowed(DEFAULT_TYPES)
+    } else {
+        allowed(&rules.types.iter().map(String::as_str).collect::<Vec<_>>())
+    };
+    if !known {
+        let types = if rules.types.is_empty() {
+            DEFAULT_TYPES.join(", ")
+        } else {
+            rules.types.join(", ")
+        };
+        diagnostics.push(Diagnostic::new(
+            "type",
+            1,
+            format!("Unknown type '{}', use one of {}.", kind, types),
+        ));
+    }
+    match caps.name("scope").map(|s| s.as_str().trim()) {
+        Some("") => diagnostics.push(Diagnostic::new("scope", 1, "Scope is empty.")),
+        Some(scope) if !rules.scopes.is_empty() && !rules.scopes.iter().any(|s| s == scope) => {
+            diagnostics.push(Diagnostic::new(
+                "scope",
+                1,
+                format!(
+                    "Unknown scope '{}', use one of {}.",
+                    scope,
+                    rules.scopes.join(", ")
+                ),
+            ))
+        }
+        None if rules.require_scope => {
+            diagnostics.push(Diagnostic::new("scope", 1, "A scope is required."))
+        }
+        _ => {}
+    }
+    if caps["description"].trim().is_empty() {
+        diagnostics.push(Diagnostic::new(
+            "conventional",
+            1,
+            "Description after the type is empty.",
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git::testing::TestRepo;
+
+    fn rules(value: Value) -> MessageRules {
+        serde_json::from_value(value).unwrap()
+    }
+
+    /// The rules that broke, in order.
+    fn broken(repo: &Repository, message: &str, rules: Option<&MessageRules>) -> Vec<String> {
+        let result = check(repo, message, rules).unwrap();
+        assert_eq!(
+            result["valid"],
+            result["diagnostics"].as_array().unwrap().is_empty()
+        );
+        result["diagnostics"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|d| d["rule"].as_str().unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn default_rules() {
+        let t = TestRepo::new();
+        assert!(broken(&t.repo, "Fix the thing\n\nBecause.", None).is_empty());
+        assert_eq!(broken(&t.repo, "# only a comment\n", None), ["empty"]);
+        assert_eq!(broken(&t.repo, &"x".repeat(73), None), ["subject-length"]);
+        assert_eq!(
+            broken(&t.repo, "Subject\nbody right away", None),
+            ["blank-second-line"]
+        );
+        // Comments are stripped before the second line is looked at
+        assert!(broken(&t.repo, "Subject\n# comment\n\nbody", None).is_empty());
+    }
+
+    #[test]
+    fn conventional_commits() {
+        let t = TestRepo::new();
+        let strict = rules(json!({
+            "conventional": true,
+            "types": ["feat", "fix"],
+            "scopes": ["ui", "git"],
+            "requireScope": true,
+        }));
+        assert!(broken(&t.repo, "feat(ui)!: new layout", Some(&strict)).is_empty());
+        assert_eq!(
+            broken(&t.repo, "New layout", Some(&strict)),
+            ["conventional"]
+        );
+        assert_eq!(broken(&t.repo, "docs(ui): readme", Some(&strict)), ["type"]);
+        assert_eq!(broken(&t.repo, "fix(db): query", Some(&strict)), ["scope"]);
+        assert_eq!(broken(&t.repo, "fix: query", Some(&strict)), ["scope"]);
+        assert_eq!(broken(&t.repo, "fix(): query", Some(&strict)), ["scope"]);
+        assert_eq!(
+            broken(&t.repo, "fix(git):  ", Some(&strict)),
+            ["conventional"]
+        );
+        assert!(broken(&t.repo, "Merge branch 'main'", Some(&strict)).is_empty());
+
+        let loose = rules(json!({ "conventional": true }));
+        assert!(broken(&t.repo, "chore: bump", Some(&loose)).is_empty());
+        assert_eq!(broken(&t.repo, "wip: bump", Some(&loose)), ["type"]);
+    }
+
+    #[test]
+    fn ticket_pattern() {
+        let t = TestRepo::new();
+        let ticket = rules(json!({ "ticketPattern": "[A-Z]+-\\d+" }));
+        assert!(broken(&t.repo, "Fix login\n\nRefs APP-12", Some(&ticket)).is_empty());
+        assert_eq!(broken(&t.repo, "Fix login", Some(&ticket)), ["ticket"]);
+        let invalid = rules(json!({ "ticketPattern": "(" }));
+        let err = check(&t.repo, "Fix", Some(&invalid)).unwrap_err();
+        assert_eq!(err.kind, crate::git::GitErrorKind::InvalidRequest);
+    }
+
+    #[test]
+    fn rules_from_config() {
+        let t = TestRepo::new();
+        let mut config = t.repo.config().unwrap();
+        config.set_bool("commitPolicy.conventional", true).unwrap();
+        config.set_str("commitPolicy.types", "feat, fix,").unwrap();
+        config.set_i64("commitPolicy.maxSubjectLength", 0).unwrap();
+        let rules = MessageRules::from_config(&t.repo.config().unwrap());
+        assert!(rules.conventional);
+        assert_eq!(rules.types, ["feat", "fix"]);
+        assert_eq!(rules.max_subject_length, None);
+        assert!(rules.blank_second_line);
+
+        let long = format!("feat: {}", "x".repeat(100));
+        assert!(broken(&t.repo, &long, None).is_empty());
+        assert_eq!(broken(&t.repo, "docs: x", None), ["type"]);
+    }
+
+    #[test]
+    fn templates() {
+        let t = TestRepo::new();
+        assert_eq!(template(&t.repo).unwrap()["template"], Value::Null);
+        t.write(".gitmessage", "repo template\n");
+        assert_eq!(
+            template(&t.repo).unwrap(),
+            json!({ "template": "repo template\n", "path": ".gitmessage" })
+        );
+        t.write("docs/template.txt", "configured\n");
+        t.repo
+            .config()
+            .unwrap()
+            .set_str("commit.template", "docs/template.txt")
+            .unwrap();
+        assert_eq!(template(&t.repo).unwrap()["path"], "docs/template.txt");
+        t.repo
+            .config()
+            .unwrap()
+            .set_str("commit.template", "missing.txt")
+            .unwrap();
+        assert!(template(&t.repo).is_err());
+    }
+}
@@ -0,0 +1,633 @@
+mod action;
+mod blame;
+mod branch;
+mod commit;
+mod compare;
+mod conflict;
+mod credentials;
+mod diff;
+mod error;
+mod file;
+mod ignore;
+mod job;
+mod log;
+mod message;
+mod operation;
+mod partial;
+mod pick;
+mod reflog;
+mod remote;
+mod stash;
+mod status;
+mod submodule;
+mod sync;
+mod tag;
+#[cfg(test)]
+mod testing;
+mod undo;
+mod worktree;
+
+use git2::{
+    build::CheckoutBuilder, BranchType, ErrorCode, IndexAddOption, Oid, Repository, ResetType,
+};
+use serde_json::{json, Value};
+use std::path::Path;
+
+pub use action::{GitAction, GitRequest};
+pub use blame::{blame, BlameChunk, BlameLine, BlameRequest};
+pub use commit::{Author, CommitOptions};
+pub use credentials::set_token;
+pub use diff::{diff, DiffRequest, FileDiff};
+pub use error::{GitError, GitErrorKind, GitResult};
+pub use job::{cancel, Job, JobFinished, Progress};
+pub use message::{Diagnostic, MessageRules};
+pub use remote::clone;
+pub use sync::{start_auto_fetch, stop_auto_fetch, BranchSync, SyncChanged};
+
+pub fn run(request: GitRequest) -> GitResult<Value> {
+    let GitRequest { workspace, action } = request;
+    println!("Running git command: {:?} in {}", action, workspace);
+
+    if let GitAction::Init = action {
+        let repo = Repository::init(&*workspace)?;
+        let stdout = format!(
+            "Initialized empty Git repository in {}",
+            repo.path().display()
+        );
+        println!("{}", stdout);
+        return Ok(output(stdout));
+    }
+
+    let repo = open(&workspace)?;
+    // Destructive actions can be taken back with `undo`
+    let guard = undo::protect(&repo, &action);
+    let result = dispatch(repo, action);
+    if let (Err(_), Some(guard)) = (&result, guard) {
+        match open(&workspace) {
+            Ok(repo) => undo::settle(&repo, &guard),
+            Err(e) => eprintln!("[UNDO] Could not reopen {}: {}", workspace, e),
+        }
+    }
+    result
+}
+
+fn dispatch(mut repo: Repository, action: GitAction) -> GitResult<Value> {
+    match action {
+        GitAction::Init => unreachable!(),
+        GitAction::Status => status::status(&repo),
+        GitAction::FileStatus { file } => status::file_status(&repo, &file),
+        GitAction::SyncStatus => remote::sync_status(&repo),
+        GitAction::Stage { file } => stage(&repo, &[repo_path(&repo, &file)]),
+        GitAction::StageAll => stage(&repo, &["*".to_string()]),
+        GitAction::Unstage { file } => {
+            println!("Unstaging file: {:?}", file);
+            unstage(&repo, &[repo_path(&repo, &file)])?;
+            println!("Unstaged successfully: {}", file);
+            Ok(output(String::new()))
+        }
+        GitAction::UnstageAll => unstage_all(&repo),
+        GitAction::Discard { file } => discard(&repo, &[repo_path(&repo, &file)]),
+        GitAction::DiscardAll => discard(&repo, &[]),
+        GitAction::StageLines(selection) => partial::stage_lines(&repo, &selection),
+        GitAction::UnstageLines(selection) => partial::unstage_lines(&repo, &selection),
+        GitAction::DiscardLines(selection) => partial::discard_lines(&repo, &selection),
+        GitAction::Commit { message, options } => {
+            commit::commit(&repo, message.as_deref(), &options)
+        }
+        GitAction::CommitTemplate => message::template(&repo),
+        GitAction::CheckCommitMessage { message, rules } => {
+            message::check(&repo, &message, rules.as_ref())
+        }
+        GitAction::RenameBranch => {
+            branch::rename(&repo, None, "main", true)?;
+            Ok(output(String::new()))
+        }
+        GitAction::Push { remote, branch } => {
+            let branch = branch_or_current(&repo, branch.as_deref())?;
+            remote::push(&repo, &remote, &branch)
+        }
+        GitAction::Branch => list_branches(&repo),
+        GitAction::Branches => branch::list(&repo),
+        GitAction::RenameBranchTo { from, to, force } => {
+            branch::rename(&repo, from.as_deref(), &to, force)
+        }
+        GitAction::DeleteBranch { name, force } => branch::delete(&repo, &name, force),
+        GitAction::DeleteRemoteBranch { remote, name } => {
+            branch::delete_remote(&repo, &remote, &name)
+        }
+        GitAction::Tags => tag::list(&repo),
+        GitAction::CreateTag {
+            name,
+            target,
+            message,
+            force,
+        } => tag::create(
+            &repo,
+            &name,
+            target.as_deref().unwrap_or("HEAD"),
+            message.as_deref().filter(|m| !m.trim().is_empty()),
+            force,
+        ),
+        GitAction::DeleteTag { name } => tag::delete(&repo, &name),
+        GitAction::DeleteRemoteTag { remote, name } => tag::delete_remote(&repo, &remote, &name),
+        GitAction::PushTags { remote, name } => tag::push(&repo, &remote, name.as_deref()),
+        GitAction::Merge {
+            branch,
+            into,
+            no_ff,
+            message,
+        } => branch::merge(&repo, &branch, into.as_deref(), no_ff, message.as_deref()),
+        GitAction::Rebase { branch, onto } => branch::rebase(&repo, branch.as_deref(), &onto),
+        GitAction::CherryPick {
+            commits,
+            mainline,
+            no_commit,
+        } => {
+            let commits: Vec<String> = commits.iter().map(|c| c.to_string()).collect();
+            pick::cherry_pick(&repo, &commits, mainline, no_commit)
+        }
+        GitAction::Revert {
+            commit,
+            mainline,
+            no_commit,
+        } => pick::revert(&repo, &commit, mainline, no_commit),
+        GitAction::ContinueOperation => operation::continue_operation(&repo),
+        GitAction::AbortOperation => operation::abort(&repo),
+        GitAction::SkipOperation => operation::skip(&repo),
+        GitAction::CreateBranch { name } => create_branch(&repo, &name),
+        GitAction::Checkout {
+            name,
+            update_submodules,
+        } => {
+            let mut result = checkout(&repo, &name)?;
+            if update_submodules {
+                result["submodules"] = submodule::update(&repo, &[], false, true)?;
+            }
+            Ok(result)
+        }
+        GitAction::Pull {
+            remote,
+            branch,
+            update_submodules,
+        } => {
+            let branch = branch_or_current(&repo, branch.as_deref())?;
+            let mut result = remote::pull(&repo, &remote, &branch)?;
+            if update_submodules {
+                result["submodules"] = submodule::update(&repo, &[], false, true)?;
+            }
+            Ok(result)
+        }
+        GitAction::Submodules => submodule::list(&repo),
+        GitAction::InitSubmodules { paths } => {
+            let paths: Vec<String> = paths.iter().map(|p| repo_path(&repo, p)).collect();
+            submodule::init(&repo, &paths)
+        }
+        GitAction::UpdateSubmodules {
+            paths,
+            init,
+            recursive,
+        } => {
+            let paths: Vec<String> = paths.iter().map(|p| repo_path(&repo, p)).collect();
+            submodule::update(&repo, &paths, init, recursive)
+        }
+        GitAction::SyncSubmodules { paths, recursive } => {
+            let paths: Vec<String> = paths.iter().map(|p| repo_path(&repo, p)).collect();
+            submodule::sync(&repo, &paths, recursive)
+        }
+        GitAction::SetRemote { name, url } => remote::set_remote(&repo, &name, &url),
+        GitAction::RemoveOrigin => {
+            repo.remote_delete("origin")?;
+            Ok(output(String::new()))
+        }
+        GitAction::Remotes => Ok(remote::remotes(&repo)?.into()),
+        GitAction::AddRemote { name, url, fetch } => remote::add_remote(&repo, &name, &url, fetch),
+        GitAction::RenameRemote { from, to } => remote::rename_remote(&repo, &from, &to),
+        GitAction::RemoveRemote { name } => remote::remove_remote(&repo, &name),
+        GitAction::SetRemoteUrl { name, url, push } => remote::set_url(&repo, &name, &url, push),
+        GitAction::Fetch { remote, prune } => {
+            remote::fetch_remotes(&repo, remote.as_deref(), prune)
+        }
+        GitAction::PruneRemote { remote } => remote::fetch_remotes(&repo, Some(&remote), true),
+        GitAction::Worktrees => worktree::list(&repo),
+        GitAction::AddWorktree {
+            path,
+            branch,
+            new_branch,
+            base,
+            lock,
+        } => worktree::add(
+            &repo,
+            &path,
+            branch.as_deref(),
+            new_branch.as_deref(),
+            base.as_deref(),
+            lock,
+        ),
+        GitAction::LockWorktree { worktree, reason } => worktree::lock(
+            &repo,
+            &worktree,
+            reason.as_deref().filter(|r| !r.trim().is_empty()),
+        ),
+        GitAction::UnlockWorktree { worktree } => worktree::unlock(&repo, &worktree),
+        GitAction::RemoveWorktree { worktree, force } => worktree::remove(&repo, &worktree, force),
+        GitAction::PruneWorktrees => worktree::prune(&repo),
+        GitAction::Graph => log::graph(&repo),
+        GitAction::FileHistory(query) => file::history(&repo, &query),
+        GitAction::Compare {
+            base,
+            head,
+            three_dot,
+            limit,
+        } => compare::compare(&repo, &base, &head, three_dot, limit.max(1)),
+        GitAction::FileContent { file, revision } => file::content(&repo, &file, &revision),
+        GitAction::History(query) => log::history(&repo, &query),
+        GitAction::StashList => stash::list(&mut repo),
+        GitAction::StashPush {
+            message,
+            include_untracked,
+            keep_index,
+            paths,
+        } => {
+            let paths: Vec<String> = paths.iter().map(|p| repo_path(&repo, p)).collect();
+            stash::push(
+                &mut repo,
+                message.as_deref().filter(|m| !m.trim().is_empty()),
+                include_untracked,
+                keep_index,
+                &paths,
+            )
+        }
+        GitAction::StashApply {
+            index,
+            reinstate_index,
+        } => stash::apply(&mut repo, index, reinstate_index),
+        GitAction::StashPop {
+            index,
+            reinstate_index,
+        } => stash::pop(&mut repo, index, reinstate_index),
+        GitAction::StashDrop { index } => stash::drop(&mut repo, index),
+        GitAction::StashShow {
+            index,
+            context_lines,
+        } => stash::show(&mut repo, index, context_lines),
+        GitAction::ExplainIgnore { file } => ignore::explain(&repo, &repo_path(&repo, &file)),
+        GitAction::AddIgnoreRule {
+            file,
+            kind,
+            ignore_file,
+            untrack,
+        } => ignore::add(&repo, &repo_path(&repo, &file), kind, ignore_file, untrack),
+        GitAction::Conflicts => conflict::list(&repo),
+        GitAction::Conflict { file } => conflict::details(&repo, &file),
+        GitAction::ResolveConflict {
+            file,
+            regions,
+            take,
+        } => conflict::resolve(&repo, &file, &regions, take),
+        GitAction::Reflog { reference, limit } => {
+            reflog::list(&repo, reference.as_deref(), limit.max(1))
+        }
+        GitAction::UndoHistory => undo::list(&repo),
+        GitAction::Undo => undo::undo(&repo),
+    }
+}
+
+pub(crate) fn open(path: &str) -> GitResult<Repository> {
+    Repository::discover(path).map_err(|e| match e.code() {
+        ErrorCode::NotFound => GitError::new(
+            GitErrorKind::NotARepository,
+            format!("not a git repository: {}", path),
+        ),
+        _ => e.into(),
+    })
+}
+
+fn branch_or_current(repo: &Repository, branch: Option<&str>) -> GitResult<String> {
+    match branch {
+        Some(branch) => Ok(branch.to_string()),
+        None => current_branch(repo)
+            .ok_or_else(|| GitError::invalid("You are not currently on a branch.")),
+    }
+}
+
+/// Same `{ stdout, stderr }` shape the CLI-backed actions used to return.
+pub(crate) fn output(stdout: String) -> Value {
+    json!({ "stdout": stdout, "stderr": "" })
+}
+
+/// Turns a path from the frontend (absolute, or relative with `\` separators)
+/// into the `/`-separated workdir-relative form libgit2 expects.
+pub(crate) fn repo_path(repo: &Repository, file: &str) -> String {
+    let file_path = Path::new(file);
+    let relative = match repo.workdir() {
+        Some(workdir) if file_path.is_absolute() => file_path
+            .strip_prefix(workdir)
+            .ok()
+            .or_else(|| {
+                let canonical = workdir.canonicalize().ok()?;
+                file_path.strip_prefix(canonical).ok()
+            })
+            .unwrap_or(file_path),
+        _ => file_path,
+    };
+    relative.to_string_lossy().replace('\\', "/")
+}
+
+pub(crate) fn head_commit(repo: &Repository) -> GitResult<Option<git2::Commit<'_>>> {
+    match repo.head() {
+        Ok(head) => Ok(Some(head.peel_to_commit()?)),
+        Err(e) if e.code() == ErrorCode::UnbornBranch || e.code() == ErrorCode::NotFound => {
+            Ok(None)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Name of the checked out branch, also when it has no commits yet.
+pub(crate) fn current_branch(repo: &Repository) -> Option<String> {
+    if repo.head_detached().unwrap_or(false) {
+        return None;
+    }
+    let head = repo.find_reference("HEAD").ok()?;
+    let target = head.symbolic_target()?;
+    Some(
+        target
+            .strip_prefix("refs/heads/")
+            .unwrap_or(target)
+            .to_string(),
+    )
+}
+
+fn stage(repo: &Repository, paths: &[String]) -> GitResult<Value> {
+    let mut index = repo.index()?;
+    index.add_all(paths, IndexAddOption::DEFAULT, None)?;
+    // add_all only picks up files present on disk, deletions need update_all
+    index.update_all(paths, None)?;
+    index.write()?;
+    Ok(output(String::new()))
+}
+
+fn unstage(repo: &Repository, paths: &[String]) -> GitResult<()> {
+    match head_commit(repo)? {
+        // Repo has commits → reset the entries to HEAD
+        Some(head) => repo.reset_default(Some(head.as_object()), paths)?,
+        // Repo has no commits yet → drop them from the index
+        None => {
+            let mut index = repo.index()?;
+            for path in paths {
+                index.remove_path(Path::new(path))?;
+            }
+            index.write()?;
+        }
+    }
+    Ok(())
+}
+
+fn unstage_all(repo: &Repository) -> GitResult<Value> {
+    match head_commit(repo)? {
+        Some(head) => repo.reset(head.as_object(), ResetType::Mixed, None)?,
+        None => {
+            let mut index = repo.index()?;
+            index.clear()?;
+            index.write()?;
+        }
+    }
+    Ok(output(String::new()))
+}
+
+/// Restores worktree files from the index, an empty list restores everything.
+fn discard(repo: &Repository, paths: &[String]) -> GitResult<Value> {
+    let mut checkout = CheckoutBuilder::new();
+    checkout.force();
+    for path in paths {
+        checkout.path(path);
+    }
+    repo.checkout_index(None, Some(&mut checkout))?;
+    Ok(output(String::new()))
+}
+
+/// Commits recorded in `MERGE_HEAD` by an unfinished merge.
+pub(crate) fn merge_heads(repo: &Repository) -> Vec<Oid> {
+    std::fs::read_to_string(repo.path().join("MERGE_HEAD"))
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| Oid::from_str(line.trim()).ok())
+        .collect()
+}
+
+pub(crate) fn short_id(repo: &Repository, id: Oid) -> String {
+    repo.find_object(id, None)
+        .and_then(|o| o.short_id())
+        .ok()
+        .and_then(|buf| buf.as_str().map(String::from))
+        .unwrap_or_else(|| id.to_string()[..7].to_string())
+}
+
+fn list_branches(repo: &Repository) -> GitResult<Value> {
+    let current = current_branch(repo);
+    let mut names = Vec::new();
+    for branch in repo.branches(Some(BranchType::Local))? {
+        let (branch, _) = branch?;
+        if let Some(name) = branch.name()? {
+            names.push(name.to_string());
+        }
+    }
+    names.sort();
+
+    let mut stdout = String::new();
+    if current.is_none() {
+        if let Some(head) = head_commit(repo)? {
+            stdout.push_str(&format!(
+                "* (HEAD detached at {})\n",
+                short_id(repo, head.id())
+            ));
+        }
+    }
+    for name in names {
+        let marker = if current.as_deref() == Some(name.as_str()) {
+            "*"
+        } else {
+            " "
+        };
+        stdout.push_str(&format!("{} {}\n", marker, name));
+    }
+    Ok(output(stdout))
+}
+
+fn create_branch(repo: &Repository, name: &str) -> GitResult<Value> {
+    let refname = format!("refs/heads/{}", name);
+    match head_commit(repo)? {
+        Some(head) => {
+            repo.branch(name, &head, false)?;
+        }
+        None => {
+            if repo.find_reference(&refname).is_ok() {
+                return Err(GitError::new(
+                    GitErrorKind::AlreadyExists,
+                    format!("a branch named '{}' already exists", name),
+                ));
+            }
+        }
+    }
+    repo.set_head(&refname)?;
+    Ok(output(format!("Switched to a new branch '{}'", name)))
+}
+
+pub(crate) fn checkout(repo: &Repository, name: &str) -> GitResult<Value> {
+    let refname = match repo.find_branch(name, BranchType::Local) {
+        Ok(branch) => branch.get().name().map(String::from),
+        Err(_) => None,
+    };
+    let refname = match refname {
+        Some(refname) => refname,
+        None => match remote::find_remote_branch(repo, name)? {
+            // Same as `git checkout <name>` guessing the remote branch
+            Some(upstream) => {
+                let commit = upstream.get().peel_to_commit()?;
+                let upstream_name = upstream.name()?.unwrap_or("").to_string();
+                let mut branch = repo.branch(name, &commit, false)?;
+                branch.set_upstream(Some(&upstream_name))?;
+                format!("refs/heads/{}", name)
+            }
+            None => {
+                let commit = repo
+                    .revparse_single(name)
+                    .and_then(|o| o.peel_to_commit())
+                    .map_err(|_| {
+                        GitError::new(
+                            GitErrorKind::NotFound,
+                            format!("pathspec '{}' did not match any file(s) known to git", name),
+                        )
+                    })?;
+                repo.checkout_tree(commit.as_object(), Some(CheckoutBuilder::new().safe()))?;
+                repo.set_head_detached(commit.id())?;
+                return Ok(output(format!(
+                    "HEAD is now at {}",
+                    short_id(repo, commit.id())
+                )));
+            }
+        },
+    };
+
+    let target = repo
+        .find_reference(&refname)
+        .and_then(|r| r.peel_to_commit())?;
+    repo.checkout_tree(target.as_object(), Some(CheckoutBuilder::new().safe()))?;
+    repo.set_head(&refname)?;
+    Ok(output(format!("Switched to branch '{}'", name)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::testing::TestRepo;
+    use super::*;
+    use serde_json::json;
+    use std::path::Path;
+
+    fn staged(t: &TestRepo, path: &str) -> bool {
+        let head = t.repo.head().unwrap().peel_to_tree().unwrap();
+        let index = t.index();
+        let entry = index.get_path(Path::new(path), 0);
+        match head.get_path(Path::new(path)) {
+            Ok(e) => entry.map(|i| i.id) != Some(e.id()),
+            Err(_) => entry.is_some(),
+        }
+    }
+
+    #[test]
+    fn init_creates_repository() {
+        let dir = tempfile::tempdir().unwrap();
+        let request =
+            GitRequest::parse("init", json!({ "workspace": dir.path().to_string_lossy() }))
+                .unwrap();
+        let result = run(request).unwrap();
+        assert!(result["stdout"]
+            .as_str()
+            .unwrap()
+            .starts_with("Initialized"));
+        assert!(Repository::open(dir.path()).is_ok());
+    }
+
+    #[test]
+    fn open_outside_repository_fails() {
+        let dir = tempfile::tempdir().unwrap();
+        let err = open(&dir.path().to_string_lossy()).err().unwrap();
+        assert_eq!(err.kind, GitErrorKind::NotARepository);
+    }
+
+    #[test]
+    fn stage_and_unstage_file() {
+        let t = TestRepo::new();
+        t.write("a.txt", "a\n");
+        t.commit("init");
+        t.write("a.txt", "changed\n");
+        t.write("dir/b.txt", "b\n");
+
+        let absolute = t.path().join("a.txt");
+        t.run("stage", json!({ "file": absolute.to_string_lossy() }))
+            .unwrap();
+        t.run("stage", json!({ "file": "dir\\b.txt" })).unwrap();
+        assert!(staged(&t, "a.txt"));
+        assert!(staged(&t, "dir/b.txt"));
+
+        t.run("unstage", json!({ "file": "a.txt" })).unwrap();
+        assert!(!staged(&t, "a.txt"));
+        t.run("unstage-all", json!({})).unwrap();
+        assert!(!staged(&t, "dir/b.txt"));
+    }
+
+    #[test]
+    fn stage_all_picks_up_deletions() {
+        let t = TestRepo::new();
+        t.write("a.txt", "a\n");
+        t.write("b.txt", "b\n");
+        t.commit("init");
+        t.remove("b.txt");
+        t.run("stage-all", json!({})).unwrap();
+        assert!(t.index().get_path(Path::new("b.txt"), 0).is_none());
+    }
+
+    #[test]
+    fn discard_restores_staged_content() {
+        let t = TestRepo::new();
+        t.write("a.txt", "a\n");
+        t.commit("init");
+        t.write("a.txt", "staged\n");
+        t.run("stage", json!({ "file": "a.txt" })).unwrap();
+        t.write("a.txt", "unstaged\n");
+        t.run("discard", json!({ "file": "a.txt" })).unwrap();
+        assert_eq!(t.read("a.txt"), "staged\n");
+    }
+
+    #[test]
+    fn create_branch_and_checkout() {
+        let t = TestRepo::new();
+        t.write("a.txt", "a\n");
+        let first = t.commit("init");
+        t.run("create branch", json!({ "name": "feature" }))
+            .unwrap();
+        assert_eq!(current_branch(&t.repo).as_deref(), Some("feature"));
+        t.write("b.txt", "b\n");
+        t.commit("feature");
+
+        t.run("checkout", json!({ "name": "main" })).unwrap();
+        assert_eq!(current_branch(&t.repo).as_deref(), Some("main"));
+        assert!(!t.path().join("b.txt").exists());
+
+        let listing = t.run("branch", json!({})).unwrap();
+        assert_eq!(listing["stdout"], "  feature\n* main\n");
+
+        t.run("checkout", json!({ "name": first.to_string() }))
+            .unwrap();
+        assert!(t.repo.head_detached().unwrap());
+    }
+
+    #[test]
+    fn checkout_unknown_revision_fails() {
+        let t = TestRepo::new();
+        t.write("a.txt", "a\n");
+        t.commit("init");
+        let err = t.run("checkout", json!({ "name": "nope" })).unwrap_err();
+        assert_eq!(err.kind, GitErrorKind::NotFound);
+    }
+}
@@ -0,0 +1,440 @@
+use super::commit::{self, CommitOptions};
+use super::pick;
+use super::status::conflicted_paths;
+use super::{head_commit, short_id, GitError, GitErrorKind, GitResult};
+use git2::build::CheckoutBuilder;
+use git2::{ErrorCode, Oid, Rebase, Repository, RepositoryState, ResetType};
+use serde_json::{json, Value};
+use std::path::Path;
+
+/// The unfinished operation the repository is in, `null` when there is none.
+pub(crate) fn state(repo: &Repository) -> GitResult<Value> {
+    let kind = match repo.state() {
+        RepositoryState::Clean => return Ok(Value::Null),
+        RepositoryState::Merge => "merge",
+        RepositoryState::Revert | RepositoryState::RevertSequence => "revert",
+        RepositoryState::CherryPick | RepositoryState::CherryPickSequence => "cherryPick",
+        RepositoryState::Bisect => "bisect",
+        RepositoryState::ApplyMailbox => "applyMailbox",
+        RepositoryState::Rebase
+        | RepositoryState::RebaseInteractive
+        | RepositoryState::RebaseMerge
+        | RepositoryState::ApplyMailboxOrRebase => "rebase",
+    };
+    let incoming = ["MERGE_HEAD", "CHERRY_PICK_HEAD", "REVERT_HEAD"]
+        .iter()
+        .find_map(|name| read_oid(&repo.path().join(name)))
+        .map(|id| short_id(repo, id));
+
+    let mut step = None;
+    let mut total = None;
+    let mut onto = None;
+    let mut head_name = None;
+    if kind == "rebase" {
+        // `rebase-merge` for merge based rebases, `rebase-apply` for the old one
+        for (dir, step_file, total_file) in [
+            ("rebase-merge", "msgnum", "end"),
+            ("rebase-apply", "next", "last"),
+        ] {
+            let dir = repo.path().join(dir);
+            if !dir.is_dir() {
+                continue;
+            }
+            step = read_number(&dir.join(step_file));
+            total = read_number(&dir.join(total_file));
+            onto = read_oid(&dir.join("onto")).map(|id| short_id(repo, id));
+            head_name = std::fs::read_to_string(dir.join("head-name"))
+                .ok()
+                .map(|name| {
+                    let name = name.trim();
+                    name.strip_prefix("refs/heads/").unwrap_or(name).to_string()
+                });
+            break;
+        }
+    }
+
+    Ok(json!({
+        "kind": kind,
+        "step": step,
+        "total": total,
+        "onto": onto,
+        "headName": head_name,
+        "incoming": incoming,
+        "conflicts": conflicted_paths(repo)?,
+    }))
+}
+
+fn read_oid(path: &Path) -> Option<Oid> {
+    let contents = std::fs::read_to_string(path).ok()?;
+    Oid::from_str(contents.lines().next()?.trim()).ok()
+}
+
+fn read_number(path: &Path) -> Option<usize> {
+    std::fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+fn no_operation() -> GitError {
+    GitError::invalid("There is no merge, rebase, cherry-pick or revert in progress.")
+}
+
+fn ensure_resolved(repo: &Repository) -> GitResult<()> {
+    if repo.index()?.has_conflicts() {
+        return Err(GitError::new(
+            GitErrorKind::MergeConflict,
+            "You must resolve all conflicts before continuing.",
+        ));
+    }
+    Ok(())
+}
+
+/// `git <operation> --continue`.
+pub fn continue_operation(repo: &Repository) -> GitResult<Value> {
+    match repo.state() {
+        RepositoryState::Merge => {
+            ensure_resolved(repo)?;
+            let message = repo.message().unwrap_or_else(|_| "Merge".into());
+            commit::commit(repo, Some(&message), &CommitOptions::default())
+        }
+        RepositoryState::CherryPick | RepositoryState::CherryPickSequence => {
+            ensure_resolved(repo)?;
+            let sequence = pick::pending(repo);
+            let original = read_oid(&repo.path().join("CHERRY_PICK_HEAD"))
+                .map(|id| repo.find_commit(id))
+                .transpose()?;
+            let author = match &original {
+                Some(commit) => commit.author(),
+                None => repo.signature()?,
+            };
+            let message = repo
+                .message()
+                .ok()
+                .or_else(|| {
+                    original
+                        .as_ref()
+                        .and_then(|c| c.message().map(String::from))
+                })
+                .unwrap_or_default();
+            let committed = commit_picked(repo, &author, &message)?;
+            match sequence {
+                Some(sequence) => pick::resume(repo, sequence),
+                None => Ok(committed),
+            }
+        }
+        RepositoryState::Revert | RepositoryState::RevertSequence => {
+            ensure_resolved(repo)?;
+            let sequence = pick::pending(repo);
+            let message = repo.message().unwrap_or_default();
+            let committed = commit_picked(repo, &repo.signature()?, &message)?;
+            match sequence {
+                Some(sequence) => pick::resume(repo, sequence),
+                None => Ok(committed),
+            }
+        }
+        RepositoryState::Rebase
+        | RepositoryState::RebaseInteractive
+        | RepositoryState::RebaseMerge => {
+            ensure_resolved(repo)?;
+            let mut rebase = open_rebase(repo)?;
+            commit_rebase_step(repo, &mut rebase)?;
+            drive_rebase(repo, &mut rebase)
+        }
+        _ => Err(no_operation()),
+    }
+}
+
+/// `git <operation> --abort`: back to where the operation started.
+pub fn abort(repo: &Repository) -> GitResult<Value> {
+    match repo.state() {
+        RepositoryState::Clean => Err(no_operation()),
+        RepositoryState::Rebase
+        | RepositoryState::RebaseInteractive
+        | RepositoryState::RebaseMerge => {
+            match repo.open_rebase(None) {
+                Ok(mut rebase) => rebase.abort()?,
+                Err(_) => abort_rebase_dir(repo)?,
+            }
+            Ok(json!({ "result": "aborted" }))
+        }
+        _ => {
+            // A stopped sequence goes back to before its first commit
+            match pick::pending(repo) {
+                Some(sequence) => {
+                    let head = repo.find_commit(sequence.head)?;
+                    repo.reset(head.as_object(), ResetType::Hard, None)?;
+                }
+                None => reset_to_head(repo)?,
+            }
+            repo.cleanup_state()?;
+            Ok(json!({ "result": "aborted" }))
+        }
+    }
+}
+
+/// `git <operation> --skip`: drops the commit being applied and moves on.
+pub fn skip(repo: &Repository) -> GitResult<Value> {
+    match repo.state() {
+        RepositoryState::Rebase
+        | RepositoryState::RebaseInteractive
+        | RepositoryState::RebaseMerge => {
+            let mut rebase = open_rebase(repo)?;
+            discard_step(repo)?;
+            drive_rebase(repo, &mut rebase)
+        }
+        RepositoryState::CherryPick
+        | RepositoryState::CherryPickSequence
+        | RepositoryState::Revert
+        | RepositoryState::RevertSequence => {
+            let sequence = pick::pending(repo);
+            reset_to_head(repo)?;
+            repo.cleanup_state()?;
+            match sequence {
+                Some(sequence) => pick::resume(repo, sequence),
+                None => Ok(json!({ "result": "skipped" })),
+            }
+        }
+        RepositoryState::Clean => Err(no_operation()),
+        _ => Err(GitError::invalid(
+            "Only rebases, cherry-picks and reverts can be skipped.",
+        )),
+    }
+}
+
+/// Rebases started by the git CLI use a todo list libgit2 can't resume.
+fn open_rebase(repo: &Repository) -> GitResult<Rebase<'_>> {
+    repo.open_rebase(None).map_err(|_| {
+        GitError::invalid(
+            "This rebase was started from the command line, continue it with \
+             `git rebase --continue` or abort it.",
+        )
+    })
+}
+
+/// `git rebase --abort` for any rebase: restores the original branch from
+/// the state git keeps in `rebase-merge` or `rebase-apply`.
+fn abort_rebase_dir(repo: &Repository) -> GitResult<()> {
+    let dir = ["rebase-merge", "rebase-apply"]
+        .iter()
+        .map(|dir| repo.path().join(dir))
+        .find(|dir| dir.is_dir())
+        .ok_or_else(no_operation)?;
+    let orig_head = read_oid(&dir.join("orig-head"))
+        .ok_or_else(|| GitError::other("the rebase state has no original HEAD"))?;
+    let head_name = std::fs::read_to_string(dir.join("head-name")).unwrap_or_default();
+    let head_name = head_name.trim();
+
+    let commit = repo.find_commit(orig_head)?;
+    repo.reset(commit.as_object(), ResetType::Hard, None)?;
+    if head_name.starts_with("refs/heads/") {
+        repo.reference(head_name, orig_head, true, "rebase: aborting")?;
+        repo.set_head(head_name)?;
+    } else {
+        repo.set_head_detached(orig_head)?;
+    }
+    // A hard reset already cleans the state up, this covers the rest
+    if dir.exists() {
+        std::fs::remove_dir_all(&dir).map_err(|e| GitError::other(e.to_string()))?;
+    }
+    Ok(())
+}
+
+fn reset_to_head(repo: &Repository) -> GitResult<()> {
+    let head = head_commit(repo)?
+        .ok_or_else(|| GitError::new(GitErrorKind::NotFound, "HEAD has no commits"))?;
+    repo.reset(head.as_object(), ResetType::Hard, None)?;
+    Ok(())
+}
+
+/// Throws away the rebase step being applied. Unlike a hard reset this
+/// keeps the rebase state, libgit2 cleans it up on `reset`.
+fn discard_step(repo: &Repository) -> GitResult<()> {
+    let head = head_commit(repo)?
+        .ok_or_else(|| GitError::new(GitErrorKind::NotFound, "HEAD has no commits"))?;
+    let tree = head.tree()?;
+    repo.checkout_tree(tree.as_object(), Some(CheckoutBuilder::new().force()))?;
+    let mut index = repo.index()?;
+    index.read_tree(&tree)?;
+    index.write()?;
+    Ok(())
+}
+
+fn commit_picked(repo: &Repository, author: &git2::Signature, message: &str) -> GitResult<Value> {
+    let committer = repo.signature()?;
+    let mut index = repo.index()?;
+    let tree = repo.find_tree(index.write_tree()?)?;
+    let head = head_commit(repo)?
+        .ok_or_else(|| GitError::new(GitErrorKind::NotFound, "HEAD has no commits"))?;
+    if head.tree_id() == tree.id() {
+        return Err(GitError::new(
+            GitErrorKind::NothingToCommit,
+            "The changes are already in HEAD, skip this commit instead.",
+        ));
+    }
+    let message = git2::message_prettify(message, Some(b'#'))?;
+    let id = repo.commit(Some("HEAD"), author, &committer, &message, &tree, &[&head])?;
+    repo.cleanup_state()?;
+    Ok(json!({ "result": "committed", "head": id.to_string() }))
+}
+
+/// Commits the current rebase step, unless it turned out to be empty.
+fn commit_rebase_step(repo: &Repository, rebase: &mut Rebase) -> GitResult<()> {
+    if rebase.operation_current().is_none() {
+        return Ok(());
+    }
+    match rebase.commit(None, &repo.signature()?, None) {
+        Ok(_) => Ok(()),
+        Err(e) if e.code() == ErrorCode::Applied => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Applies the remaining rebase steps, stopping at the next conflict.
+pub(crate) fn drive_rebase(repo: &Repository, rebase: &mut Rebase) -> GitResult<Value> {
+    let signature = repo.signature()?;
+    let total = rebase.len();
+    while let Some(operation) = rebase.next() {
+        let operation = operation?;
+        if repo.index()?.has_conflicts() {
+            return Ok(json!({
+                "result": "conflicts",
+                "step": rebase.operation_current().map(|i| i + 1),
+                "total": total,
+                "stoppedAt": operation.id().to_string(),
+                "conflicts": conflicted_paths(repo)?,
+            }));
+        }
+        match rebase.commit(None, &signature, None) {
+            Ok(_) => {}
+            // Already upstream, git drops these as well
+            Err(e) if e.code() == ErrorCode::Applied => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    rebase.finish(Some(&signature))?;
+    let head = head_commit(repo)?.map(|c| c.id().to_string());
+    Ok(json!({ "result": "rebased", "total": total, "head": head }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git::branch;
+    use crate::git::testing::TestRepo;
+
+    fn resolve(t: &TestRepo, file: &str, contents: &str) {
+        t.write(file, contents);
+        let mut index = t.index();
+        index.add_path(Path::new(file)).unwrap();
+        index.write().unwrap();
+    }
+
+    /// `feature` rebased onto `main`, stopping on its first commit.
+    fn stopped_rebase() -> TestRepo {
+        let t = TestRepo::new();
+        t.conflict("c.txt", "base\n", "ours\n", "theirs\n");
+        t.switch("main");
+        t.repo.cleanup_state().unwrap();
+        t.switch("other");
+        t.write("next.txt", "next\n");
+        t.commit("next");
+        let result = branch::rebase(&t.repo, Some("other"), "main").unwrap();
+        assert_eq!(result["result"], "conflicts");
+        t
+    }
+
+    #[test]
+    fn clean_repository_has_no_operation() {
+        let t = TestRepo::new();
+        t.write("a.txt", "a\n");
+        t.commit("a");
+        assert_eq!(state(&t.repo).unwrap(), Value::Null);
+        assert_eq!(
+            continue_operation(&t.repo).unwrap_err().kind,
+            GitErrorKind::InvalidRequest
+        );
+        assert_eq!(
+            abort(&t.repo).unwrap_err().kind,
+            GitErrorKind::InvalidRequest
+        );
+        assert_eq!(
+            skip(&t.repo).unwrap_err().kind,
+            GitErrorKind::InvalidRequest
+        );
+    }
+
+    #[test]
+    fn merge_state_and_continue() {
+        let t = TestRepo::new();
+        t.conflict("c.txt", "base\n", "ours\n", "theirs\n");
+        let current = state(&t.repo).unwrap();
+        assert_eq!(current["kind"], "merge");
+        assert_eq!(current["conflicts"], json!(["c.txt"]));
+        assert!(current["incoming"].is_string());
+
+        let err = continue_operation(&t.repo).unwrap_err();
+        assert_eq!(err.kind, GitErrorKind::MergeConflict);
+
+        resolve(&t, "c.txt", "both\n");
+        continue_operation(&t.repo).unwrap();
+        assert_eq!(t.repo.state(), RepositoryState::Clean);
+        let head = t.repo.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(head.parent_count(), 2);
+    }
+
+    #[test]
+    fn abort_merge_restores_head() {
+        let t = TestRepo::new();
+        t.conflict("c.txt", "base\n", "ours\n", "theirs\n");
+        let head = t.head();
+        assert_eq!(abort(&t.repo).unwrap()["result"], "aborted");
+        assert_eq!(t.repo.state(), RepositoryState::Clean);
+        assert_eq!(t.head(), head);
+        assert_eq!(t.read("c.txt"), "ours\n");
+        assert_eq!(
+            skip(&t.repo).unwrap_err().kind,
+            GitErrorKind::InvalidRequest
+        );
+    }
+
+    #[test]
+    fn rebase_state_and_continue() {
+        let t = stopped_rebase();
+        let current = state(&t.repo).unwrap();
+        assert_eq!(current["kind"], "rebase");
+        assert_eq!(current["headName"], "other");
+        assert_eq!(
+            (current["step"].as_u64(), current["total"].as_u64()),
+            (Some(1), Some(2))
+        );
+
+        resolve(&t, "c.txt", "both\n");
+        let result = continue_operation(&t.repo).unwrap();
+        assert_eq!(result["result"], "rebased");
+        assert_eq!(t.repo.state(), RepositoryState::Clean);
+        let tip = t.repo.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(tip.summary(), Some("next"));
+        assert_eq!(t.read("c.txt"), "both\n");
+    }
+
+    #[test]
+    fn rebase_skip_drops_the_commit() {
+        let t = stopped_rebase();
+        let main = t.repo.refname_to_id("refs/heads/main").unwrap();
+        let result = skip(&t.repo).unwrap();
+        assert_eq!(result["result"], "rebased");
+        let tip = t.repo.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(tip.summary(), Some("next"));
+        assert_eq!(tip.parent_id(0).unwrap(), main);
+        assert_eq!(t.read("c.txt"), "ours\n");
+    }
+
+    #[test]
+    fn rebase_abort_restores_branch() {
+        let t = stopped_rebase();
+        let before = t.repo.refname_to_id("refs/heads/other").unwrap();
+        abort(&t.repo).unwrap();
+        assert_eq!(t.repo.state(), RepositoryState::Clean);
+        assert_eq!(t.repo.head().unwrap().name(), Some("refs/heads/other"));
+        assert_eq!(t.head(), before);
+        assert_eq!(t.read("c.txt"), "theirs\n");
+    }
+}
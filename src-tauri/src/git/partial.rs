@@ -0,0 +1,445 @@
+use super::action::RepoFile;
+use super::diff::hunk_id;
+use super::{head_commit, output, repo_path, GitError, GitErrorKind, GitResult};
+use git2::build::{CheckoutBuilder, TreeUpdateBuilder};
+use git2::{Delta, DiffOptions, IndexEntry, IndexTime, Patch, Repository};
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+/// Hunks of one file picked from a `git_diff` result.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LineSelection {
+    pub file: RepoFile,
+    pub hunks: Vec<HunkSelection>,
+    /// Must match the `contextLines` of the diff the hunks came from.
+    #[serde(default = "default_context_lines")]
+    pub context_lines: u32,
+}
+
+fn default_context_lines() -> u32 {
+    3
+}
+
+#[derive(Debug, Deserialize)]
+pub struct HunkSelection {
+    /// `id` of the hunk in the `git_diff` result.
+    pub id: String,
+    /// Indexes into the hunk's `lines`, the whole hunk when omitted.
+    pub lines: Option<Vec<usize>>,
+}
+
+/// Like `git add -p`: applies the selected worktree changes to the index.
+pub fn stage_lines(repo: &Repository, selection: &LineSelection) -> GitResult<Value> {
+    let path = repo_path(repo, &selection.file);
+    let mut options = diff_options(&path, selection.context_lines);
+    options.include_untracked(true).show_untracked_content(true);
+    let diff = repo.diff_index_to_workdir(None, Some(&mut options))?;
+    let patch = file_patch(&diff, selection)?;
+
+    let mut index = repo.index()?;
+    let current = index.get_path(Path::new(&path), 0);
+    let base = match &current {
+        Some(entry) => repo.find_blob(entry.id)?.content().to_vec(),
+        None => Vec::new(),
+    };
+    let content = apply(&base, &patch, &selected(&patch, selection)?, false);
+    // Staging every line of a deleted file stages the deletion
+    if patch.delta().status() == Delta::Deleted && content.is_empty() {
+        index.remove_path(Path::new(&path))?;
+    } else {
+        let mode = u32::from(patch.delta().new_file().mode());
+        index.add_frombuffer(&index_entry(current, &path, mode), &content)?;
+    }
+    index.write()?;
+    Ok(output(String::new()))
+}
+
+/// Like `git reset -p`: takes the selected staged changes back out of the index.
+pub fn unstage_lines(repo: &Repository, selection: &LineSelection) -> GitResult<Value> {
+    let path = repo_path(repo, &selection.file);
+    let mut options = diff_options(&path, selection.context_lines);
+    let head = match head_commit(repo)? {
+        Some(head) => Some(head.tree()?),
+        None => None,
+    };
+    let diff = repo.diff_tree_to_index(head.as_ref(), None, Some(&mut options))?;
+    let patch = file_patch(&diff, selection)?;
+
+    let mut index = repo.index()?;
+    let current = index.get_path(Path::new(&path), 0);
+    let base = match &current {
+        Some(entry) => repo.find_blob(entry.id)?.content().to_vec(),
+        None => Vec::new(),
+    };
+    let content = apply(&base, &patch, &selected(&patch, selection)?, true);
+    // Unstaging every line of a new file leaves it untracked again
+    if patch.delta().status() == Delta::Added && content.is_empty() {
+        index.remove_path(Path::new(&path))?;
+    } else {
+        let mode = u32::from(patch.delta().old_file().mode());
+        index.add_frombuffer(&index_entry(current, &path, mode), &content)?;
+    }
+    index.write()?;
+    Ok(output(String::new()))
+}
+
+/// Like `git checkout -p`: reverts the selected worktree changes on disk.
+pub fn discard_lines(repo: &Repository, selection: &LineSelection) -> GitResult<Value> {
+    let path = repo_path(repo, &selection.file);
+    let mut options = diff_options(&path, selection.context_lines);
+    options.include_untracked(true).show_untracked_content(true);
+    let diff = repo.diff_index_to_workdir(None, Some(&mut options))?;
+    let patch = file_patch(&diff, selection)?;
+
+    let workdir = repo
+        .workdir()
+        .ok_or_else(|| GitError::invalid("cannot discard changes in a bare repository"))?;
+    let file = workdir.join(&path);
+    // The diff is of the clean content, e.g. with LF line endings under
+    // core.autocrlf, so the lines are put back into that and the result is
+    // checked out through the same filters
+    let base = repo.find_blob(repo.blob_path(&file)?)?.content().to_vec();
+    let content = apply(&base, &patch, &selected(&patch, selection)?, true);
+    if patch.delta().status() == Delta::Untracked && content.is_empty() {
+        std::fs::remove_file(&file).map_err(|e| GitError::other(e.to_string()))?;
+        return Ok(output(String::new()));
+    }
+    let empty = repo.find_tree(repo.treebuilder(None)?.write()?)?;
+    let mut update = TreeUpdateBuilder::new();
+    update.upsert(
+        path.as_str(),
+        repo.blob(&content)?,
+        patch.delta().new_file().mode(),
+    );
+    let tree = repo.find_tree(update.create_updated(repo, &empty)?)?;
+    let mut checkout = CheckoutBuilder::new();
+    checkout
+        .force()
+        .update_index(false)
+        .disable_pathspec_match(true)
+        .path(&path);
+    repo.checkout_tree(tree.as_object(), Some(&mut checkout))?;
+    Ok(output(String::new()))
+}
+
+fn diff_options(path: &str, context_lines: u32) -> DiffOptions {
+    let mut options = DiffOptions::new();
+    options
+        .pathspec(path)
+        .disable_pathspec_match(true)
+        .context_lines(context_lines);
+    options
+}
+
+fn stale(path: &str) -> GitError {
+    GitError::new(
+        GitErrorKind::Stale,
+        format!(
+            "'{}' changed since the diff was loaded, refresh and try again",
+            path
+        ),
+    )
+}
+
+fn file_patch<'d>(diff: &'d git2::Diff, selection: &LineSelection) -> GitResult<Patch<'d>> {
+    let path = selection.file.to_string();
+    let patch = match diff.deltas().len() {
+        0 => None,
+        _ => Patch::from_diff(diff, 0)?,
+    };
+    let patch = patch.ok_or_else(|| stale(&path))?;
+    if patch.delta().flags().is_binary() {
+        return Err(GitError::invalid(format!(
+            "cannot select lines of binary file '{}'",
+            path
+        )));
+    }
+    Ok(patch)
+}
+
+/// Resolves the selected hunk ids to `(hunk, line)` positions in `patch`.
+fn selected(patch: &Patch, selection: &LineSelection) -> GitResult<HashSet<(usize, usize)>> {
+    let mut ids = HashMap::new();
+    for hunk_idx in 0..patch.num_hunks() {
+        ids.insert(hunk_id(patch, hunk_idx)?, hunk_idx);
+    }
+
+    let mut selected = HashSet::new();
+    for hunk in &selection.hunks {
+        let hunk_idx = *ids.get(&hunk.id).ok_or_else(|| stale(&selection.file))?;
+        // Same numbering as `lines` in the diff, which leaves out the
+        // "no newline at end of file" markers
+        let changes: Vec<(usize, char)> = (0..patch.num_lines_in_hunk(hunk_idx)?)
+            .map(|i| patch.line_in_hunk(hunk_idx, i).map(|l| l.origin()))
+            .collect::<Result<Vec<_>, _>>()?
+            .into_iter()
+            .filter(|origin| matches!(origin, ' ' | '+' | '-'))
+            .enumerate()
+            .collect();
+        match &hunk.lines {
+            None => selected.extend(
+                changes
+                    .iter()
+                    .filter(|(_, origin)| *origin != ' ')
+                    .map(|(i, _)| (hunk_idx, *i)),
+            ),
+            Some(lines) => {
+                for &line in lines {
+                    match changes.get(line) {
+                        Some((_, '+' | '-')) => {
+                            selected.insert((hunk_idx, line));
+                        }
+                        Some(_) => {}
+                        None => {
+                            return Err(GitError::invalid(format!(
+                                "line {} is not part of the hunk",
+                                line
+                            )))
+                        }
+                    }
+                }
+            }
+        }
+    }
+    if selected.is_empty() {
+        return Err(GitError::invalid("no changed lines selected"));
+    }
+    Ok(selected)
+}
+
+/// Rebuilds a file from `base` with only the selected changes of `patch`.
+///
+/// Forward, `base` is the old side and selected changes are applied to it.
+/// In reverse, `base` is the new side and selected changes are undone.
+fn apply(base: &[u8], patch: &Patch, selected: &HashSet<(usize, usize)>, reverse: bool) -> Vec<u8> {
+    let base_lines: Vec<&[u8]> = base.split_inclusive(|b| *b == b'\n').collect();
+    let mut out = Vec::with_capacity(base.len());
+    let mut next = 0;
+    let copy_to = |out: &mut Vec<u8>, next: &mut usize, end: usize| {
+        while *next < end.min(base_lines.len()) {
+            push_line(out, base_lines[*next]);
+            *next += 1;
+        }
+    };
+
+    for hunk_idx in 0..patch.num_hunks() {
+        let line_count = patch.num_lines_in_hunk(hunk_idx).unwrap_or(0);
+        let mut idx = 0;
+        for line_idx in 0..line_count {
+            let line = match patch.line_in_hunk(hunk_idx, line_idx) {
+                Ok(line) => line,
+                Err(_) => continue,
+            };
+            let (base_lineno, kind) = match (line.origin(), reverse) {
+                (' ', false) => (line.old_lineno(), ' '),
+                (' ', true) => (line.new_lineno(), ' '),
+                // A line of the base side: kept unless selected
+                ('-', false) | ('+', true) => (line.old_lineno().or(line.new_lineno()), 'b'),
+                // A line of the other side: taken over when selected
+                ('+', false) | ('-', true) => (None, 'o'),
+                _ => continue,
+            };
+            let is_selected = selected.contains(&(hunk_idx, idx));
+            idx += 1;
+            match kind {
+                ' ' => copy_to(&mut out, &mut next, base_lineno.unwrap_or(0) as usize),
+                'b' => {
+                    let lineno = base_lineno.unwrap_or(0) as usize;
+                    copy_to(&mut out, &mut next, lineno.saturating_sub(1));
+                    if !is_selected {
+                        copy_to(&mut out, &mut next, lineno);
+                    }
+                    next = next.max(lineno);
+                }
+                _ => {
+                    if is_selected {
+                        push_line(&mut out, line.content());
+                    }
+                }
+            }
+        }
+    }
+    copy_to(&mut out, &mut next, base_lines.len());
+    out
+}
+
+/// Appends a line, giving the previous one its newline back if it was the
+/// last line of a file without one.
+fn push_line(out: &mut Vec<u8>, line: &[u8]) {
+    if out.last().is_some_and(|b| *b != b'\n') {
+        out.push(b'\n');
+    }
+    out.extend_from_slice(line);
+}
+
+/// Entry to write the new blob under. The stat data is cleared so status
+/// compares content instead of trusting the worktree file's timestamps.
+fn index_entry(current: Option<IndexEntry>, path: &str, mode: u32) -> IndexEntry {
+    let zero = IndexTime::new(0, 0);
+    match current {
+        Some(entry) => IndexEntry {
+            ctime: zero,
+            mtime: zero,
+            ..entry
+        },
+        None => IndexEntry {
+            ctime: zero,
+            mtime: zero,
+            dev: 0,
+            ino: 0,
+            mode,
+            uid: 0,
+            gid: 0,
+            file_size: 0,
+            id: git2::Oid::zero(),
+            flags: path.len().min(0xfff) as u16,
+            flags_extended: 0,
+            path: path.as_bytes().to_vec(),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::git::diff::{diff, DiffHunk};
+    use crate::git::testing::TestRepo;
+    use crate::git::GitErrorKind;
+    use serde_json::{json, Value};
+    use std::path::Path;
+
+    const TEN: &str = "1\n2\n3\n4\n5\n6\n7\n8\n9\n10\n";
+
+    fn hunks(t: &TestRepo, mode: &str) -> Vec<DiffHunk> {
+        let request = json!({ "workspace": t.url(), "mode": mode, "includeUntracked": true });
+        let mut files = diff(serde_json::from_value(request).unwrap()).unwrap();
+        files.pop().map(|f| f.hunks).unwrap_or_default()
+    }
+
+    fn staged(t: &TestRepo, file: &str) -> String {
+        let index = t.index();
+        let entry = index.get_path(Path::new(file), 0).unwrap();
+        String::from_utf8(t.repo.find_blob(entry.id).unwrap().content().to_vec()).unwrap()
+    }
+
+    fn select(file: &str, id: &str, lines: Value) -> Value {
+        json!({ "file": file, "hunks": [{ "id": id, "lines": lines }] })
+    }
+
+    /// `a.txt` with changes at the top and the bottom, two hunks apart.
+    fn two_hunks() -> TestRepo {
+        let t = TestRepo::new();
+        t.write("a.txt", TEN);
+        t.commit("init");
+        t.write(
+            "a.txt",
+            &TEN.replace("1\n", "one\n").replace("10\n", "ten\n"),
+        );
+        t
+    }
+
+    #[test]
+    fn stages_one_hunk() {
+        let t = two_hunks();
+        let hunks = hunks(&t, "unstaged");
+        assert_eq!(hunks.len(), 2);
+        t.run("stage-lines", select("a.txt", &hunks[1].id, Value::Null))
+            .unwrap();
+        assert_eq!(staged(&t, "a.txt"), TEN.replace("10\n", "ten\n"));
+        // The other hunk is still unstaged, with the same id
+        let left = self::hunks(&t, "unstaged");
+        assert_eq!(left.len(), 1);
+        assert_eq!(left[0].id, hunks[0].id);
+    }
+
+    #[test]
+    fn stages_selected_lines() {
+        let t = TestRepo::new();
+        t.write("a.txt", "a\nb\n");
+        t.commit("init");
+        t.write("a.txt", "a\nx\ny\nb\n");
+        let hunk = &hunks(&t, "unstaged")[0];
+        let x = hunk.lines.iter().position(|l| l.content == "x").unwrap();
+        t.run("stage-lines", select("a.txt", &hunk.id, json!([x])))
+            .unwrap();
+        assert_eq!(staged(&t, "a.txt"), "a\nx\nb\n");
+        assert_eq!(t.read("a.txt"), "a\nx\ny\nb\n");
+    }
+
+    #[test]
+    fn stale_hunk_is_rejected() {
+        let t = two_hunks();
+        let id = hunks(&t, "unstaged")[0].id.clone();
+        t.write("a.txt", &TEN.replace("1\n", "uno\n"));
+        let err = t
+            .run("stage-lines", select("a.txt", &id, Value::Null))
+            .unwrap_err();
+        assert_eq!(err.kind, GitErrorKind::Stale);
+        assert_eq!(staged(&t, "a.txt"), TEN);
+    }
+
+    #[test]
+    fn unstages_one_hunk() {
+        let t = two_hunks();
+        t.run("stage", json!({ "file": "a.txt" })).unwrap();
+        let hunks = hunks(&t, "staged");
+        t.run("unstage-lines", select("a.txt", &hunks[0].id, Value::Null))
+            .unwrap();
+        assert_eq!(staged(&t, "a.txt"), TEN.replace("10\n", "ten\n"));
+    }
+
+    #[test]
+    fn discards_one_hunk() {
+        let t = two_hunks();
+        let hunks = hunks(&t, "unstaged");
+        t.run("discard-lines", select("a.txt", &hunks[0].id, Value::Null))
+            .unwrap();
+        assert_eq!(t.read("a.txt"), TEN.replace("10\n", "ten\n"));
+    }
+
+    #[test]
+    fn discards_lines_of_a_crlf_file() {
+        let t = TestRepo::new();
+        t.repo
+            .config()
+            .unwrap()
+            .set_bool("core.autocrlf", true)
+            .unwrap();
+        let crlf = |text: &str| text.replace('\n', "\r\n");
+        t.write("a.txt", &crlf(TEN));
+        t.commit("init");
+        let changed = TEN.replace("1\n", "one\n").replace("10\n", "ten\n");
+        t.write("a.txt", &crlf(&changed));
+
+        let hunks = hunks(&t, "unstaged");
+        assert_eq!(hunks.len(), 2);
+        t.run("discard-lines", select("a.txt", &hunks[0].id, Value::Null))
+            .unwrap();
+        assert_eq!(t.read("a.txt"), crlf(&TEN.replace("10\n", "ten\n")));
+        // Only the hunk that was kept is left
+        let left = self::hunks(&t, "unstaged");
+        assert_eq!(left.len(), 1);
+        assert_eq!(left[0].id, hunks[1].id);
+    }
+
+    #[test]
+    fn whole_new_file_round_trip() {
+        let t = TestRepo::new();
+        t.write("a.txt", "a\n");
+        t.commit("init");
+        t.write("new.txt", "n\n");
+        let id = hunks(&t, "unstaged")[0].id.clone();
+        t.run("stage-lines", select("new.txt", &id, Value::Null))
+            .unwrap();
+        assert_eq!(staged(&t, "new.txt"), "n\n");
+
+        let id = hunks(&t, "staged")[0].id.clone();
+        t.run("unstage-lines", select("new.txt", &id, Value::Null))
+            .unwrap();
+        // Unstaging all of a new file leaves it untracked
+        assert!(t.index().get_path(Path::new("new.txt"), 0).is_none());
+        assert_eq!(t.read("new.txt"), "n\n");
+    }
+}
@@ -0,0 +1,532 @@
+use super::{current_branch, head_commit, short_id, GitError, GitErrorKind, GitResult};
+use git2::{build::CheckoutBuilder, Commit, Config, Oid, Repository, RepositoryState, Tree};
+use serde_json::{json, Value};
+use std::fs;
+
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum PickKind {
+    CherryPick,
+    Revert,
+}
+
+impl PickKind {
+    fn verb(self) -> &'static str {
+        match self {
+            PickKind::CherryPick => "pick",
+            PickKind::Revert => "revert",
+        }
+    }
+
+    fn head_file(self) -> &'static str {
+        match self {
+            PickKind::CherryPick => "CHERRY_PICK_HEAD",
+            PickKind::Revert => "REVERT_HEAD",
+        }
+    }
+}
+
+/// Commits still to be applied after a cherry-pick stopped at a conflict,
+/// kept in `.git/sequencer` the way git does so either can resume it.
+pub(crate) struct Sequence {
+    kind: PickKind,
+    commits: Vec<Oid>,
+    mainline: Option<u32>,
+    /// HEAD before the first commit was applied, where an abort goes back to.
+    pub(crate) head: Oid,
+}
+
+pub fn cherry_pick(
+    repo: &Repository,
+    revisions: &[String],
+    mainline: Option<u32>,
+    no_commit: bool,
+) -> GitResult<Value> {
+    if revisions.is_empty() {
+        return Err(GitError::invalid("No commits to cherry-pick"));
+    }
+    let commits = revisions
+        .iter()
+        .map(|revision| repo.revparse_single(revision)?.peel_to_commit())
+        .collect::<Result<Vec<_>, _>>()?;
+    apply(
+        repo,
+        PickKind::CherryPick,
+        commits,
+        mainline,
+        no_commit,
+        None,
+    )
+}
+
+pub fn revert(
+    repo: &Repository,
+    revision: &str,
+    mainline: Option<u32>,
+    no_commit: bool,
+) -> GitResult<Value> {
+    let commit = repo.revparse_single(revision)?.peel_to_commit()?;
+    apply(
+        repo,
+        PickKind::Revert,
+        vec![commit],
+        mainline,
+        no_commit,
+        None,
+    )
+}
+
+/// The rest of an interrupted sequence, read before the state is cleaned up.
+pub(crate) fn pending(repo: &Repository) -> Option<Sequence> {
+    let dir = repo.path().join("sequencer");
+    let todo = fs::read_to_string(dir.join("todo")).ok()?;
+    let head = Oid::from_str(fs::read_to_string(dir.join("head")).ok()?.trim()).ok()?;
+    let mut kind = PickKind::CherryPick;
+    let mut commits = Vec::new();
+    // The first line is the commit that stopped, the rest is still to do
+    for line in todo.lines().skip(1) {
+        let mut words = line.split_whitespace();
+        match words.next() {
+            Some("pick" | "p") => kind = PickKind::CherryPick,
+            Some("revert") => kind = PickKind::Revert,
+            _ => continue,
+        }
+        // git may have abbreviated the ids
+        if let Some(commit) = words.next().and_then(|id| repo.revparse_single(id).ok()) {
+            commits.push(commit.id());
+        }
+    }
+    let mainline = Config::open(&dir.join("opts"))
+        .and_then(|opts| opts.get_i32("options.mainline"))
+        .ok()
+        .map(|n| n as u32);
+    Some(Sequence {
+        kind,
+        commits,
+        mainline,
+        head,
+    })
+}
+
+/// Applies what is left of a sequence on top of HEAD.
+pub(crate) fn resume(repo: &Repository, sequence: Sequence) -> GitResult<Value> {
+    let commits = sequence
+        .commits
+        .iter()
+        .map(|id| repo.find_commit(*id))
+        .collect::<Result<Vec<_>, _>>()?;
+    if commits.is_empty() {
+        let head = head_commit(repo)?.map(|c| c.id().to_string());
+        return Ok(json!({ "result": "committed", "commits": [], "head": head }));
+    }
+    apply(
+        repo,
+        sequence.kind,
+        commits,
+        sequence.mainline,
+        false,
+        Some(sequence.head),
+    )
+}
+
+/// Applies `commits` one by one on top of HEAD, committing each unless
+/// `no_commit`. Stops at the first conflict with the conflicts written to
+/// the working tree and index, like `git cherry-pick` and `git revert` do.
+fn apply(
+    repo: &Repository,
+    kind: PickKind,
+    commits: Vec<Commit>,
+    mainline: Option<u32>,
+    no_commit: bool,
+    sequence_head: Option<Oid>,
+) -> GitResult<Value> {
+    if repo.state() != RepositoryState::Clean {
+        return Err(GitError::invalid(
+            "Finish or abort the operation in progress first.",
+        ));
+    }
+    let head = head_commit(repo)?
+        .ok_or_else(|| GitError::new(GitErrorKind::NotFound, "HEAD has no commits"))?;
+    let staged = repo.diff_tree_to_index(Some(&head.tree()?), None, None)?;
+    if staged.deltas().len() > 0 {
+        return Err(GitError::new(
+            GitErrorKind::DirtyWorkingTree,
+            "Your index contains uncommitted changes, commit or stash them first.",
+        ));
+    }
+
+    let committer = repo.signature()?;
+    let mut ours = head.clone();
+    let mut tree = head.tree()?;
+    let mut created = Vec::new();
+    let mut skipped = Vec::new();
+    for (i, commit) in commits.iter().enumerate() {
+        let (base, theirs) = sides(repo, kind, commit, mainline)?;
+        let mut index = repo.merge_trees(&base, &tree, &theirs, None)?;
+        let message = message(kind, commit, mainline)?;
+
+        if index.has_conflicts() {
+            move_head(repo, &head, &ours, kind, &commits[i.saturating_sub(1)])?;
+            let label = format!(
+                "{} ({})",
+                short_id(repo, commit.id()),
+                commit.summary().unwrap_or("")
+            );
+            let mut checkout = CheckoutBuilder::new();
+            checkout
+                .safe()
+                .allow_conflicts(true)
+                .conflict_style_merge(true)
+                .our_label("HEAD")
+                .their_label(&label);
+            repo.checkout_index(Some(&mut index), Some(&mut checkout))?;
+            let mut repo_index = repo.index()?;
+            repo_index.clear()?;
+            for entry in index.iter() {
+                repo_index.add(&entry)?;
+            }
+            repo_index.write()?;
+
+            let conflicts = super::status::conflicted_paths(repo)?;
+            write_state(repo, kind, commit, &message, &conflicts, no_commit)?;
+            let remaining: Vec<Oid> = commits[i + 1..].iter().map(|c| c.id()).collect();
+            if !no_commit && !remaining.is_empty() {
+                let sequence = Sequence {
+                    kind,
+                    commits: remaining.clone(),
+                    mainline,
+                    head: sequence_head.unwrap_or(head.id()),
+                };
+                write_sequence(repo, &sequence, commit.id(), ours.id())?;
+            }
+            println!(
+                "[PICK] Stopped at {} with {} conflict(s)",
+                short_id(repo, commit.id()),
+                conflicts.len()
+            );
+            return Ok(json!({
+                "result": "conflicts",
+                "commits": created,
+                "skipped": skipped,
+                "stoppedAt": commit.id().to_string(),
+                "remaining": remaining.iter().map(|id| id.to_string()).collect::<Vec<_>>(),
+                "conflicts": conflicts,
+            }));
+        }
+
+        let new_tree = repo.find_tree(index.write_tree_to(repo)?)?;
+        // Already in HEAD, git would stop on an empty commit here
+        if new_tree.id() == tree.id() {
+            skipped.push(commit.id().to_string());
+            continue;
+        }
+        if !no_commit {
+            let author = match kind {
+                PickKind::CherryPick => commit.author(),
+                PickKind::Revert => committer.clone(),
+            };
+            let id = repo.commit(None, &author, &committer, &message, &new_tree, &[&ours])?;
+            ours = repo.find_commit(id)?;
+            created.push(id.to_string());
+        }
+        tree = new_tree;
+    }
+
+    if no_commit {
+        repo.checkout_tree(tree.as_object(), Some(CheckoutBuilder::new().safe()))?;
+    } else if let Some(last) = commits.last() {
+        move_head(repo, &head, &ours, kind, last)?;
+    }
+    println!(
+        "[PICK] Applied {} commit(s), skipped {}",
+        commits.len() - skipped.len(),
+        skipped.len()
+    );
+    let result = if no_commit { "applied" } else { "committed" };
+    Ok(json!({
+        "result": result,
+        "commits": created,
+        "skipped": skipped,
+        "head": ours.id().to_string(),
+    }))
+}
+
+/// The trees to merge: the change a commit made relative to its `mainline`
+/// parent, backwards for a revert.
+fn sides<'r>(
+    repo: &'r Repository,
+    kind: PickKind,
+    commit: &Commit<'r>,
+    mainline: Option<u32>,
+) -> GitResult<(Tree<'r>, Tree<'r>)> {
+    let parent = match (commit.parent_count(), mainline) {
+        (0, _) => None,
+        (1, None) => Some(commit.parent(0)?),
+        (1, Some(_)) => {
+            return Err(GitError::invalid(format!(
+                "Mainline was specified but commit {} is not a merge.",
+                short_id(repo, commit.id())
+            )))
+        }
+        (_, None) => {
+            return Err(GitError::invalid(format!(
+                "Commit {} is a merge but no mainline was given.",
+                short_id(repo, commit.id())
+            )))
+        }
+        (count, Some(n)) => {
+            if n == 0 || n as usize > count {
+                return Err(GitError::invalid(format!(
+                    "Commit {} does not have parent {}",
+                    short_id(repo, commit.id()),
+                    n
+                )));
+            }
+            Some(commit.parent(n as usize - 1)?)
+        }
+    };
+    let parent_tree = match parent {
+        Some(parent) => parent.tree()?,
+        None => repo.find_tree(repo.treebuilder(None)?.write()?)?,
+    };
+    Ok(match kind {
+        PickKind::CherryPick => (parent_tree, commit.tree()?),
+        PickKind::Revert => (commit.tree()?, parent_tree),
+    })
+}
+
+fn message(kind: PickKind, commit: &Commit, mainline: Option<u32>) -> GitResult<String> {
+    Ok(match kind {
+        PickKind::CherryPick => commit.message().unwrap_or("").to_string(),
+        PickKind::Revert => {
+            let mut message = format!(
+                "Revert \"{}\"\n\nThis reverts commit {}",
+                commit.summary().unwrap_or(""),
+                commit.id()
+            );
+            if let Some(n) = mainline {
+                let parent = commit.parent_id(n as usize - 1)?;
+                message.push_str(&format!(", reversing\nchanges made to {}", parent));
+            }
+            message.push_str(".\n");
+            message
+        }
+    })
+}
+
+/// Checks out the commits created so far and moves the branch to them.
+fn move_head(
+    repo: &Repository,
+    head: &Commit,
+    ours: &Commit,
+    kind: PickKind,
+    last: &Commit,
+) -> GitResult<()> {
+    if ours.id() == head.id() {
+        return Ok(());
+    }
+    repo.checkout_tree(ours.as_object(), Some(CheckoutBuilder::new().safe()))?;
+    let reflog = format!(
+        "{}: {}",
+        match kind {
+            PickKind::CherryPick => "cherry-pick",
+            PickKind::Revert => "revert",
+        },
+        last.summary().unwrap_or("")
+    );
+    match current_branch(repo) {
+        Some(branch) => {
+            repo.reference(&format!("refs/heads/{}", branch), ours.id(), true, &reflog)?;
+        }
+        None => repo.set_head_detached(ours.id())?,
+    }
+    Ok(())
+}
+
+fn write_state(
+    repo: &Repository,
+    kind: PickKind,
+    commit: &Commit,
+    message: &str,
+    conflicts: &[String],
+    no_commit: bool,
+) -> GitResult<()> {
+    let mut merge_msg = message.trim_end().to_string();
+    merge_msg.push_str("\n\n# Conflicts:\n");
+    for path in conflicts {
+        merge_msg.push_str(&format!("#\t{}\n", path));
+    }
+    write(repo, "MERGE_MSG", &merge_msg)?;
+    // Without a commit to continue to, git only leaves the message behind
+    if !no_commit {
+        write(repo, kind.head_file(), &format!("{}\n", commit.id()))?;
+    }
+    Ok(())
+}
+
+fn write_sequence(
+    repo: &Repository,
+    sequence: &Sequence,
+    stopped: Oid,
+    current: Oid,
+) -> GitResult<()> {
+    fs::create_dir_all(repo.path().join("sequencer"))
+        .map_err(|e| GitError::other(e.to_string()))?;
+    let mut todo = String::new();
+    for id in std::iter::once(&stopped).chain(&sequence.commits) {
+        let summary = repo
+            .find_commit(*id)
+            .map(|c| c.summary().unwrap_or("").to_string())
+            .unwrap_or_default();
+        todo.push_str(&format!("{} {} {}\n", sequence.kind.verb(), id, summary));
+    }
+    write(repo, "sequencer/todo", &todo)?;
+    write(repo, "sequencer/head", &format!("{}\n", sequence.head))?;
+    write(repo, "sequencer/abort-safety", &format!("{}\n", current))?;
+    if let Some(mainline) = sequence.mainline {
+        write(
+            repo,
+            "sequencer/opts",
+            &format!("[options]\n\tmainline = {}\n", mainline),
+        )?;
+    }
+    Ok(())
+}
+
+fn write(repo: &Repository, name: &str, contents: &str) -> GitResult<()> {
+    fs::write(repo.path().join(name), contents).map_err(|e| GitError::other(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git::operation;
+    use crate::git::testing::TestRepo;
+    use std::path::Path;
+
+    /// `topic` adds a.txt, changes c.txt and adds d.txt; main changes c.txt.
+    fn topic() -> (TestRepo, Vec<String>) {
+        let t = TestRepo::new();
+        t.write("c.txt", "base\n");
+        let base = t.commit("base");
+        t.repo
+            .branch("topic", &t.repo.find_commit(base).unwrap(), false)
+            .unwrap();
+        t.write("c.txt", "main\n");
+        t.commit("main");
+        t.switch("topic");
+        let mut commits = Vec::new();
+        t.write("a.txt", "a\n");
+        commits.push(t.commit("add a").to_string());
+        t.write("c.txt", "topic\n");
+        commits.push(t.commit("change c").to_string());
+        t.write("d.txt", "d\n");
+        commits.push(t.commit("add d").to_string());
+        t.switch("main");
+        (t, commits)
+    }
+
+    fn summary(t: &TestRepo) -> String {
+        let head = t.repo.head().unwrap().peel_to_commit().unwrap();
+        head.summary().unwrap().to_string()
+    }
+
+    #[test]
+    fn picks_a_commit() {
+        let (t, commits) = topic();
+        let result = cherry_pick(&t.repo, &commits[..1], None, false).unwrap();
+        assert_eq!(result["result"], "committed");
+        assert_eq!(summary(&t), "add a");
+        assert_eq!(t.read("a.txt"), "a\n");
+
+        let result = cherry_pick(&t.repo, &commits[..1], None, false).unwrap();
+        assert_eq!(result["skipped"], json!([commits[0]]));
+    }
+
+    #[test]
+    fn no_commit_only_applies() {
+        let (t, commits) = topic();
+        let head = t.head();
+        let result = cherry_pick(&t.repo, &commits[2..], None, true).unwrap();
+        assert_eq!(result["result"], "applied");
+        assert_eq!(t.head(), head);
+        assert_eq!(t.read("d.txt"), "d\n");
+    }
+
+    #[test]
+    fn refuses_staged_changes() {
+        let (t, commits) = topic();
+        t.write("e.txt", "e\n");
+        let mut index = t.index();
+        index.add_path(Path::new("e.txt")).unwrap();
+        index.write().unwrap();
+        let err = cherry_pick(&t.repo, &commits, None, false).unwrap_err();
+        assert_eq!(err.kind, GitErrorKind::DirtyWorkingTree);
+    }
+
+    #[test]
+    fn reverts_with_message() {
+        let t = TestRepo::new();
+        t.write("a.txt", "a\n");
+        t.commit("add a");
+        t.write("a.txt", "b\n");
+        let change = t.commit("change a");
+        revert(&t.repo, &change.to_string(), None, false).unwrap();
+        assert_eq!(t.read("a.txt"), "a\n");
+        let head = t.repo.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(
+            head.message(),
+            Some(format!("Revert \"change a\"\n\nThis reverts commit {}.\n", change).as_str())
+        );
+    }
+
+    #[test]
+    fn merges_need_a_mainline() {
+        let t = TestRepo::new();
+        t.conflict("c.txt", "base\n", "ours\n", "theirs\n");
+        t.write("c.txt", "both\n");
+        let mut index = t.index();
+        index.add_path(Path::new("c.txt")).unwrap();
+        index.write().unwrap();
+        operation::continue_operation(&t.repo).unwrap();
+        let merge = t.head().to_string();
+
+        let err = revert(&t.repo, &merge, None, false).unwrap_err();
+        assert_eq!(err.kind, GitErrorKind::InvalidRequest);
+        let err = revert(&t.repo, &merge, Some(3), false).unwrap_err();
+        assert_eq!(err.kind, GitErrorKind::InvalidRequest);
+        revert(&t.repo, &merge, Some(1), false).unwrap();
+        assert_eq!(t.read("c.txt"), "ours\n");
+    }
+
+    #[test]
+    fn sequence_stops_and_resumes() {
+        let (t, commits) = topic();
+        let result = cherry_pick(&t.repo, &commits, None, false).unwrap();
+        assert_eq!(result["result"], "conflicts");
+        assert_eq!(result["stoppedAt"], commits[1]);
+        assert_eq!(result["remaining"], json!([commits[2]]));
+        assert_eq!(summary(&t), "add a");
+        let sequence = pending(&t.repo).unwrap();
+        assert_eq!(sequence.commits, vec![Oid::from_str(&commits[2]).unwrap()]);
+
+        t.write("c.txt", "both\n");
+        let mut index = t.index();
+        index.add_path(Path::new("c.txt")).unwrap();
+        index.write().unwrap();
+        operation::continue_operation(&t.repo).unwrap();
+        assert_eq!(t.repo.state(), RepositoryState::Clean);
+        assert_eq!(summary(&t), "add d");
+        assert!(pending(&t.repo).is_none());
+    }
+
+    #[test]
+    fn abort_goes_back_before_the_sequence() {
+        let (t, commits) = topic();
+        let head = t.head();
+        cherry_pick(&t.repo, &commits, None, false).unwrap();
+        operation::abort(&t.repo).unwrap();
+        assert_eq!(t.head(), head);
+        assert_eq!(t.repo.state(), RepositoryState::Clean);
+        assert!(!t.path().join("a.txt").exists());
+    }
+}
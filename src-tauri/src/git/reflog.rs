@@ -0,0 +1,107 @@
+use super::log::Person;
+use super::{short_id, GitError, GitErrorKind, GitResult};
+use git2::Repository;
+use serde_json::{json, Value};
+
+/// `git reflog show <reference>`: where HEAD or a branch pointed over
+/// time, newest first, with the operation that moved it.
+pub fn list(repo: &Repository, reference: Option<&str>, limit: usize) -> GitResult<Value> {
+    let name = match reference {
+        None | Some("HEAD") => "HEAD".to_string(),
+        Some(reference) => repo
+            .resolve_reference_from_short_name(reference)
+            .map_err(|_| {
+                GitError::new(
+                    GitErrorKind::NotFound,
+                    format!("unknown reference '{}'", reference),
+                )
+            })?
+            .name()
+            .unwrap_or(reference)
+            .to_string(),
+    };
+    let shorthand = ["refs/heads/", "refs/remotes/", "refs/tags/", "refs/"]
+        .iter()
+        .find_map(|prefix| name.strip_prefix(prefix))
+        .unwrap_or(&name);
+
+    let reflog = repo.reflog(&name)?;
+    let mut entries = Vec::new();
+    for (index, entry) in reflog.iter().enumerate().take(limit) {
+        let message = entry.message().unwrap_or("").to_string();
+        let old = entry.id_old();
+        let new = entry.id_new();
+        entries.push(json!({
+            "index": index,
+            "selector": format!("{}@{{{}}}", shorthand, index),
+            "old": (!old.is_zero()).then(|| old.to_string()),
+            "new": (!new.is_zero()).then(|| new.to_string()),
+            "shortNew": (!new.is_zero()).then(|| short_id(repo, new)),
+            "operation": operation(&message),
+            "message": message,
+            "committer": Person::from(entry.committer()),
+        }));
+    }
+    Ok(json!({
+        "reference": name,
+        "entries": entries,
+        "total": reflog.len(),
+    }))
+}
+
+/// The command that wrote the entry, `commit` for `commit (amend): …`,
+/// `merge` for `merge feature: Fast-forward`.
+fn operation(message: &str) -> &str {
+    message
+        .split(':')
+        .next()
+        .and_then(|head| head.split_whitespace().next())
+        .unwrap_or("")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git::testing::TestRepo;
+
+    #[test]
+    fn lists_head_and_branch_entries() {
+        let t = TestRepo::new();
+        t.write("a.txt", "a\n");
+        let first = t.commit("first");
+        t.write("a.txt", "b\n");
+        let second = t.commit("second");
+
+        let head = list(&t.repo, None, 10).unwrap();
+        assert_eq!(head["reference"], "HEAD");
+        assert_eq!(head["total"], 2);
+        let entries = head["entries"].as_array().unwrap();
+        assert_eq!(entries[0]["selector"], "HEAD@{0}");
+        assert_eq!(entries[0]["new"], second.to_string());
+        assert_eq!(entries[0]["old"], first.to_string());
+        assert_eq!(entries[1]["old"], Value::Null);
+
+        let main = list(&t.repo, Some("main"), 1).unwrap();
+        assert_eq!(main["reference"], "refs/heads/main");
+        assert_eq!(main["entries"].as_array().unwrap().len(), 1);
+        assert_eq!(main["entries"][0]["selector"], "main@{0}");
+        assert_eq!(main["total"], 2);
+    }
+
+    #[test]
+    fn unknown_references() {
+        let t = TestRepo::new();
+        t.write("a.txt", "a\n");
+        t.commit("first");
+        let err = list(&t.repo, Some("nope"), 10).unwrap_err();
+        assert_eq!(err.kind, GitErrorKind::NotFound);
+    }
+
+    #[test]
+    fn operations() {
+        assert_eq!(operation("commit (amend): fix"), "commit");
+        assert_eq!(operation("merge feature: Fast-forward"), "merge");
+        assert_eq!(operation("checkout: moving from main to x"), "checkout");
+        assert_eq!(operation(""), "");
+    }
+}
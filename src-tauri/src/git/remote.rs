@@ -0,0 +1,675 @@
+use super::branch::{merge_commit, MergeOutcome};
+use super::{
+    credentials, current_branch, head_commit, job, output, short_id, submodule, GitError,
+    GitErrorKind, GitResult,
+};
+use git2::{
+    build::{CheckoutBuilder, RepoBuilder},
+    BranchType, Cred, CredentialType, ErrorCode, FetchOptions, FetchPrune, PushOptions,
+    RemoteCallbacks, Repository,
+};
+use serde_json::{json, Value};
+use std::cell::RefCell;
+use std::path::Path;
+
+/// Credentials the same way the git CLI would find them: the configured
+/// credential helper for https and the ssh agent for ssh remotes. A token
+/// the app signed in with is tried first for its host.
+pub(crate) fn callbacks<'a>(repo: Option<&Repository>) -> RemoteCallbacks<'a> {
+    let config = match repo {
+        Some(r) => r.config(),
+        None => git2::Config::open_default(),
+    }
+    .ok();
+    let attempts = RefCell::new(0);
+    let tried_token = RefCell::new(false);
+    let mut callbacks = RemoteCallbacks::new();
+    callbacks.credentials(move |url, username, allowed| {
+        // libgit2 keeps asking while the server rejects us, so give up eventually
+        *attempts.borrow_mut() += 1;
+        if *attempts.borrow() > 3 {
+            return Err(git2::Error::new(
+                git2::ErrorCode::Auth,
+                git2::ErrorClass::Callback,
+                "authentication failed",
+            ));
+        }
+        if allowed.contains(CredentialType::USERNAME) {
+            return Cred::username(username.unwrap_or("git"));
+        }
+        if allowed.contains(CredentialType::SSH_KEY) {
+            return Cred::ssh_key_from_agent(username.unwrap_or("git"));
+        }
+        if allowed.contains(CredentialType::USER_PASS_PLAINTEXT) {
+            // Once only, a rejected token falls through to the helper
+            if !tried_token.replace(true) {
+                if let Some(token) = credentials::token_for(url) {
+                    return Cred::userpass_plaintext(&token.username, &token.secret);
+                }
+            }
+            if let Some(config) = &config {
+                if let Ok(cred) = Cred::credential_helper(config, url, username) {
+                    return Ok(cred);
+                }
+            }
+        }
+        if allowed.contains(CredentialType::DEFAULT) {
+            return Cred::default();
+        }
+        Err(git2::Error::new(
+            git2::ErrorCode::Auth,
+            git2::ErrorClass::Callback,
+            "authentication required, sign in with GitHub or set up a git credential helper",
+        ))
+    });
+    if let Some(job) = job::current() {
+        let sideband = job.clone();
+        callbacks.sideband_progress(move |data| sideband.sideband(data));
+        let transfer = job.clone();
+        callbacks.transfer_progress(move |stats| transfer.transfer(&stats));
+        let push = job.clone();
+        callbacks.push_transfer_progress(move |current, total, bytes| {
+            push.push_transfer(current, total, bytes)
+        });
+        // Pushes can't be stopped mid-upload, but this is the last chance
+        // before the pack goes out
+        callbacks.push_negotiation(move |_| job.check());
+    }
+    callbacks
+}
+
+pub(crate) fn fetch_options<'a>(repo: &Repository) -> FetchOptions<'a> {
+    let mut options = FetchOptions::new();
+    options.remote_callbacks(callbacks(Some(repo)));
+    options
+}
+
+/// Fetches from a configured remote, or from a URL like `git fetch <url>`.
+pub(crate) fn fetch(repo: &Repository, remote: &str, refspecs: &[&str]) -> GitResult<()> {
+    let mut remote = match repo.find_remote(remote) {
+        Ok(remote) => remote,
+        Err(_) if !git2::Remote::is_valid_name(remote) => repo.remote_anonymous(remote)?,
+        Err(e) => return Err(e.into()),
+    };
+    remote.fetch(refspecs, Some(&mut fetch_options(repo)), None)?;
+    Ok(())
+}
+
+/// Remote-tracking branch `<remote>/<name>` if exactly one remote has it.
+pub(crate) fn find_remote_branch<'r>(
+    repo: &'r Repository,
+    name: &str,
+) -> GitResult<Option<git2::Branch<'r>>> {
+    let mut found = None;
+    for remote in repo.remotes()?.iter().flatten() {
+        if let Ok(branch) = repo.find_branch(&format!("{}/{}", remote, name), BranchType::Remote) {
+            if found.is_some() {
+                return Ok(None);
+            }
+            found = Some(branch);
+        }
+    }
+    Ok(found)
+}
+
+/// Ahead/behind of the current branch from the refs already fetched.
+/// Fetching is left to the auto-fetch scheduler, so this never blocks on
+/// the network.
+pub fn sync_status(repo: &Repository) -> GitResult<Value> {
+    let upstream = current_branch(repo)
+        .and_then(|name| repo.find_branch(&name, BranchType::Local).ok())
+        .and_then(|branch| branch.upstream().ok());
+    let upstream_name = upstream
+        .as_ref()
+        .and_then(|u| u.name().ok().flatten().map(String::from));
+    let upstream_id = upstream.and_then(|u| u.get().target());
+    let (ahead, behind) = match (head_commit(repo)?, upstream_id) {
+        (Some(head), Some(upstream_id)) => repo.graph_ahead_behind(head.id(), upstream_id)?,
+        // No upstream set, return zeros
+        _ => (0, 0),
+    };
+    Ok(json!({
+        "ahead": ahead,
+        "behind": behind,
+        "upstream": upstream_name,
+    }))
+}
+
+/// Pushes `refspecs`, turning rejected ref updates into errors.
+pub(crate) fn push_refspecs(
+    repo: &Repository,
+    remote_name: &str,
+    refspecs: &[String],
+) -> GitResult<()> {
+    let mut remote = repo.find_remote(remote_name)?;
+    let rejected = RefCell::new(None);
+    let mut callbacks = callbacks(Some(repo));
+    callbacks.push_update_reference(|refname, status| {
+        if let Some(message) = status {
+            *rejected.borrow_mut() = Some(format!("{} rejected: {}", refname, message));
+        }
+        Ok(())
+    });
+    let mut options = PushOptions::new();
+    options.remote_callbacks(callbacks);
+    remote.push(refspecs, Some(&mut options))?;
+    drop(options);
+    if let Some(message) = rejected.into_inner() {
+        let kind = if message.contains("fast-forward") || message.contains("fetch first") {
+            GitErrorKind::NonFastForward
+        } else {
+            GitErrorKind::Other
+        };
+        return Err(GitError::new(kind, message));
+    }
+    Ok(())
+}
+
+pub fn push(repo: &Repository, remote_name: &str, branch: &str) -> GitResult<Value> {
+    println!(
+        "[PUSH] Pushing to {}/{} with upstream tracking",
+        remote_name, branch
+    );
+    push_refspecs(
+        repo,
+        remote_name,
+        &[format!("refs/heads/{0}:refs/heads/{0}", branch)],
+    )?;
+
+    // Same as `push -u`
+    let upstream = format!("{}/{}", remote_name, branch);
+    match repo
+        .find_branch(branch, BranchType::Local)
+        .and_then(|mut b| b.set_upstream(Some(&upstream)))
+    {
+        Ok(()) => println!("[PUSH] Upstream set to: {}", upstream),
+        Err(_) => println!("[PUSH] Warning: Upstream not set after push"),
+    }
+    Ok(output(format!("{} -> {}", branch, upstream)))
+}
+
+pub fn pull(repo: &Repository, remote_name: &str, branch: &str) -> GitResult<Value> {
+    if current_branch(repo).is_none() {
+        return Err(GitError::invalid("You are not currently on a branch."));
+    }
+    fetch(repo, remote_name, &[branch])?;
+    let fetch_head = repo.find_reference("FETCH_HEAD")?;
+    let incoming = repo.reference_to_annotated_commit(&fetch_head)?;
+    let message = format!("Merge branch '{}' of {}", branch, remote_name);
+    let reflog = format!("pull: Fast-forward to {}/{}", remote_name, branch);
+
+    match merge_commit(repo, &incoming, &message, &reflog, false)? {
+        MergeOutcome::UpToDate => Ok(output("Already up to date.".into())),
+        MergeOutcome::FastForward(id) => {
+            let stdout = format!("Fast-forward to {}", short_id(repo, id));
+            println!("{}", stdout);
+            Ok(output(stdout))
+        }
+        // Same shape as `merge`, plus the CLI text the pull used to print
+        MergeOutcome::Conflicts(conflicts) => {
+            let mut stdout: String = conflicts
+                .iter()
+                .map(|path| format!("CONFLICT (content): Merge conflict in {}\n", path))
+                .collect();
+            stdout.push_str("Automatic merge failed; fix conflicts and then commit the result.");
+            let head = head_commit(repo)?.map(|c| c.id().to_string());
+            Ok(json!({
+                "result": "conflicts",
+                "head": head,
+                "conflicts": conflicts,
+                "stdout": stdout,
+                "stderr": "",
+            }))
+        }
+        MergeOutcome::Merged(_) => {
+            println!("{}", message);
+            Ok(output(format!(
+                "{}\nMerge made by the 'ort' strategy.",
+                message
+            )))
+        }
+    }
+}
+
+pub fn set_remote(repo: &Repository, remote_name: &str, url: &str) -> GitResult<Value> {
+    // Add remote
+    repo.remote(remote_name, url)
+        .map_err(|e| GitError::from(e).context("Git remote add failed"))?;
+
+    // 🚨 FETCH remote branches so origin/main exists
+    fetch(repo, remote_name, &[]).map_err(|e| e.context("Git fetch failed"))?;
+
+    // Set upstream only for local branches that exist on the remote
+    for branch in repo.branches(Some(BranchType::Local))? {
+        let (mut branch, _) = branch?;
+        let name = match branch.name()? {
+            Some(name) => name.to_string(),
+            None => continue,
+        };
+        let remote_branch = format!("{}/{}", remote_name, name);
+        if repo
+            .find_branch(&remote_branch, BranchType::Remote)
+            .is_err()
+        {
+            continue;
+        }
+        branch.set_upstream(Some(&remote_branch)).map_err(|e| {
+            GitError::from(e).context(&format!("Failed to set upstream for branch '{}'", name))
+        })?;
+    }
+
+    Ok(json!({
+        "status": "success",
+        "message": "Upstream set for all local branches."
+    }))
+}
+
+/// Every configured remote with its fetch and push URLs.
+pub fn remotes(repo: &Repository) -> GitResult<Vec<Value>> {
+    let mut remotes = Vec::new();
+    for name in repo.remotes()?.iter().flatten() {
+        let remote = repo.find_remote(name)?;
+        let url = remote.url().unwrap_or("");
+        remotes.push(json!({
+            "name": name,
+            "url": url,
+            // Same as the fetch URL unless `pushurl` is set
+            "pushUrl": remote.pushurl().unwrap_or(url),
+        }));
+    }
+    Ok(remotes)
+}
+
+pub fn add_remote(
+    repo: &Repository,
+    remote_name: &str,
+    url: &str,
+    fetch_now: bool,
+) -> GitResult<Value> {
+    repo.remote(remote_name, url).map_err(|e| {
+        if e.code() == ErrorCode::Exists {
+            GitError::new(
+                GitErrorKind::AlreadyExists,
+                format!("remote {} already exists.", remote_name),
+            )
+        } else {
+            e.into()
+        }
+    })?;
+    if fetch_now {
+        fetch(repo, remote_name, &[]).map_err(|e| e.context("Git fetch failed"))?;
+    }
+    Ok(json!({ "name": remote_name, "url": url }))
+}
+
+/// Renames a remote along with its tracking branches and upstream config.
+pub fn rename_remote(repo: &Repository, from: &str, to: &str) -> GitResult<Value> {
+    find(repo, from)?;
+    let problems = repo.remote_rename(from, to)?;
+    // Refspecs that weren't the default `+refs/heads/*:refs/remotes/<name>/*`
+    let problems: Vec<String> = problems.iter().flatten().map(String::from).collect();
+    for problem in &problems {
+        println!("[REMOTE] Not updated: {}", problem);
+    }
+    Ok(json!({ "from": from, "to": to, "notUpdated": problems }))
+}
+
+pub fn remove_remote(repo: &Repository, remote_name: &str) -> GitResult<Value> {
+    find(repo, remote_name)?;
+    repo.remote_delete(remote_name)?;
+    Ok(json!({ "name": remote_name }))
+}
+
+/// `git remote set-url [--push]`.
+pub fn set_url(repo: &Repository, remote_name: &str, url: &str, push: bool) -> GitResult<Value> {
+    find(repo, remote_name)?;
+    if push {
+        repo.remote_set_pushurl(remote_name, Some(url))?;
+    } else {
+        repo.remote_set_url(remote_name, url)?;
+    }
+    Ok(json!({ "name": remote_name, "url": url, "push": push }))
+}
+
+/// Fetches one remote, or all of them, optionally pruning tracking branches
+/// that are gone from the remote.
+pub fn fetch_remotes(
+    repo: &Repository,
+    remote_name: Option<&str>,
+    prune: bool,
+) -> GitResult<Value> {
+    let names: Vec<String> = match remote_name {
+        Some(name) => vec![name.to_string()],
+        None => repo.remotes()?.iter().flatten().map(String::from).collect(),
+    };
+    let mut pruned = Vec::new();
+    for name in &names {
+        let mut remote = find(repo, name)?;
+        let before = tracking_refs(repo, name)?;
+        let mut options = fetch_options(repo);
+        if prune {
+            options.prune(FetchPrune::On);
+        }
+        println!("[FETCH] Fetching {}", name);
+        remote.fetch::<&str>(&[], Some(&mut options), None)?;
+        let after = tracking_refs(repo, name)?;
+        pruned.extend(before.into_iter().filter(|r| !after.contains(r)));
+    }
+    Ok(json!({ "remotes": names, "pruned": pruned }))
+}
+
+fn tracking_refs(repo: &Repository, remote_name: &str) -> GitResult<Vec<String>> {
+    let prefix = format!("{}/", remote_name);
+    let mut names = Vec::new();
+    for branch in repo.branches(Some(BranchType::Remote))? {
+        let (branch, _) = branch?;
+        if let Some(name) = branch.name()?.filter(|n| n.starts_with(&prefix)) {
+            names.push(name.to_string());
+        }
+    }
+    Ok(names)
+}
+
+fn find<'r>(repo: &'r Repository, remote_name: &str) -> GitResult<git2::Remote<'r>> {
+    repo.find_remote(remote_name).map_err(|_| {
+        GitError::new(
+            GitErrorKind::NotFound,
+            format!("No such remote '{}'", remote_name),
+        )
+    })
+}
+
+/// With `recurse_submodules` also clones the submodules, like
+/// `git clone --recurse-submodules`.
+pub fn clone(repo_url: &str, target_dir: &str, recurse_submodules: bool) -> GitResult<bool> {
+    let mut options = FetchOptions::new();
+    options.remote_callbacks(callbacks(None));
+    let mut checkout = CheckoutBuilder::new();
+    if let Some(job) = job::current() {
+        checkout.progress(move |_, current, total| job.checkout(current, total));
+    }
+    let repo = RepoBuilder::new()
+        .fetch_options(options)
+        .with_checkout(checkout)
+        .clone(repo_url, Path::new(target_dir))
+        .map_err(|e| GitError::from(e).context("Git clone failed"))?;
+    if recurse_submodules {
+        submodule::update(&repo, &[], true, true)?;
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git::testing::TestRepo;
+    use serde_json::json;
+
+    /// A repository with one commit pushed to a bare `origin`, and a second
+    /// clone of that remote.
+    fn setup() -> (TestRepo, TestRepo, TestRepo) {
+        let origin = TestRepo::bare();
+        let local = TestRepo::new();
+        local.write("a.txt", "a\n");
+        local.commit("init");
+        local.repo.remote("origin", &origin.url()).unwrap();
+        local
+            .run("push", json!({ "remote": "origin", "branch": "main" }))
+            .unwrap();
+        let other = TestRepo::clone(&origin.url());
+        (origin, local, other)
+    }
+
+    #[test]
+    fn push_sets_upstream() {
+        let (origin, local, _other) = setup();
+        assert_eq!(
+            origin.repo.refname_to_id("refs/heads/main").unwrap(),
+            local.head()
+        );
+        let branch = local.repo.find_branch("main", BranchType::Local).unwrap();
+        assert_eq!(
+            branch.upstream().unwrap().name().unwrap(),
+            Some("origin/main")
+        );
+    }
+
+    #[test]
+    fn pull_fast_forwards_by_remote_name() {
+        let (_origin, local, other) = setup();
+        other.write("b.txt", "b\n");
+        let id = other.commit("remote change");
+        other
+            .repo
+            .find_remote("origin")
+            .unwrap()
+            .push(&["refs/heads/main"], None)
+            .unwrap();
+
+        let result = pull(&local.repo, "origin", "main").unwrap();
+        assert!(result["stdout"]
+            .as_str()
+            .unwrap()
+            .starts_with("Fast-forward"));
+        assert_eq!(local.head(), id);
+        assert_eq!(local.read("b.txt"), "b\n");
+    }
+
+    #[test]
+    fn pull_accepts_remote_url() {
+        let (origin, local, other) = setup();
+        other.write("b.txt", "b\n");
+        let id = other.commit("remote change");
+        other
+            .repo
+            .find_remote("origin")
+            .unwrap()
+            .push(&["refs/heads/main"], None)
+            .unwrap();
+
+        pull(&local.repo, &origin.url(), "main").unwrap();
+        assert_eq!(local.head(), id);
+    }
+
+    #[test]
+    fn pull_action_accepts_origin_url() {
+        let (origin, local, other) = setup();
+        other.write("b.txt", "b\n");
+        let id = other.commit("remote change");
+        other
+            .repo
+            .find_remote("origin")
+            .unwrap()
+            .push(&["refs/heads/main"], None)
+            .unwrap();
+
+        // What the Git panel sends, `status.origin` is the URL
+        local
+            .run("pull", json!({ "remote": origin.url(), "branch": "main" }))
+            .unwrap();
+        assert_eq!(local.head(), id);
+    }
+
+    #[test]
+    fn pull_merges_diverged_branches() {
+        let (_origin, local, other) = setup();
+        other.write("b.txt", "b\n");
+        other.commit("remote change");
+        other
+            .repo
+            .find_remote("origin")
+            .unwrap()
+            .push(&["refs/heads/main"], None)
+            .unwrap();
+        local.write("c.txt", "c\n");
+        local.commit("local change");
+
+        let result = pull(&local.repo, "origin", "main").unwrap();
+        assert!(result["stdout"].as_str().unwrap().contains("Merge made"));
+        assert_eq!(
+            local
+                .repo
+                .head()
+                .unwrap()
+                .peel_to_commit()
+                .unwrap()
+                .parent_count(),
+            2
+        );
+        assert_eq!(local.read("b.txt"), "b\n");
+        assert_eq!(local.read("c.txt"), "c\n");
+    }
+
+    #[test]
+    fn pull_returns_conflicts() {
+        let (_origin, local, other) = setup();
+        other.write("a.txt", "theirs\n");
+        other.commit("remote change");
+        other
+            .repo
+            .find_remote("origin")
+            .unwrap()
+            .push(&["refs/heads/main"], None)
+            .unwrap();
+        local.write("a.txt", "ours\n");
+        local.commit("local change");
+
+        let result = pull(&local.repo, "origin", "main").unwrap();
+        assert_eq!(result["result"], "conflicts");
+        assert_eq!(result["conflicts"], json!(["a.txt"]));
+        assert_eq!(result["head"], local.head().to_string());
+        assert!(local.repo.index().unwrap().has_conflicts());
+    }
+
+    #[test]
+    fn pull_needs_a_branch() {
+        let (_origin, local, _other) = setup();
+        local.repo.set_head_detached(local.head()).unwrap();
+        let err = pull(&local.repo, "origin", "main").unwrap_err();
+        assert_eq!(err.kind, GitErrorKind::InvalidRequest);
+    }
+
+    #[test]
+    fn fetch_from_unknown_remote_fails() {
+        let (_origin, local, _other) = setup();
+        let err = fetch(&local.repo, "upstream", &[]).unwrap_err();
+        assert_eq!(err.kind, GitErrorKind::NotFound);
+    }
+
+    #[test]
+    fn manages_remotes_by_name() {
+        let (origin, local, other) = setup();
+        add_remote(&local.repo, "upstream", &other.url(), true).unwrap();
+        assert!(local
+            .repo
+            .find_branch("upstream/main", BranchType::Remote)
+            .is_ok());
+        let err = add_remote(&local.repo, "upstream", &other.url(), false).unwrap_err();
+        assert_eq!(err.kind, GitErrorKind::AlreadyExists);
+
+        set_url(
+            &local.repo,
+            "upstream",
+            "https://example.com/push.git",
+            true,
+        )
+        .unwrap();
+        let listed = remotes(&local.repo).unwrap();
+        assert_eq!(
+            listed,
+            vec![
+                json!({ "name": "origin", "url": origin.url(), "pushUrl": origin.url() }),
+                json!({
+                    "name": "upstream",
+                    "url": other.url(),
+                    "pushUrl": "https://example.com/push.git",
+                }),
+            ]
+        );
+
+        rename_remote(&local.repo, "upstream", "fork").unwrap();
+        assert!(local
+            .repo
+            .find_branch("fork/main", BranchType::Remote)
+            .is_ok());
+        remove_remote(&local.repo, "fork").unwrap();
+        assert_eq!(remotes(&local.repo).unwrap().len(), 1);
+        let err = remove_remote(&local.repo, "fork").unwrap_err();
+        assert_eq!(err.kind, GitErrorKind::NotFound);
+    }
+
+    #[test]
+    fn fetch_prunes_deleted_branches() {
+        let (origin, local, _other) = setup();
+        let head = local.repo.find_commit(local.head()).unwrap();
+        local.repo.branch("topic", &head, false).unwrap();
+        push_refspecs(
+            &local.repo,
+            "origin",
+            &["refs/heads/topic:refs/heads/topic".into()],
+        )
+        .unwrap();
+        fetch_remotes(&local.repo, None, false).unwrap();
+        assert!(local
+            .repo
+            .find_branch("origin/topic", BranchType::Remote)
+            .is_ok());
+
+        origin
+            .repo
+            .find_reference("refs/heads/topic")
+            .unwrap()
+            .delete()
+            .unwrap();
+        let fetched = fetch_remotes(&local.repo, Some("origin"), false).unwrap();
+        assert_eq!(fetched["pruned"], json!([]));
+        let fetched = fetch_remotes(&local.repo, Some("origin"), true).unwrap();
+        assert_eq!(fetched["pruned"], json!(["origin/topic"]));
+    }
+
+    #[test]
+    fn clones_a_repository() {
+        let (origin, _local, _other) = setup();
+        let target = tempfile::tempdir().unwrap();
+        let path = target.path().join("clone");
+        clone(&origin.url(), path.to_str().unwrap(), false).unwrap();
+        assert_eq!(std::fs::read_to_string(path.join("a.txt")).unwrap(), "a\n");
+    }
+
+    #[test]
+    fn sync_status_reads_refs_only() {
+        let (_origin, local, other) = setup();
+        other.write("b.txt", "b\n");
+        other.commit("remote change");
+        other
+            .run("push", json!({ "remote": "origin", "branch": "main" }))
+            .unwrap();
+        local.write("c.txt", "c\n");
+        local.commit("local change");
+
+        let status = sync_status(&local.repo).unwrap();
+        assert_eq!(
+            status,
+            json!({ "ahead": 1, "behind": 0, "upstream": "origin/main" })
+        );
+        // Counts only move once the scheduler has fetched
+        fetch(&local.repo, "origin", &[]).unwrap();
+        assert_eq!(sync_status(&local.repo).unwrap()["behind"], 1);
+
+        // An unreachable remote doesn't matter, nothing is fetched
+        local
+            .repo
+            .remote_set_url("origin", "https://127.0.0.1:9/missing.git")
+            .unwrap();
+        assert_eq!(sync_status(&local.repo).unwrap()["ahead"], 1);
+    }
+
+    #[test]
+    fn sync_status_without_upstream() {
+        let t = TestRepo::new();
+        assert_eq!(
+            sync_status(&t.repo).unwrap(),
+            json!({ "ahead": 0, "behind": 0, "upstream": null })
+        );
+    }
+}
@@ -0,0 +1,597 @@
+use super::diff::file_diffs;
+use super::status::conflicted_paths;
+use super::{current_branch, short_id, GitError, GitErrorKind, GitResult};
+use git2::{
+    build::TreeUpdateBuilder, DiffOptions, ErrorCode, FileMode, IndexEntry, ObjectType, Oid,
+    Repository, Signature, StashApplyOptions, StashFlags, StashSaveOptions, StatusOptions,
+    TreeWalkMode, TreeWalkResult,
+};
+use serde_json::{json, Value};
+use std::path::Path;
+
+/// `stash@{n}` entries, newest first.
+pub fn list(repo: &mut Repository) -> GitResult<Value> {
+    let mut entries = Vec::new();
+    repo.stash_foreach(|index, message, id| {
+        entries.push((index, message.to_string(), *id));
+        true
+    })?;
+
+    let stashes: Vec<Value> = entries
+        .into_iter()
+        .map(|(index, message, id)| {
+            let time = repo
+                .find_commit(id)
+                .map(|c| c.time().seconds())
+                .unwrap_or(0);
+            json!({
+                "index": index,
+                "name": format!("stash@{{{}}}", index),
+                "hash": id.to_string(),
+                "shortHash": short_id(repo, id),
+                "message": message,
+                "branch": stash_branch(&message),
+                "date": time,
+            })
+        })
+        .collect();
+    Ok(stashes.into())
+}
+
+/// Branch from `WIP on main: …` or `On main: …`, none for a detached HEAD.
+fn stash_branch(message: &str) -> Option<&str> {
+    let rest = message
+        .strip_prefix("WIP on ")
+        .or_else(|| message.strip_prefix("On "))?;
+    let branch = rest.split(':').next()?;
+    (branch != "(no branch)").then_some(branch)
+}
+
+pub fn push(
+    repo: &mut Repository,
+    message: Option<&str>,
+    include_untracked: bool,
+    keep_index: bool,
+    paths: &[String],
+) -> GitResult<Value> {
+    let signature = repo.signature()?;
+    let mut flags = StashFlags::DEFAULT;
+    if include_untracked {
+        flags |= StashFlags::INCLUDE_UNTRACKED;
+    }
+    if keep_index {
+        flags |= StashFlags::KEEP_INDEX;
+    }
+    let nothing_to_save = |e: git2::Error| {
+        if e.code() == ErrorCode::NotFound {
+            GitError::new(GitErrorKind::NothingToCommit, "No local changes to save")
+        } else {
+            e.into()
+        }
+    };
+    let id = if paths.is_empty() {
+        repo.stash_save2(&signature, message, Some(flags))
+            .map_err(nothing_to_save)?
+    } else {
+        let others = Snapshot::outside(repo, paths, include_untracked)?;
+        let backup = others.save(repo, &signature)?;
+        let mut options = StashSaveOptions::new(signature.clone());
+        options.flags(Some(flags));
+        for path in paths {
+            options.pathspec(path);
+        }
+        let id = match (repo.stash_save_ext(Some(&mut options)), backup) {
+            (Ok(id), Some(_)) => {
+                let kept = "Could not put back the changes outside the stashed paths, \
+                            they are kept in stash@{1}";
+                others.restore(repo).map_err(|e| e.context(kept))?;
+                repo.stash_drop(1)?;
+                id
+            }
+            (Ok(id), None) => id,
+            // Nothing was stashed and nothing reset, the backup isn't needed
+            (Err(e), Some(_)) if e.code() == ErrorCode::NotFound => {
+                repo.stash_drop(0)?;
+                return Err(nothing_to_save(e));
+            }
+            (Err(e), _) => return Err(nothing_to_save(e)),
+        };
+        match limit_untracked(repo, id, paths, &signature)? {
+            Some(id) => id,
+            None => {
+                repo.stash_drop(0)?;
+                return Err(GitError::new(
+                    GitErrorKind::NothingToCommit,
+                    "No local changes to save",
+                ));
+            }
+        }
+    };
+
+    let mut entry = repo
+        .find_commit(id)?
+        .message()
+        .unwrap_or("")
+        .trim_end()
+        .to_string();
+    // git2 can't take a message together with paths, so it goes into the
+    // reflog entry that the stash list reads instead
+    if let (Some(message), false) = (message, paths.is_empty()) {
+        entry = format!(
+            "On {}: {}",
+            current_branch(repo).unwrap_or_else(|| "(no branch)".into()),
+            message
+        );
+        let mut reflog = repo.reflog("refs/stash")?;
+        reflog.remove(0, false)?;
+        reflog.append(id, &signature, Some(&entry))?;
+        reflog.write()?;
+    }
+    println!("[STASH] Saved {}", entry);
+    Ok(json!({
+        "index": 0,
+        "hash": id.to_string(),
+        "message": entry,
+    }))
+}
+
+fn selected(paths: &[String], path: &str) -> bool {
+    paths.iter().any(|p| {
+        let p = p.trim_end_matches('/');
+        path == p || path.starts_with(&format!("{}/", p))
+    })
+}
+
+/// libgit2 puts every untracked file into a path-limited stash, which would
+/// clash with the restored copies on pop. Rewrites the top entry so its
+/// untracked parent only has files under `paths`. `None` when that leaves
+/// the entry without changes.
+fn limit_untracked(
+    repo: &Repository,
+    id: Oid,
+    paths: &[String],
+    signature: &Signature,
+) -> GitResult<Option<Oid>> {
+    let stash = repo.find_commit(id)?;
+    let Ok(untracked) = stash.parent(2) else {
+        return Ok(Some(id));
+    };
+    let tree = untracked.tree()?;
+    let mut update = TreeUpdateBuilder::new();
+    let (mut kept, mut removed) = (0, 0);
+    tree.walk(TreeWalkMode::PreOrder, |dir, entry| {
+        if entry.kind() == Some(ObjectType::Tree) {
+            return TreeWalkResult::Ok;
+        }
+        let path = format!("{}{}", dir, entry.name().unwrap_or(""));
+        if selected(paths, &path) {
+            kept += 1;
+        } else {
+            update.remove(&path);
+            removed += 1;
+        }
+        TreeWalkResult::Ok
+    })?;
+    if removed == 0 {
+        return Ok(Some(id));
+    }
+
+    let mut parents = vec![stash.parent(0)?, stash.parent(1)?];
+    if kept > 0 {
+        let tree = repo.find_tree(update.create_updated(repo, &tree)?)?;
+        let message = untracked.message().unwrap_or("");
+        let id = repo.commit(None, signature, signature, message, &tree, &[])?;
+        parents.push(repo.find_commit(id)?);
+    } else if stash.tree_id() == parents[0].tree_id()
+        && parents[1].tree_id() == parents[0].tree_id()
+    {
+        return Ok(None);
+    }
+    let parents: Vec<&git2::Commit> = parents.iter().collect();
+    let message = stash.message().unwrap_or("");
+    let rewritten = repo.commit(
+        None,
+        signature,
+        signature,
+        message,
+        &stash.tree()?,
+        &parents,
+    )?;
+    // Swap the entry in place so the stash list doesn't show both
+    let reflog_message = repo
+        .reflog("refs/stash")?
+        .get(0)
+        .and_then(|entry| entry.message().map(String::from))
+        .unwrap_or_else(|| message.to_string());
+    repo.reference("refs/stash", rewritten, true, &reflog_message)?;
+    let mut reflog = repo.reflog("refs/stash")?;
+    reflog.remove(1, false)?;
+    reflog.write()?;
+    Ok(Some(rewritten))
+}
+
+/// Index and worktree state of changed files outside a path-limited stash.
+/// libgit2 resets the whole working tree after stashing only some paths,
+/// so everything else is saved as a stash entry of its own first and put
+/// back afterwards.
+struct Snapshot {
+    files: Vec<(String, Option<IndexEntry>, Option<Vec<u8>>)>,
+}
+
+impl Snapshot {
+    fn outside(repo: &Repository, paths: &[String], include_untracked: bool) -> GitResult<Self> {
+        let workdir = repo
+            .workdir()
+            .ok_or_else(|| GitError::invalid("cannot stash in a bare repository"))?;
+        let mut options = StatusOptions::new();
+        options
+            .include_untracked(include_untracked)
+            .recurse_untracked_dirs(true)
+            .exclude_submodules(true);
+        let index = repo.index()?;
+        let mut files = Vec::new();
+        for entry in repo.statuses(Some(&mut options))?.iter() {
+            let path = match entry.path() {
+                Some(path) => path.to_string(),
+                None => continue,
+            };
+            if selected(paths, &path) || entry.status().is_ignored() {
+                continue;
+            }
+            let contents = std::fs::read(workdir.join(&path)).ok();
+            files.push((path.clone(), index.get_path(Path::new(&path), 0), contents));
+        }
+        Ok(Snapshot { files })
+    }
+
+    /// Pushes the files as a stash entry without touching the working tree,
+    /// so they can still be applied if `restore` fails halfway. `None` when
+    /// there is nothing outside the paths.
+    fn save(&self, repo: &Repository, signature: &Signature) -> GitResult<Option<Oid>> {
+        if self.files.is_empty() {
+            return Ok(None);
+        }
+        let head = repo.head()?.peel_to_commit()?;
+        let head_tree = head.tree()?;
+        let mut staged = TreeUpdateBuilder::new();
+        let mut worktree = TreeUpdateBuilder::new();
+        let mut untracked = TreeUpdateBuilder::new();
+        let mut has_untracked = false;
+        for (path, entry, contents) in &self.files {
+            match entry {
+                Some(entry) => {
+                    staged.upsert(path, entry.id, file_mode(entry.mode));
+                }
+                None if head_tree.get_path(Path::new(path)).is_ok() => {
+                    staged.remove(path);
+                }
+                None => {}
+            }
+            match (entry, contents) {
+                (Some(entry), Some(contents)) => {
+                    worktree.upsert(path, repo.blob(contents)?, file_mode(entry.mode));
+                }
+                (Some(_), None) => {
+                    worktree.remove(path);
+                }
+                (None, Some(contents)) => {
+                    untracked.upsert(path, repo.blob(contents)?, FileMode::Blob);
+                    has_untracked = true;
+                }
+                (None, None) => {}
+            }
+        }
+
+        let branch = current_branch(repo).unwrap_or_else(|| "(no branch)".into());
+        let staged_tree = repo.find_tree(staged.create_updated(repo, &head_tree)?)?;
+        let index_commit = repo.commit(
+            None,
+            signature,
+            signature,
+            &format!("index on {}: {}", branch, short_id(repo, head.id())),
+            &staged_tree,
+            &[&head],
+        )?;
+        let mut parents = vec![head.clone(), repo.find_commit(index_commit)?];
+        if has_untracked {
+            let empty = repo.find_tree(repo.treebuilder(None)?.write()?)?;
+            let tree = repo.find_tree(untracked.create_updated(repo, &empty)?)?;
+            let id = repo.commit(
+                None,
+                signature,
+                signature,
+                &format!(
+                    "untracked files on {}: {}",
+                    branch,
+                    short_id(repo, head.id())
+                ),
+                &tree,
+                &[],
+            )?;
+            parents.push(repo.find_commit(id)?);
+        }
+        let message = format!("On {}: changes outside the stashed paths", branch);
+        let tree = repo.find_tree(worktree.create_updated(repo, &staged_tree)?)?;
+        let parents: Vec<&git2::Commit> = parents.iter().collect();
+        let id = repo.commit(None, signature, signature, &message, &tree, &parents)?;
+        repo.reference_ensure_log("refs/stash")?;
+        repo.reference("refs/stash", id, true, &message)?;
+        Ok(Some(id))
+    }
+
+    fn restore(self, repo: &Repository) -> GitResult<()> {
+        let workdir = repo
+            .workdir()
+            .ok_or_else(|| GitError::invalid("cannot stash in a bare repository"))?;
+        let mut index = repo.index()?;
+        for (path, entry, contents) in self.files {
+            match entry {
+                Some(entry) => index.add(&entry)?,
+                None => {
+                    let _ = index.remove_path(Path::new(&path));
+                }
+            }
+            let full_path = workdir.join(&path);
+            let written = match contents {
+                // Untracked directories are removed along with their files
+                Some(contents) => full_path
+                    .parent()
+                    .map_or(Ok(()), std::fs::create_dir_all)
+                    .and_then(|_| std::fs::write(&full_path, contents)),
+                None if full_path.exists() => std::fs::remove_file(&full_path),
+                None => Ok(()),
+            };
+            written.map_err(|e| GitError::other(e.to_string()))?;
+        }
+        index.write()?;
+        Ok(())
+    }
+}
+
+fn file_mode(mode: u32) -> FileMode {
+    match mode {
+        0o100755 => FileMode::BlobExecutable,
+        0o120000 => FileMode::Link,
+        _ => FileMode::Blob,
+    }
+}
+
+/// Applies a stash. Conflicting paths are reported instead of failing so
+/// they can go through the conflict resolution flow.
+pub fn apply(repo: &mut Repository, index: usize, reinstate_index: bool) -> GitResult<Value> {
+    let mut options = StashApplyOptions::new();
+    if reinstate_index {
+        options.reinstantiate_index();
+    }
+    match repo.stash_apply(index, Some(&mut options)) {
+        Ok(()) => {}
+        Err(e) if e.code() == ErrorCode::MergeConflict => {}
+        Err(e) => return Err(e.into()),
+    }
+    let conflicts = conflicted_paths(repo)?;
+    if !conflicts.is_empty() {
+        println!("[STASH] Applied stash@{{{}}} with conflicts", index);
+    }
+    Ok(json!({
+        "index": index,
+        "conflicts": conflicts,
+    }))
+}
+
+/// Like `git stash pop`: the entry is only dropped when it applied cleanly.
+pub fn pop(repo: &mut Repository, index: usize, reinstate_index: bool) -> GitResult<Value> {
+    let id = stash_id(repo, index)?;
+    let mut result = apply(repo, index, reinstate_index)?;
+    let clean = result["conflicts"].as_array().is_some_and(|c| c.is_empty());
+    if clean {
+        repo.stash_drop(index)?;
+    }
+    result["dropped"] = json!(clean.then(|| id.to_string()));
+    Ok(result)
+}
+
+pub fn drop(repo: &mut Repository, index: usize) -> GitResult<Value> {
+    let id = stash_id(repo, index)?;
+    repo.stash_drop(index)?;
+    Ok(json!({
+        "index": index,
+        "dropped": id.to_string(),
+    }))
+}
+
+/// The changes a stash entry holds, untracked files included, in the same
+/// shape as `git_diff`.
+pub fn show(repo: &mut Repository, index: usize, context_lines: u32) -> GitResult<Value> {
+    let id = stash_id(repo, index)?;
+    let stash = repo.find_commit(id)?;
+    let base = stash.parent(0)?.tree()?;
+    let mut options = DiffOptions::new();
+    options.context_lines(context_lines);
+    let diff = repo.diff_tree_to_tree(Some(&base), Some(&stash.tree()?), Some(&mut options))?;
+    let mut files = file_diffs(&diff)?;
+
+    // Untracked files are kept in a third parent
+    if let Ok(untracked) = stash.parent(2) {
+        let diff = repo.diff_tree_to_tree(None, Some(&untracked.tree()?), Some(&mut options))?;
+        for mut file in file_diffs(&diff)? {
+            file.status = "untracked";
+            files.push(file);
+        }
+    }
+    serde_json::to_value(files).map_err(|e| GitError::other(e.to_string()))
+}
+
+fn stash_id(repo: &mut Repository, index: usize) -> GitResult<Oid> {
+    let mut found = None;
+    repo.stash_foreach(|i, _, id| {
+        if i == index {
+            found = Some(*id);
+        }
+        found.is_none()
+    })?;
+    found.ok_or_else(|| {
+        GitError::new(
+            GitErrorKind::NotFound,
+            format!("stash@{{{}}} does not exist", index),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git::testing::TestRepo;
+    use serde_json::json;
+
+    fn messages(t: &TestRepo) -> Vec<String> {
+        let mut repo = Repository::open(t.path()).unwrap();
+        let list = list(&mut repo).unwrap();
+        list.as_array()
+            .unwrap()
+            .iter()
+            .map(|s| s["message"].as_str().unwrap().to_string())
+            .collect()
+    }
+
+    fn setup() -> TestRepo {
+        let t = TestRepo::new();
+        t.write("a.txt", "a\n");
+        t.write("b.txt", "b\n");
+        t.commit("init");
+        t
+    }
+
+    #[test]
+    fn reads_branch_from_message() {
+        assert_eq!(stash_branch("WIP on main: abc init"), Some("main"));
+        assert_eq!(stash_branch("On feature/x: message"), Some("feature/x"));
+        assert_eq!(stash_branch("WIP on (no branch): abc init"), None);
+        assert_eq!(stash_branch("something else"), None);
+    }
+
+    #[test]
+    fn push_and_pop() {
+        let t = setup();
+        t.write("a.txt", "changed\n");
+        let pushed = t.run("stash-push", json!({ "message": "wip" })).unwrap();
+        assert_eq!(pushed["message"], "On main: wip");
+        assert_eq!(t.read("a.txt"), "a\n");
+        assert_eq!(messages(&t), ["On main: wip"]);
+
+        let popped = t.run("stash-pop", json!({ "index": 0 })).unwrap();
+        assert_eq!(popped["dropped"], pushed["hash"]);
+        assert_eq!(t.read("a.txt"), "changed\n");
+        assert!(messages(&t).is_empty());
+    }
+
+    #[test]
+    fn nothing_to_stash() {
+        let t = setup();
+        let err = t.run("stash-push", json!({})).unwrap_err();
+        assert_eq!(err.kind, GitErrorKind::NothingToCommit);
+        let err = t
+            .run("stash-push", json!({ "paths": ["a.txt"] }))
+            .unwrap_err();
+        assert_eq!(err.kind, GitErrorKind::NothingToCommit);
+        assert!(messages(&t).is_empty());
+    }
+
+    #[test]
+    fn path_limited_push_keeps_other_changes() {
+        let t = setup();
+        t.write("a.txt", "stash me\n");
+        t.write("b.txt", "staged\n");
+        t.run("stage", json!({ "file": "b.txt" })).unwrap();
+        t.write("b.txt", "staged and changed\n");
+        t.write("new/c.txt", "untracked\n");
+
+        let pushed = t
+            .run(
+                "stash-push",
+                json!({ "paths": ["a.txt"], "message": "only a", "includeUntracked": true }),
+            )
+            .unwrap();
+        assert_eq!(pushed["message"], "On main: only a");
+        assert_eq!(t.read("a.txt"), "a\n");
+        assert_eq!(t.read("b.txt"), "staged and changed\n");
+        assert_eq!(t.read("new/c.txt"), "untracked\n");
+        let index = t.index();
+        let staged = index.get_path(Path::new("b.txt"), 0).unwrap();
+        assert_eq!(t.repo.find_blob(staged.id).unwrap().content(), b"staged\n");
+        // The backup of the other changes is gone again
+        assert_eq!(messages(&t), ["On main: only a"]);
+
+        let mut repo = Repository::open(t.path()).unwrap();
+        let shown = show(&mut repo, 0, 3).unwrap();
+        assert_eq!(shown.as_array().unwrap().len(), 1);
+        assert_eq!(shown[0]["newPath"], "a.txt");
+
+        // Only `a.txt` comes back, the other files are left alone. libgit2
+        // won't apply onto staged changes
+        t.run("unstage-all", json!({})).unwrap();
+        t.run("stash-pop", json!({ "index": 0 })).unwrap();
+        assert_eq!(t.read("a.txt"), "stash me\n");
+        assert_eq!(t.read("new/c.txt"), "untracked\n");
+        assert!(messages(&t).is_empty());
+    }
+
+    #[test]
+    fn untracked_outside_paths_is_nothing_to_stash() {
+        let t = setup();
+        t.write("new.txt", "untracked\n");
+        let err = t
+            .run(
+                "stash-push",
+                json!({ "paths": ["a.txt"], "includeUntracked": true }),
+            )
+            .unwrap_err();
+        assert_eq!(err.kind, GitErrorKind::NothingToCommit);
+        assert_eq!(t.read("new.txt"), "untracked\n");
+        assert!(messages(&t).is_empty());
+    }
+
+    #[test]
+    fn backup_holds_changes_outside_paths() {
+        let t = setup();
+        t.write("a.txt", "stash me\n");
+        t.write("b.txt", "keep me\n");
+        t.write("new.txt", "untracked\n");
+        let mut repo = Repository::open(t.path()).unwrap();
+        let paths = ["a.txt".to_string()];
+        let others = Snapshot::outside(&repo, &paths, true).unwrap();
+        others
+            .save(&repo, &crate::git::testing::signature())
+            .unwrap();
+
+        // A failed restore leaves this entry to apply by hand
+        assert_eq!(messages(&t), ["On main: changes outside the stashed paths"]);
+        t.write("b.txt", "b\n");
+        t.remove("new.txt");
+        apply(&mut repo, 0, true).unwrap();
+        assert_eq!(t.read("b.txt"), "keep me\n");
+        assert_eq!(t.read("new.txt"), "untracked\n");
+        assert_eq!(t.read("a.txt"), "stash me\n");
+    }
+
+    #[test]
+    fn apply_reports_conflicts() {
+        let t = setup();
+        t.write("a.txt", "stashed\n");
+        t.run("stash-push", json!({})).unwrap();
+        t.write("a.txt", "committed\n");
+        t.commit("change a");
+
+        let result = t.run("stash-pop", json!({ "index": 0 })).unwrap();
+        assert_eq!(result["conflicts"], json!(["a.txt"]));
+        // Like `git stash pop`, a conflicting entry is kept
+        assert!(result["dropped"].is_null());
+        assert_eq!(messages(&t).len(), 1);
+    }
+
+    #[test]
+    fn unknown_index() {
+        let t = setup();
+        let err = t.run("stash-drop", json!({ "index": 3 })).unwrap_err();
+        assert_eq!(err.kind, GitErrorKind::NotFound);
+    }
+}
@@ -0,0 +1,465 @@
+use super::{current_branch, head_commit, operation, remote, repo_path, short_id, GitResult};
+use git2::{
+    Delta, DiffDelta, DiffFindOptions, FileMode, IndexConflict, ObjectType, Oid, Repository,
+    Status, StatusOptions, SubmoduleIgnore, SubmoduleStatus,
+};
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::path::Path;
+
+/// One changed path, with both sides of the porcelain `XY` code.
+#[derive(Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct FileChange {
+    path: String,
+    /// The letter for the list the entry is in, a two-letter code for conflicts.
+    status: String,
+    index: char,
+    worktree: char,
+    /// Source path of a rename or copy.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    orig_path: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    submodule: Option<SubmoduleChange>,
+}
+
+/// The `S<c><m><u>` part of porcelain v2 for submodule entries.
+#[derive(Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct SubmoduleChange {
+    commit_changed: bool,
+    tracked_changes: bool,
+    untracked_changes: bool,
+}
+
+fn index_char(status: Status) -> char {
+    if status.contains(Status::INDEX_NEW) {
+        'A'
+    } else if status.contains(Status::INDEX_MODIFIED) {
+        'M'
+    } else if status.contains(Status::INDEX_DELETED) {
+        'D'
+    } else if status.contains(Status::INDEX_RENAMED) {
+        'R'
+    } else if status.contains(Status::INDEX_TYPECHANGE) {
+        'T'
+    } else {
+        ' '
+    }
+}
+
+fn worktree_char(status: Status) -> char {
+    if status.contains(Status::WT_MODIFIED) {
+        'M'
+    } else if status.contains(Status::WT_DELETED) {
+        'D'
+    } else if status.contains(Status::WT_RENAMED) {
+        'R'
+    } else if status.contains(Status::WT_TYPECHANGE) {
+        'T'
+    } else {
+        ' '
+    }
+}
+
+fn index_bits() -> Status {
+    Status::INDEX_NEW
+        | Status::INDEX_MODIFIED
+        | Status::INDEX_DELETED
+        | Status::INDEX_RENAMED
+        | Status::INDEX_TYPECHANGE
+}
+
+fn delta_path(delta: &DiffDelta, new: bool) -> Option<String> {
+    let file = if new {
+        delta.new_file()
+    } else {
+        delta.old_file()
+    };
+    file.path().map(|p| p.to_string_lossy().replace('\\', "/"))
+}
+
+/// Unmerged `XY` code from which stages of the conflict exist.
+fn conflict_code(conflict: &IndexConflict) -> &'static str {
+    match (
+        conflict.ancestor.is_some(),
+        conflict.our.is_some(),
+        conflict.their.is_some(),
+    ) {
+        (true, true, true) => "UU",
+        (false, true, true) => "AA",
+        (true, false, true) => "DU",
+        (true, true, false) => "UD",
+        (false, true, false) => "AU",
+        (false, false, true) => "UA",
+        _ => "DD",
+    }
+}
+
+pub(crate) fn conflict_codes(repo: &Repository) -> GitResult<HashMap<String, &'static str>> {
+    let mut codes = HashMap::new();
+    let index = repo.index()?;
+    if !index.has_conflicts() {
+        return Ok(codes);
+    }
+    for conflict in index.conflicts()? {
+        let conflict = conflict?;
+        let entry = conflict
+            .our
+            .as_ref()
+            .or(conflict.their.as_ref())
+            .or(conflict.ancestor.as_ref());
+        if let Some(entry) = entry {
+            let path = String::from_utf8_lossy(&entry.path).to_string();
+            codes.insert(path, conflict_code(&conflict));
+        }
+    }
+    Ok(codes)
+}
+
+/// Conflicted paths, sorted.
+pub(crate) fn conflicted_paths(repo: &Repository) -> GitResult<Vec<String>> {
+    let mut paths: Vec<String> = conflict_codes(repo)?.into_keys().collect();
+    paths.sort();
+    Ok(paths)
+}
+
+fn submodule_change(repo: &Repository, path: &str) -> Option<SubmoduleChange> {
+    let name = repo.find_submodule(path).ok()?.name()?.to_string();
+    let status = repo.submodule_status(&name, SubmoduleIgnore::None).ok()?;
+    Some(SubmoduleChange {
+        commit_changed: status
+            .intersects(SubmoduleStatus::WD_MODIFIED | SubmoduleStatus::INDEX_MODIFIED),
+        tracked_changes: status
+            .intersects(SubmoduleStatus::WD_INDEX_MODIFIED | SubmoduleStatus::WD_WD_MODIFIED),
+        untracked_changes: status.contains(SubmoduleStatus::WD_UNTRACKED),
+    })
+}
+
+/// libgit2 status only finds renames, this adds the copies among the staged
+/// files like porcelain v2 with `status.renames=copies`: new files similar to
+/// a file changed in the same diff, keyed by the new path.
+fn copies(repo: &Repository) -> GitResult<HashMap<String, String>> {
+    let mut copies = HashMap::new();
+    let head = match head_commit(repo)? {
+        Some(head) => head,
+        None => return Ok(copies),
+    };
+    let mut diff = repo.diff_tree_to_index(Some(&head.tree()?), None, None)?;
+    diff.find_similar(Some(DiffFindOptions::new().renames(true).copies(true)))?;
+    // Every empty file would otherwise look like a copy of another empty file
+    let empty_blob = Oid::hash_object(ObjectType::Blob, b"")?;
+    for delta in diff.deltas() {
+        if delta.status() != Delta::Copied || delta.new_file().id() == empty_blob {
+            continue;
+        }
+        if let (Some(path), Some(source)) = (delta_path(&delta, true), delta_path(&delta, false)) {
+            copies.insert(path, source);
+        }
+    }
+    Ok(copies)
+}
+
+pub fn status(repo: &Repository) -> GitResult<Value> {
+    let mut opts = StatusOptions::new();
+    opts.include_untracked(true)
+        .include_ignored(true)
+        .recurse_untracked_dirs(false)
+        .recurse_ignored_dirs(false)
+        .exclude_submodules(false)
+        .renames_head_to_index(true);
+    let statuses = repo.statuses(Some(&mut opts))?;
+    let conflict_codes = conflict_codes(repo)?;
+    let has_added = statuses
+        .iter()
+        .any(|e| e.status().contains(Status::INDEX_NEW));
+    let copies = if has_added {
+        copies(repo)?
+    } else {
+        HashMap::new()
+    };
+
+    let mut staged = vec![];
+    let mut unstaged = vec![];
+    let mut untracked = vec![];
+    let mut ignored = vec![];
+    let mut conflicts = vec![];
+    for entry in statuses.iter() {
+        let status = entry.status();
+        let head_to_index = entry.head_to_index();
+        let index_to_workdir = entry.index_to_workdir();
+        let file = match index_to_workdir
+            .as_ref()
+            .and_then(|d| delta_path(d, true))
+            .or_else(|| head_to_index.as_ref().and_then(|d| delta_path(d, true)))
+            .or_else(|| entry.path().map(String::from))
+        {
+            Some(p) => p,
+            None => continue,
+        };
+
+        if status.contains(Status::IGNORED) {
+            ignored.push(FileChange::simple(file, '!'));
+            continue;
+        }
+        if status.contains(Status::CONFLICTED) {
+            let code = conflict_codes.get(&file).copied().unwrap_or("UU");
+            let mut chars = code.chars();
+            conflicts.push(FileChange {
+                path: file,
+                status: code.to_string(),
+                index: chars.next().unwrap_or('U'),
+                worktree: chars.next().unwrap_or('U'),
+                orig_path: None,
+                submodule: None,
+            });
+            continue;
+        }
+        if status.contains(Status::WT_NEW) && !status.intersects(index_bits()) {
+            untracked.push(FileChange::simple(file, '?'));
+            continue;
+        }
+
+        let mut index = index_char(status);
+        let worktree = worktree_char(status);
+        let mut orig_path = head_to_index
+            .as_ref()
+            .filter(|d| d.status() == Delta::Renamed)
+            .and_then(|d| delta_path(d, false));
+        if index == 'A' {
+            if let Some(source) = copies.get(&file) {
+                index = 'C';
+                orig_path = Some(source.clone());
+            }
+        }
+        let is_submodule = [&head_to_index, &index_to_workdir].iter().any(|d| {
+            d.as_ref().is_some_and(|d| {
+                d.new_file().mode() == FileMode::Commit || d.old_file().mode() == FileMode::Commit
+            })
+        });
+        let submodule = if is_submodule {
+            submodule_change(repo, &file)
+        } else {
+            None
+        };
+
+        let change = FileChange {
+            path: file,
+            status: String::new(),
+            index,
+            worktree,
+            orig_path,
+            submodule,
+        };
+        // `MM`, `AM`, `RD`… belong to both lists
+        if index != ' ' {
+            staged.push(FileChange {
+                status: index.to_string(),
+                ..change.clone()
+            });
+        }
+        if worktree != ' ' {
+            unstaged.push(FileChange {
+                status: worktree.to_string(),
+                ..change
+            });
+        }
+    }
+
+    let detached = repo.head_detached().unwrap_or(false);
+    let branch = if detached {
+        "HEAD".to_string()
+    } else {
+        current_branch(repo).unwrap_or_else(|| "master".to_string())
+    };
+    let origin = repo
+        .find_remote("origin")
+        .ok()
+        .and_then(|r| r.url().map(String::from))
+        .unwrap_or_default();
+    Ok(json!({
+        "staged": staged,
+        "unstaged": unstaged,
+        "untracked": untracked,
+        "ignored": ignored,
+        "conflicts": conflicts,
+        "branch": branch,
+        "detached": detached,
+        "head": head_commit(repo)?.map(|c| short_id(repo, c.id())),
+        "operation": operation::state(repo)?,
+        "origin": origin,
+        "remotes": remote::remotes(repo)?
+    }))
+}
+
+impl FileChange {
+    /// Untracked (`?`) and ignored (`!`) entries use the same code on both sides.
+    fn simple(path: String, code: char) -> Self {
+        FileChange {
+            path,
+            status: if code == '?' {
+                "U".into()
+            } else {
+                code.to_string()
+            },
+            index: code,
+            worktree: code,
+            orig_path: None,
+            submodule: None,
+        }
+    }
+}
+
+pub fn file_status(repo: &Repository, file: &str) -> GitResult<Value> {
+    let path = repo_path(repo, file);
+    // Directories and paths outside the repo have no single status
+    let status = match repo.status_file(Path::new(&path)) {
+        Ok(s) => s,
+        Err(_) => return Ok(json!({ "status": "" })),
+    };
+    let git_state = if status.contains(Status::WT_NEW) && !status.intersects(index_bits()) {
+        "U"
+    } else if status.intersects(Status::INDEX_DELETED | Status::WT_DELETED) {
+        "D"
+    } else if status.intersects(Status::INDEX_MODIFIED | Status::WT_MODIFIED) {
+        "M"
+    } else if status.contains(Status::INDEX_NEW) {
+        "A"
+    } else {
+        ""
+    };
+    Ok(json!({ "status": git_state }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git::testing::TestRepo;
+    use serde_json::json;
+
+    fn entries(status: &Value, list: &str) -> Vec<(String, String)> {
+        status[list]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|e| {
+                (
+                    e["path"].as_str().unwrap().to_string(),
+                    e["status"].as_str().unwrap().to_string(),
+                )
+            })
+            .collect()
+    }
+
+    fn pair(path: &str, status: &str) -> (String, String) {
+        (path.to_string(), status.to_string())
+    }
+
+    #[test]
+    fn sorts_changes_into_lists() {
+        let t = TestRepo::new();
+        t.write(".gitignore", "*.log\n");
+        t.write("a.txt", "a\n");
+        t.write("b.txt", "b\n");
+        t.commit("init");
+        t.write("a.txt", "staged\n");
+        t.run("stage", json!({ "file": "a.txt" })).unwrap();
+        t.write("a.txt", "staged and changed\n");
+        t.remove("b.txt");
+        t.write("new.txt", "n\n");
+        t.write("debug.log", "l\n");
+
+        let s = status(&t.repo).unwrap();
+        assert_eq!(entries(&s, "staged"), [pair("a.txt", "M")]);
+        assert_eq!(
+            entries(&s, "unstaged"),
+            [pair("a.txt", "M"), pair("b.txt", "D")]
+        );
+        assert_eq!(entries(&s, "untracked"), [pair("new.txt", "U")]);
+        assert_eq!(entries(&s, "ignored"), [pair("debug.log", "!")]);
+        assert_eq!(s["branch"], "main");
+        assert_eq!(s["detached"], false);
+    }
+
+    #[test]
+    fn reports_staged_rename() {
+        let t = TestRepo::new();
+        t.write(
+            "old.txt",
+            "some content\nthat is long enough\nto be similar\n",
+        );
+        t.commit("init");
+        std::fs::rename(t.path().join("old.txt"), t.path().join("new.txt")).unwrap();
+        t.run("stage-all", json!({})).unwrap();
+
+        let s = status(&t.repo).unwrap();
+        assert_eq!(entries(&s, "staged"), [pair("new.txt", "R")]);
+        assert_eq!(s["staged"][0]["origPath"], "old.txt");
+    }
+
+    #[test]
+    fn reports_copy_of_changed_file() {
+        let t = TestRepo::new();
+        let text = "line one\nline two\nline three\nline four\n";
+        t.write("a.txt", text);
+        t.write("empty.txt", "");
+        t.commit("init");
+        t.write("a.txt", &format!("{}line five\n", text));
+        t.write("b.txt", text);
+        t.write("empty2.txt", "");
+        t.run("stage-all", json!({})).unwrap();
+
+        let s = status(&t.repo).unwrap();
+        let staged = &s["staged"];
+        let find = |path: &str| {
+            staged
+                .as_array()
+                .unwrap()
+                .iter()
+                .find(|e| e["path"] == path)
+                .unwrap()
+                .clone()
+        };
+        assert_eq!(find("b.txt")["status"], "C");
+        assert_eq!(find("b.txt")["origPath"], "a.txt");
+        // Empty files are never copies of each other
+        assert_eq!(find("empty2.txt")["status"], "A");
+    }
+
+    #[test]
+    fn reports_conflicts() {
+        let t = TestRepo::new();
+        t.conflict("c.txt", "base\n", "ours\n", "theirs\n");
+        let s = status(&t.repo).unwrap();
+        assert_eq!(entries(&s, "conflicts"), [pair("c.txt", "UU")]);
+        assert!(entries(&s, "staged").is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn reports_type_change() {
+        let t = TestRepo::new();
+        t.write("a.txt", "a\n");
+        t.write("link", "b\n");
+        t.commit("init");
+        t.remove("link");
+        std::os::unix::fs::symlink("a.txt", t.path().join("link")).unwrap();
+
+        let s = status(&t.repo).unwrap();
+        assert_eq!(entries(&s, "unstaged"), [pair("link", "T")]);
+    }
+
+    #[test]
+    fn file_status_codes() {
+        let t = TestRepo::new();
+        t.write("a.txt", "a\n");
+        t.commit("init");
+        t.write("a.txt", "changed\n");
+        t.write("new.txt", "n\n");
+        let code = |file: &str| file_status(&t.repo, file).unwrap()["status"].clone();
+        assert_eq!(code("a.txt"), "M");
+        assert_eq!(code("new.txt"), "U");
+        assert_eq!(code(&t.path().join("a.txt").to_string_lossy()), "M");
+    }
+}
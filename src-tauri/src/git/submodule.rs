@@ -0,0 +1,316 @@
+use super::{remote, short_id, GitError, GitErrorKind, GitResult};
+use git2::{
+    build::CheckoutBuilder, Oid, Repository, Submodule, SubmoduleIgnore, SubmoduleStatus,
+    SubmoduleUpdateOptions,
+};
+use serde_json::{json, Value};
+
+/// Submodules with the commit the superproject records and the one that is
+/// checked out, like `git submodule status`.
+pub fn list(repo: &Repository) -> GitResult<Value> {
+    let mut submodules = Vec::new();
+    for submodule in repo.submodules()? {
+        let name = submodule.name().unwrap_or("").to_string();
+        let status = repo.submodule_status(&name, SubmoduleIgnore::None)?;
+        let recorded = submodule.index_id().or(submodule.head_id());
+        let checked_out = submodule.workdir_id();
+        let sub_repo = submodule.open().ok();
+        let id = |id: Option<Oid>| id.map(|id| id.to_string());
+        let short = |id: Option<Oid>| match (&sub_repo, id) {
+            (Some(sub_repo), Some(id)) => Some(short_id(sub_repo, id)),
+            (None, Some(id)) => Some(id.to_string()[..7].to_string()),
+            _ => None,
+        };
+        submodules.push(json!({
+            "name": name,
+            "path": submodule.path().to_string_lossy().replace('\\', "/"),
+            "url": submodule.url(),
+            "branch": submodule.branch(),
+            "recorded": id(recorded),
+            "recordedShort": short(recorded),
+            "checkedOut": id(checked_out),
+            "checkedOutShort": short(checked_out),
+            "initialized": !status.contains(SubmoduleStatus::WD_UNINITIALIZED)
+                && is_initialized(repo, &name),
+            "outOfDate": checked_out.is_some() && checked_out != recorded,
+            "dirty": status.intersects(
+                SubmoduleStatus::WD_INDEX_MODIFIED
+                    | SubmoduleStatus::WD_WD_MODIFIED
+                    | SubmoduleStatus::WD_UNTRACKED
+            ),
+        }));
+    }
+    Ok(submodules.into())
+}
+
+/// `git submodule init`: copies the URLs from `.gitmodules` into the
+/// repository config. All submodules when `paths` is empty.
+pub fn init(repo: &Repository, paths: &[String]) -> GitResult<Value> {
+    let mut initialized = Vec::new();
+    for mut submodule in select(repo, paths)? {
+        let name = submodule.name().unwrap_or("").to_string();
+        if is_initialized(repo, &name) {
+            continue;
+        }
+        submodule.init(false)?;
+        println!(
+            "[SUBMODULE] Registered '{}' for {}",
+            name,
+            submodule.path().display()
+        );
+        initialized.push(submodule.path().to_string_lossy().replace('\\', "/"));
+    }
+    Ok(json!({ "initialized": initialized }))
+}
+
+/// `git submodule update [--init] [--recursive]`: clones missing
+/// submodules and checks out the commits the superproject records.
+/// Submodules that were never initialized are skipped unless `init`.
+pub fn update(
+    repo: &Repository,
+    paths: &[String],
+    init: bool,
+    recursive: bool,
+) -> GitResult<Value> {
+    let mut updated = Vec::new();
+    let mut skipped = Vec::new();
+    update_into(repo, "", paths, init, recursive, &mut updated, &mut skipped)?;
+    Ok(json!({ "updated": updated, "skipped": skipped }))
+}
+
+fn update_into(
+    repo: &Repository,
+    prefix: &str,
+    paths: &[String],
+    init: bool,
+    recursive: bool,
+    updated: &mut Vec<Value>,
+    skipped: &mut Vec<String>,
+) -> GitResult<()> {
+    for mut submodule in select(repo, paths)? {
+        let name = submodule.name().unwrap_or("").to_string();
+        let path = format!(
+            "{}{}",
+            prefix,
+            submodule.path().to_string_lossy().replace('\\', "/")
+        );
+        if !init && !is_initialized(repo, &name) {
+            skipped.push(path);
+            continue;
+        }
+        let before = submodule.workdir_id();
+        let recorded = submodule.index_id().or(submodule.head_id());
+        let cloned = submodule.open().is_ok();
+        if !cloned || before != recorded {
+            let mut checkout = CheckoutBuilder::new();
+            checkout.safe();
+            let mut options = SubmoduleUpdateOptions::new();
+            options
+                .fetch(remote::fetch_options(repo))
+                .checkout(checkout);
+            submodule
+                .update(init, Some(&mut options))
+                .map_err(|e| GitError::from(e).context(&format!("Submodule '{}'", path)))?;
+            println!(
+                "[SUBMODULE] {} at {}",
+                path,
+                recorded.map(|id| id.to_string()).unwrap_or_default()
+            );
+            updated.push(json!({
+                "path": path,
+                "from": before.map(|id| id.to_string()),
+                "to": recorded.map(|id| id.to_string()),
+                "cloned": !cloned,
+            }));
+        }
+        if recursive {
+            let sub_repo = submodule.open()?;
+            update_into(
+                &sub_repo,
+                &format!("{}/", path),
+                &[],
+                init,
+                recursive,
+                updated,
+                skipped,
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// `git submodule sync`: points the repository config and the submodule's
+/// `origin` at the URL in `.gitmodules` again.
+pub fn sync(repo: &Repository, paths: &[String], recursive: bool) -> GitResult<Value> {
+    let mut synced = Vec::new();
+    sync_into(repo, "", paths, recursive, &mut synced)?;
+    Ok(json!({ "synced": synced }))
+}
+
+fn sync_into(
+    repo: &Repository,
+    prefix: &str,
+    paths: &[String],
+    recursive: bool,
+    synced: &mut Vec<String>,
+) -> GitResult<()> {
+    for mut submodule in select(repo, paths)? {
+        let name = submodule.name().unwrap_or("").to_string();
+        if !is_initialized(repo, &name) {
+            continue;
+        }
+        let path = format!(
+            "{}{}",
+            prefix,
+            submodule.path().to_string_lossy().replace('\\', "/")
+        );
+        submodule.sync()?;
+        println!("[SUBMODULE] Synchronized URL for {}", path);
+        synced.push(path.clone());
+        if recursive {
+            if let Ok(sub_repo) = submodule.open() {
+                sync_into(&sub_repo, &format!("{}/", path), &[], recursive, synced)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The submodules at `paths`, or all of them.
+fn select<'r>(repo: &'r Repository, paths: &[String]) -> GitResult<Vec<Submodule<'r>>> {
+    let submodules = repo.submodules()?;
+    if paths.is_empty() {
+        return Ok(submodules);
+    }
+    let mut selected = Vec::new();
+    for path in paths {
+        let path = path.trim_end_matches('/');
+        let index = submodules
+            .iter()
+            .position(|s| s.path().to_string_lossy().replace('\\', "/") == path)
+            .ok_or_else(|| {
+                GitError::new(
+                    GitErrorKind::NotFound,
+                    format!("no submodule at '{}'", path),
+                )
+            })?;
+        selected.push(index);
+    }
+    Ok(submodules
+        .into_iter()
+        .enumerate()
+        .filter(|(i, _)| selected.contains(i))
+        .map(|(_, s)| s)
+        .collect())
+}
+
+/// `git submodule init` ran for it, its URL is in the repository config.
+fn is_initialized(repo: &Repository, name: &str) -> bool {
+    repo.config()
+        .and_then(|c| c.get_string(&format!("submodule.{}.url", name)))
+        .is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git::testing::TestRepo;
+    use std::path::Path;
+
+    /// A superproject with `lib` as a submodule, cloned without it.
+    fn setup() -> (TestRepo, TestRepo, TestRepo) {
+        let lib = TestRepo::new();
+        lib.write("lib.txt", "lib\n");
+        lib.commit("lib");
+        let upstream = TestRepo::new();
+        {
+            let mut submodule = upstream
+                .repo
+                .submodule(&lib.url(), Path::new("lib"), true)
+                .unwrap();
+            submodule.clone(None).unwrap();
+            submodule.add_finalize().unwrap();
+        }
+        upstream.commit_index("add lib");
+        let clone = TestRepo::clone(&upstream.url());
+        (lib, upstream, clone)
+    }
+
+    #[test]
+    fn lists_uninitialized_submodules() {
+        let (lib, _upstream, clone) = setup();
+        let listed = list(&clone.repo).unwrap();
+        assert_eq!(listed[0]["path"], "lib");
+        assert_eq!(listed[0]["url"], lib.url());
+        assert_eq!(listed[0]["recorded"], lib.head().to_string());
+        assert_eq!(listed[0]["initialized"], false);
+        assert_eq!(listed[0]["checkedOut"], Value::Null);
+
+        let updated = update(&clone.repo, &[], false, false).unwrap();
+        assert_eq!(updated["skipped"], json!(["lib"]));
+    }
+
+    #[test]
+    fn init_and_update() {
+        let (lib, _upstream, clone) = setup();
+        assert_eq!(
+            init(&clone.repo, &[]).unwrap()["initialized"],
+            json!(["lib"])
+        );
+        assert_eq!(init(&clone.repo, &[]).unwrap()["initialized"], json!([]));
+
+        let updated = update(&clone.repo, &["lib/".into()], false, true).unwrap();
+        assert_eq!(updated["updated"][0]["cloned"], true);
+        assert_eq!(updated["updated"][0]["to"], lib.head().to_string());
+        assert_eq!(clone.read("lib/lib.txt"), "lib\n");
+
+        let listed = list(&clone.repo).unwrap();
+        assert_eq!(listed[0]["initialized"], true);
+        assert_eq!(listed[0]["checkedOut"], lib.head().to_string());
+        assert_eq!(listed[0]["outOfDate"], false);
+        assert_eq!(listed[0]["dirty"], false);
+
+        // Nothing to do the second time
+        let updated = update(&clone.repo, &[], false, false).unwrap();
+        assert_eq!(updated["updated"], json!([]));
+    }
+
+    #[test]
+    fn update_with_init_clones() {
+        let (_lib, _upstream, clone) = setup();
+        let updated = update(&clone.repo, &[], true, false).unwrap();
+        assert_eq!(updated["updated"].as_array().unwrap().len(), 1);
+        assert!(clone.path().join("lib/lib.txt").exists());
+    }
+
+    #[test]
+    fn sync_uses_the_gitmodules_url() {
+        let (lib, _upstream, clone) = setup();
+        update(&clone.repo, &[], true, false).unwrap();
+        let gitmodules = clone.read(".gitmodules");
+        let moved = gitmodules.replace(&lib.url(), "https://example.com/lib.git");
+        clone.write(".gitmodules", &moved);
+
+        assert_eq!(
+            sync(&clone.repo, &[], true).unwrap()["synced"],
+            json!(["lib"])
+        );
+        let config = clone.repo.config().unwrap().snapshot().unwrap();
+        assert_eq!(
+            config.get_str("submodule.lib.url").unwrap(),
+            "https://example.com/lib.git"
+        );
+        let sub_repo = Repository::open(clone.path().join("lib")).unwrap();
+        assert_eq!(
+            sub_repo.find_remote("origin").unwrap().url(),
+            Some("https://example.com/lib.git")
+        );
+    }
+
+    #[test]
+    fn unknown_paths() {
+        let (_lib, _upstream, clone) = setup();
+        let err = update(&clone.repo, &["vendor".into()], true, false).unwrap_err();
+        assert_eq!(err.kind, GitErrorKind::NotFound);
+    }
+}
@@ -0,0 +1,261 @@
+use super::{open, remote, GitError, GitResult};
+use git2::{BranchType, Repository};
+use serde::Serialize;
+use std::collections::{BTreeSet, HashMap};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+/// Longest wait between fetches while they keep failing.
+const MAX_BACKOFF: Duration = Duration::from_secs(30 * 60);
+const MIN_INTERVAL: u64 = 10;
+
+/// Ahead/behind of one local branch against its upstream.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct BranchSync {
+    pub name: String,
+    pub upstream: String,
+    pub ahead: usize,
+    pub behind: usize,
+}
+
+/// Payload of the `git-sync-changed` event.
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SyncChanged {
+    pub workspace: String,
+    pub branches: Vec<BranchSync>,
+    /// Why the last fetch failed, the numbers are from the refs we have.
+    pub error: Option<GitError>,
+}
+
+struct Scheduler {
+    stop: Arc<AtomicBool>,
+    interval: Arc<AtomicU64>,
+}
+
+static SCHEDULERS: LazyLock<Mutex<HashMap<PathBuf, Scheduler>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Fetches the remotes of a workspace's tracked branches every
+/// `interval_secs` in the background, calling `on_change` whenever
+/// ahead/behind of any branch changes or fetching starts or stops failing.
+/// Starting it again for the same workspace only changes the interval.
+pub fn start_auto_fetch(
+    workspace: &str,
+    interval_secs: u64,
+    on_change: impl Fn(SyncChanged) + Send + 'static,
+) -> GitResult<()> {
+    let repo = open(workspace)?;
+    let key = repo_key(&repo);
+    let interval_secs = interval_secs.max(MIN_INTERVAL);
+
+    let mut schedulers = SCHEDULERS.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(scheduler) = schedulers.get(&key) {
+        scheduler.interval.store(interval_secs, Ordering::Relaxed);
+        println!(
+            "[AUTO-FETCH] Interval for {} set to {}s",
+            key.display(),
+            interval_secs
+        );
+        return Ok(());
+    }
+    let stop = Arc::new(AtomicBool::new(false));
+    let interval = Arc::new(AtomicU64::new(interval_secs));
+    schedulers.insert(
+        key.clone(),
+        Scheduler {
+            stop: stop.clone(),
+            interval: interval.clone(),
+        },
+    );
+    drop(schedulers);
+
+    println!(
+        "[AUTO-FETCH] Starting for {} every {}s",
+        key.display(),
+        interval_secs
+    );
+    let workspace = workspace.to_string();
+    std::thread::spawn(move || {
+        let mut last: Option<(Vec<BranchSync>, bool)> = None;
+        let mut failures = 0;
+        while !stop.load(Ordering::Relaxed) {
+            let (branches, error) = tick(&workspace);
+            if error.is_some() {
+                failures += 1;
+            } else {
+                failures = 0;
+            }
+            let state = (branches.clone(), error.is_some());
+            if last.as_ref() != Some(&state) {
+                on_change(SyncChanged {
+                    workspace: workspace.clone(),
+                    branches,
+                    error,
+                });
+                last = Some(state);
+            }
+
+            let interval = Duration::from_secs(interval.load(Ordering::Relaxed));
+            let delay = (interval * 2u32.pow(failures.min(8))).min(MAX_BACKOFF.max(interval));
+            let started = Instant::now();
+            while started.elapsed() < delay && !stop.load(Ordering::Relaxed) {
+                std::thread::sleep(Duration::from_millis(250));
+            }
+        }
+        println!("[AUTO-FETCH] Stopped for {}", workspace);
+    });
+    Ok(())
+}
+
+/// False when the workspace had no scheduler running.
+pub fn stop_auto_fetch(workspace: &str) -> GitResult<bool> {
+    let key = repo_key(&open(workspace)?);
+    let removed = SCHEDULERS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .remove(&key);
+    if let Some(scheduler) = &removed {
+        scheduler.stop.store(true, Ordering::Relaxed);
+    }
+    Ok(removed.is_some())
+}
+
+fn repo_key(repo: &Repository) -> PathBuf {
+    repo.workdir().unwrap_or_else(|| repo.path()).to_path_buf()
+}
+
+/// One round: fetch every remote a branch tracks, then count. A failed
+/// fetch still reports the numbers from the refs already there.
+fn tick(workspace: &str) -> (Vec<BranchSync>, Option<GitError>) {
+    let repo = match open(workspace) {
+        Ok(repo) => repo,
+        Err(e) => return (Vec::new(), Some(e)),
+    };
+    let mut error = None;
+    let remotes = match tracked_remotes(&repo) {
+        Ok(remotes) => remotes,
+        Err(e) => return (Vec::new(), Some(e)),
+    };
+    for name in remotes {
+        if let Err(e) = remote::fetch(&repo, &name, &[]) {
+            eprintln!("[AUTO-FETCH] Fetching {} failed: {}", name, e);
+            error.get_or_insert(e);
+        }
+    }
+    match tracked_branches(&repo) {
+        Ok(branches) => (branches, error),
+        Err(e) => (Vec::new(), Some(error.unwrap_or(e))),
+    }
+}
+
+fn tracked_remotes(repo: &Repository) -> GitResult<BTreeSet<String>> {
+    let mut remotes = BTreeSet::new();
+    for branch in repo.branches(Some(BranchType::Local))? {
+        let (branch, _) = branch?;
+        let Some(refname) = branch.get().name() else {
+            continue;
+        };
+        if let Ok(remote) = repo.branch_upstream_remote(refname) {
+            if let Some(remote) = remote.as_str() {
+                remotes.insert(remote.to_string());
+            }
+        }
+    }
+    Ok(remotes)
+}
+
+/// Every local branch with an upstream that still exists, by name.
+pub(crate) fn tracked_branches(repo: &Repository) -> GitResult<Vec<BranchSync>> {
+    let mut branches = Vec::new();
+    for branch in repo.branches(Some(BranchType::Local))? {
+        let (branch, _) = branch?;
+        let (Some(name), Ok(upstream)) = (branch.name()?, branch.upstream()) else {
+            continue;
+        };
+        let (Some(local_id), Some(upstream_id)) = (branch.get().target(), upstream.get().target())
+        else {
+            continue;
+        };
+        let (ahead, behind) = repo.graph_ahead_behind(local_id, upstream_id)?;
+        branches.push(BranchSync {
+            name: name.to_string(),
+            upstream: upstream.name()?.unwrap_or("").to_string(),
+            ahead,
+            behind,
+        });
+    }
+    branches.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(branches)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git::testing::TestRepo;
+    use serde_json::json;
+    use std::sync::mpsc;
+
+    #[test]
+    fn scheduler_fetches_and_reports() {
+        let origin = TestRepo::bare();
+        let local = TestRepo::new();
+        local.write("a.txt", "a\n");
+        local.commit("init");
+        local.repo.remote("origin", &origin.url()).unwrap();
+        local
+            .run("push", json!({ "remote": "origin", "branch": "main" }))
+            .unwrap();
+        let other = TestRepo::clone(&origin.url());
+        other.write("b.txt", "b\n");
+        other.commit("remote change");
+        other
+            .run("push", json!({ "remote": "origin", "branch": "main" }))
+            .unwrap();
+
+        let (sender, changes) = mpsc::channel();
+        start_auto_fetch(&local.url(), 60, move |changed| {
+            let _ = sender.send(changed);
+        })
+        .unwrap();
+        let changed = changes.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert!(stop_auto_fetch(&local.url()).unwrap());
+        assert!(!stop_auto_fetch(&local.url()).unwrap());
+
+        assert!(changed.error.is_none());
+        assert_eq!(
+            changed.branches,
+            [BranchSync {
+                name: "main".into(),
+                upstream: "origin/main".into(),
+                ahead: 0,
+                behind: 1,
+            }]
+        );
+    }
+
+    #[test]
+    fn failed_fetch_keeps_the_counts() {
+        let local = TestRepo::new();
+        local.write("a.txt", "a\n");
+        let head = local.commit("init");
+        local
+            .repo
+            .remote("origin", "/nonexistent/origin.git")
+            .unwrap();
+        local
+            .repo
+            .reference("refs/remotes/origin/main", head, true, "test")
+            .unwrap();
+        let mut main = local.repo.find_branch("main", BranchType::Local).unwrap();
+        main.set_upstream(Some("origin/main")).unwrap();
+
+        let (branches, error) = tick(&local.url());
+        assert!(error.is_some());
+        assert_eq!(branches.len(), 1);
+        assert_eq!((branches[0].ahead, branches[0].behind), (0, 0));
+    }
+}
@@ -0,0 +1,225 @@
+use super::{remote, short_id, GitError, GitErrorKind, GitResult};
+use git2::{ErrorCode, Repository};
+use serde_json::{json, Value};
+
+/// Tags with the commit they point at, newest first. Annotated tags also
+/// carry their tagger and message.
+pub fn list(repo: &Repository) -> GitResult<Value> {
+    let mut tags = Vec::new();
+    for name in repo.tag_names(None)?.iter().flatten() {
+        let reference = match repo.find_reference(&format!("refs/tags/{}", name)) {
+            Ok(reference) => reference,
+            Err(_) => continue,
+        };
+        // Tags of trees or blobs don't show up in history
+        let commit = match reference.peel_to_commit() {
+            Ok(commit) => commit,
+            Err(_) => continue,
+        };
+        let annotated = reference.target().and_then(|id| repo.find_tag(id).ok());
+
+        let (tagger, date, message) = match &annotated {
+            Some(tag) => (
+                tag.tagger().map(|t| {
+                    json!({
+                        "name": t.name().unwrap_or(""),
+                        "email": t.email().unwrap_or(""),
+                    })
+                }),
+                tag.tagger()
+                    .map(|t| t.when().seconds())
+                    .unwrap_or_else(|| commit.time().seconds()),
+                tag.message().unwrap_or("").trim_end().to_string(),
+            ),
+            None => (None, commit.time().seconds(), String::new()),
+        };
+        tags.push(json!({
+            "name": name,
+            "hash": commit.id().to_string(),
+            "shortHash": short_id(repo, commit.id()),
+            "summary": commit.summary().unwrap_or(""),
+            "annotated": annotated.is_some(),
+            "tagger": tagger,
+            "date": date,
+            "message": message,
+        }));
+    }
+    tags.sort_by_key(|tag| std::cmp::Reverse(tag["date"].as_i64().unwrap_or(0)));
+    Ok(tags.into())
+}
+
+/// An annotated tag when there is a message, a lightweight one otherwise.
+pub fn create(
+    repo: &Repository,
+    name: &str,
+    target: &str,
+    message: Option<&str>,
+    force: bool,
+) -> GitResult<Value> {
+    let object = repo
+        .revparse_single(target)?
+        .peel_to_commit()?
+        .into_object();
+    let created = match message {
+        Some(message) => {
+            let message = git2::message_prettify(message, Some(b'#'))?;
+            repo.tag(name, &object, &repo.signature()?, &message, force)
+        }
+        None => repo.tag_lightweight(name, &object, force),
+    };
+    let id = created.map_err(|e| {
+        if e.code() == ErrorCode::Exists {
+            GitError::new(
+                GitErrorKind::AlreadyExists,
+                format!("tag '{}' already exists", name),
+            )
+        } else {
+            e.into()
+        }
+    })?;
+    println!("[TAG] Created {} at {}", name, short_id(repo, object.id()));
+    Ok(json!({
+        "name": name,
+        "hash": object.id().to_string(),
+        "annotated": message.is_some(),
+        "tag": message.map(|_| id.to_string()),
+    }))
+}
+
+pub fn delete(repo: &Repository, name: &str) -> GitResult<Value> {
+    let reference = repo
+        .find_reference(&format!("refs/tags/{}", name))
+        .map_err(|_| not_found(name))?;
+    let target = reference.peel_to_commit().map(|c| short_id(repo, c.id()));
+    repo.tag_delete(name)?;
+    if let Ok(target) = &target {
+        println!("Deleted tag '{}' (was {})", name, target);
+    }
+    Ok(json!({ "name": name }))
+}
+
+/// `git push <remote> --delete refs/tags/<name>`.
+pub fn delete_remote(repo: &Repository, remote_name: &str, name: &str) -> GitResult<Value> {
+    remote::push_refspecs(repo, remote_name, &[format!(":refs/tags/{}", name)])?;
+    Ok(json!({ "remote": remote_name, "name": name }))
+}
+
+/// Pushes one tag, or every tag like `git push --tags` when `name` is none.
+pub fn push(repo: &Repository, remote_name: &str, name: Option<&str>) -> GitResult<Value> {
+    let names: Vec<String> = match name {
+        Some(name) => {
+            repo.find_reference(&format!("refs/tags/{}", name))
+                .map_err(|_| not_found(name))?;
+            vec![name.to_string()]
+        }
+        None => repo
+            .tag_names(None)?
+            .iter()
+            .flatten()
+            .map(String::from)
+            .collect(),
+    };
+    if names.is_empty() {
+        return Ok(json!({ "remote": remote_name, "pushed": [] }));
+    }
+    let refspecs: Vec<String> = names
+        .iter()
+        .map(|name| format!("refs/tags/{0}:refs/tags/{0}", name))
+        .collect();
+    println!("[PUSH] Pushing {} tag(s) to {}", names.len(), remote_name);
+    remote::push_refspecs(repo, remote_name, &refspecs)?;
+    Ok(json!({ "remote": remote_name, "pushed": names }))
+}
+
+fn not_found(name: &str) -> GitError {
+    GitError::new(GitErrorKind::NotFound, format!("tag '{}' not found", name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git::testing::TestRepo;
+
+    fn repo() -> TestRepo {
+        let t = TestRepo::new();
+        t.write("a.txt", "a\n");
+        t.commit("first");
+        t
+    }
+
+    #[test]
+    fn creates_lightweight_and_annotated_tags() {
+        let t = repo();
+        let head = t.head().to_string();
+        let light = create(&t.repo, "v1", "HEAD", None, false).unwrap();
+        assert_eq!(light["annotated"], false);
+        assert_eq!(light["tag"], Value::Null);
+        let annotated = create(&t.repo, "v2", "main", Some("Release\n\n# note\n"), false).unwrap();
+        assert!(annotated["tag"].is_string());
+
+        let tags = list(&t.repo).unwrap();
+        let v2 = tags
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|t| t["name"] == "v2")
+            .unwrap();
+        assert_eq!(v2["hash"], head);
+        assert_eq!(v2["message"], "Release");
+        assert_eq!(v2["tagger"]["name"], "Test");
+        assert_eq!(tags.as_array().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn existing_tag_needs_force() {
+        let t = repo();
+        create(&t.repo, "v1", "HEAD", None, false).unwrap();
+        t.write("a.txt", "b\n");
+        let second = t.commit("second");
+        let err = create(&t.repo, "v1", "HEAD", None, false).unwrap_err();
+        assert_eq!(err.kind, GitErrorKind::AlreadyExists);
+        create(&t.repo, "v1", "HEAD", None, true).unwrap();
+        let target = t
+            .repo
+            .revparse_single("v1")
+            .unwrap()
+            .peel_to_commit()
+            .unwrap();
+        assert_eq!(target.id(), second);
+    }
+
+    #[test]
+    fn deletes_tags() {
+        let t = repo();
+        create(&t.repo, "v1", "HEAD", None, false).unwrap();
+        delete(&t.repo, "v1").unwrap();
+        assert!(t.repo.tag_names(None).unwrap().is_empty());
+        assert_eq!(
+            delete(&t.repo, "v1").unwrap_err().kind,
+            GitErrorKind::NotFound
+        );
+    }
+
+    #[test]
+    fn pushes_and_deletes_remote_tags() {
+        let origin = TestRepo::bare();
+        let t = repo();
+        t.repo.remote("origin", &origin.url()).unwrap();
+        create(&t.repo, "v1", "HEAD", None, false).unwrap();
+        create(&t.repo, "v2", "HEAD", Some("two"), false).unwrap();
+
+        let pushed = push(&t.repo, "origin", Some("v1")).unwrap();
+        assert_eq!(pushed["pushed"], json!(["v1"]));
+        assert_eq!(origin.repo.tag_names(None).unwrap().len(), 1);
+        push(&t.repo, "origin", None).unwrap();
+        assert_eq!(origin.repo.tag_names(None).unwrap().len(), 2);
+
+        delete_remote(&t.repo, "origin", "v1").unwrap();
+        let names = origin.repo.tag_names(None).unwrap();
+        assert_eq!(names.iter().flatten().collect::<Vec<_>>(), ["v2"]);
+        assert_eq!(
+            push(&t.repo, "origin", Some("v3")).unwrap_err().kind,
+            GitErrorKind::NotFound
+        );
+    }
+}
@@ -0,0 +1,158 @@
+//! Throwaway repositories for the tests of the git modules.
+
+use super::{run, GitRequest, GitResult};
+use git2::build::CheckoutBuilder;
+use git2::{Index, IndexAddOption, Oid, Repository, RepositoryInitOptions, Signature};
+use serde_json::Value;
+use std::fs;
+use std::path::{Path, PathBuf};
+use tempfile::TempDir;
+
+/// A repository on `main` in a temporary directory that is removed on drop.
+pub(crate) struct TestRepo {
+    pub dir: TempDir,
+    pub repo: Repository,
+}
+
+impl TestRepo {
+    pub fn new() -> Self {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = init(dir.path(), false);
+        TestRepo { dir, repo }
+    }
+
+    /// A bare repository, e.g. as a remote to push to and fetch from.
+    pub fn bare() -> Self {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = init(dir.path(), true);
+        TestRepo { dir, repo }
+    }
+
+    /// A clone of `url` with its own working directory.
+    pub fn clone(url: &str) -> Self {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = Repository::clone(url, dir.path()).unwrap();
+        configure(&repo);
+        TestRepo { dir, repo }
+    }
+
+    pub fn path(&self) -> PathBuf {
+        self.dir.path().to_path_buf()
+    }
+
+    pub fn url(&self) -> String {
+        self.dir.path().to_string_lossy().into_owned()
+    }
+
+    pub fn write(&self, file: &str, contents: &str) {
+        let path = self.dir.path().join(file);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+
+    pub fn read(&self, file: &str) -> String {
+        fs::read_to_string(self.dir.path().join(file)).unwrap()
+    }
+
+    pub fn remove(&self, file: &str) {
+        fs::remove_file(self.dir.path().join(file)).unwrap();
+    }
+
+    /// The index as on disk, `run` writes it through its own handle.
+    pub fn index(&self) -> Index {
+        let mut index = self.repo.index().unwrap();
+        index.read(true).unwrap();
+        index
+    }
+
+    /// Stages every change and commits it on HEAD.
+    pub fn commit(&self, message: &str) -> Oid {
+        let mut index = self.index();
+        index.add_all(["*"], IndexAddOption::DEFAULT, None).unwrap();
+        index.update_all(["*"], None).unwrap();
+        index.write().unwrap();
+        self.commit_index(message)
+    }
+
+    /// Commits whatever is staged on HEAD.
+    pub fn commit_index(&self, message: &str) -> Oid {
+        let mut index = self.index();
+        let tree = self.repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let parent = self.repo.head().ok().map(|h| h.peel_to_commit().unwrap());
+        let parents: Vec<_> = parent.iter().collect();
+        let signature = signature();
+        self.repo
+            .commit(
+                Some("HEAD"),
+                &signature,
+                &signature,
+                message,
+                &tree,
+                &parents,
+            )
+            .unwrap()
+    }
+
+    /// Commits `ours` on main and `theirs` on a new `other` branch on top of
+    /// `base`, then merges `other` into main, leaving `file` conflicted.
+    pub fn conflict(&self, file: &str, base: &str, ours: &str, theirs: &str) {
+        self.write(file, base);
+        let base = self.commit("base");
+        self.repo
+            .branch("other", &self.repo.find_commit(base).unwrap(), false)
+            .unwrap();
+        self.write(file, ours);
+        self.commit("ours");
+        self.switch("other");
+        self.write(file, theirs);
+        self.commit("theirs");
+        self.switch("main");
+        let other = self.repo.find_reference("refs/heads/other").unwrap();
+        let other = self.repo.reference_to_annotated_commit(&other).unwrap();
+        self.repo.merge(&[&other], None, None).unwrap();
+    }
+
+    /// Force checks out a local branch.
+    pub fn switch(&self, branch: &str) {
+        self.repo
+            .set_head(&format!("refs/heads/{}", branch))
+            .unwrap();
+        self.repo
+            .checkout_head(Some(CheckoutBuilder::new().force()))
+            .unwrap();
+    }
+
+    pub fn head(&self) -> Oid {
+        self.repo.head().unwrap().target().unwrap()
+    }
+
+    /// Runs `action` through `git::run` like the `git_command` command does.
+    pub fn run(&self, action: &str, payload: Value) -> GitResult<Value> {
+        let mut payload = match payload {
+            Value::Null => serde_json::json!({}),
+            payload => payload,
+        };
+        payload["workspace"] = self.url().into();
+        run(GitRequest::parse(action, payload)?)
+    }
+}
+
+pub(crate) fn signature() -> Signature<'static> {
+    Signature::now("Test", "test@example.com").unwrap()
+}
+
+fn init(path: &Path, bare: bool) -> Repository {
+    let repo = Repository::init_opts(
+        path,
+        RepositoryInitOptions::new().bare(bare).initial_head("main"),
+    )
+    .unwrap();
+    configure(&repo);
+    repo
+}
+
+fn configure(repo: &Repository) {
+    let mut config = repo.config().unwrap();
+    config.set_str("user.name", "Test").unwrap();
+    config.set_str("user.email", "test@example.com").unwrap();
+}
@@ -0,0 +1,1049 @@
+use super::action::GitAction;
+use super::{
+    current_branch, head_commit, merge_heads, repo_path, short_id, worktree, GitError,
+    GitErrorKind, GitResult,
+};
+use git2::{
+    build::{CheckoutBuilder, TreeUpdateBuilder},
+    BranchType, Commit, FileMode, Index, Oid, Repository, Signature, Status, StatusOptions, Tree,
+    WorktreeAddOptions,
+};
+use serde_json::{json, Value};
+use std::path::{Path, PathBuf};
+
+/// Safety refs are `refs/undo/<n>`, the highest `n` is undone first.
+const PREFIX: &str = "refs/undo/";
+/// Snapshots kept, older ones are dropped.
+const KEEP: usize = 20;
+
+/// Name of an action that can lose work, `None` for everything else.
+///
+/// `StashPush` isn't recorded, the changes it takes away are in the stash
+/// entry it creates and `stash-pop` brings them back. `DeleteRemoteBranch`
+/// keeps the tracking ref and its commits, pushing them again restores the
+/// branch on the remote.
+fn label(action: &GitAction) -> Option<&'static str> {
+    Some(match action {
+        GitAction::UnstageAll => "unstage-all",
+        GitAction::Discard { .. } => "discard",
+        GitAction::DiscardAll => "discard-all",
+        GitAction::DiscardLines(_) => "discard-lines",
+        GitAction::Commit { .. } => "commit",
+        GitAction::RenameBranch => "rename-branch",
+        GitAction::DeleteRemoteBranch { .. } => "delete-remote-branch",
+        GitAction::Checkout { .. } => "checkout",
+        GitAction::Pull { .. } => "pull",
+        GitAction::Merge { .. } => "merge",
+        GitAction::Rebase { .. } => "rebase",
+        GitAction::CherryPick { .. } => "cherry-pick",
+        GitAction::Revert { .. } => "revert",
+        GitAction::ContinueOperation => "continue-operation",
+        GitAction::AbortOperation => "abort-operation",
+        GitAction::SkipOperation => "skip-operation",
+        GitAction::ResolveConflict { .. } => "resolve-conflict",
+        GitAction::RenameBranchTo { .. } => "rename-branch",
+        GitAction::DeleteBranch { .. } => "delete-branch",
+        GitAction::DeleteTag { .. } => "delete-tag",
+        GitAction::RemoveWorktree { force: true, .. } => "remove-worktree",
+        GitAction::AddIgnoreRule { untrack: true, .. } => "add-ignore-rule",
+        GitAction::StashApply { .. } => "stash-apply",
+        GitAction::StashPop { .. } => "stash-pop",
+        GitAction::StashDrop { .. } => "stash-drop",
+        _ => return None,
+    })
+}
+
+/// Refs the action may move, create or delete besides HEAD's branch.
+fn touched_refs(repo: &Repository, action: &GitAction) -> Vec<String> {
+    let heads = |name: &str| format!("refs/heads/{}", name);
+    match action {
+        GitAction::Checkout { name, .. } => vec![heads(name)],
+        GitAction::DeleteBranch { name, .. } => vec![heads(name)],
+        GitAction::RenameBranch => current_branch(repo)
+            .iter()
+            .map(|f| heads(f))
+            .chain([heads("main")])
+            .collect(),
+        GitAction::RenameBranchTo { from, to, .. } => {
+            let from = from.as_deref().map(String::from).or(current_branch(repo));
+            from.iter().map(|f| heads(f)).chain([heads(to)]).collect()
+        }
+        GitAction::DeleteRemoteBranch { remote, name } => {
+            vec![format!("refs/remotes/{}/{}", &**remote, &**name)]
+        }
+        GitAction::Merge {
+            into: Some(into), ..
+        } => vec![heads(into)],
+        GitAction::Rebase {
+            branch: Some(branch),
+            ..
+        } => vec![heads(branch)],
+        GitAction::DeleteTag { name } => vec![format!("refs/tags/{}", name)],
+        _ => Vec::new(),
+    }
+}
+
+/// Working tree files a snapshot keeps. Only actions that overwrite or
+/// delete them need any, the rest is undone from the refs and the index.
+enum Files {
+    None,
+    /// Changes to these paths, e.g. the file a discard restores.
+    Paths(Vec<String>),
+    /// Every change to a file git knows about, what a hard reset throws
+    /// away.
+    Tracked,
+    /// Untracked files as well, for a working tree that gets deleted.
+    All,
+}
+
+fn files(repo: &Repository, action: &GitAction) -> Files {
+    match action {
+        GitAction::Discard { file } | GitAction::ResolveConflict { file, .. } => {
+            Files::Paths(vec![repo_path(repo, file)])
+        }
+        GitAction::DiscardLines(selection) => Files::Paths(vec![repo_path(repo, &selection.file)]),
+        GitAction::DiscardAll | GitAction::AbortOperation | GitAction::SkipOperation => {
+            Files::Tracked
+        }
+        GitAction::RemoveWorktree { .. } => Files::All,
+        _ => Files::None,
+    }
+}
+
+/// A safety ref `protect` recorded and what went into it, to tell whether
+/// a failed action changed anything.
+pub(crate) struct Guard {
+    name: String,
+    label: &'static str,
+    refs: Vec<String>,
+    stash: Option<usize>,
+    files: Files,
+    /// A linked worktree that is about to be deleted, the snapshot is of it.
+    worktree: Option<(String, PathBuf)>,
+}
+
+/// Records a safety ref before `action` runs if it can lose work. Failing
+/// to record one doesn't stop the action.
+pub(crate) fn protect(repo: &Repository, action: &GitAction) -> Option<Guard> {
+    let label = label(action)?;
+    let (linked, worktree) = match action {
+        GitAction::RemoveWorktree { worktree, .. } => match worktree::open(repo, worktree) {
+            Ok((linked, name, path)) => (Some(linked), Some((name, path))),
+            // Nothing left to lose when its directory is already gone
+            Err(_) => return None,
+        },
+        _ => (None, None),
+    };
+    let target = linked.as_ref().unwrap_or(repo);
+    let guard = Guard {
+        name: String::new(),
+        label,
+        refs: touched_refs(target, action),
+        stash: match action {
+            GitAction::StashPop { index, .. } | GitAction::StashDrop { index } => Some(*index),
+            _ => None,
+        },
+        files: files(target, action),
+        worktree,
+    };
+    let result = next_name(repo).and_then(|name| {
+        let guard = Guard { name, ..guard };
+        let recorded = record(target, &guard)?;
+        prune(repo)?;
+        Ok(recorded.then_some(guard))
+    });
+    match result {
+        Ok(guard) => guard,
+        Err(e) => {
+            println!(
+                "[UNDO] Could not record a safety ref before {}: {}",
+                label, e
+            );
+            None
+        }
+    }
+}
+
+/// After the guarded action failed: drops its safety ref when the action
+/// left everything as it was, keeps it when it got halfway, e.g. stopped
+/// at a conflict, so that can still be undone.
+pub(crate) fn settle(repo: &Repository, guard: &Guard) {
+    let unchanged = match &guard.worktree {
+        Some((_, path)) => Repository::open(path)
+            .map_err(GitError::from)
+            .and_then(|linked| unchanged(&linked, guard)),
+        None => unchanged(repo, guard),
+    };
+    match unchanged {
+        Ok(true) => forget(repo, &guard.name),
+        Ok(false) => {}
+        Err(e) => eprintln!("[UNDO] Keeping {}: {}", guard.name, e),
+    }
+}
+
+fn unchanged(repo: &Repository, guard: &Guard) -> GitResult<bool> {
+    let recorded = repo.find_reference(&guard.name)?.peel_to_commit()?;
+    Ok(snapshot(repo, guard)?.matches(&recorded))
+}
+
+/// Drops a safety ref.
+fn forget(repo: &Repository, name: &str) {
+    if let Ok(mut reference) = repo.find_reference(name) {
+        let _ = reference.delete();
+    }
+}
+
+/// The snapshots `undo` can go back to, newest first.
+pub fn list(repo: &Repository) -> GitResult<Value> {
+    let mut entries = Vec::new();
+    for (number, name) in numbered(repo)?.into_iter().rev() {
+        let commit = repo.find_reference(&name)?.peel_to_commit()?;
+        let snapshot = match Snapshot::parse(&commit) {
+            Ok(snapshot) => snapshot,
+            Err(e) => {
+                eprintln!("[UNDO] Skipping {}: {}", name, e);
+                continue;
+            }
+        };
+        entries.push(json!({
+            "id": number,
+            "ref": name,
+            "action": snapshot.action,
+            "time": commit.time().seconds(),
+            "branch": snapshot.branch(),
+            "head": snapshot.head_commit(repo).map(|id| id.to_string()),
+            "shortHead": snapshot.head_commit(repo).map(|id| short_id(repo, id)),
+        }));
+    }
+    Ok(entries.into())
+}
+
+/// Puts HEAD, the refs the action touched and the index back the way they
+/// were before the last recorded action, an unfinished merge, rebase or
+/// cherry-pick included. Files the action changed go back too, files
+/// changed since then are left alone unless the snapshot kept them. A
+/// deleted worktree is added again.
+pub fn undo(repo: &Repository) -> GitResult<Value> {
+    let (_, name) = numbered(repo)?
+        .pop()
+        .ok_or_else(|| GitError::new(GitErrorKind::NotFound, "Nothing to undo."))?;
+    let commit = repo.find_reference(&name)?.peel_to_commit()?;
+    let snapshot = Snapshot::parse(&commit)?;
+    match &snapshot.worktree {
+        Some((worktree, path)) => {
+            let linked = add_worktree(repo, worktree, path, &snapshot)?;
+            restore(&linked, &linked.find_commit(commit.id())?, &snapshot)?;
+        }
+        None => restore(repo, &commit, &snapshot)?,
+    }
+    forget(repo, &name);
+
+    println!("[UNDO] Undid {} from {}", snapshot.action, name);
+    let refs: Vec<&String> = snapshot.refs.iter().map(|(r, _)| r).collect();
+    Ok(json!({
+        "undone": snapshot.action,
+        "branch": snapshot.branch(),
+        "head": snapshot.head_commit(repo).map(|id| id.to_string()),
+        "refs": refs,
+        "stashes": snapshot.stashes.len(),
+        "worktree": snapshot.worktree.as_ref().map(|(_, path)| path.to_string_lossy()),
+    }))
+}
+
+fn restore(repo: &Repository, commit: &Commit, snapshot: &Snapshot) -> GitResult<()> {
+    let state = commit.parent(0)?.tree()?;
+    let target = commit.tree()?;
+    // Conflicted files hold the markers the action wrote, checkout refuses
+    // to run with them in the index
+    let mut forced = snapshot.files.clone();
+    let mut index = repo.index()?;
+    let mut conflicted = Vec::new();
+    for conflict in index.conflicts()? {
+        let conflict = conflict?;
+        let entry = conflict.our.or(conflict.their).or(conflict.ancestor);
+        conflicted.extend(entry.map(|e| String::from_utf8_lossy(&e.path).into_owned()));
+    }
+    for path in &conflicted {
+        index.conflict_remove(Path::new(path))?;
+    }
+    index.write()?;
+    forced.extend(conflicted);
+
+    // Before HEAD moves, so files still as the action left them count as
+    // unchanged and the ones edited since are skipped
+    let mut checkout = CheckoutBuilder::new();
+    checkout.safe().allow_conflicts(true);
+    repo.checkout_tree(target.as_object(), Some(&mut checkout))?;
+
+    let reflog = format!("undo: {}", snapshot.action);
+    for (refname, id) in &snapshot.refs {
+        match id {
+            Some(id) => {
+                repo.reference(refname, *id, true, &reflog)?;
+            }
+            None => forget(repo, refname),
+        }
+    }
+    match &snapshot.head {
+        Head::Branch(refname) => repo.set_head(refname)?,
+        Head::Detached(id) => repo.set_head_detached(*id)?,
+    }
+    if !forced.is_empty() {
+        let mut checkout = CheckoutBuilder::new();
+        checkout.force().disable_pathspec_match(true);
+        for path in &forced {
+            checkout.path(path);
+        }
+        repo.checkout_tree(target.as_object(), Some(&mut checkout))?;
+    }
+
+    // The raw index brings back conflicts, which a tree can't hold
+    match state.get_name("index") {
+        Some(entry) => {
+            let blob = repo.find_blob(entry.id())?;
+            std::fs::write(repo.path().join("index"), blob.content())
+                .map_err(|e| GitError::other(e.to_string()))?;
+            index.read(true)?;
+        }
+        None => {
+            index.clear()?;
+            index.write()?;
+        }
+    }
+    repo.cleanup_state()?;
+    for entry in state.iter() {
+        let Some(file) = entry.name().filter(|n| STATE_FILES.contains(n)) else {
+            continue;
+        };
+        write_entry(repo, entry.to_object(repo)?, &repo.path().join(file))?;
+    }
+    for (id, message) in &snapshot.stashes {
+        repo.reference_ensure_log("refs/stash")?;
+        repo.reference("refs/stash", *id, true, message)?;
+    }
+    Ok(())
+}
+
+/// Adds a deleted linked worktree again on the branch it had checked out.
+fn add_worktree(
+    repo: &Repository,
+    name: &str,
+    path: &Path,
+    snapshot: &Snapshot,
+) -> GitResult<Repository> {
+    let main = Repository::open(repo.commondir())?;
+    if let Ok(existing) = main.find_worktree(name) {
+        if existing.validate().is_ok() {
+            return Ok(Repository::open_from_worktree(&existing)?);
+        }
+        existing.prune(None)?;
+    }
+    let mut options = WorktreeAddOptions::new();
+    let linked = match &snapshot.head {
+        Head::Branch(refname) => {
+            let reference = main.find_reference(refname)?;
+            options.reference(Some(&reference));
+            let added = main.worktree(name, path, Some(&options))?;
+            Repository::open_from_worktree(&added)?
+        }
+        Head::Detached(_) => {
+            // Without a branch libgit2 creates one named after the worktree
+            let added = main.worktree(name, path, Some(&options))?;
+            let linked = Repository::open_from_worktree(&added)?;
+            linked.set_head_detached(
+                head_commit(&linked)?
+                    .map(|c| c.id())
+                    .unwrap_or_else(Oid::zero),
+            )?;
+            main.find_branch(name, BranchType::Local)?.delete()?;
+            linked
+        }
+    };
+    Ok(linked)
+}
+
+enum Head {
+    Branch(String),
+    Detached(Oid),
+}
+
+/// What a safety ref's commit message records, one `key: value` per line.
+struct Snapshot {
+    action: String,
+    head: Head,
+    /// Ref and where it pointed, `None` when it didn't exist.
+    refs: Vec<(String, Option<Oid>)>,
+    /// Stash entries the action removed.
+    stashes: Vec<(Oid, String)>,
+    /// Working tree files the snapshot's tree has as they were.
+    files: Vec<String>,
+    /// Name and path of the deleted linked worktree the snapshot is of.
+    worktree: Option<(String, PathBuf)>,
+}
+
+impl Snapshot {
+    fn parse(commit: &Commit) -> GitResult<Self> {
+        let corrupt = |line: &str| {
+            GitError::other(format!(
+                "safety ref {} is corrupt at '{}'",
+                commit.id(),
+                line
+            ))
+        };
+        let mut action = None;
+        let mut head = None;
+        let mut snapshot = Snapshot {
+            action: String::new(),
+            head: Head::Detached(Oid::zero()),
+            refs: Vec::new(),
+            stashes: Vec::new(),
+            files: Vec::new(),
+            worktree: None,
+        };
+        let mut worktree_name = None;
+        let message = commit.message().unwrap_or("");
+        for line in message.lines().skip_while(|l| !l.is_empty()).skip(1) {
+            let (key, value) = line.split_once(": ").ok_or_else(|| corrupt(line))?;
+            let oid = |s: &str| match Oid::from_str(s) {
+                Ok(id) if id.to_string() == s => Ok(id),
+                _ => Err(corrupt(line)),
+            };
+            match key {
+                "action" => action = Some(value.to_string()),
+                "head" => {
+                    head = Some(match value.strip_prefix("detached ") {
+                        Some(id) => Head::Detached(oid(id)?),
+                        None => Head::Branch(value.to_string()),
+                    })
+                }
+                "ref" => {
+                    let (refname, id) = value.rsplit_once(' ').ok_or_else(|| corrupt(line))?;
+                    let id = match id {
+                        "none" => None,
+                        id => Some(oid(id)?),
+                    };
+                    snapshot.refs.push((refname.to_string(), id));
+                }
+                "stash" => {
+                    let (id, message) = value.split_once(' ').unwrap_or((value, ""));
+                    snapshot.stashes.push((oid(id)?, message.to_string()));
+                }
+                "file" => snapshot.files.push(value.to_string()),
+                "worktree" => worktree_name = Some(value.to_string()),
+                "worktree-path" => {
+                    let name = worktree_name.take().ok_or_else(|| corrupt(line))?;
+                    snapshot.worktree = Some((name, PathBuf::from(value)));
+                }
+                _ => return Err(corrupt(line)),
+            }
+        }
+        snapshot.action = action.ok_or_else(|| corrupt("action"))?;
+        snapshot.head = head.ok_or_else(|| corrupt("head"))?;
+        Ok(snapshot)
+    }
+
+    fn branch(&self) -> Option<&str> {
+        match &self.head {
+            Head::Branch(refname) => Some(refname.strip_prefix("refs/heads/").unwrap_or(refname)),
+            Head::Detached(_) => None,
+        }
+    }
+
+    fn head_commit(&self, repo: &Repository) -> Option<Oid> {
+        match &self.head {
+            Head::Detached(id) => Some(*id),
+            Head::Branch(refname) => self
+                .refs
+                .iter()
+                .find(|(r, _)| r == refname)
+                .map(|(_, id)| *id)
+                .unwrap_or_else(|| repo.refname_to_id(refname).ok()),
+        }
+    }
+}
+
+/// Files in the git directory that make up an unfinished operation.
+const STATE_FILES: &[&str] = &[
+    "MERGE_HEAD",
+    "MERGE_MSG",
+    "MERGE_MODE",
+    "CHERRY_PICK_HEAD",
+    "REVERT_HEAD",
+    "rebase-merge",
+    "rebase-apply",
+    "sequencer",
+];
+
+/// A safety ref's content before it's written.
+struct Taken<'r> {
+    message: String,
+    state: Tree<'r>,
+    worktree: Tree<'r>,
+    /// Commits the refs, stashes and the unfinished operation point to.
+    keep: Vec<Commit<'r>>,
+}
+
+impl Taken<'_> {
+    fn matches(&self, recorded: &Commit) -> bool {
+        recorded.tree_id() == self.worktree.id()
+            && recorded.message() == Some(self.message.as_str())
+            && recorded.parent(0).map(|p| p.tree_id()).ok() == Some(self.state.id())
+    }
+}
+
+/// Writes the safety ref `guard.name`: a commit of the staged tree with the
+/// files the action may overwrite, whose first parent holds the raw index
+/// and the unfinished operation's files on top of HEAD. Refs, stashes and
+/// incoming commits it mentions are further parents so they can't be
+/// garbage collected. Returns false when nothing changed since the last
+/// safety ref, which is kept instead.
+fn record(repo: &Repository, guard: &Guard) -> GitResult<bool> {
+    let taken = snapshot(repo, guard)?;
+    if let Some((_, last)) = numbered(repo)?.last() {
+        if taken.matches(&repo.find_reference(last)?.peel_to_commit()?) {
+            return Ok(false);
+        }
+    }
+
+    let signature = repo
+        .signature()
+        .or_else(|_| Signature::now("Eternal", "eternal@localhost"))?;
+    let head = head_commit(repo)?;
+    let parents: Vec<&Commit> = head.iter().collect();
+    let state_commit = repo.commit(
+        None,
+        &signature,
+        &signature,
+        &format!("state before {}", guard.label),
+        &taken.state,
+        &parents,
+    )?;
+    let state_commit = repo.find_commit(state_commit)?;
+    let parents: Vec<&Commit> = std::iter::once(&state_commit).chain(&taken.keep).collect();
+    let id = repo.commit(
+        None,
+        &signature,
+        &signature,
+        &taken.message,
+        &taken.worktree,
+        &parents,
+    )?;
+    repo.reference(&guard.name, id, true, &format!("undo: {}", guard.label))?;
+    println!("[UNDO] Recorded {} before {}", guard.name, guard.label);
+    Ok(true)
+}
+
+fn snapshot<'r>(repo: &'r Repository, guard: &Guard) -> GitResult<Taken<'r>> {
+    let head = head_commit(repo)?;
+    let mut message = format!(
+        "undo: {} on {}\n\naction: {}\n",
+        guard.label,
+        current_branch(repo).unwrap_or_else(|| "(no branch)".into()),
+        guard.label
+    );
+    let mut keep = Vec::new();
+
+    let head_ref = repo.find_reference("HEAD")?;
+    let mut all_refs = Vec::new();
+    match head_ref.symbolic_target() {
+        Some(target) => {
+            message.push_str(&format!("head: {}\n", target));
+            all_refs.push(target.to_string());
+        }
+        None => {
+            let id = head.as_ref().map(|h| h.id()).unwrap_or_else(Oid::zero);
+            message.push_str(&format!("head: detached {}\n", id));
+        }
+    }
+    for refname in &guard.refs {
+        if !all_refs.contains(refname) {
+            all_refs.push(refname.clone());
+        }
+    }
+    for refname in &all_refs {
+        let id = repo.refname_to_id(refname).ok();
+        match id {
+            Some(id) => message.push_str(&format!("ref: {} {}\n", refname, id)),
+            None => message.push_str(&format!("ref: {} none\n", refname)),
+        }
+        keep.extend(id.and_then(|id| repo.find_commit(id).ok()));
+    }
+    if let Some(index) = guard.stash {
+        if let Ok(reflog) = repo.reflog("refs/stash") {
+            if let Some(entry) = reflog.get(index) {
+                message.push_str(&format!(
+                    "stash: {} {}\n",
+                    entry.id_new(),
+                    entry.message().unwrap_or("")
+                ));
+                keep.extend(repo.find_commit(entry.id_new()).ok());
+            }
+        }
+    }
+    for id in merge_heads(repo).into_iter().chain(
+        ["CHERRY_PICK_HEAD", "REVERT_HEAD"]
+            .iter()
+            .filter_map(|file| std::fs::read_to_string(repo.path().join(file)).ok())
+            .filter_map(|id| Oid::from_str(id.trim()).ok()),
+    ) {
+        keep.extend(repo.find_commit(id).ok());
+    }
+    let files = changed_files(repo, &guard.files)?;
+    for path in &files {
+        message.push_str(&format!("file: {}\n", path));
+    }
+    if let Some((name, path)) = &guard.worktree {
+        message.push_str(&format!(
+            "worktree: {}\nworktree-path: {}\n",
+            name,
+            path.display()
+        ));
+    }
+
+    let staged = repo.find_tree(index_tree(repo)?)?;
+    let state = repo.find_tree(state_tree(repo, &staged)?)?;
+    let worktree = repo.find_tree(worktree_tree(repo, &staged, &files)?)?;
+    Ok(Taken {
+        message,
+        state,
+        worktree,
+        keep,
+    })
+}
+
+/// `.git/index` as it is, conflicts included, the files of an unfinished
+/// operation and the staged tree, which keeps the staged blobs alive.
+fn state_tree(repo: &Repository, staged: &Tree) -> GitResult<Oid> {
+    let mut builder = repo.treebuilder(None)?;
+    builder.insert("staged", staged.id(), FileMode::Tree.into())?;
+    let index = repo.path().join("index");
+    if index.is_file() {
+        builder.insert("index", repo.blob_path(&index)?, FileMode::Blob.into())?;
+    }
+    for name in STATE_FILES {
+        if let Some((id, mode)) = dir_entry(repo, &repo.path().join(name))? {
+            builder.insert(name, id, mode.into())?;
+        }
+    }
+    Ok(builder.write()?)
+}
+
+fn dir_entry(repo: &Repository, path: &Path) -> GitResult<Option<(Oid, FileMode)>> {
+    if path.is_file() {
+        return Ok(Some((repo.blob_path(path)?, FileMode::Blob)));
+    }
+    if !path.is_dir() {
+        return Ok(None);
+    }
+    let mut builder = repo.treebuilder(None)?;
+    let entries = std::fs::read_dir(path).map_err(|e| GitError::other(e.to_string()))?;
+    for entry in entries.flatten() {
+        if let Some((id, mode)) = dir_entry(repo, &entry.path())? {
+            builder.insert(
+                entry.file_name().to_string_lossy().as_ref(),
+                id,
+                mode.into(),
+            )?;
+        }
+    }
+    Ok(Some((builder.write()?, FileMode::Tree)))
+}
+
+/// Writes a blob as a file or a tree as a directory, the reverse of
+/// `dir_entry`.
+fn write_entry(repo: &Repository, object: git2::Object, path: &Path) -> GitResult<()> {
+    let io = |e: std::io::Error| GitError::other(e.to_string());
+    match object.into_tree() {
+        Ok(tree) => {
+            std::fs::create_dir_all(path).map_err(io)?;
+            for entry in tree.iter() {
+                let name = entry.name().unwrap_or("");
+                write_entry(repo, entry.to_object(repo)?, &path.join(name))?;
+            }
+        }
+        Err(object) => {
+            let blob = object
+                .into_blob()
+                .map_err(|_| GitError::other("unexpected object in safety ref"))?;
+            std::fs::write(path, blob.content()).map_err(io)?;
+        }
+    }
+    Ok(())
+}
+
+/// The staged tree, conflicted files have no entry in it.
+fn index_tree(repo: &Repository) -> GitResult<Oid> {
+    let mut resolved = Index::new()?;
+    for entry in repo.index()?.iter() {
+        // Bits 12-13 of the flags are the stage, 0 outside conflicts
+        if (entry.flags >> 12) & 0x3 == 0 {
+            resolved.add(&entry)?;
+        }
+    }
+    Ok(resolved.write_tree_to(repo)?)
+}
+
+/// Paths with changes in the working tree that `files` asks to keep,
+/// staged new files included as a hard reset deletes them.
+fn changed_files(repo: &Repository, files: &Files) -> GitResult<Vec<String>> {
+    let mut options = StatusOptions::new();
+    options.exclude_submodules(true);
+    match files {
+        Files::None => return Ok(Vec::new()),
+        Files::Paths(paths) => {
+            for path in paths {
+                options.pathspec(path);
+            }
+        }
+        Files::Tracked => {}
+        Files::All => {
+            options.include_untracked(true).recurse_untracked_dirs(true);
+        }
+    }
+    if repo.workdir().is_none() {
+        return Ok(Vec::new());
+    }
+    let mut paths = Vec::new();
+    for entry in repo.statuses(Some(&mut options))?.iter() {
+        if entry.status().intersects(
+            Status::INDEX_NEW
+                | Status::WT_NEW
+                | Status::WT_MODIFIED
+                | Status::WT_DELETED
+                | Status::WT_TYPECHANGE
+                | Status::WT_RENAMED
+                | Status::CONFLICTED,
+        ) {
+            paths.extend(entry.path().map(String::from));
+        }
+    }
+    Ok(paths)
+}
+
+/// The index tree with `files` as they are in the working tree.
+fn worktree_tree(repo: &Repository, index_tree: &Tree, files: &[String]) -> GitResult<Oid> {
+    let Some(workdir) = repo.workdir().filter(|_| !files.is_empty()) else {
+        return Ok(index_tree.id());
+    };
+    let mut update = TreeUpdateBuilder::new();
+    for path in files {
+        match file_blob(repo, &workdir.join(path))? {
+            Some((id, mode)) => update.upsert(path.as_str(), id, mode),
+            None => update.remove(path.as_str()),
+        };
+    }
+    Ok(update.create_updated(repo, index_tree)?)
+}
+
+/// The file as a blob with its mode, `None` if it's gone.
+fn file_blob(repo: &Repository, path: &Path) -> GitResult<Option<(Oid, FileMode)>> {
+    let Ok(metadata) = path.symlink_metadata() else {
+        return Ok(None);
+    };
+    if metadata.file_type().is_symlink() {
+        let target = std::fs::read_link(path).map_err(|e| GitError::other(e.to_string()))?;
+        let id = repo.blob(target.to_string_lossy().as_bytes())?;
+        return Ok(Some((id, FileMode::Link)));
+    }
+    if !metadata.is_file() {
+        return Ok(None);
+    }
+    let id = repo.blob_path(path)?;
+    Ok(Some((id, file_mode(&metadata))))
+}
+
+#[cfg(unix)]
+fn file_mode(metadata: &std::fs::Metadata) -> FileMode {
+    use std::os::unix::fs::PermissionsExt;
+    match metadata.permissions().mode() & 0o111 {
+        0 => FileMode::Blob,
+        _ => FileMode::BlobExecutable,
+    }
+}
+
+#[cfg(not(unix))]
+fn file_mode(_: &std::fs::Metadata) -> FileMode {
+    FileMode::Blob
+}
+
+/// `refs/undo/<n>` refs sorted by `n`.
+fn numbered(repo: &Repository) -> GitResult<Vec<(u64, String)>> {
+    let mut refs = Vec::new();
+    for reference in repo.references_glob(&format!("{}*", PREFIX))?.flatten() {
+        let Some(name) = reference.name() else {
+            continue;
+        };
+        if let Ok(number) = name[PREFIX.len()..].parse::<u64>() {
+            refs.push((number, name.to_string()));
+        }
+    }
+    refs.sort();
+    Ok(refs)
+}
+
+fn next_name(repo: &Repository) -> GitResult<String> {
+    let next = numbered(repo)?.last().map(|(n, _)| n + 1).unwrap_or(1);
+    Ok(format!("{}{}", PREFIX, next))
+}
+
+fn prune(repo: &Repository) -> GitResult<()> {
+    let refs = numbered(repo)?;
+    let excess = refs.len().saturating_sub(KEEP);
+    for (_, name) in refs.into_iter().take(excess) {
+        forget(repo, &name);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git::testing::TestRepo;
+
+    fn undo_refs(t: &TestRepo) -> Vec<String> {
+        numbered(&t.repo)
+            .unwrap()
+            .into_iter()
+            .map(|(_, name)| name)
+            .collect()
+    }
+
+    fn last_snapshot(t: &TestRepo) -> Snapshot {
+        let (_, name) = numbered(&t.repo).unwrap().pop().unwrap();
+        let commit = t
+            .repo
+            .find_reference(&name)
+            .unwrap()
+            .peel_to_commit()
+            .unwrap();
+        Snapshot::parse(&commit).unwrap()
+    }
+
+    #[test]
+    fn snapshot_round_trip() {
+        let t = TestRepo::new();
+        t.write("a.txt", "a\n");
+        t.commit("a");
+        t.write("a.txt", "changed\n");
+        t.write("new.txt", "untracked\n");
+        t.run("discard", json!({ "file": "a.txt" })).unwrap();
+
+        let snapshot = last_snapshot(&t);
+        assert_eq!(snapshot.action, "discard");
+        assert_eq!(snapshot.branch(), Some("main"));
+        assert_eq!(
+            snapshot.refs,
+            [("refs/heads/main".to_string(), Some(t.head()))]
+        );
+        assert_eq!(snapshot.files, ["a.txt"]);
+        assert_eq!(snapshot.head_commit(&t.repo), Some(t.head()));
+        // Only the discarded file is kept, the untracked one isn't hashed
+        let (_, name) = numbered(&t.repo).unwrap().pop().unwrap();
+        let tree = t
+            .repo
+            .find_reference(&name)
+            .unwrap()
+            .peel_to_tree()
+            .unwrap();
+        assert!(tree.get_name("new.txt").is_none());
+        let kept = tree.get_name("a.txt").unwrap().to_object(&t.repo).unwrap();
+        assert_eq!(kept.as_blob().unwrap().content(), b"changed\n");
+
+        let listed = list(&t.repo).unwrap();
+        assert_eq!(listed[0]["action"], "discard");
+        assert_eq!(listed[0]["ref"], name);
+    }
+
+    #[test]
+    fn corrupt_snapshots_are_errors() {
+        let t = TestRepo::new();
+        t.write("a.txt", "a\n");
+        let head = t.repo.find_commit(t.commit("a")).unwrap();
+        let tree = head.tree().unwrap();
+        let signature = crate::git::testing::signature();
+        for message in [
+            "undo: x\n\nhead: refs/heads/main\n",
+            "undo: x\n\naction: x\n",
+            "undo: x\n\naction: x\nhead: detached nope\n",
+            "undo: x\n\naction: x\nhead: refs/heads/main\nref: refs/heads/main 123\n",
+        ] {
+            let id = t
+                .repo
+                .commit(None, &signature, &signature, message, &tree, &[&head])
+                .unwrap();
+            let commit = t.repo.find_commit(id).unwrap();
+            assert!(Snapshot::parse(&commit).is_err(), "{}", message);
+            t.repo.reference("refs/undo/1", id, true, "test").unwrap();
+            assert!(undo(&t.repo).is_err());
+            assert_eq!(list(&t.repo).unwrap(), json!([]));
+        }
+    }
+
+    #[test]
+    fn undoes_a_discard_and_keeps_later_edits() {
+        let t = TestRepo::new();
+        t.write("a.txt", "a\n");
+        t.write("b.txt", "b\n");
+        t.commit("ab");
+        t.write("a.txt", "lost\n");
+        t.run("discard", json!({ "file": "a.txt" })).unwrap();
+        assert_eq!(t.read("a.txt"), "a\n");
+        t.write("b.txt", "later\n");
+
+        let undone = t.run("undo", Value::Null).unwrap();
+        assert_eq!(undone["undone"], "discard");
+        assert_eq!(t.read("a.txt"), "lost\n");
+        assert_eq!(t.read("b.txt"), "later\n");
+        assert!(undo_refs(&t).is_empty());
+    }
+
+    #[test]
+    fn undoes_a_discard_all() {
+        let t = TestRepo::new();
+        t.write("a.txt", "a\n");
+        t.write("b.txt", "b\n");
+        t.commit("ab");
+        t.write("a.txt", "lost a\n");
+        t.remove("b.txt");
+        t.run("discard-all", Value::Null).unwrap();
+        assert_eq!(t.read("b.txt"), "b\n");
+
+        t.run("undo", Value::Null).unwrap();
+        assert_eq!(t.read("a.txt"), "lost a\n");
+        assert!(!t.path().join("b.txt").exists());
+    }
+
+    #[test]
+    fn undoes_a_commit_and_an_unstage() {
+        let t = TestRepo::new();
+        t.write("a.txt", "a\n");
+        let first = t.commit("a");
+        t.write("a.txt", "staged\n");
+        let mut index = t.index();
+        index.add_path(Path::new("a.txt")).unwrap();
+        index.write().unwrap();
+        t.write("a.txt", "staged\nunstaged\n");
+
+        t.run("commit", json!({ "message": "second" })).unwrap();
+        assert_ne!(t.head(), first);
+        t.run("undo", Value::Null).unwrap();
+        assert_eq!(t.head(), first);
+        assert_eq!(t.read("a.txt"), "staged\nunstaged\n");
+        let staged = t.index().get_path(Path::new("a.txt"), 0).unwrap();
+        assert_eq!(t.repo.find_blob(staged.id).unwrap().content(), b"staged\n");
+
+        t.run("unstage-all", Value::Null).unwrap();
+        assert_eq!(
+            t.index().get_path(Path::new("a.txt"), 0).unwrap().id,
+            t.repo
+                .find_commit(first)
+                .unwrap()
+                .tree()
+                .unwrap()
+                .get_name("a.txt")
+                .unwrap()
+                .id()
+        );
+        t.run("undo", Value::Null).unwrap();
+        assert_eq!(
+            t.index().get_path(Path::new("a.txt"), 0).unwrap().id,
+            staged.id
+        );
+    }
+
+    #[test]
+    fn keeps_the_ref_of_an_action_that_stopped_halfway() {
+        let t = TestRepo::new();
+        t.write("c.txt", "base\n");
+        let base = t.commit("base");
+        t.repo
+            .branch("other", &t.repo.find_commit(base).unwrap(), false)
+            .unwrap();
+        t.write("c.txt", "ours\n");
+        let ours = t.commit("ours");
+        t.switch("other");
+        t.write("c.txt", "theirs\n");
+        t.commit("theirs");
+        t.switch("main");
+
+        // Checks out `other`, then fails on the missing branch
+        let err = t
+            .run("merge", json!({ "branch": "missing", "into": "other" }))
+            .unwrap_err();
+        assert_eq!(err.kind, GitErrorKind::NotFound);
+        assert_eq!(undo_refs(&t).len(), 1);
+        t.run("undo", Value::Null).unwrap();
+        assert_eq!(t.repo.head().unwrap().name(), Some("refs/heads/main"));
+        assert_eq!(t.read("c.txt"), "ours\n");
+
+        let merged = t.run("merge", json!({ "branch": "other" })).unwrap();
+        assert_eq!(merged["result"], "conflicts");
+        assert!(t.path().join(".git/MERGE_HEAD").exists());
+        t.run("undo", Value::Null).unwrap();
+        assert_eq!(t.head(), ours);
+        assert_eq!(t.read("c.txt"), "ours\n");
+        assert!(!t.path().join(".git/MERGE_HEAD").exists());
+        assert!(!t.index().has_conflicts());
+        assert!(undo_refs(&t).is_empty());
+    }
+
+    #[test]
+    fn forgets_the_ref_of_an_action_that_changed_nothing() {
+        let t = TestRepo::new();
+        t.write("a.txt", "a\n");
+        t.commit("a");
+        t.write("a.txt", "edit\n");
+        let err = t
+            .run("delete-branch", json!({ "name": "missing" }))
+            .unwrap_err();
+        assert_eq!(err.kind, GitErrorKind::NotFound);
+        assert!(undo_refs(&t).is_empty());
+        let err = t.run("undo", Value::Null).unwrap_err();
+        assert_eq!(err.kind, GitErrorKind::NotFound);
+    }
+
+    #[test]
+    fn nothing_new_to_record() {
+        let t = TestRepo::new();
+        t.write("a.txt", "a\n");
+        t.commit("a");
+        t.run("discard-all", Value::Null).unwrap();
+        t.run("discard-all", Value::Null).unwrap();
+        assert_eq!(undo_refs(&t), ["refs/undo/1"]);
+    }
+
+    #[test]
+    fn brings_back_a_force_removed_worktree() {
+        let t = TestRepo::new();
+        t.write("a.txt", "a\n");
+        t.commit("a");
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("feature");
+        t.run(
+            "add-worktree",
+            json!({ "path": path, "newBranch": "feature" }),
+        )
+        .unwrap();
+        std::fs::write(path.join("a.txt"), "edited\n").unwrap();
+        std::fs::write(path.join("new.txt"), "untracked\n").unwrap();
+        t.run(
+            "remove-worktree",
+            json!({ "worktree": "feature", "force": true }),
+        )
+        .unwrap();
+        assert!(!path.exists());
+
+        let undone = t.run("undo", Value::Null).unwrap();
+        assert_eq!(undone["undone"], "remove-worktree");
+        assert_eq!(
+            std::fs::read_to_string(path.join("a.txt")).unwrap(),
+            "edited\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(path.join("new.txt")).unwrap(),
+            "untracked\n"
+        );
+        let linked = Repository::open(&path).unwrap();
+        assert_eq!(linked.head().unwrap().name(), Some("refs/heads/feature"));
+    }
+}
@@ -0,0 +1,467 @@
+use super::{current_branch, head_commit, short_id, GitError, GitErrorKind, GitResult};
+use git2::{
+    BranchType, Repository, StatusOptions, Worktree, WorktreeAddOptions, WorktreeLockStatus,
+    WorktreePruneOptions,
+};
+use serde_json::{json, Value};
+use std::path::{Path, PathBuf};
+
+/// The main worktree first, then every linked one, like `git worktree list`.
+pub fn list(repo: &Repository) -> GitResult<Value> {
+    let main = main_repo(repo)?;
+    let current = canonical(repo.workdir().unwrap_or_else(|| repo.path()));
+    let mut worktrees = Vec::new();
+    if let Some(workdir) = main.workdir() {
+        let mut entry = describe(workdir);
+        entry["name"] = Value::Null;
+        entry["main"] = true.into();
+        entry["current"] = (canonical(workdir) == current).into();
+        entry["locked"] = false.into();
+        entry["lockReason"] = Value::Null;
+        entry["prunable"] = false.into();
+        worktrees.push(entry);
+    }
+    let mut linked_worktrees = Vec::new();
+    for name in main.worktrees()?.iter().flatten() {
+        let worktree = main.find_worktree(name)?;
+        let mut entry = linked(&worktree)?;
+        entry["current"] = (canonical(worktree.path()) == current).into();
+        linked_worktrees.push(entry);
+    }
+    linked_worktrees.sort_by(|a, b| a["path"].as_str().cmp(&b["path"].as_str()));
+    worktrees.extend(linked_worktrees);
+    Ok(worktrees.into())
+}
+
+/// `git worktree add`. Checks out `branch`, or creates `new_branch` from
+/// `base`. With neither it uses the branch named like the directory,
+/// creating it from `base` if needed.
+pub fn add(
+    repo: &Repository,
+    path: &str,
+    branch: Option<&str>,
+    new_branch: Option<&str>,
+    base: Option<&str>,
+    lock: bool,
+) -> GitResult<Value> {
+    let main = main_repo(repo)?;
+    let path = match Path::new(path) {
+        path if path.is_absolute() => path.to_path_buf(),
+        // Relative to the workspace the request came from
+        path => repo.workdir().unwrap_or_else(|| repo.path()).join(path),
+    };
+    if path.exists()
+        && path
+            .read_dir()
+            .map(|mut d| d.next().is_some())
+            .unwrap_or(true)
+    {
+        return Err(GitError::new(
+            GitErrorKind::AlreadyExists,
+            format!("'{}' already exists", path.display()),
+        ));
+    }
+    let dir_name = path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .ok_or_else(|| GitError::invalid(format!("'{}' is not a valid path", path.display())))?;
+
+    let base_commit = || -> GitResult<git2::Commit<'_>> {
+        match base {
+            Some(base) => Ok(repo.revparse_single(base)?.peel_to_commit()?),
+            None => head_commit(repo)?
+                .ok_or_else(|| GitError::invalid("HEAD has no commits to branch from")),
+        }
+    };
+    // A new branch is only created once everything else checks out
+    let (existing, new_branch) = match (branch, new_branch) {
+        (Some(_), Some(_)) => {
+            return Err(GitError::invalid(
+                "Pass either branch or newBranch, not both.",
+            ))
+        }
+        (Some(name), None) => {
+            let branch = repo.find_branch(name, BranchType::Local).map_err(|_| {
+                GitError::new(
+                    GitErrorKind::NotFound,
+                    format!("branch '{}' not found", name),
+                )
+            })?;
+            (Some(branch), None)
+        }
+        (None, Some(name)) => (None, Some(name)),
+        (None, None) => match repo.find_branch(dir_name, BranchType::Local) {
+            Ok(branch) => (Some(branch), None),
+            Err(_) => (None, Some(dir_name)),
+        },
+    };
+    if let Some(branch) = &existing {
+        if checked_out(&main, branch.get().name())? {
+            return Err(GitError::new(
+                GitErrorKind::AlreadyExists,
+                format!(
+                    "'{}' is already checked out in another worktree",
+                    branch.name()?.unwrap_or("")
+                ),
+            ));
+        }
+    }
+
+    // The admin directory under .git/worktrees takes the directory's name
+    let existing_names: Vec<String> = main
+        .worktrees()?
+        .iter()
+        .flatten()
+        .map(String::from)
+        .collect();
+    let mut name = dir_name.to_string();
+    let mut suffix = 1;
+    while existing_names.contains(&name) {
+        name = format!("{}{}", dir_name, suffix);
+        suffix += 1;
+    }
+
+    let (mut branch, created) = match existing {
+        Some(branch) => (branch, false),
+        None => {
+            let new_branch = new_branch.unwrap_or(dir_name);
+            (repo.branch(new_branch, &base_commit()?, false)?, true)
+        }
+    };
+    let branch_name = branch.name()?.unwrap_or("").to_string();
+    let added = {
+        let mut options = WorktreeAddOptions::new();
+        options.lock(lock).reference(Some(branch.get()));
+        main.worktree(&name, &path, Some(&options))
+    };
+    let worktree = match added {
+        Ok(worktree) => worktree,
+        Err(e) => {
+            // Don't leave the branch behind for a worktree that doesn't exist
+            if created {
+                if let Err(cleanup) = branch.delete() {
+                    eprintln!(
+                        "[WORKTREE] Could not delete branch {}: {}",
+                        branch_name, cleanup
+                    );
+                }
+            }
+            return Err(e.into());
+        }
+    };
+    println!(
+        "[WORKTREE] Added {} at {} on {}",
+        name,
+        path.display(),
+        branch_name
+    );
+    linked(&worktree)
+}
+
+pub fn lock(repo: &Repository, worktree: &str, reason: Option<&str>) -> GitResult<Value> {
+    let worktree = find(repo, worktree)?;
+    if let WorktreeLockStatus::Locked(_) = worktree.is_locked()? {
+        return Err(GitError::new(
+            GitErrorKind::Locked,
+            format!("'{}' is already locked", worktree.path().display()),
+        ));
+    }
+    worktree.lock(reason)?;
+    linked(&worktree)
+}
+
+pub fn unlock(repo: &Repository, worktree: &str) -> GitResult<Value> {
+    let worktree = find(repo, worktree)?;
+    if let WorktreeLockStatus::Unlocked = worktree.is_locked()? {
+        return Err(GitError::invalid(format!(
+            "'{}' is not locked",
+            worktree.path().display()
+        )));
+    }
+    worktree.unlock()?;
+    linked(&worktree)
+}
+
+/// `git worktree remove`. Refuses dirty or locked worktrees unless forced.
+pub fn remove(repo: &Repository, worktree: &str, force: bool) -> GitResult<Value> {
+    let worktree = find(repo, worktree)?;
+    let name = worktree.name().unwrap_or("").to_string();
+    if !force {
+        if let WorktreeLockStatus::Locked(reason) = worktree.is_locked()? {
+            return Err(GitError::new(
+                GitErrorKind::Locked,
+                match reason.filter(|r| !r.is_empty()) {
+                    Some(reason) => format!("'{}' is locked: {}", name, reason),
+                    None => format!("'{}' is locked", name),
+                },
+            ));
+        }
+        if worktree.validate().is_ok() && is_dirty(worktree.path()) == Some(true) {
+            return Err(GitError::new(
+                GitErrorKind::DirtyWorkingTree,
+                format!(
+                    "'{}' contains modified or untracked files, use force to delete it",
+                    name
+                ),
+            ));
+        }
+    }
+    let mut options = WorktreePruneOptions::new();
+    options.valid(true).locked(force).working_tree(true);
+    worktree.prune(Some(&mut options))?;
+    println!("[WORKTREE] Removed {}", name);
+    Ok(json!({ "name": name, "path": worktree.path().to_string_lossy() }))
+}
+
+/// `git worktree prune`: forgets worktrees whose directory is gone.
+pub fn prune(repo: &Repository) -> GitResult<Value> {
+    let main = main_repo(repo)?;
+    let mut pruned = Vec::new();
+    for name in main.worktrees()?.iter().flatten() {
+        let worktree = main.find_worktree(name)?;
+        if worktree.is_prunable(None)? {
+            worktree.prune(None)?;
+            pruned.push(name.to_string());
+        }
+    }
+    println!("[WORKTREE] Pruned {:?}", pruned);
+    Ok(json!({ "pruned": pruned }))
+}
+
+/// Worktrees live in the main repository, whichever one the request is for.
+fn main_repo(repo: &Repository) -> GitResult<Repository> {
+    Ok(Repository::open(repo.commondir())?)
+}
+
+/// A linked worktree's repository with its name and path.
+pub(crate) fn open(repo: &Repository, worktree: &str) -> GitResult<(Repository, String, PathBuf)> {
+    let found = find(repo, worktree)?;
+    let linked = Repository::open_from_worktree(&found)?;
+    Ok((
+        linked,
+        found.name().unwrap_or("").to_string(),
+        found.path().to_path_buf(),
+    ))
+}
+
+/// A linked worktree by name or by path.
+fn find(repo: &Repository, worktree: &str) -> GitResult<Worktree> {
+    let main = main_repo(repo)?;
+    if let Ok(found) = main.find_worktree(worktree) {
+        return Ok(found);
+    }
+    let wanted = canonical(&repo.workdir().unwrap_or_else(|| repo.path()).join(worktree));
+    for name in main.worktrees()?.iter().flatten() {
+        let found = main.find_worktree(name)?;
+        if canonical(found.path()) == wanted {
+            return Ok(found);
+        }
+    }
+    Err(GitError::new(
+        GitErrorKind::NotFound,
+        format!("'{}' is not a working tree", worktree),
+    ))
+}
+
+/// Whether the main worktree or any linked one has `refname` checked out.
+fn checked_out(main: &Repository, refname: Option<&str>) -> GitResult<bool> {
+    let Some(refname) = refname else {
+        return Ok(false);
+    };
+    let on_branch = |repo: &Repository| {
+        repo.find_reference("HEAD")
+            .ok()
+            .is_some_and(|head| head.symbolic_target() == Some(refname))
+    };
+    if on_branch(main) {
+        return Ok(true);
+    }
+    for name in main.worktrees()?.iter().flatten() {
+        let worktree = main.find_worktree(name)?;
+        if let Ok(repo) = Repository::open_from_worktree(&worktree) {
+            if on_branch(&repo) {
+                return Ok(true);
+            }
+        }
+    }
+    Ok(false)
+}
+
+fn linked(worktree: &Worktree) -> GitResult<Value> {
+    let (locked, reason) = match worktree.is_locked()? {
+        WorktreeLockStatus::Locked(reason) => (true, reason.filter(|r| !r.is_empty())),
+        WorktreeLockStatus::Unlocked => (false, None),
+    };
+    let mut entry = describe(worktree.path());
+    entry["name"] = worktree.name().into();
+    entry["main"] = false.into();
+    entry["current"] = false.into();
+    entry["locked"] = locked.into();
+    entry["lockReason"] = reason.into();
+    entry["prunable"] = worktree.is_prunable(None)?.into();
+    Ok(entry)
+}
+
+/// Path, HEAD, branch and dirty state of the checkout at `path`. Everything
+/// but the path is null when it can't be opened, e.g. it was deleted.
+fn describe(path: &Path) -> Value {
+    let mut entry = json!({
+        "path": path.to_string_lossy().trim_end_matches(['/', '\\']),
+        "head": null,
+        "shortHead": null,
+        "branch": null,
+        "detached": null,
+        "dirty": null,
+    });
+    let Ok(repo) = Repository::open(path) else {
+        return entry;
+    };
+    if let Ok(Some(head)) = head_commit(&repo) {
+        entry["head"] = head.id().to_string().into();
+        entry["shortHead"] = short_id(&repo, head.id()).into();
+    }
+    entry["branch"] = current_branch(&repo).into();
+    entry["detached"] = repo.head_detached().unwrap_or(false).into();
+    entry["dirty"] = is_dirty(path).into();
+    entry
+}
+
+/// Modified, staged or untracked files, ignoring ignored ones.
+fn is_dirty(path: &Path) -> Option<bool> {
+    let repo = Repository::open(path).ok()?;
+    let mut options = StatusOptions::new();
+    options
+        .include_untracked(true)
+        .include_ignored(false)
+        .exclude_submodules(true);
+    let statuses = repo.statuses(Some(&mut options)).ok()?;
+    Some(!statuses.is_empty())
+}
+
+fn canonical(path: &Path) -> PathBuf {
+    path.canonicalize().unwrap_or_else(|_| path.to_path_buf())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git::testing::TestRepo;
+
+    fn repo() -> (TestRepo, tempfile::TempDir) {
+        let t = TestRepo::new();
+        t.write("a.txt", "a\n");
+        t.commit("first");
+        (t, tempfile::tempdir().unwrap())
+    }
+
+    fn at(dir: &tempfile::TempDir, name: &str) -> String {
+        dir.path().join(name).to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn adds_and_lists_worktrees() {
+        let (t, dir) = repo();
+        let added = add(&t.repo, &at(&dir, "feature"), None, None, None, false).unwrap();
+        assert_eq!(added["name"], "feature");
+        assert_eq!(added["branch"], "feature");
+        assert_eq!(added["head"], t.head().to_string());
+        assert!(t.repo.find_branch("feature", BranchType::Local).is_ok());
+
+        add(
+            &t.repo,
+            &at(&dir, "other"),
+            None,
+            Some("topic"),
+            Some("HEAD"),
+            true,
+        )
+        .unwrap();
+        let listed = list(&t.repo).unwrap();
+        let listed = listed.as_array().unwrap();
+        assert_eq!(listed.len(), 3);
+        assert_eq!(listed[0]["main"], true);
+        assert_eq!(listed[0]["current"], true);
+        assert_eq!(listed[0]["branch"], "main");
+        assert_eq!(listed[2]["branch"], "topic");
+        assert_eq!(listed[2]["locked"], true);
+    }
+
+    #[test]
+    fn refuses_a_checked_out_branch() {
+        let (t, dir) = repo();
+        let err = add(&t.repo, &at(&dir, "wt"), Some("main"), None, None, false).unwrap_err();
+        assert_eq!(err.kind, GitErrorKind::AlreadyExists);
+        let err = add(
+            &t.repo,
+            &at(&dir, "wt"),
+            Some("main"),
+            Some("x"),
+            None,
+            false,
+        )
+        .unwrap_err();
+        assert_eq!(err.kind, GitErrorKind::InvalidRequest);
+        let err = add(&t.repo, &at(&dir, "wt"), Some("gone"), None, None, false).unwrap_err();
+        assert_eq!(err.kind, GitErrorKind::NotFound);
+    }
+
+    #[test]
+    fn failed_add_deletes_the_new_branch() {
+        let (t, dir) = repo();
+        std::fs::write(dir.path().join("file"), "not a directory").unwrap();
+        let path = at(&dir, "file/wt");
+        assert!(add(&t.repo, &path, None, Some("topic"), None, false).is_err());
+        assert!(t.repo.find_branch("topic", BranchType::Local).is_err());
+
+        // An existing branch stays
+        let head = t.repo.find_commit(t.head()).unwrap();
+        t.repo.branch("kept", &head, false).unwrap();
+        assert!(add(&t.repo, &path, Some("kept"), None, None, false).is_err());
+        assert!(t.repo.find_branch("kept", BranchType::Local).is_ok());
+    }
+
+    #[test]
+    fn lock_unlock_and_remove() {
+        let (t, dir) = repo();
+        let path = at(&dir, "wt");
+        add(&t.repo, &path, None, None, None, false).unwrap();
+
+        let locked = lock(&t.repo, "wt", Some("on a usb stick")).unwrap();
+        assert_eq!(locked["lockReason"], "on a usb stick");
+        assert_eq!(
+            lock(&t.repo, "wt", None).unwrap_err().kind,
+            GitErrorKind::Locked
+        );
+        assert_eq!(
+            remove(&t.repo, "wt", false).unwrap_err().kind,
+            GitErrorKind::Locked
+        );
+        unlock(&t.repo, &path).unwrap();
+        assert_eq!(
+            unlock(&t.repo, "wt").unwrap_err().kind,
+            GitErrorKind::InvalidRequest
+        );
+
+        std::fs::write(Path::new(&path).join("new.txt"), "new\n").unwrap();
+        let err = remove(&t.repo, "wt", false).unwrap_err();
+        assert_eq!(err.kind, GitErrorKind::DirtyWorkingTree);
+        remove(&t.repo, "wt", true).unwrap();
+        assert!(!Path::new(&path).exists());
+        assert_eq!(list(&t.repo).unwrap().as_array().unwrap().len(), 1);
+        assert_eq!(
+            remove(&t.repo, "wt", true).unwrap_err().kind,
+            GitErrorKind::NotFound
+        );
+    }
+
+    #[test]
+    fn prunes_deleted_worktrees() {
+        let (t, dir) = repo();
+        let path = at(&dir, "wt");
+        add(&t.repo, &path, None, None, None, false).unwrap();
+        std::fs::remove_dir_all(&path).unwrap();
+        assert_eq!(list(&t.repo).unwrap()[1]["prunable"], true);
+        assert_eq!(prune(&t.repo).unwrap()["pruned"], json!(["wt"]));
+        assert_eq!(list(&t.repo).unwrap().as_array().unwrap().len(), 1);
+    }
+}
@@ -1,3 +1,5 @@
+mod git;
+
 use notify::{Config, RecommendedWatcher, RecursiveMode, Watcher};
 use serde::{Deserialize, Serialize};
 use std::ffi::OsStr;
@@ -5,6 +7,7 @@ use std::fs;
 use std::os::windows::process::CommandExt;
 use std::path::PathBuf;
 use std::process::Command;
+use std::sync::Arc;
 use std::{path::Path, sync::mpsc::channel, time::Duration};
 use tauri::{AppHandle, Emitter};
 
@@ -78,13 +81,19 @@ async fn get_user_access_token(user_id: String, sk: String) -> Result<String, St
     let parsed: Result<OAuthTokenResponse, _> = serde_json::from_str(&raw);
 
     let body = match parsed {
-        Ok(body) => {
-            println!("Parsed JSON: {:?}", body);
-            body
-        }
+        // The body holds the token, so only say where parsing failed
+        Ok(body) => body,
         Err(e) => {
-            println!("\n❌ JSON PARSE ERROR:\n{}\n", e);
-            return Err(format!("JSON parse error: {}", e));
+            println!(
+                "\n❌ JSON PARSE ERROR at line {} column {}\n",
+                e.line(),
+                e.column()
+            );
+            return Err(format!(
+                "JSON parse error at line {} column {}",
+                e.line(),
+                e.column()
+            ));
         }
     };
     let token = body
@@ -93,7 +102,8 @@ async fn get_user_access_token(user_id: String, sk: String) -> Result<String, St
         .ok_or("No OAuth token returned")?
         .token
         .clone();
-    println!("Access token: {}", token);
+    // Git operations on github.com authenticate with it from now on
+    git::set_token("github.com", None, Some(token.clone()));
     return Ok(token);
 }
 
@@ -200,815 +210,147 @@ async fn watch_workspace(path: String, app: AppHandle) -> Result<(), String> {
 async fn git_command(
     action: String,
     payload: serde_json::Value,
-) -> Result<serde_json::Value, String> {
-    let maybe_path = payload["workspace"].as_str();
-    let path = match maybe_path {
-        Some(p) if !p.trim().is_empty() => p,
-        _ => {
-            return Err("Missing workspace path".into());
-        }
-    };
-    println!("Running git command: {} in {}", action, path);
-    if action == "init" {
-        let out = Command::new("git")
-            .arg("init")
-            .current_dir(path)
-            .creation_flags(0x08000000)
-            .output()
-            .map_err(|e| e.to_string())?;
-        println!("{}", String::from_utf8_lossy(&out.stdout));
-        return Ok(serde_json::json!({
-            "stdout": String::from_utf8_lossy(&out.stdout),
-            "stderr": String::from_utf8_lossy(&out.stderr)
-        }));
-    }
-    if action == "status" {
-        let out = Command::new("git")
-            .arg("status")
-            .arg("--porcelain")
-            .arg("--ignored")
-            .current_dir(path)
-            .creation_flags(0x08000000)
-            .output()
-            .map_err(|e| e.to_string())?;
-        if !out.status.success() {
-            return Err(format!(
-                "Git command failed (exit {}): {}\n{}",
-                out.status.code().unwrap_or(-1),
-                String::from_utf8_lossy(&out.stderr),
-                String::from_utf8_lossy(&out.stdout)
-            ));
-        }
-        let text = String::from_utf8_lossy(&out.stdout);
-        let mut staged = vec![];
-        let mut unstaged = vec![];
-        let mut untracked = vec![];
-        let mut ignored = vec![]; // <---- NEW VECTOR
-        for line in text.lines() {
-            if line.len() < 3 {
-                continue;
-            }
-            let status_code = &line[0..2];
-            let file = line[3..].to_string();
-            match status_code {
-                "M " | "A " | "D " => {
-                    let status = if status_code == "M " {
-                        "M"
-                    } else if status_code == "A " {
-                        "A"
-                    } else {
-                        "D"
-                    };
-                    staged.push(serde_json::json!({
-                        "path": file,
-                        "status": status
-                    }));
-                }
-                " M" | " D" | "MM" | "AM" => {
-                    let status = if status_code == " M" {
-                        "M"
-                    } else if status_code == " D" {
-                        "D"
-                    } else {
-                        "M"
-                    };
-                    unstaged.push(serde_json::json!({
-                        "path": file,
-                        "status": status
-                    }));
-                }
-
-                "??" => {
-                    untracked.push(serde_json::json!({
-                        "path": file,
-                        "status": "U"
-                    }));
-                }
-                "!!" => {
-                    ignored.push(serde_json::json!({
-                        "path": file
-                    }));
-                }
+) -> Result<serde_json::Value, git::GitError> {
+    let request = git::GitRequest::parse(&action, payload)?;
+    tokio::task::spawn_blocking(move || git::run(request)).await?
+}
 
-                _ => {}
-            }
-        }
-        let branch_out = Command::new("git")
-            .args(["rev-parse", "--abbrev-ref", "HEAD"])
-            .current_dir(path)
-            .creation_flags(0x08000000)
-            .output();
+#[tauri::command]
+async fn git_diff(request: git::DiffRequest) -> Result<Vec<git::FileDiff>, git::GitError> {
+    tokio::task::spawn_blocking(move || git::diff(request)).await?
+}
 
-        let branch = match branch_out {
-            Ok(b) if b.status.success() => String::from_utf8_lossy(&b.stdout).trim().to_string(),
-            _ => "master".to_string(),
-        };
-        let origin_out = Command::new("git")
-            .args(["remote", "get-url", "origin"])
-            .current_dir(path)
-            .creation_flags(0x08000000)
-            .output()
-            .ok();
-        let origin = origin_out
-            .and_then(|o| {
-                if o.status.success() {
-                    Some(String::from_utf8_lossy(&o.stdout).trim().to_string())
-                } else {
-                    None
+#[tauri::command]
+async fn git_blame(
+    request: git::BlameRequest,
+    app: AppHandle,
+) -> Result<Vec<git::BlameLine>, git::GitError> {
+    tokio::task::spawn_blocking(move || {
+        let mut all = Vec::new();
+        git::blame(&request, |lines, done| match &request.request_id {
+            Some(request_id) => {
+                let chunk = git::BlameChunk {
+                    request_id: request_id.clone(),
+                    lines,
+                    done,
+                };
+                if let Err(e) = app.emit("git-blame-chunk", chunk) {
+                    eprintln!("[BLAME] Failed to emit git-blame-chunk: {}", e);
                 }
-            })
-            .unwrap_or_default();
-        return Ok(serde_json::json!({
-            "staged": staged,
-            "unstaged": unstaged,
-            "untracked": untracked,
-            "ignored": ignored,
-            "branch": branch,
-            "origin": origin
-        }));
-    }
-    if action == "file_status" {
-        let file: String = payload
-            .get("file")
-            .and_then(|v| v.as_str())
-            .ok_or("Missing file field")?
-            .to_string();
-        let out = Command::new("git")
-            .args(["status", "--porcelain", &file])
-            .current_dir(path)
-            .creation_flags(0x08000000)
-            .output()
-            .map_err(|e| e.to_string())?;
-        if !out.status.success() {
-            return Err(format!(
-                "Git file_status failed (exit {}): {}\n{}",
-                out.status.code().unwrap_or(-1),
-                String::from_utf8_lossy(&out.stderr),
-                String::from_utf8_lossy(&out.stdout)
-            ));
-        }
-        let text = String::from_utf8_lossy(&out.stdout);
-        let mut git_state = "";
-        for line in text.lines() {
-            if line.starts_with("??") {
-                git_state = "U"; // untracked
-                continue;
-            }
-            // Git porcelain XY format
-            let chars: Vec<char> = line.chars().collect();
-            if chars.len() < 2 {
-                continue;
-            }
-            let x = chars[0];
-            let y = chars[1];
-            if x == 'D' || y == 'D' {
-                git_state = "D";
-                continue;
-            }
-            if x == 'A' {
-                git_state = "A";
             }
-            if y == 'M' || x == 'M' {
-                git_state = "M";
-            }
-        }
-        return Ok(serde_json::json!({ "status": git_state }));
-    }
-    if action == "sync-status" {
-        // First fetch to update remote refs
-        let fetch_result = Command::new("git")
-            .args(["fetch", "--quiet"])
-            .current_dir(path)
-            .creation_flags(0x08000000)
-            .output();
-
-        // Get current branch
-        let current_branch = Command::new("git")
-            .args(["rev-parse", "--abbrev-ref", "HEAD"])
-            .current_dir(path)
-            .creation_flags(0x08000000)
-            .output()
-            .map_err(|e| e.to_string())?;
-
-        let current_branch_name = String::from_utf8_lossy(&current_branch.stdout).trim().to_string();
-
-        // Check if upstream exists
-        let upstream_check = Command::new("git")
-            .args(["rev-parse", "--abbrev-ref", "@{u}"])
-            .current_dir(path)
-            .creation_flags(0x08000000)
-            .output()
-            .map_err(|e| e.to_string())?;
-
-        let has_upstream = upstream_check.status.success();
-        let upstream_name = if has_upstream {
-            String::from_utf8_lossy(&upstream_check.stdout).trim().to_string()
-        } else {
-            "none".to_string()
-        };
-
-        println!("[SYNC-STATUS] Branch: {}, Upstream: {}, Fetch success: {}",
-                 current_branch_name, upstream_name, fetch_result.is_ok());
-
-        if !has_upstream {
-            // No upstream set, return zeros
-            return Ok(serde_json::json!({
-                "ahead": 0,
-                "behind": 0
-            }));
-        }
-
-        // Check commits ahead (to push)
-        let ahead = Command::new("git")
-            .args(["rev-list", "--count", "@{u}..HEAD"])
-            .current_dir(path)
-            .creation_flags(0x08000000)
-            .output()
-            .map_err(|e| e.to_string())?;
-
-        // Check commits behind (to pull)
-        let behind = Command::new("git")
-            .args(["rev-list", "--count", "HEAD..@{u}"])
-            .current_dir(path)
-            .creation_flags(0x08000000)
-            .output()
-            .map_err(|e| e.to_string())?;
-
-        let ahead_count = String::from_utf8_lossy(&ahead.stdout)
-            .trim()
-            .parse::<i32>()
-            .unwrap_or(0);
-        let behind_count = String::from_utf8_lossy(&behind.stdout)
-            .trim()
-            .parse::<i32>()
-            .unwrap_or(0);
-
-        println!("[SYNC-STATUS] Ahead: {}, Behind: {}", ahead_count, behind_count);
-
-        return Ok(serde_json::json!({
-            "ahead": ahead_count,
-            "behind": behind_count
-        }));
-    }
-    if action == "stage" {
-        let file_path = payload["file"].as_str().ok_or("file must be a string")?;
-
-        let out = Command::new("git")
-            .arg("add")
-            .arg(file_path)
-            .current_dir(path)
-            .creation_flags(0x08000000)
-            .output()
-            .map_err(|e| e.to_string())?;
-        if !out.status.success() {
-            return Err(format!(
-                "Git command failed (exit {}): {}\n{}",
-                out.status.code().unwrap_or(-1),
-                String::from_utf8_lossy(&out.stderr),
-                String::from_utf8_lossy(&out.stdout)
-            ));
-        }
-        println!("{}", String::from_utf8_lossy(&out.stdout));
-        return Ok(serde_json::json!({
-            "stdout": String::from_utf8_lossy(&out.stdout),
-            "stderr": String::from_utf8_lossy(&out.stderr)
-        }));
-    }
-
-    if action == "stage-all" {
-        let out = Command::new("git")
-            .arg("add")
-            .arg(".")
-            .current_dir(path)
-            .creation_flags(0x08000000)
-            .output()
-            .map_err(|e| e.to_string())?;
-        if !out.status.success() {
-            return Err(format!(
-                "Git command failed (exit {}): {}\n{}",
-                out.status.code().unwrap_or(-1),
-                String::from_utf8_lossy(&out.stderr),
-                String::from_utf8_lossy(&out.stdout)
-            ));
-        }
-        println!("{}", String::from_utf8_lossy(&out.stdout));
-        return Ok(serde_json::json!({
-            "stdout": String::from_utf8_lossy(&out.stdout),
-            "stderr": String::from_utf8_lossy(&out.stderr)
-        }));
-    }
-
-    if action == "unstage-all" {
-        let out = Command::new("git")
-            .arg("reset")
-            .current_dir(path)
-            .creation_flags(0x08000000)
-            .output()
-            .map_err(|e| e.to_string())?;
-        if !out.status.success() {
-            return Err(format!(
-                "Git command failed (exit {}): {}\n{}",
-                out.status.code().unwrap_or(-1),
-                String::from_utf8_lossy(&out.stderr),
-                String::from_utf8_lossy(&out.stdout)
-            ));
-        }
-        println!("{}", String::from_utf8_lossy(&out.stdout));
-        return Ok(serde_json::json!({
-            "stdout": String::from_utf8_lossy(&out.stdout),
-            "stderr": String::from_utf8_lossy(&out.stderr)
-        }));
-    }
-    if action == "discard" {
-        let file_path = payload["file"].as_str().ok_or("file must be a string")?;
-        let out = Command::new("git")
-            .arg("restore")
-            .arg(file_path)
-            .current_dir(path)
-            .creation_flags(0x08000000)
-            .output()
-            .map_err(|e| e.to_string())?;
-        if !out.status.success() {
-            return Err(format!(
-                "Git command failed (exit {}): {}\n{}",
-                out.status.code().unwrap_or(-1),
-                String::from_utf8_lossy(&out.stderr),
-                String::from_utf8_lossy(&out.stdout)
-            ));
-        }
-        println!("{}", String::from_utf8_lossy(&out.stdout));
-        return Ok(serde_json::json!({
-            "stdout": String::from_utf8_lossy(&out.stdout),
-            "stderr": String::from_utf8_lossy(&out.stderr)
-        }));
-    }
-    if action == "discard-all" {
-        let out = Command::new("git")
-            .arg("restore")
-            .arg(".")
-            .current_dir(path)
-            .creation_flags(0x08000000)
-            .output()
-            .map_err(|e| e.to_string())?;
-        if !out.status.success() {
-            return Err(format!(
-                "Git command failed (exit {}): {}\n{}",
-                out.status.code().unwrap_or(-1),
-                String::from_utf8_lossy(&out.stderr),
-                String::from_utf8_lossy(&out.stdout)
-            ));
-        }
-        println!("{}", String::from_utf8_lossy(&out.stdout));
-        return Ok(serde_json::json!({
-            "stdout": String::from_utf8_lossy(&out.stdout),
-            "stderr": String::from_utf8_lossy(&out.stderr)
-        }));
-    }
-    if action == "unstage" {
-        let file_path = payload["file"].as_str().ok_or("file must be a string")?;
-        println!("Unstaging file: {:?}", file_path);
-        // Check if HEAD exists
-        let head_exists = Command::new("git")
-            .args(["rev-parse", "--verify", "HEAD"])
-            .current_dir(path)
-            .creation_flags(0x08000000)
-            .output()
-            .map(|out| out.status.success())
-            .unwrap_or(false);
-        let out = if head_exists {
-            // Repo has commits → safe to use restore
-            Command::new("git")
-                .args(["restore", "--staged", file_path])
-                .current_dir(path)
-                .creation_flags(0x08000000)
-                .output()
-        } else {
-            // Repo has no commits yet → use rm --cached fallback
-            Command::new("git")
-                .args(["rm", "--cached", file_path])
-                .current_dir(path)
-                .creation_flags(0x08000000)
-                .output()
-        }
-        .map_err(|e| e.to_string())?;
-        if !out.status.success() {
-            return Err(format!(
-                "Git command failed (exit {}): {}\n{}",
-                out.status.code().unwrap_or(-1),
-                String::from_utf8_lossy(&out.stderr),
-                String::from_utf8_lossy(&out.stdout)
-            ));
-        }
-
-        println!("Unstaged successfully: {}", file_path);
-        return Ok(serde_json::json!({
-            "stdout": String::from_utf8_lossy(&out.stdout),
-            "stderr": String::from_utf8_lossy(&out.stderr)
-        }));
-    }
-    if action == "commit" {
-        let out = Command::new("git")
-            .arg("commit")
-            .arg("-m")
-            .arg(payload["message"].as_str().unwrap_or(""))
-            .current_dir(path)
-            .creation_flags(0x08000000)
-            .output()
-            .map_err(|e| e.to_string())?;
-        if !out.status.success() {
-            return Err(format!(
-                "Git command failed (exit {}): {}\n{}",
-                out.status.code().unwrap_or(-1),
-                String::from_utf8_lossy(&out.stderr),
-                String::from_utf8_lossy(&out.stdout)
-            ));
-        }
-        println!("{}", String::from_utf8_lossy(&out.stdout));
-        return Ok(serde_json::json!({
-            "stdout": String::from_utf8_lossy(&out.stdout),
-            "stderr": String::from_utf8_lossy(&out.stderr)
-        }));
-    }
-    if action == "renamebranch" {
-        let out = Command::new("git")
-            .arg("branch")
-            .arg("-M")
-            .arg("main")
-            .current_dir(path)
-            .creation_flags(0x08000000)
-            .output()
-            .map_err(|e| e.to_string())?;
-        if !out.status.success() {
-            return Err(format!(
-                "Git command failed (exit {}): {}\n{}",
-                out.status.code().unwrap_or(-1),
-                String::from_utf8_lossy(&out.stderr),
-                String::from_utf8_lossy(&out.stdout)
-            ));
-        }
-        println!("{}", String::from_utf8_lossy(&out.stdout));
-        return Ok(serde_json::json!({
-            "stdout": String::from_utf8_lossy(&out.stdout),
-            "stderr": String::from_utf8_lossy(&out.stderr)
-        }));
-    }
-    if action == "push" {
-        let remote = payload["remote"].as_str().unwrap_or("origin");
-        let branch = payload["branch"].as_str().unwrap_or("master");
-
-        println!("[PUSH] Pushing to {}/{} with upstream tracking", remote, branch);
-
-        let out = Command::new("git")
-            .arg("push")
-            .arg("-u")
-            .arg(remote)
-            .arg(branch)
-            .current_dir(path)
-            .creation_flags(0x08000000)
-            .output()
-            .map_err(|e| e.to_string())?;
-        if !out.status.success() {
-            return Err(format!(
-                "Git command failed (exit {}): {}\n{}",
-                out.status.code().unwrap_or(-1),
-                String::from_utf8_lossy(&out.stderr),
-                String::from_utf8_lossy(&out.stdout)
-            ));
-        }
-
-        // Verify upstream was set
-        let verify_upstream = Command::new("git")
-            .args(["rev-parse", "--abbrev-ref", "@{u}"])
-            .current_dir(path)
-            .creation_flags(0x08000000)
-            .output()
-            .map_err(|e| e.to_string())?;
-
-        if verify_upstream.status.success() {
-            let upstream_stdout = String::from_utf8_lossy(&verify_upstream.stdout);
-            let upstream = upstream_stdout.trim();
-            println!("[PUSH] Upstream set to: {}", upstream);
-        } else {
-            println!("[PUSH] Warning: Upstream not set after push");
-        }
-
-        println!("{}", String::from_utf8_lossy(&out.stdout));
-        return Ok(serde_json::json!({
-            "stdout": String::from_utf8_lossy(&out.stdout),
-            "stderr": String::from_utf8_lossy(&out.stderr)
-        }));
-    }
-    if action == "branch" {
-        let out = Command::new("git")
-            .arg("branch")
-            .current_dir(path)
-            .creation_flags(0x08000000)
-            .output()
-            .map_err(|e| e.to_string())?;
-
-        if !out.status.success() {
-            return Err(format!(
-                "Git command failed (exit {}): {}\n{}",
-                out.status.code().unwrap_or(-1),
-                String::from_utf8_lossy(&out.stderr),
-                String::from_utf8_lossy(&out.stdout)
-            ));
-        }
-
-        return Ok(serde_json::json!({
-            "stdout": String::from_utf8_lossy(&out.stdout),
-            "stderr": String::from_utf8_lossy(&out.stderr)
-        }));
-    }
-    if action == "create branch" {
-        let out = Command::new("git")
-            .arg("checkout")
-            .arg("-b")
-            .arg(payload["name"].as_str().unwrap_or(""))
-            .current_dir(path)
-            .creation_flags(0x08000000)
-            .output()
-            .map_err(|e| e.to_string())?;
-
-        if !out.status.success() {
-            return Err(format!(
-                "Git command failed (exit {}): {}\n{}",
-                out.status.code().unwrap_or(-1),
-                String::from_utf8_lossy(&out.stderr),
-                String::from_utf8_lossy(&out.stdout)
-            ));
-        }
-
-        return Ok(serde_json::json!({
-            "stdout": String::from_utf8_lossy(&out.stdout),
-            "stderr": String::from_utf8_lossy(&out.stderr)
-        }));
-    }
-    if action == "checkout" {
-        let out = Command::new("git")
-            .arg("checkout")
-            .arg(payload["name"].as_str().unwrap_or(""))
-            .current_dir(path)
-            .creation_flags(0x08000000)
-            .output()
-            .map_err(|e| e.to_string())?;
-
-        if !out.status.success() {
-            return Err(format!(
-                "Git command failed (exit {}): {}\n{}",
-                out.status.code().unwrap_or(-1),
-                String::from_utf8_lossy(&out.stderr),
-                String::from_utf8_lossy(&out.stdout)
-            ));
-        }
-
-        return Ok(serde_json::json!({
-            "stdout": String::from_utf8_lossy(&out.stdout),
-            "stderr": String::from_utf8_lossy(&out.stderr)
-        }));
-    }
-    if action == "pull" {
-        let out = Command::new("git")
-            .arg("pull")
-            .arg(payload["remote"].as_str().unwrap_or("origin"))
-            .arg(payload["branch"].as_str().unwrap_or("master"))
-            .current_dir(path)
-            .creation_flags(0x08000000)
-            .output()
-            .map_err(|e| e.to_string())?;
-        if !out.status.success() {
-            return Err(format!(
-                "Git command failed (exit {}): {}\n{}",
-                out.status.code().unwrap_or(-1),
-                String::from_utf8_lossy(&out.stderr),
-                String::from_utf8_lossy(&out.stdout)
-            ));
-        }
-        println!("{}", String::from_utf8_lossy(&out.stdout));
-        return Ok(serde_json::json!({
-            "stdout": String::from_utf8_lossy(&out.stdout),
-            "stderr": String::from_utf8_lossy(&out.stderr)
-        }));
-    }
-    if action == "set-remote" {
-        let url = payload["url"].as_str().unwrap_or("");
-
-        // Add remote
-        let out = Command::new("git")
-            .arg("remote")
-            .arg("add")
-            .arg("origin")
-            .arg(url)
-            .current_dir(path)
-            .creation_flags(0x08000000)
-            .output()
-            .map_err(|e| e.to_string())?;
-
-        if !out.status.success() {
-            return Err(format!(
-                "Git remote add failed: {}",
-                String::from_utf8_lossy(&out.stderr)
-            ));
-        }
-
-        // 🚨 FETCH remote branches so origin/main exists
-        let fetch = Command::new("git")
-            .arg("fetch")
-            .arg("origin")
-            .current_dir(path)
-            .creation_flags(0x08000000)
-            .output()
-            .map_err(|e| e.to_string())?;
-
-        if !fetch.status.success() {
-            return Err(format!(
-                "Git fetch failed: {}",
-                String::from_utf8_lossy(&fetch.stderr)
-            ));
-        }
-
-        // Get list of remote branches
-        let remote_branch_list = Command::new("git")
-            .args(["branch", "-r", "--format=%(refname:short)"])
-            .current_dir(path)
-            .creation_flags(0x08000000)
-            .output()
-            .map_err(|e| e.to_string())?;
-
-        if !remote_branch_list.status.success() {
-            return Err(format!(
-                "Failed to list remote branches: {}\n{}",
-                String::from_utf8_lossy(&remote_branch_list.stderr),
-                String::from_utf8_lossy(&remote_branch_list.stdout)
-            ));
-        }
-
-        // Parse remote branch names (strip "origin/" prefix)
-        let remote_branches: Vec<String> = String::from_utf8_lossy(&remote_branch_list.stdout)
-            .lines()
-            .map(|s| s.trim().to_string())
-            .filter(|s| s.starts_with("origin/"))
-            .map(|s| s.strip_prefix("origin/").unwrap().to_string())
-            .collect();
-
-        let branch_list = Command::new("git")
-            .args(["branch", "--format=%(refname:short)"])
-            .current_dir(path)
-            .creation_flags(0x08000000)
-            .output()
-            .map_err(|e| e.to_string())?;
-
-        if !branch_list.status.success() {
-            return Err(format!(
-                "Failed to list branches: {}\n{}",
-                String::from_utf8_lossy(&branch_list.stderr),
-                String::from_utf8_lossy(&branch_list.stdout)
-            ));
-        }
-
-        // Step 2: Parse branch names
-        let local_branches: Vec<String> = String::from_utf8_lossy(&branch_list.stdout)
-            .lines()
-            .map(|s| s.trim().to_string())
-            .filter(|s| !s.is_empty())
-            .collect();
-
-        // Step 3: Loop and set upstream only for branches that exist on remote
-        for branch in local_branches {
-            if remote_branches.contains(&branch) {
-                let remote_branch = format!("origin/{}", branch);
-                let out = Command::new("git")
-                    .arg("branch")
-                    .arg(format!("--set-upstream-to={}", &remote_branch))
-                    .arg(&branch)
-                    .current_dir(path)
-                    .creation_flags(0x08000000)
-                    .output()
-                    .map_err(|e| e.to_string())?;
+            None => all.extend(lines),
+        })?;
+        Ok(all)
+    })
+    .await?
+}
 
-                if !out.status.success() {
-                    return Err(format!(
-                        "Failed to set upstream for branch '{}': {}\n{}",
-                        branch,
-                        String::from_utf8_lossy(&out.stderr),
-                        String::from_utf8_lossy(&out.stdout)
-                    ));
-                }
-            }
+/// A job reporting `git-progress` events, cancellable with `git_cancel`.
+fn git_job(app: &AppHandle, job_id: String) -> Result<Arc<git::Job>, git::GitError> {
+    let app = app.clone();
+    git::Job::new(job_id, move |progress| {
+        if let Err(e) = app.emit("git-progress", progress) {
+            eprintln!("[JOB] Failed to emit git-progress: {}", e);
         }
+    })
+}
 
-        return Ok(serde_json::json!({
-            "status": "success",
-            "message": "Upstream set for all local branches."
-        }));
+fn emit_job_finished<T: Serialize>(
+    app: &AppHandle,
+    job: &git::Job,
+    result: &Result<T, git::GitError>,
+) {
+    let finished = git::JobFinished {
+        job_id: job.id().to_string(),
+        result: result
+            .as_ref()
+            .ok()
+            .and_then(|value| serde_json::to_value(value).ok()),
+        error: result.as_ref().err().cloned(),
+    };
+    if let Err(e) = app.emit("git-job-finished", finished) {
+        eprintln!("[JOB] Failed to emit git-job-finished: {}", e);
     }
+}
 
-    if action == "remove origin" {
-        let out = Command::new("git")
-            .arg("remote")
-            .arg("rm")
-            .arg("origin")
-            .current_dir(path)
-            .creation_flags(0x08000000)
-            .output()
-            .map_err(|e| e.to_string())?;
-        if !out.status.success() {
-            return Err(format!(
-                "Git command failed (exit {}): {}\n{}",
-                out.status.code().unwrap_or(-1),
-                String::from_utf8_lossy(&out.stderr),
-                String::from_utf8_lossy(&out.stdout)
-            ));
-        }
-        println!("{}", String::from_utf8_lossy(&out.stdout));
-        return Ok(serde_json::json!({
-            "stdout": String::from_utf8_lossy(&out.stdout),
-            "stderr": String::from_utf8_lossy(&out.stderr)
-        }));
+#[tauri::command]
+async fn git_clone(
+    repo_url: String,
+    target_dir: String,
+    job_id: Option<String>,
+    recurse_submodules: Option<bool>,
+    app: AppHandle,
+) -> Result<bool, git::GitError> {
+    if repo_url.is_empty() || target_dir.is_empty() {
+        return Err(git::GitError::invalid(
+            "Repository URL and target directory are required",
+        ));
     }
-    if action == "graph" {
-        use regex::Regex;
-        let output = Command::new("git")
-            .arg("log")
-            .arg("--graph")
-            .arg("--pretty=format:%h|%s|%d")
-            .current_dir(path)
-            .creation_flags(0x08000000)
-            .output()
-            .map_err(|e| e.to_string())?;
-
-        if !output.status.success() {
-            return Err(String::from_utf8_lossy(&output.stderr).to_string());
-        }
-
-        let stdout = String::from_utf8_lossy(&output.stdout);
-        let re = Regex::new(r"^([|\\/\* ]*)(.*)$").unwrap();
-
-        let mut commits = Vec::new();
-
-        for line in stdout.lines() {
-            if let Some(caps) = re.captures(line) {
-                let graph_ascii = caps
-                    .get(1)
-                    .map(|m| m.as_str().to_string())
-                    .unwrap_or_default();
-                let rest = caps.get(2).map(|m| m.as_str()).unwrap_or("").trim();
-
-                let fields: Vec<&str> = rest.splitn(3, '|').collect();
-                let hash = fields.get(0).unwrap_or(&"").trim();
-                let message = fields.get(1).unwrap_or(&"").trim();
-                let refs = fields.get(2).unwrap_or(&"").trim();
+    let recurse_submodules = recurse_submodules.unwrap_or(false);
+    let job = job_id.map(|id| git_job(&app, id)).transpose()?;
+    tokio::task::spawn_blocking(move || match job {
+        Some(job) => {
+            let result = job.run(|| git::clone(&repo_url, &target_dir, recurse_submodules));
+            emit_job_finished(&app, &job, &result);
+            result
+        }
+        None => git::clone(&repo_url, &target_dir, recurse_submodules),
+    })
+    .await?
+}
 
-                // Skip graph-only continuation lines (like "\" or "|")
-                if hash.is_empty() && message.is_empty() {
-                    commits.push(serde_json::json!({
-                        "graph": graph_ascii,
-                        "hash": "",
-                        "message": "",
-                        "isHead": false,
-                        "remote": false
-                    }));
-                    continue;
-                }
+/// Starts a `git_command` action as a job and returns right away. Progress
+/// comes as `git-progress` events and the outcome as `git-job-finished`.
+#[tauri::command]
+async fn git_start_job(
+    job_id: String,
+    action: String,
+    payload: serde_json::Value,
+    app: AppHandle,
+) -> Result<(), git::GitError> {
+    let request = git::GitRequest::parse(&action, payload)?;
+    let job = git_job(&app, job_id)?;
+    tokio::task::spawn_blocking(move || {
+        let result = job.run(|| git::run(request));
+        emit_job_finished(&app, &job, &result);
+    });
+    Ok(())
+}
 
-                commits.push(serde_json::json!({
-                    "graph": graph_ascii,
-                    "hash": hash,
-                    "message": message,
-                    "isHead": refs.contains("HEAD"),
-                    "remote": refs.contains("origin"),
-                }));
-            }
+/// Fetches `workspace` in the background every `interval_secs` (five
+/// minutes by default), emitting `git-sync-changed` when ahead/behind moves.
+#[tauri::command]
+fn git_auto_fetch_start(
+    workspace: String,
+    interval_secs: Option<u64>,
+    app: AppHandle,
+) -> Result<(), git::GitError> {
+    git::start_auto_fetch(&workspace, interval_secs.unwrap_or(300), move |changed| {
+        if let Err(e) = app.emit("git-sync-changed", changed) {
+            eprintln!("[AUTO-FETCH] Failed to emit git-sync-changed: {}", e);
         }
-
-        return Ok(commits.into());
-    }
-
-    Err(format!("Unknown git action: {}", action))
+    })
 }
 
 #[tauri::command]
-async fn git_clone(repo_url: String, target_dir: String) -> Result<bool, String> {
-    if repo_url.is_empty() || target_dir.is_empty() {
-        return Err("Repository URL and target directory are required".into());
-    }
+fn git_auto_fetch_stop(workspace: String) -> Result<bool, git::GitError> {
+    git::stop_auto_fetch(&workspace)
+}
 
-    let output = Command::new("git")
-        .args(["clone", &repo_url, &target_dir])
-        .creation_flags(0x08000000)
-        .output()
-        .map_err(|e| format!("Failed to start git process: {}", e))?;
+/// Sets or clears the token git uses for https remotes on `host`,
+/// `github.com` by default.
+#[tauri::command]
+fn git_set_token(host: Option<String>, username: Option<String>, token: Option<String>) {
+    git::set_token(
+        host.as_deref().unwrap_or("github.com"),
+        username.as_deref(),
+        token,
+    );
+}
 
-    if output.status.success() {
-        Ok(true)
-    } else {
-        let stderr = String::from_utf8_lossy(&output.stderr).to_string();
-        Err(format!(
-            "Git clone failed with code {}: {}",
-            output.status.code().unwrap_or(-1),
-            stderr
-        ))
-    }
+#[tauri::command]
+fn git_cancel(job_id: String) -> bool {
+    git::cancel(&job_id)
 }
 
 #[derive(Deserialize)]
@@ -1205,6 +547,13 @@ pub fn run() {
             replace_in_workspace,
             git_clone,
             git_command,
+            git_start_job,
+            git_cancel,
+            git_set_token,
+            git_auto_fetch_start,
+            git_auto_fetch_stop,
+            git_diff,
+            git_blame,
             watch_workspace,
             get_user_access_token,
             generate_project
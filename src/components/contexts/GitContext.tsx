@@ -1,5 +1,6 @@
-import { createContext, useContext, useState } from "react";
+import { createContext, useContext, useRef, useState } from "react";
 import { invoke } from "@tauri-apps/api/core";
+import { listen } from "@tauri-apps/api/event";
 import { useUser } from "@clerk/clerk-react";
 import { message } from "@tauri-apps/plugin-dialog";
 import { useEffect } from "react";
@@ -49,6 +50,16 @@ interface GitContextType {
   branches: string[];
   setBranches: React.Dispatch<React.SetStateAction<string[]>>;
 }
+interface GitSyncChanged {
+  workspace: string;
+  branches: {
+    name: string;
+    upstream: string;
+    ahead: number;
+    behind: number;
+  }[];
+  error: { kind: string; message: string } | null;
+}
 const GitContext = createContext<GitContextType | undefined>(undefined);
 export const GitProvider = ({ children }: { children: React.ReactNode }) => {
   const workspace = localStorage.getItem("workspacePath");
@@ -58,6 +69,7 @@ export const GitProvider = ({ children }: { children: React.ReactNode }) => {
     unstaged: [],
     untracked: [],
     ignored: [],
+    conflicts: [],
     branch: "master",
     origin: "",
   });
@@ -105,14 +117,17 @@ export const GitProvider = ({ children }: { children: React.ReactNode }) => {
   }
   function normalizeGitPayloadPaths(status: GitStatus): GitStatus {
     const fixSlash = (p: string) => p.replace(/\//g, "\\");
+    const fixFile = (f: Gitfile) => ({
+      ...f,
+      path: fixSlash(f.path),
+      origPath: f.origPath && fixSlash(f.origPath),
+    });
     return {
-      staged: status.staged.map((f) => ({ ...f, path: fixSlash(f.path) })),
-      unstaged: status.unstaged.map((f) => ({ ...f, path: fixSlash(f.path) })),
-      untracked: status.untracked.map((f) => ({
-        ...f,
-        path: fixSlash(f.path),
-      })),
-      ignored: status.ignored.map((f) => ({ ...f, path: fixSlash(f.path) })),
+      staged: status.staged.map(fixFile),
+      unstaged: status.unstaged.map(fixFile),
+      untracked: status.untracked.map(fixFile),
+      ignored: status.ignored.map(fixFile),
+      conflicts: (status.conflicts ?? []).map(fixFile),
       branch: status.branch || "master",
       origin: status.origin,
     };
@@ -122,11 +137,9 @@ export const GitProvider = ({ children }: { children: React.ReactNode }) => {
       const result = await invoke<T>("git_command", { action, payload });
       return result;
     } catch (e: any) {
-      // Check if it's a lock file conflict (exit code 128 with index.lock message)
+      // The backend rejects with { kind, message }, see GitErrorKind in src-tauri
       const isLockConflict =
-        e.code === 128 &&
-        e.message?.includes("index.lock") &&
-        e.message?.includes("File exists");
+        e.kind === "Locked" && e.message?.includes("index.lock");
 
       if (isLockConflict && (payload as any).workspace) {
         try {
@@ -153,7 +166,7 @@ export const GitProvider = ({ children }: { children: React.ReactNode }) => {
       }
 
       const err: GitError = new Error(e.message || String(e));
-      err.code = e.code || "GIT_ACTION_FAILED";
+      err.code = e.kind || e.code || "GIT_ACTION_FAILED";
       err.details = e.stack || undefined;
       throw err;
     }
@@ -202,6 +215,40 @@ export const GitProvider = ({ children }: { children: React.ReactNode }) => {
       checkRemoteBranchExists().then(setRemoteBranchExists);
     }
   }, [status.branch]);
+  // The backend fetches in the background and reports ahead/behind of
+  // every tracked branch, see git_auto_fetch_start in src-tauri
+  const branchRef = useRef(status.branch);
+  branchRef.current = status.branch;
+  useEffect(() => {
+    if (!workspace) return;
+    const unlistenPromise = listen<GitSyncChanged>(
+      "git-sync-changed",
+      (event) => {
+        if (event.payload.workspace !== workspace) return;
+        const current = event.payload.branches.find(
+          (b) => b.name === branchRef.current
+        );
+        setSyncStatus({
+          ahead: current?.ahead ?? 0,
+          behind: current?.behind ?? 0,
+        });
+        if (event.payload.error) {
+          console.warn("Background fetch failed", event.payload.error);
+        }
+      }
+    );
+    invoke("git_auto_fetch_start", { workspace }).catch((e) =>
+      console.error("Failed to start background fetch", e)
+    );
+    return () => {
+      invoke("git_auto_fetch_stop", { workspace }).catch((e) =>
+        console.error("Failed to stop background fetch", e)
+      );
+      unlistenPromise
+        .then((unlisten) => unlisten())
+        .catch((e) => console.error("Failed to unlisten git-sync-changed:", e));
+    };
+  }, [workspace]);
   async function fetchSyncStatus() {
     if (!workspace) return;
     incrementLoading();
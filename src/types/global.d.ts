@@ -150,13 +150,22 @@ declare global {
   }
   interface Gitfile {
     path: string;
-    status: "A" | "M" | "U" | "D";
+    status: "A" | "M" | "U" | "D" | "R" | "C" | "T";
+    index?: string;
+    worktree?: string;
+    origPath?: string;
+    submodule?: {
+      commitChanged: boolean;
+      trackedChanges: boolean;
+      untrackedChanges: boolean;
+    };
   }
   interface GitStatus {
     staged: GitFile[];
     unstaged: GitFile[];
     untracked: GitFile[];
     ignored: GitFile[];
+    conflicts: GitFile[];
     branch?: string;
     origin?: string;
   }